@@ -0,0 +1,129 @@
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    io::split,
+    net::{TcpListener, TcpStream},
+    sync::Mutex,
+    task::JoinHandle,
+    time::{interval, timeout},
+};
+
+use crate::{
+    cluster::state::Cluster,
+    pubsub::state::{publish, PubSub},
+    resp::{
+        resp::{send_command, unwrap_value_to_string, write_value, RespReader},
+        value::Value,
+    },
+};
+
+const CRON_PERIOD: Duration = Duration::from_millis(100);
+const REPLY_TIMEOUT: Duration = Duration::from_millis(500);
+
+//Serve gossip from other nodes and keep pinging them
+pub async fn run_cluster_bus(listener: TcpListener, cluster: Arc<Mutex<Cluster>>, pubsub: Arc<Mutex<PubSub>>) {
+    spawn_cluster_cron(cluster.clone());
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let cluster = cluster.clone();
+                let pubsub = pubsub.clone();
+                tokio::spawn(async move {
+                    handle_bus_connection(stream, cluster, pubsub).await;
+                });
+            }
+            Err(e) => eprintln!("Got error when listenning on cluster bus... --- error: {}", e),
+        }
+    }
+}
+
+fn spawn_cluster_cron(cluster: Arc<Mutex<Cluster>>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut cron = interval(CRON_PERIOD);
+        loop {
+            cron.tick().await;
+            let targets = {
+                let mut cluster = cluster.lock().await;
+                cluster.check_failures();
+                cluster.ping_targets()
+            };
+            for (host, bus_port, message) in targets {
+                let cluster = cluster.clone();
+                tokio::spawn(async move {
+                    //no reply is fine, the node just stays silent for failure detection
+                    if let Ok(Ok(Value::Array(reply))) =
+                        timeout(REPLY_TIMEOUT, send_command(&host, bus_port, &message)).await
+                    {
+                        let reply = value_to_strings(&reply);
+                        if let Err(e) = cluster.lock().await.process_gossip(&reply) {
+                            eprintln!("Bad gossip reply from {}:{} --- error: {}", host, bus_port, e);
+                        }
+                    }
+                });
+            }
+        }
+    })
+}
+
+async fn handle_bus_connection(stream: TcpStream, cluster: Arc<Mutex<Cluster>>, pubsub: Arc<Mutex<PubSub>>) {
+    let (reader, writer) = split(stream);
+    let mut reader = RespReader::new(reader);
+    let writer = Arc::new(Mutex::new(writer));
+    loop {
+        match reader.read_value().await {
+            //PUBLISH <channel> <message> fanned out by another node
+            Ok(Some((Value::Array(message), _)))
+                if message.len() == 3 && unwrap_value_to_string(&message[0]).unwrap_or_default() == "PUBLISH" =>
+            {
+                let message = value_to_strings(&message);
+                let count = publish(pubsub.clone(), &message[1], &message[2]).await;
+                write_value(writer.clone(), Value::SimpleInterger(count.to_string()).serialize()).await;
+            }
+            Ok(Some((Value::Array(message), _))) => {
+                let message = value_to_strings(&message);
+                let mut cluster = cluster.lock().await;
+                let result = match cluster.process_gossip(&message) {
+                    Ok(()) => Value::Array(
+                        cluster
+                            .gossip_message("PONG")
+                            .into_iter()
+                            .map(Value::BulkString)
+                            .collect(),
+                    ),
+                    Err(e) => Value::SimpleError(format!("ERR {}", e)),
+                };
+                drop(cluster);
+                write_value(writer.clone(), result.serialize()).await;
+            }
+            Ok(Some(_)) => {
+                write_value(
+                    writer.clone(),
+                    Value::SimpleError("ERR Bad cluster bus message".to_string()).serialize(),
+                )
+                .await;
+            }
+            Ok(None) => break,
+            Err(e) => {
+                eprintln!("Got error when read cluster bus message : {}", e);
+                break;
+            }
+        }
+    }
+}
+
+fn value_to_strings(values: &[Value]) -> Vec<String> {
+    values
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap_or_default())
+        .collect()
+}
+
+//Fan a published message out to every other node, classic pub/sub is cluster wide
+pub fn broadcast_publish(peers: Vec<(String, usize)>, channel: &str, message: &str) {
+    let command = vec!["PUBLISH".to_string(), channel.to_string(), message.to_string()];
+    for (host, bus_port) in peers {
+        let command = command.clone();
+        tokio::spawn(async move {
+            let _ = timeout(REPLY_TIMEOUT, send_command(&host, bus_port, &command)).await;
+        });
+    }
+}
@@ -0,0 +1,30 @@
+pub const CLUSTER_SLOTS: usize = 16384;
+
+//CRC16 XMODEM, the one used by redis cluster
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            if crc & 0x8000 != 0 {
+                crc = (crc << 1) ^ 0x1021;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+    crc
+}
+
+//Only the part inside the first non empty {...} is hashed when there is one
+pub fn key_hash_slot(key: &str) -> usize {
+    let key = key.as_bytes();
+    if let Some(start) = key.iter().position(|c| *c == b'{') {
+        if let Some(length) = key[start + 1..].iter().position(|c| *c == b'}') {
+            if length > 0 {
+                return crc16(&key[start + 1..start + 1 + length]) as usize % CLUSTER_SLOTS;
+            }
+        }
+    }
+    crc16(key) as usize % CLUSTER_SLOTS
+}
@@ -0,0 +1,695 @@
+use anyhow::Result;
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    cluster::slot::{key_hash_slot, CLUSTER_SLOTS},
+    rdb::{argument::Argument, replication::generate_replid},
+    resp::value::Value,
+    store::store::Store,
+};
+
+//Cluster bus listens on the client port plus this offset
+pub const CLUSTER_BUS_PORT_OFFSET: usize = 10000;
+//Failure reports older than this many node timeouts are ignored
+const FAILURE_REPORT_VALIDITY_MULT: u32 = 2;
+const MAX_PING_PERIOD: Duration = Duration::from_secs(1);
+
+#[derive(Clone)]
+pub struct ClusterNode {
+    pub id: String,
+    pub host: String,
+    pub port: usize,
+    pub config_epoch: u64,
+    //MEET was sent but the node has not answered yet
+    pub handshake: bool,
+    //the id is a random one we made up, the node has not told us its own yet
+    pub placeholder: bool,
+    //we can not reach it
+    pub pfail: bool,
+    //enough masters agree it can not be reached
+    pub fail: bool,
+    pub created: Instant,
+    pub last_ping_sent: Option<Instant>,
+    pub last_pong: Option<Instant>,
+    //reporter node id -> when it flagged this node as failing
+    pub failure_reports: HashMap<String, Instant>,
+}
+impl ClusterNode {
+    pub fn new(id: String, host: String, port: usize) -> Self {
+        ClusterNode {
+            id,
+            host,
+            port,
+            config_epoch: 0,
+            handshake: false,
+            placeholder: false,
+            pfail: false,
+            fail: false,
+            created: Instant::now(),
+            last_ping_sent: None,
+            last_pong: None,
+            failure_reports: HashMap::new(),
+        }
+    }
+    //State of the node as told to others in gossip
+    fn gossip_flag(&self) -> &str {
+        if self.fail {
+            "fail"
+        } else if self.pfail {
+            "pfail"
+        } else {
+            "ok"
+        }
+    }
+}
+
+pub struct Cluster {
+    enabled: bool,
+    myself: String,
+    nodes: HashMap<String, ClusterNode>,
+    //owner node id of every slot
+    slots: Vec<Option<String>>,
+    current_epoch: u64,
+    node_timeout: Duration,
+    //slot -> node id the slot is moving to / coming from
+    migrating: HashMap<usize, String>,
+    importing: HashMap<usize, String>,
+}
+impl Cluster {
+    pub fn from_argument(rdb_argument: &Argument) -> Result<Self> {
+        let myself = ClusterNode::new(
+            generate_replid(),
+            "127.0.0.1".to_string(),
+            rdb_argument.get_port()?,
+        );
+        let mut cluster = Cluster {
+            enabled: rdb_argument.is_cluster_enabled(),
+            myself: myself.id.clone(),
+            nodes: HashMap::from([(myself.id.clone(), myself)]),
+            slots: vec![None; CLUSTER_SLOTS],
+            current_epoch: 0,
+            node_timeout: Duration::from_millis(rdb_argument.get_cluster_node_timeout()?),
+            migrating: HashMap::new(),
+            importing: HashMap::new(),
+        };
+        //"<host> <port> <start>-<end>[,<start>-<end>...]" of nodes known at startup,
+        //they get their real id once they answer on the bus
+        for node in rdb_argument.get_cluster_nodes()? {
+            let parts = node.split_whitespace().collect::<Vec<&str>>();
+            if parts.len() != 3 {
+                return Err(anyhow::anyhow!("Cluster node needs <host> <port> <slots>, got {}", node));
+            }
+            let mut node = ClusterNode::new(
+                generate_replid(),
+                parts[0].to_string(),
+                parts[1].parse::<usize>()?,
+            );
+            node.placeholder = true;
+            for slot in parse_slot_ranges(parts[2])? {
+                cluster.slots[slot] = Some(node.id.clone());
+            }
+            cluster.nodes.insert(node.id.clone(), node);
+        }
+        Ok(cluster)
+    }
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+    pub fn get_myself(&self) -> &ClusterNode {
+        self.nodes.get(&self.myself).unwrap()
+    }
+    pub fn get_slot_owner(&self, slot: usize) -> Option<&ClusterNode> {
+        self.slots[slot].as_ref().and_then(|owner| self.nodes.get(owner))
+    }
+    //Error to reply when the keys of a command are not served by this node
+    pub fn check_keys(&self, keys: &[String], asking: bool, storage: &Store) -> Option<Value> {
+        if !self.enabled || keys.is_empty() {
+            return None;
+        }
+        let slot = key_hash_slot(&keys[0]);
+        if keys.iter().any(|key| key_hash_slot(key) != slot) {
+            return Some(Value::SimpleError(
+                "CROSSSLOT Keys in request don't hash to the same slot".to_string(),
+            ));
+        }
+        if self.slots[slot].as_ref() == Some(&self.myself) {
+            //keys already moved away are asked to the target
+            if let Some(target) = self.migrating.get(&slot).and_then(|target| self.nodes.get(target)) {
+                let missing = keys.iter().filter(|key| !storage.exists(key)).count();
+                if missing == keys.len() {
+                    return Some(Value::SimpleError(format!(
+                        "ASK {} {}:{}",
+                        slot, target.host, target.port
+                    )));
+                } else if missing > 0 {
+                    return Some(Value::SimpleError(
+                        "TRYAGAIN Multiple keys request during rehashing of slot".to_string(),
+                    ));
+                }
+            }
+            return None;
+        }
+        if asking && self.importing.contains_key(&slot) {
+            return None;
+        }
+        match self.get_slot_owner(slot) {
+            Some(owner) if owner.fail => Some(Value::SimpleError(
+                "CLUSTERDOWN The cluster is down".to_string(),
+            )),
+            Some(owner) => Some(Value::SimpleError(format!(
+                "MOVED {} {}:{}",
+                slot, owner.host, owner.port
+            ))),
+            None => Some(Value::SimpleError(
+                "CLUSTERDOWN Hash slot not served".to_string(),
+            )),
+        }
+    }
+    //Assign slots to this node, nothing changes when one of them is taken
+    pub fn add_slots(&mut self, slots: &[usize]) -> Value {
+        for slot in slots {
+            if *slot >= CLUSTER_SLOTS {
+                return Value::SimpleError("ERR Invalid or out of range slot".to_string());
+            }
+            if self.slots[*slot].is_some() {
+                return Value::SimpleError(format!("ERR Slot {} is already busy", slot));
+            }
+        }
+        for slot in slots {
+            self.slots[*slot] = Some(self.myself.clone());
+        }
+        Value::SimpleString("OK".to_string())
+    }
+    //Start a handshake, the node joins once it answers on the bus
+    pub fn meet(&mut self, host: String, port: usize) -> Value {
+        let known = self
+            .nodes
+            .values()
+            .any(|node| node.host == host && node.port == port);
+        if !known {
+            let mut node = ClusterNode::new(generate_replid(), host, port);
+            node.handshake = true;
+            node.placeholder = true;
+            self.nodes.insert(node.id.clone(), node);
+        }
+        Value::SimpleString("OK".to_string())
+    }
+    //CLUSTER SETSLOT <slot> MIGRATING|IMPORTING|NODE <node-id> and STABLE
+    pub fn set_slot(
+        &mut self,
+        slot: usize,
+        action: &str,
+        node_id: Option<&str>,
+        keys_in_slot: usize,
+    ) -> Value {
+        let owned = self.slots[slot].as_ref() == Some(&self.myself);
+        let node_id = match (action, node_id) {
+            ("STABLE", _) => {
+                self.migrating.remove(&slot);
+                self.importing.remove(&slot);
+                return Value::SimpleString("OK".to_string());
+            }
+            (_, Some(node_id)) if self.nodes.contains_key(node_id) => node_id.to_string(),
+            (_, Some(node_id)) => {
+                return Value::SimpleError(format!("ERR I don't know about node {}", node_id))
+            }
+            (_, None) => {
+                return Value::SimpleError(
+                    "ERR Invalid CLUSTER SETSLOT action or number of arguments. Try CLUSTER HELP"
+                        .to_string(),
+                )
+            }
+        };
+        match action {
+            "MIGRATING" => {
+                if !owned {
+                    return Value::SimpleError(format!(
+                        "ERR I'm not the owner of hash slot {}",
+                        slot
+                    ));
+                }
+                if node_id == self.myself {
+                    return Value::SimpleError("ERR Target node is myself".to_string());
+                }
+                self.migrating.insert(slot, node_id);
+            }
+            "IMPORTING" => {
+                if owned {
+                    return Value::SimpleError(format!(
+                        "ERR I'm already the owner of hash slot {}",
+                        slot
+                    ));
+                }
+                self.importing.insert(slot, node_id);
+            }
+            "NODE" => {
+                if owned && node_id != self.myself && keys_in_slot > 0 {
+                    return Value::SimpleError(format!(
+                        "ERR Can't assign hashslot {} to a different node while I still hold keys for this hash slot.",
+                        slot
+                    ));
+                }
+                if node_id != self.myself {
+                    self.migrating.remove(&slot);
+                } else if self.importing.remove(&slot).is_some() {
+                    //a fresh config epoch makes our claim win over the old owner in gossip
+                    self.current_epoch += 1;
+                    let current_epoch = self.current_epoch;
+                    self.nodes.get_mut(&self.myself).unwrap().config_epoch = current_epoch;
+                }
+                self.slots[slot] = Some(node_id);
+            }
+            _ => {
+                return Value::SimpleError(
+                    "ERR Invalid CLUSTER SETSLOT action or number of arguments. Try CLUSTER HELP"
+                        .to_string(),
+                )
+            }
+        }
+        Value::SimpleString("OK".to_string())
+    }
+
+    //=====================Cluster bus=========================//
+    //[kind, id, host, port, current epoch, config epoch, slots, "<id> <host> <port> <flag>"...]
+    pub fn gossip_message(&self, kind: &str) -> Vec<String> {
+        let myself = self.get_myself();
+        let slots = self
+            .slot_ranges(&self.myself)
+            .into_iter()
+            .map(|(start, end)| format!("{}-{}", start, end))
+            .collect::<Vec<String>>();
+        let mut message = vec![
+            kind.to_string(),
+            myself.id.clone(),
+            myself.host.clone(),
+            myself.port.to_string(),
+            self.current_epoch.to_string(),
+            myself.config_epoch.to_string(),
+            if slots.is_empty() { "-".to_string() } else { slots.join(",") },
+        ];
+        for node in self.nodes.values() {
+            //made up ids would spread to other nodes as nodes of their own
+            if node.id == self.myself || node.handshake || node.placeholder {
+                continue;
+            }
+            message.push(format!(
+                "{} {} {} {}",
+                node.id,
+                node.host,
+                node.port,
+                node.gossip_flag()
+            ));
+        }
+        message
+    }
+    //Bus address and message of every node due for a ping
+    pub fn ping_targets(&mut self) -> Vec<(String, usize, Vec<String>)> {
+        let ping_period = (self.node_timeout / 4).min(MAX_PING_PERIOD);
+        let mut targets = Vec::new();
+        for node in self.nodes.values_mut() {
+            if node.id == self.myself
+                || node
+                    .last_ping_sent
+                    .is_some_and(|sent| sent.elapsed() < ping_period)
+            {
+                continue;
+            }
+            node.last_ping_sent = Some(Instant::now());
+            //nodes that never answered do not know us yet
+            let kind = if node.last_pong.is_none() { "MEET" } else { "PING" };
+            targets.push((node.host.clone(), node.port + CLUSTER_BUS_PORT_OFFSET, kind));
+        }
+        targets
+            .into_iter()
+            .map(|(host, port, kind)| (host, port, self.gossip_message(kind)))
+            .collect()
+    }
+    //Bus address of every other node we can talk to
+    pub fn bus_peers(&self) -> Vec<(String, usize)> {
+        self.nodes
+            .values()
+            .filter(|node| node.id != self.myself && !node.handshake && !node.fail)
+            .map(|node| (node.host.clone(), node.port + CLUSTER_BUS_PORT_OFFSET))
+            .collect()
+    }
+    //Node already known at this address under another id: a placeholder added by MEET or at
+    //startup, or a node that came back with a new id. It is replaced rather than kept twice
+    fn find_by_address(&self, host: &str, port: usize) -> Option<String> {
+        self.nodes
+            .values()
+            .find(|node| node.id != self.myself && node.host == host && node.port == port)
+            .map(|node| node.id.clone())
+    }
+    fn is_myself_address(&self, host: &str, port: usize) -> bool {
+        let myself = self.get_myself();
+        myself.host == host && myself.port == port
+    }
+    fn rename_node(&mut self, old_id: &str, new_id: &str) {
+        let mut node = match self.nodes.remove(old_id) {
+            Some(node) => node,
+            None => return,
+        };
+        node.id = new_id.to_string();
+        node.placeholder = false;
+        self.nodes.insert(new_id.to_string(), node);
+        for owner in self.slots.iter_mut().flatten() {
+            if owner == old_id {
+                *owner = new_id.to_string();
+            }
+        }
+        for node_id in self.migrating.values_mut().chain(self.importing.values_mut()) {
+            if node_id == old_id {
+                *node_id = new_id.to_string();
+            }
+        }
+    }
+    //The config with the greater epoch wins the slot
+    fn claim_slot(&mut self, slot: usize, sender_id: &str, sender_epoch: u64) {
+        if let Some(owner) = self.get_slot_owner(slot) {
+            if owner.id == sender_id || owner.config_epoch >= sender_epoch {
+                return;
+            }
+            if owner.id == self.myself {
+                self.migrating.remove(&slot);
+            }
+        }
+        self.slots[slot] = Some(sender_id.to_string());
+    }
+    //Apply a PING/MEET/PONG from another node
+    pub fn process_gossip(&mut self, message: &[String]) -> Result<()> {
+        if message.len() < 7 {
+            return Err(anyhow::anyhow!("Gossip message is too short"));
+        }
+        let kind = message[0].to_uppercase();
+        let sender_id = message[1].clone();
+        let sender_host = message[2].clone();
+        let sender_port = message[3].parse::<usize>()?;
+        let current_epoch = message[4].parse::<u64>()?;
+        let config_epoch = message[5].parse::<u64>()?;
+        if sender_id == self.myself {
+            return Ok(());
+        }
+        if !self.nodes.contains_key(&sender_id) {
+            match self.find_by_address(&sender_host, sender_port) {
+                Some(old_id) => self.rename_node(&old_id, &sender_id),
+                None if kind == "MEET" => {
+                    let node = ClusterNode::new(sender_id.clone(), sender_host, sender_port);
+                    self.nodes.insert(sender_id.clone(), node);
+                }
+                //only MEET lets an unknown node join
+                None => return Ok(()),
+            }
+        }
+
+        let now = Instant::now();
+        self.current_epoch = self.current_epoch.max(current_epoch);
+        let sender = self.nodes.get_mut(&sender_id).unwrap();
+        sender.handshake = false;
+        sender.pfail = false;
+        sender.fail = false;
+        sender.last_pong = Some(now);
+        sender.config_epoch = config_epoch;
+        if message[6] != "-" {
+            for slot in parse_slot_ranges(&message[6])? {
+                self.claim_slot(slot, &sender_id, config_epoch);
+            }
+        }
+
+        for gossip in &message[7..] {
+            let parts = gossip.split_whitespace().collect::<Vec<&str>>();
+            let (id, host, port, flag) = match parts.as_slice() {
+                [id, host, port, flag] => (*id, *host, port.parse::<usize>()?, *flag),
+                _ => continue,
+            };
+            //another node's placeholder for us, or an old id of ours
+            if id == self.myself || self.is_myself_address(host, port) {
+                continue;
+            }
+            if !self.nodes.contains_key(id) {
+                match self.find_by_address(host, port) {
+                    Some(old_id) => self.rename_node(&old_id, id),
+                    None if flag == "fail" => continue,
+                    None => {
+                        let node = ClusterNode::new(id.to_string(), host.to_string(), port);
+                        self.nodes.insert(id.to_string(), node);
+                    }
+                }
+            }
+            let node = self.nodes.get_mut(id).unwrap();
+            match flag {
+                "pfail" => {
+                    node.failure_reports.insert(sender_id.clone(), now);
+                }
+                "fail" => {
+                    node.failure_reports.insert(sender_id.clone(), now);
+                    node.fail = true;
+                }
+                _ => {
+                    node.failure_reports.remove(&sender_id);
+                }
+            }
+        }
+        Ok(())
+    }
+    //Flag silent nodes as PFAIL, then FAIL once a majority of masters agree
+    pub fn check_failures(&mut self) {
+        let report_validity = self.node_timeout * FAILURE_REPORT_VALIDITY_MULT;
+        let masters = self
+            .slots
+            .iter()
+            .flatten()
+            .cloned()
+            .collect::<HashSet<String>>();
+        let needed = masters.len() / 2 + 1;
+        let i_am_master = masters.contains(&self.myself);
+
+        let mut expired_handshakes = Vec::new();
+        for node in self.nodes.values_mut() {
+            if node.id == self.myself {
+                continue;
+            }
+            let silent_since = node.last_pong.unwrap_or(node.created);
+            if node.handshake {
+                if silent_since.elapsed() > self.node_timeout {
+                    expired_handshakes.push(node.id.clone());
+                }
+                continue;
+            }
+            node.pfail = silent_since.elapsed() > self.node_timeout;
+            node.failure_reports
+                .retain(|reporter, reported| masters.contains(reporter) && reported.elapsed() <= report_validity);
+            if node.pfail && !node.fail {
+                let votes = node.failure_reports.len() + usize::from(i_am_master);
+                if votes >= needed {
+                    node.fail = true;
+                }
+            }
+        }
+        for node_id in expired_handshakes {
+            self.nodes.remove(&node_id);
+        }
+    }
+    //========================================================//
+
+    //Contiguous slot ranges owned by a node
+    fn slot_ranges(&self, node_id: &str) -> Vec<(usize, usize)> {
+        let mut ranges: Vec<(usize, usize)> = Vec::new();
+        for (slot, owner) in self.slots.iter().enumerate() {
+            if owner.as_deref() != Some(node_id) {
+                continue;
+            }
+            match ranges.last_mut() {
+                Some((_, end)) if *end + 1 == slot => *end = slot,
+                _ => ranges.push((slot, slot)),
+            }
+        }
+        ranges
+    }
+    fn node_endpoint(node: &ClusterNode) -> Value {
+        Value::Array(vec![
+            Value::BulkString(node.host.clone()),
+            Value::SimpleInterger(node.port.to_string()),
+            Value::BulkString(node.id.clone()),
+        ])
+    }
+    pub fn display_slots(&self) -> Value {
+        let mut ranges = self
+            .nodes
+            .values()
+            .flat_map(|node| {
+                self.slot_ranges(&node.id)
+                    .into_iter()
+                    .map(move |(start, end)| (start, end, node))
+            })
+            .collect::<Vec<(usize, usize, &ClusterNode)>>();
+        ranges.sort_by_key(|(start, _, _)| *start);
+        Value::Array(
+            ranges
+                .into_iter()
+                .map(|(start, end, node)| {
+                    Value::Array(vec![
+                        Value::SimpleInterger(start.to_string()),
+                        Value::SimpleInterger(end.to_string()),
+                        Self::node_endpoint(node),
+                    ])
+                })
+                .collect(),
+        )
+    }
+    pub fn display_shards(&self) -> Value {
+        let mut nodes = self
+            .nodes
+            .values()
+            .filter(|node| !node.handshake)
+            .collect::<Vec<&ClusterNode>>();
+        nodes.sort_by_key(|node| (node.host.clone(), node.port));
+        Value::Array(
+            nodes
+                .into_iter()
+                .map(|node| {
+                    let slots = self
+                        .slot_ranges(&node.id)
+                        .into_iter()
+                        .flat_map(|(start, end)| {
+                            [
+                                Value::SimpleInterger(start.to_string()),
+                                Value::SimpleInterger(end.to_string()),
+                            ]
+                        })
+                        .collect();
+                    let node_fields = vec![
+                        ("id", Value::BulkString(node.id.clone())),
+                        ("port", Value::SimpleInterger(node.port.to_string())),
+                        ("ip", Value::BulkString(node.host.clone())),
+                        ("endpoint", Value::BulkString(node.host.clone())),
+                        ("role", Value::BulkString("master".to_string())),
+                        ("replication-offset", Value::SimpleInterger("0".to_string())),
+                        (
+                            "health",
+                            Value::BulkString(if node.fail { "fail" } else { "online" }.to_string()),
+                        ),
+                    ];
+                    Value::Array(vec![
+                        Value::BulkString("slots".to_string()),
+                        Value::Array(slots),
+                        Value::BulkString("nodes".to_string()),
+                        Value::Array(vec![Value::Array(
+                            node_fields
+                                .into_iter()
+                                .flat_map(|(name, value)| [Value::BulkString(name.to_string()), value])
+                                .collect(),
+                        )]),
+                    ])
+                })
+                .collect(),
+        )
+    }
+    pub fn display_nodes(&self) -> Value {
+        let mut lines = Vec::new();
+        for node in self.nodes.values() {
+            let mut flags = Vec::new();
+            if node.id == self.myself {
+                flags.push("myself");
+            }
+            flags.push(if node.handshake { "handshake" } else { "master" });
+            if node.fail {
+                flags.push("fail");
+            } else if node.pfail {
+                flags.push("fail?");
+            }
+            let connected = node.id == self.myself
+                || node
+                    .last_pong
+                    .is_some_and(|pong| pong.elapsed() <= self.node_timeout);
+            let mut line = format!(
+                "{} {}:{}@{} {} - 0 0 {} {}",
+                node.id,
+                node.host,
+                node.port,
+                node.port + CLUSTER_BUS_PORT_OFFSET,
+                flags.join(","),
+                node.config_epoch,
+                if connected { "connected" } else { "disconnected" }
+            );
+            for (start, end) in self.slot_ranges(&node.id) {
+                if start == end {
+                    line += &format!(" {}", start);
+                } else {
+                    line += &format!(" {}-{}", start, end);
+                }
+            }
+            if node.id == self.myself {
+                let mut migrations = self
+                    .migrating
+                    .iter()
+                    .map(|(slot, target)| (*slot, format!(" [{}->-{}]", slot, target)))
+                    .chain(
+                        self.importing
+                            .iter()
+                            .map(|(slot, source)| (*slot, format!(" [{}-<-{}]", slot, source))),
+                    )
+                    .collect::<Vec<(usize, String)>>();
+                migrations.sort();
+                for (_, migration) in migrations {
+                    line += &migration;
+                }
+            }
+            lines.push(line);
+        }
+        lines.sort();
+        Value::BulkString(lines.join("\n") + "\n")
+    }
+    pub fn display_info(&self) -> Value {
+        let mut assigned = 0;
+        let mut pfail = 0;
+        let mut fail = 0;
+        for owner in self.slots.iter().flatten() {
+            assigned += 1;
+            match self.nodes.get(owner) {
+                Some(node) if node.fail => fail += 1,
+                Some(node) if node.pfail => pfail += 1,
+                _ => (),
+            }
+        }
+        let size = self
+            .slots
+            .iter()
+            .flatten()
+            .collect::<HashSet<&String>>()
+            .len();
+        let info = [
+            format!(
+                "cluster_state:{}",
+                if assigned == CLUSTER_SLOTS && fail == 0 { "ok" } else { "fail" }
+            ),
+            format!("cluster_slots_assigned:{}", assigned),
+            format!("cluster_slots_ok:{}", assigned - pfail - fail),
+            format!("cluster_slots_pfail:{}", pfail),
+            format!("cluster_slots_fail:{}", fail),
+            format!("cluster_known_nodes:{}", self.nodes.len()),
+            format!("cluster_size:{}", size),
+            format!("cluster_current_epoch:{}", self.current_epoch),
+            format!("cluster_my_epoch:{}", self.get_myself().config_epoch),
+        ];
+        Value::BulkString(info.join("\r\n"))
+    }
+}
+
+//"0-5460,5461" -> every slot number in the ranges
+pub fn parse_slot_ranges(ranges: &str) -> Result<Vec<usize>> {
+    let mut slots = Vec::new();
+    for range in ranges.split(',') {
+        let (start, end) = match range.split_once('-') {
+            Some((start, end)) => (start.parse::<usize>()?, end.parse::<usize>()?),
+            None => (range.parse::<usize>()?, range.parse::<usize>()?),
+        };
+        if start > end || end >= CLUSTER_SLOTS {
+            return Err(anyhow::anyhow!("Invalid slot range {}", range));
+        }
+        slots.extend(start..=end);
+    }
+    Ok(slots)
+}
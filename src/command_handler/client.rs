@@ -0,0 +1,120 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+use tokio::{io::WriteHalf, net::TcpStream, sync::Mutex};
+
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+
+//State that belongs to one connection
+pub struct Client {
+    id: u64,
+    address: String,
+    listening_port: Option<usize>,
+    //ASKING was sent, the next command may touch an importing slot
+    asking: bool,
+    //pub/sub subscriptions, any of them puts the connection in subscriber mode
+    channels: Vec<String>,
+    patterns: Vec<String>,
+    shard_channels: Vec<String>,
+    writer: Option<Arc<Mutex<WriteHalf<TcpStream>>>>,
+}
+impl Client {
+    pub fn new(address: String, writer: Option<Arc<Mutex<WriteHalf<TcpStream>>>>) -> Self {
+        Client {
+            id: NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed),
+            address,
+            listening_port: None,
+            asking: false,
+            channels: Vec::new(),
+            patterns: Vec::new(),
+            shard_channels: Vec::new(),
+            writer,
+        }
+    }
+    pub fn get_id(&self) -> u64 {
+        self.id
+    }
+    //Ip part of the peer address
+    pub fn get_ip(&self) -> String {
+        match self.address.rsplit_once(':') {
+            Some((ip, _)) => ip.to_string(),
+            None => self.address.clone(),
+        }
+    }
+    pub fn set_listening_port(&mut self, port: usize) {
+        self.listening_port = Some(port);
+    }
+    pub fn get_listening_port(&self) -> Option<usize> {
+        self.listening_port
+    }
+    pub fn set_asking(&mut self, asking: bool) {
+        self.asking = asking;
+    }
+    pub fn is_asking(&self) -> bool {
+        self.asking
+    }
+    //False when already subscribed
+    pub fn add_channel(&mut self, channel: &str) -> bool {
+        add_subscription(&mut self.channels, channel)
+    }
+    pub fn remove_channel(&mut self, channel: &str) -> bool {
+        remove_subscription(&mut self.channels, channel)
+    }
+    pub fn get_channels(&self) -> Vec<String> {
+        self.channels.clone()
+    }
+    pub fn add_pattern(&mut self, pattern: &str) -> bool {
+        add_subscription(&mut self.patterns, pattern)
+    }
+    pub fn remove_pattern(&mut self, pattern: &str) -> bool {
+        remove_subscription(&mut self.patterns, pattern)
+    }
+    pub fn get_patterns(&self) -> Vec<String> {
+        self.patterns.clone()
+    }
+    pub fn add_shard_channel(&mut self, channel: &str) -> bool {
+        add_subscription(&mut self.shard_channels, channel)
+    }
+    pub fn remove_shard_channel(&mut self, channel: &str) -> bool {
+        remove_subscription(&mut self.shard_channels, channel)
+    }
+    pub fn get_shard_channels(&self) -> Vec<String> {
+        self.shard_channels.clone()
+    }
+    pub fn count_subscriptions(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+    //Sharded subscriptions are counted on their own in the confirmations
+    pub fn count_shard_subscriptions(&self) -> usize {
+        self.shard_channels.len()
+    }
+    pub fn is_subscribed(&self) -> bool {
+        self.count_subscriptions() + self.count_shard_subscriptions() > 0
+    }
+    //RESET, back to a fresh connection that keeps its id
+    pub fn reset(&mut self) {
+        self.asking = false;
+        self.channels.clear();
+        self.patterns.clear();
+        self.shard_channels.clear();
+    }
+    //None for internal clients like the link with master
+    pub fn get_writer(&self) -> Option<Arc<Mutex<WriteHalf<TcpStream>>>> {
+        self.writer.clone()
+    }
+}
+
+fn add_subscription(subscriptions: &mut Vec<String>, name: &str) -> bool {
+    if subscriptions.iter().any(|subscription| subscription == name) {
+        return false;
+    }
+    subscriptions.push(name.to_string());
+    true
+}
+
+fn remove_subscription(subscriptions: &mut Vec<String>, name: &str) -> bool {
+    let len = subscriptions.len();
+    subscriptions.retain(|subscription| subscription != name);
+    subscriptions.len() != len
+}
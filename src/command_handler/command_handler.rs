@@ -1,64 +1,195 @@
 use crate::{
-    rdb::{argument::Argument, parse_rdb::RdbFile, replication::Replication},
-    resp::value::Value,
+    cluster::state::{parse_slot_ranges, Cluster},
+    cluster::slot::{key_hash_slot, CLUSTER_SLOTS},
+    cluster::bus::broadcast_publish,
+    command_handler::{client::Client, context::ServerContext},
+    pubsub::{
+        glob::glob_match,
+        notify::{notify_flags_to_string, parse_notify_flags, NOTIFY_STREAM},
+        state::{publish, spublish, PubSub},
+    },
+    rdb::{
+        argument::Argument,
+        master_link::spawn_master_link,
+        replication::{ReplicaHandler, Replication, Role},
+    },
+    resp::{
+        resp::{parse_payload, send_commands, unwrap_value_to_bytes, unwrap_value_to_string, write_value},
+        value::Value,
+    },
     store::{
+        bitmap::{self, BitOperation, BitfieldType, Overflow, BITMAP_MAX_OFFSET},
+        blocking::block_on_keys,
         entry::StreamEntryValidate,
-        store::{Store, StoreValueType},
+        geo::{self, GeoShape},
+        set::SetOperation,
+        sorted_set::{format_score, Aggregate, LexBound, ScoreBound, ZAddOptions, ZAddResult, ZRangeSpec},
+        hyperloglog,
+        lcs,
+        handle::StoreHandle,
+        store::{random_index, Store},
         transaction::Transaction,
     },
-    unwrap_value_to_string,
 };
 use anyhow::Result;
 use std::sync::Arc;
-use std::{collections::HashMap, time::Duration};
-use tokio::{sync::Mutex, time::sleep};
+use std::time::Duration;
+use tokio::sync::Mutex;
 
 pub async fn command_handler(
     command: String,
     command_content: Vec<Value>,
-    storage: Arc<Mutex<Store>>,
+    context: &ServerContext,
     rdb_argument: &mut Argument,
-    rdb_file: &mut RdbFile,
-    replication: Arc<Mutex<Replication>>,
     transaction: &mut Transaction,
+    client: &mut Client,
 ) -> Value {
+    let ServerContext {
+        storage,
+        replication,
+        cluster,
+        pubsub,
+    } = context.clone();
+    //inside MULTI everything but the transaction commands waits for EXEC
+    if transaction.is_in_multi()
+        && !matches!(command.as_str(), "MULTI" | "EXEC" | "DISCARD" | "WATCH" | "RESET")
+    {
+        return queue_command(command, command_content, transaction);
+    }
+    //handlers index their arguments, never hand them too few
+    if let Some(error) = check_arity(&command, &command_content) {
+        return error;
+    }
     match command.as_str() {
-        "PING" => handle_ping().expect("Error when handle PING"),
+        "PING" => handle_ping(command_content, client).expect("Error when handle PING"),
         "ECHO" => handle_echo(command_content).expect("Error when handle ECHO"),
-        "SET" => handle_set(command, command_content, transaction, storage)
+        "SET" => handle_set(command_content, storage)
             .await
             .expect("Error when handle SET"),
-        "GET" => handle_get(command, command_content, storage, rdb_file, transaction)
+        "GET" => handle_get(command_content, storage)
             .await
             .expect("Error when handle GET"),
-        "CONFIG" => handle_config(command_content, rdb_argument).expect("Error when handle CONFIG"),
-        "KEYS" => handle_key(command_content, rdb_file).expect("Error when handle KEY"),
+        "CONFIG" => handle_config(command_content, rdb_argument, storage, replication)
+            .await
+            .expect("Error when handle CONFIG"),
+        "KEYS" => handle_key(command_content, storage)
+            .await
+            .expect("Error when handle KEY"),
         "INFO" => handle_info(command_content, replication)
             .await
             .expect("Error when handle KEY"),
-        "REPLCONF" => handle_replconf().expect("Error when handle replconf"),
-        "PSYNC" => handle_psync(replication)
+        "REPLCONF" => handle_replconf(command_content, replication, client)
+            .await
+            .expect("Error when handle replconf"),
+        "PSYNC" => handle_psync(command_content, storage, replication, client)
             .await
             .expect("Error when handle psync"),
+        "REPLICAOF" | "SLAVEOF" => handle_replicaof(command_content, rdb_argument, context)
+            .await
+            .expect("Error when handle replicaof"),
+        "CLUSTER" => handle_cluster(command_content, storage, cluster)
+            .await
+            .expect("Error when handle cluster"),
+        "ASKING" => handle_asking(client).expect("Error when handle asking"),
+        "MIGRATE" => handle_migrate(command_content, storage)
+            .await
+            .expect("Error when handle migrate"),
+        "DUMP" => handle_dump(command_content, storage)
+            .await
+            .expect("Error when handle dump"),
+        "RESTORE" => handle_restore(command_content, storage)
+            .await
+            .expect("Error when handle restore"),
+        "SUBSCRIBE" | "PSUBSCRIBE" | "SSUBSCRIBE" => {
+            handle_subscribe(command_content, pubsub, client, &command.to_lowercase())
+                .await
+                .expect("Error when handle subscribe")
+        }
+        "UNSUBSCRIBE" | "PUNSUBSCRIBE" | "SUNSUBSCRIBE" => {
+            handle_unsubscribe(command_content, pubsub, client, &command.to_lowercase())
+                .await
+                .expect("Error when handle unsubscribe")
+        }
+        "PUBLISH" => handle_publish(command_content, replication, cluster, pubsub)
+            .await
+            .expect("Error when handle publish"),
+        "SPUBLISH" => handle_spublish(command_content, pubsub)
+            .await
+            .expect("Error when handle spublish"),
+        "PUBSUB" => handle_pubsub(command_content, pubsub)
+            .await
+            .expect("Error when handle pubsub"),
         "TYPE" => handle_type(command_content, storage)
             .await
             .expect("Error when handle type"),
+        "OBJECT" => handle_object(command_content, storage)
+            .await
+            .expect("Error when handle object"),
         "XADD" => handle_xadd(command_content, storage)
             .await
             .expect("Error when handle xadd"),
         "XRANGE" => handle_xrange(command_content, storage)
             .await
             .expect("Error when handle xrange"),
-        "XREAD" => handle_xread(command_content, storage)
+        "XREAD" => handle_xread(command_content, storage, !transaction.is_executing())
             .await
             .expect("Error when handle xread"),
-        "INCR" => handle_incr(command, command_content, storage, transaction)
+        "INCR" | "DECR" | "INCRBY" | "DECRBY" => handle_incrby(command_content, storage, &command)
+            .await
+            .expect("Error when handle incrby"),
+        "INCRBYFLOAT" => handle_incrbyfloat(command_content, storage)
+            .await
+            .expect("Error when handle incrbyfloat"),
+        "MGET" => handle_mget(command_content, storage)
+            .await
+            .expect("Error when handle mget"),
+        "MSET" | "MSETNX" => handle_mset(command_content, storage, &command)
+            .await
+            .expect("Error when handle mset"),
+        "GETSET" => handle_getset(command_content, storage)
+            .await
+            .expect("Error when handle getset"),
+        "GETDEL" => handle_getdel(command_content, storage)
+            .await
+            .expect("Error when handle getdel"),
+        "DEL" => handle_del(command_content, storage)
+            .await
+            .expect("Error when handle del"),
+        "GETEX" => handle_getex(command_content, storage)
+            .await
+            .expect("Error when handle getex"),
+        "SETNX" => handle_setnx(command_content, storage)
+            .await
+            .expect("Error when handle setnx"),
+        "SETEX" | "PSETEX" => handle_setex(command_content, storage, &command)
+            .await
+            .expect("Error when handle setex"),
+        "APPEND" => handle_append(command_content, storage)
+            .await
+            .expect("Error when handle append"),
+        "STRLEN" => handle_strlen(command_content, storage)
             .await
-            .expect("Error when handle incr"),
+            .expect("Error when handle strlen"),
+        "GETRANGE" => handle_getrange(command_content, storage)
+            .await
+            .expect("Error when handle getrange"),
+        "SETRANGE" => handle_setrange(command_content, storage)
+            .await
+            .expect("Error when handle setrange"),
+        "LCS" => handle_lcs(command_content, storage)
+            .await
+            .expect("Error when handle lcs"),
         "MULTI" => handle_multi(transaction)
             .await
             .expect("Error when handle multi"),
         "DISCARD" => handle_discard(transaction).expect("Error when handle discard"),
+        "WATCH" => handle_watch(command_content, storage, transaction)
+            .await
+            .expect("Error when handle watch"),
+        "UNWATCH" => handle_unwatch(transaction).expect("Error when handle unwatch"),
+        "RESET" => handle_reset(pubsub, transaction, client)
+            .await
+            .expect("Error when handle reset"),
         "RPUSH" => handle_rpush(command_content, storage)
             .await
             .expect("Error when handle rpush"),
@@ -71,38 +202,661 @@ pub async fn command_handler(
         "LLEN" => handle_llen(command_content, storage)
             .await
             .expect("Error when handle llen"),
-        "LPOP" => handle_lpop(command_content, storage)
+        "LPOP" | "RPOP" => handle_lpop(command_content, storage, command == "LPOP")
             .await
             .expect("Error when handle lpop"),
-        "BLPOP" => handle_blpop(command_content, storage)
+        "LINDEX" => handle_lindex(command_content, storage)
+            .await
+            .expect("Error when handle lindex"),
+        "LSET" => handle_lset(command_content, storage)
+            .await
+            .expect("Error when handle lset"),
+        "LINSERT" => handle_linsert(command_content, storage)
+            .await
+            .expect("Error when handle linsert"),
+        "LREM" => handle_lrem(command_content, storage)
+            .await
+            .expect("Error when handle lrem"),
+        "LTRIM" => handle_ltrim(command_content, storage)
+            .await
+            .expect("Error when handle ltrim"),
+        "LPOS" => handle_lpos(command_content, storage)
+            .await
+            .expect("Error when handle lpos"),
+        "RPUSHX" | "LPUSHX" => handle_pushx(command_content, storage, command == "LPUSHX")
+            .await
+            .expect("Error when handle pushx"),
+        "LMOVE" => handle_lmove(command_content, storage)
+            .await
+            .expect("Error when handle lmove"),
+        "LMPOP" => handle_lmpop(command_content, storage)
+            .await
+            .expect("Error when handle lmpop"),
+        "BLPOP" | "BRPOP" => handle_blpop(command_content, storage, !transaction.is_executing(), command == "BLPOP")
             .await
             .expect("Error when handle bpop"),
+        "BLMOVE" | "BRPOPLPUSH" => {
+            handle_blmove(command_content, storage, !transaction.is_executing(), command == "BRPOPLPUSH")
+                .await
+                .expect("Error when handle blmove")
+        }
+        "BLMPOP" => handle_blmpop(command_content, storage, !transaction.is_executing())
+            .await
+            .expect("Error when handle blmpop"),
+        "HSET" | "HMSET" => handle_hset(command_content, storage, command == "HMSET")
+            .await
+            .expect("Error when handle hset"),
+        "HSETNX" => handle_hsetnx(command_content, storage)
+            .await
+            .expect("Error when handle hsetnx"),
+        "HGET" => handle_hget(command_content, storage)
+            .await
+            .expect("Error when handle hget"),
+        "HMGET" => handle_hmget(command_content, storage)
+            .await
+            .expect("Error when handle hmget"),
+        "HDEL" => handle_hdel(command_content, storage)
+            .await
+            .expect("Error when handle hdel"),
+        "HEXISTS" => handle_hexists(command_content, storage)
+            .await
+            .expect("Error when handle hexists"),
+        "HLEN" => handle_hlen(command_content, storage)
+            .await
+            .expect("Error when handle hlen"),
+        "HGETALL" | "HKEYS" | "HVALS" => {
+            handle_hgetall(command_content, storage, command != "HVALS", command != "HKEYS")
+                .await
+                .expect("Error when handle hgetall")
+        }
+        "HSTRLEN" => handle_hstrlen(command_content, storage)
+            .await
+            .expect("Error when handle hstrlen"),
+        "HINCRBY" => handle_hincrby(command_content, storage)
+            .await
+            .expect("Error when handle hincrby"),
+        "HINCRBYFLOAT" => handle_hincrbyfloat(command_content, storage)
+            .await
+            .expect("Error when handle hincrbyfloat"),
+        "HRANDFIELD" => handle_hrandfield(command_content, storage)
+            .await
+            .expect("Error when handle hrandfield"),
+        "HSCAN" => handle_hscan(command_content, storage)
+            .await
+            .expect("Error when handle hscan"),
+        "HEXPIRE" => handle_hexpire(command_content, storage)
+            .await
+            .expect("Error when handle hexpire"),
+        "HTTL" => handle_httl(command_content, storage)
+            .await
+            .expect("Error when handle httl"),
+        "HPERSIST" => handle_hpersist(command_content, storage)
+            .await
+            .expect("Error when handle hpersist"),
+        "SADD" => handle_sadd(command_content, storage)
+            .await
+            .expect("Error when handle sadd"),
+        "SREM" => handle_srem(command_content, storage)
+            .await
+            .expect("Error when handle srem"),
+        "SISMEMBER" => handle_sismember(command_content, storage)
+            .await
+            .expect("Error when handle sismember"),
+        "SMISMEMBER" => handle_smismember(command_content, storage)
+            .await
+            .expect("Error when handle smismember"),
+        "SMEMBERS" => handle_smembers(command_content, storage)
+            .await
+            .expect("Error when handle smembers"),
+        "SCARD" => handle_scard(command_content, storage)
+            .await
+            .expect("Error when handle scard"),
+        "SPOP" => handle_spop(command_content, storage)
+            .await
+            .expect("Error when handle spop"),
+        "SRANDMEMBER" => handle_srandmember(command_content, storage)
+            .await
+            .expect("Error when handle srandmember"),
+        "SMOVE" => handle_smove(command_content, storage)
+            .await
+            .expect("Error when handle smove"),
+        "SINTER" | "SUNION" | "SDIFF" => handle_sinter(command_content, storage, set_operation(&command))
+            .await
+            .expect("Error when handle sinter"),
+        "SINTERSTORE" | "SUNIONSTORE" | "SDIFFSTORE" => {
+            handle_sinterstore(command_content, storage, set_operation(&command), &command.to_lowercase())
+                .await
+                .expect("Error when handle sinterstore")
+        }
+        "SINTERCARD" => handle_sintercard(command_content, storage)
+            .await
+            .expect("Error when handle sintercard"),
+        "ZADD" => handle_zadd(command_content, storage)
+            .await
+            .expect("Error when handle zadd"),
+        "ZINCRBY" => handle_zincrby(command_content, storage)
+            .await
+            .expect("Error when handle zincrby"),
+        "ZREM" => handle_zrem(command_content, storage)
+            .await
+            .expect("Error when handle zrem"),
+        "ZSCORE" => handle_zscore(command_content, storage)
+            .await
+            .expect("Error when handle zscore"),
+        "ZMSCORE" => handle_zmscore(command_content, storage)
+            .await
+            .expect("Error when handle zmscore"),
+        "ZCARD" => handle_zcard(command_content, storage)
+            .await
+            .expect("Error when handle zcard"),
+        "ZCOUNT" => handle_zcount(command_content, storage)
+            .await
+            .expect("Error when handle zcount"),
+        "ZRANK" | "ZREVRANK" => handle_zrank(command_content, storage, command == "ZREVRANK")
+            .await
+            .expect("Error when handle zrank"),
+        "ZRANGE" => handle_zrange(command_content, storage)
+            .await
+            .expect("Error when handle zrange"),
+        "ZRANGESTORE" => handle_zrangestore(command_content, storage)
+            .await
+            .expect("Error when handle zrangestore"),
+        "ZREMRANGEBYRANK" | "ZREMRANGEBYSCORE" | "ZREMRANGEBYLEX" => {
+            handle_zremrange(command_content, storage, &command)
+                .await
+                .expect("Error when handle zremrange")
+        }
+        "ZPOPMIN" | "ZPOPMAX" => handle_zpopmin(command_content, storage, command == "ZPOPMAX")
+            .await
+            .expect("Error when handle zpopmin"),
+        "BZPOPMIN" | "BZPOPMAX" => {
+            handle_bzpopmin(command_content, storage, !transaction.is_executing(), command == "BZPOPMAX")
+                .await
+                .expect("Error when handle bzpopmin")
+        }
+        "ZUNION" | "ZINTER" | "ZDIFF" | "ZUNIONSTORE" | "ZINTERSTORE" | "ZDIFFSTORE" => {
+            handle_zunion(command_content, storage, &command)
+                .await
+                .expect("Error when handle zunion")
+        }
+        "ZINTERCARD" => handle_zintercard(command_content, storage)
+            .await
+            .expect("Error when handle zintercard"),
+        "ZRANDMEMBER" => handle_zrandmember(command_content, storage)
+            .await
+            .expect("Error when handle zrandmember"),
+        "ZMPOP" => handle_zmpop(command_content, storage)
+            .await
+            .expect("Error when handle zmpop"),
+        "BZMPOP" => handle_bzmpop(command_content, storage, !transaction.is_executing())
+            .await
+            .expect("Error when handle bzmpop"),
+        "GEOADD" => handle_geoadd(command_content, storage)
+            .await
+            .expect("Error when handle geoadd"),
+        "GEOPOS" => handle_geopos(command_content, storage)
+            .await
+            .expect("Error when handle geopos"),
+        "GEODIST" => handle_geodist(command_content, storage)
+            .await
+            .expect("Error when handle geodist"),
+        "GEOHASH" => handle_geohash(command_content, storage)
+            .await
+            .expect("Error when handle geohash"),
+        "GEOSEARCH" => handle_geosearch(command_content, storage, false)
+            .await
+            .expect("Error when handle geosearch"),
+        "GEOSEARCHSTORE" => handle_geosearch(command_content, storage, true)
+            .await
+            .expect("Error when handle geosearchstore"),
+        "PFADD" => handle_pfadd(command_content, storage)
+            .await
+            .expect("Error when handle pfadd"),
+        "PFCOUNT" => handle_pfcount(command_content, storage)
+            .await
+            .expect("Error when handle pfcount"),
+        "PFMERGE" => handle_pfmerge(command_content, storage)
+            .await
+            .expect("Error when handle pfmerge"),
+        "PFDEBUG" => handle_pfdebug(command_content, storage)
+            .await
+            .expect("Error when handle pfdebug"),
+        "PFSELFTEST" => handle_pfselftest().expect("Error when handle pfselftest"),
+        "SETBIT" => handle_setbit(command_content, storage)
+            .await
+            .expect("Error when handle setbit"),
+        "GETBIT" => handle_getbit(command_content, storage)
+            .await
+            .expect("Error when handle getbit"),
+        "BITCOUNT" => handle_bitcount(command_content, storage)
+            .await
+            .expect("Error when handle bitcount"),
+        "BITPOS" => handle_bitpos(command_content, storage)
+            .await
+            .expect("Error when handle bitpos"),
+        "BITOP" => handle_bitop(command_content, storage)
+            .await
+            .expect("Error when handle bitop"),
+        "BITFIELD" => handle_bitfield(command_content, storage, false)
+            .await
+            .expect("Error when handle bitfield"),
+        "BITFIELD_RO" => handle_bitfield(command_content, storage, true)
+            .await
+            .expect("Error when handle bitfield_ro"),
         c => {
             eprintln!("Invalid command: {}", c);
             Value::NullBulkString
         }
     }
 }
-pub fn handle_ping() -> Result<Value> {
-    Ok(Value::SimpleString("PONG".to_string()))
+//Check the command can run before putting it in the transaction queue,
+//a failure makes EXEC discard the whole transaction
+//Run the queued commands while holding the store, so no other client sees a half applied transaction.
+//A replica gets the transaction from its master stream, which it already forwards as is, so it does not propagate
+pub async fn exec_transaction(
+    context: &ServerContext,
+    rdb_argument: &mut Argument,
+    transaction: &mut Transaction,
+    client: &mut Client,
+    propagate: bool,
+) -> Value {
+    if !transaction.is_in_multi() {
+        return Value::SimpleError("ERR EXEC without MULTI".to_string());
+    }
+    if transaction.is_aborted() {
+        transaction.take_queue();
+        transaction.unwatch();
+        return Value::SimpleError(
+            "EXECABORT Transaction discarded because of previous errors.".to_string(),
+        );
+    }
+    let queue = transaction.take_queue();
+    //the queued commands lock the store through this context, it stays ours until the context goes
+    let exec_context = context.hold_store().await;
+    //a watched key changed, drop the queued commands
+    if transaction.is_watch_dirty(&*exec_context.storage.lock().await) {
+        transaction.unwatch();
+        return Value::NullArray;
+    }
+    transaction.unwatch();
+
+    let mut results = Vec::new();
+    let mut writes = Vec::new();
+    let executing = transaction.is_executing();
+    transaction.set_executing(true);
+    for (command, command_content) in queue {
+        let result = command_handler(
+            command.clone(),
+            command_content.clone(),
+            &exec_context,
+            rdb_argument,
+            transaction,
+            client,
+        )
+        .await;
+        if let Some(payload) = replicated_command(&command, &command_content, &result) {
+            writes.push(payload);
+        }
+        results.push(result);
+    }
+    transaction.set_executing(executing);
+
+    //propagate before releasing the store so replicas see the same order,
+    //several writes go as one transaction so a replica never applies part of it
+    if propagate && !writes.is_empty() {
+        if writes.len() > 1 {
+            writes.insert(0, vec![Value::BulkString("MULTI".to_string())]);
+            writes.push(vec![Value::BulkString("EXEC".to_string())]);
+        }
+        let mut replication = exec_context.replication.lock().await;
+        for payload in writes {
+            replication.propagate(&Value::Array(payload)).await.unwrap();
+        }
+    }
+    Value::Array(results)
+}
+fn queue_command(command: String, command_content: Vec<Value>, transaction: &mut Transaction) -> Value {
+    if command_arity(&command).is_none() {
+        transaction.abort();
+        let args = command_content
+            .iter()
+            .map(|value| format!("'{}' ", unwrap_value_to_string(value).unwrap_or_default()))
+            .collect::<String>();
+        return Value::SimpleError(format!(
+            "ERR unknown command '{}', with args beginning with: {}",
+            command, args
+        ));
+    }
+    if let Some(error) = check_arity(&command, &command_content) {
+        transaction.abort();
+        return error;
+    }
+    if matches!(
+        command.as_str(),
+        //MIGRATE talks to another node, EXEC would hold the store for every client meanwhile
+        "PSYNC" | "REPLCONF" | "REPLICAOF" | "SLAVEOF" | "MIGRATE"
+    ) {
+        transaction.abort();
+        return Value::SimpleError("ERR Command not allowed inside a transaction".to_string());
+    }
+    transaction.queue(command, command_content)
+}
+//Error for a known command called with the wrong number of arguments
+fn check_arity(command: &str, command_content: &[Value]) -> Option<Value> {
+    let arity = command_arity(command)?;
+    let argc = command_content.len() as i64 + 1;
+    if (arity > 0 && argc != arity) || (arity < 0 && argc < -arity) {
+        return Some(Value::SimpleError(format!(
+            "ERR wrong number of arguments for '{}' command",
+            command.to_lowercase()
+        )));
+    }
+    None
+}
+//Number of arguments including the command name, negative means at least that many
+pub fn command_arity(command: &str) -> Option<i64> {
+    let arity = match command.to_uppercase().as_str() {
+        "PING" | "INFO" | "REPLCONF" | "UNSUBSCRIBE" | "PUNSUBSCRIBE" | "SUNSUBSCRIBE" => -1,
+        "ECHO" | "GET" | "KEYS" | "TYPE" | "INCR" | "LLEN" | "DUMP" => 2,
+        "DECR" | "GETDEL" | "STRLEN" => 2,
+        "INCRBY" | "DECRBY" | "INCRBYFLOAT" | "GETSET" | "SETNX" | "APPEND" => 3,
+        "SETEX" | "PSETEX" | "GETRANGE" | "SETRANGE" => 4,
+        "MGET" | "GETEX" | "OBJECT" | "DEL" => -2,
+        "MSET" | "MSETNX" | "LCS" => -3,
+        "SET" | "RPUSH" | "LPUSH" | "BLPOP" | "BRPOP" => -3,
+        "BRPOPLPUSH" => 4,
+        "BLMOVE" => 6,
+        "BLMPOP" => -5,
+        "LINDEX" => 3,
+        "LSET" | "LREM" | "LTRIM" => 4,
+        "LINSERT" | "LMOVE" => 5,
+        "LPOS" | "RPUSHX" | "LPUSHX" => -3,
+        "LMPOP" => -4,
+        "CONFIG" | "CLUSTER" | "WATCH" | "LPOP" | "RPOP" | "SUBSCRIBE" | "PSUBSCRIBE" | "SSUBSCRIBE" | "PUBSUB" => -2,
+        "PSYNC" | "REPLICAOF" | "SLAVEOF" | "PUBLISH" | "SPUBLISH" => 3,
+        "ASKING" | "MULTI" | "EXEC" | "DISCARD" | "UNWATCH" | "RESET" => 1,
+        "XRANGE" | "XREAD" | "RESTORE" => -4,
+        "XADD" => -5,
+        "MIGRATE" => -6,
+        "LRANGE" => 4,
+        "HSET" | "HMSET" => -4,
+        "HSETNX" | "HINCRBY" | "HINCRBYFLOAT" => 4,
+        "HGET" | "HEXISTS" | "HSTRLEN" => 3,
+        "HMGET" | "HDEL" | "HSCAN" => -3,
+        "HLEN" | "HGETALL" | "HKEYS" | "HVALS" => 2,
+        "HRANDFIELD" => -2,
+        "HEXPIRE" => -6,
+        "HTTL" | "HPERSIST" => -5,
+        "SADD" | "SREM" | "SMISMEMBER" | "SINTERSTORE" | "SUNIONSTORE" | "SDIFFSTORE" | "SINTERCARD" => -3,
+        "SISMEMBER" => 3,
+        "SMEMBERS" | "SCARD" => 2,
+        "SPOP" | "SRANDMEMBER" | "SINTER" | "SUNION" | "SDIFF" => -2,
+        "SMOVE" => 4,
+        "ZADD" => -4,
+        "ZREM" | "ZMSCORE" | "ZRANK" | "ZREVRANK" | "BZPOPMIN" | "BZPOPMAX" => -3,
+        "ZSCORE" => 3,
+        "ZINCRBY" | "ZCOUNT" | "ZREMRANGEBYRANK" | "ZREMRANGEBYSCORE" | "ZREMRANGEBYLEX" => 4,
+        "ZCARD" => 2,
+        "ZRANGE" => -4,
+        "ZRANGESTORE" => -5,
+        "ZPOPMIN" | "ZPOPMAX" | "ZRANDMEMBER" => -2,
+        "ZUNIONSTORE" | "ZINTERSTORE" | "ZDIFFSTORE" | "ZMPOP" => -4,
+        "ZUNION" | "ZINTER" | "ZDIFF" | "ZINTERCARD" => -3,
+        "BZMPOP" | "GEOADD" => -5,
+        "GEOPOS" | "GEOHASH" => -2,
+        "GEODIST" => -4,
+        "PFADD" | "PFCOUNT" | "PFMERGE" => -2,
+        "PFDEBUG" => 3,
+        "PFSELFTEST" => 1,
+        "SETBIT" => 4,
+        "GETBIT" => 3,
+        "BITCOUNT" | "BITFIELD" | "BITFIELD_RO" => -2,
+        "BITPOS" => -3,
+        "BITOP" => -4,
+        "GEOSEARCH" => -7,
+        "GEOSEARCHSTORE" => -8,
+        _ => return None,
+    };
+    Some(arity)
+}
+//Commands that change the dataset, they are refused on replicas and propagated by masters
+pub fn is_write_command(command: &str) -> bool {
+    matches!(
+        command.to_uppercase().as_str(),
+        "SET" | "INCR" | "XADD" | "RPUSH" | "LPUSH" | "LPOP" | "RPOP" | "LSET" | "LINSERT"
+            | "LREM" | "LTRIM" | "RPUSHX" | "LPUSHX" | "LMOVE" | "LMPOP" | "BLPOP" | "BRPOP"
+            | "BRPOPLPUSH" | "BLMOVE" | "BLMPOP" | "RESTORE" | "HSET" | "HMSET" | "HSETNX" | "HDEL"
+            | "HINCRBY" | "HINCRBYFLOAT" | "HEXPIRE" | "HPERSIST" | "SADD" | "SREM" | "SPOP" | "SMOVE"
+            | "SINTERSTORE" | "SUNIONSTORE" | "SDIFFSTORE" | "ZADD" | "ZINCRBY" | "ZREM" | "ZRANGESTORE"
+            | "ZREMRANGEBYRANK" | "ZREMRANGEBYSCORE" | "ZREMRANGEBYLEX" | "ZPOPMIN" | "ZPOPMAX"
+            | "BZPOPMIN" | "BZPOPMAX" | "ZUNIONSTORE" | "ZINTERSTORE" | "ZDIFFSTORE" | "ZMPOP" | "BZMPOP"
+            | "GEOADD" | "GEOSEARCHSTORE" | "PFADD" | "PFMERGE" | "PFDEBUG"
+            | "SETBIT" | "BITOP" | "BITFIELD" | "DECR" | "INCRBY" | "DECRBY" | "INCRBYFLOAT" | "MSET"
+            | "MSETNX" | "GETSET" | "GETDEL" | "GETEX" | "SETNX" | "SETEX" | "PSETEX" | "APPEND"
+            | "SETRANGE" | "MIGRATE" | "DEL"
+    )
+}
+//Commands that can wait for data, the connection watches for the client going away meanwhile
+pub fn is_blocking_command(command: &str) -> bool {
+    matches!(
+        command.to_uppercase().as_str(),
+        "BLPOP" | "BRPOP" | "BRPOPLPUSH" | "BLMOVE" | "BLMPOP" | "BZPOPMIN" | "BZPOPMAX" | "BZMPOP" | "XREAD"
+    )
+}
+//What replicas must run to reproduce a write, None when nothing changed
+pub fn replicated_command(command: &str, command_content: &[Value], result: &Value) -> Option<Vec<Value>> {
+    //PUBLISH is not a write but subscribers on replicas must get the message too
+    let is_publish = matches!(command.to_uppercase().as_str(), "PUBLISH" | "SPUBLISH");
+    if !(is_write_command(command) || is_publish) || matches!(result, Value::SimpleError(_)) {
+        return None;
+    }
+    let command = command.to_uppercase();
+    match (command.as_str(), result) {
+        //a blocking pop replicates as the pop that served it
+        ("BLPOP", Value::Array(popped)) => Some(vec![
+            Value::BulkString("LPOP".to_string()),
+            popped.first()?.clone(),
+        ]),
+        ("BRPOP", Value::Array(popped)) => Some(vec![
+            Value::BulkString("RPOP".to_string()),
+            popped.first()?.clone(),
+        ]),
+        ("BLMPOP" | "BZMPOP", Value::Array(popped)) => {
+            let count = match popped.get(1)? {
+                Value::Array(values) => values.len(),
+                _ => return None,
+            };
+            let args = command_content
+                .iter()
+                .map(|value| unwrap_value_to_string(value).unwrap_or_default())
+                .collect::<Vec<String>>();
+            let numkeys = args.get(1)?.parse::<usize>().ok()?;
+            Some(vec![
+                Value::BulkString(command[1..].to_string()),
+                Value::BulkString("1".to_string()),
+                popped.first()?.clone(),
+                Value::BulkString(args.get(2 + numkeys)?.clone()),
+                Value::BulkString("COUNT".to_string()),
+                Value::BulkString(count.to_string()),
+            ])
+        }
+        ("BLMOVE" | "BRPOPLPUSH", Value::BulkString(_)) => {
+            let args = command_content
+                .iter()
+                .map(|value| unwrap_value_to_string(value).unwrap_or_default())
+                .collect::<Vec<String>>();
+            let (from, to) = if command == "BRPOPLPUSH" {
+                ("RIGHT".to_string(), "LEFT".to_string())
+            } else {
+                (args.get(2)?.clone(), args.get(3)?.clone())
+            };
+            Some(
+                ["LMOVE", args.first()?, args.get(1)?, &from, &to]
+                    .iter()
+                    .map(|arg| Value::BulkString(arg.to_string()))
+                    .collect(),
+            )
+        }
+        ("BZPOPMIN" | "BZPOPMAX", Value::Array(popped)) => Some(vec![
+            Value::BulkString(command[1..].to_string()),
+            popped.first()?.clone(),
+        ]),
+        ("BLPOP" | "BRPOP" | "BLMPOP" | "BLMOVE" | "BRPOPLPUSH" | "BZPOPMIN" | "BZPOPMAX" | "BZMPOP", _) => None,
+        //SPOP picks at random, replicas remove the same members
+        ("SPOP", Value::BulkString(_) | Value::Array(_)) => {
+            let popped = match result {
+                Value::Array(popped) if !popped.is_empty() => popped.clone(),
+                Value::Array(_) => return None,
+                popped => vec![popped.clone()],
+            };
+            let mut payload = vec![
+                Value::BulkString("SREM".to_string()),
+                Value::BulkString(unwrap_value_to_string(command_content.first()?).ok()?),
+            ];
+            payload.extend(popped);
+            Some(payload)
+        }
+        ("SPOP", _) => None,
+        //the keys left for another node, replicas only have to drop them
+        ("MIGRATE", Value::SimpleString(reply)) if reply == "OK" => {
+            let args = command_content
+                .iter()
+                .map(|value| unwrap_value_to_string(value).unwrap_or_default())
+                .collect::<Vec<String>>();
+            let options = args.get(5..).unwrap_or_default();
+            if options.iter().any(|option| option.eq_ignore_ascii_case("COPY")) {
+                return None;
+            }
+            let keys = match options.iter().position(|option| option.eq_ignore_ascii_case("KEYS")) {
+                Some(index) => options[index + 1..].to_vec(),
+                None => vec![args.get(2)?.clone()],
+            };
+            let mut payload = vec![Value::BulkString("DEL".to_string())];
+            payload.extend(keys.into_iter().map(Value::BulkString));
+            Some(payload)
+        }
+        ("MIGRATE", _) => None,
+        //replicas set the value master computed instead of redoing the float math
+        ("INCRBYFLOAT", Value::BulkString(value)) => Some(vec![
+            Value::BulkString("SET".to_string()),
+            Value::BulkString(unwrap_value_to_string(command_content.first()?).ok()?),
+            Value::BulkString(value.clone()),
+            Value::BulkString("KEEPTTL".to_string()),
+        ]),
+        ("HINCRBYFLOAT", Value::BulkString(value)) => Some(vec![
+            Value::BulkString("HSET".to_string()),
+            Value::BulkString(unwrap_value_to_string(command_content.first()?).ok()?),
+            Value::BulkString(unwrap_value_to_string(command_content.get(1)?).ok()?),
+            Value::BulkString(value.clone()),
+        ]),
+        _ => {
+            let mut payload = vec![Value::BulkString(command)];
+            payload.extend(command_content.iter().map(|value| match value {
+                Value::BulkBytes(value) => Value::BulkBytes(value.clone()),
+                _ => Value::BulkString(unwrap_value_to_string(value).unwrap_or_default()),
+            }));
+            Some(payload)
+        }
+    }
+}
+//Keys a command touches, used to route it to the node owning their slot
+pub fn get_command_keys(command: &str, command_content: &[Value]) -> Vec<String> {
+    let args = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap_or_default())
+        .collect::<Vec<String>>();
+    match command.to_uppercase().as_str() {
+        "SET" | "GET" | "INCR" | "TYPE" | "XADD" | "XRANGE" | "RPUSH" | "LPUSH" | "LRANGE"
+        | "LLEN" | "LPOP" | "RPOP" | "LINDEX" | "LSET" | "LINSERT" | "LREM" | "LTRIM" | "LPOS"
+        | "RPUSHX" | "LPUSHX" | "DUMP" | "RESTORE" | "HSET" | "HMSET" | "HSETNX" | "HGET"
+        | "HMGET" | "HDEL" | "HEXISTS" | "HLEN" | "HGETALL" | "HKEYS" | "HVALS" | "HSTRLEN"
+        | "HINCRBY" | "HINCRBYFLOAT" | "HRANDFIELD" | "HSCAN" | "HEXPIRE" | "HTTL" | "HPERSIST"
+        | "SADD" | "SREM" | "SISMEMBER" | "SMISMEMBER" | "SMEMBERS" | "SCARD" | "SPOP"
+        | "SRANDMEMBER" | "ZADD" | "ZINCRBY" | "ZREM" | "ZSCORE" | "ZMSCORE" | "ZCARD" | "ZCOUNT"
+        | "ZRANK" | "ZREVRANK" | "ZRANGE" | "ZREMRANGEBYRANK" | "ZREMRANGEBYSCORE"
+        | "ZREMRANGEBYLEX" | "ZPOPMIN" | "ZPOPMAX" | "ZRANDMEMBER" | "GEOADD" | "GEOPOS" | "GEODIST"
+        | "GEOHASH" | "GEOSEARCH" | "PFADD" | "SETBIT" | "GETBIT" | "BITCOUNT" | "BITPOS" | "BITFIELD"
+        | "BITFIELD_RO" | "DECR" | "INCRBY" | "DECRBY" | "INCRBYFLOAT" | "GETSET" | "GETDEL" | "GETEX"
+        | "SETNX" | "SETEX" | "PSETEX" | "APPEND" | "STRLEN" | "GETRANGE" | "SETRANGE" => {
+            args.into_iter().take(1).collect()
+        }
+        //MSET key value [key value ...]
+        "MSET" | "MSETNX" => args.into_iter().step_by(2).collect(),
+        "LCS" => args.into_iter().take(2).collect(),
+        //BITOP operation destkey key [key ...]
+        "BITOP" => args.into_iter().skip(1).collect(),
+        //PFDEBUG subcommand key, OBJECT subcommand key
+        "PFDEBUG" | "OBJECT" => args.into_iter().skip(1).take(1).collect(),
+        "GEOSEARCHSTORE" => args.into_iter().take(2).collect(),
+        //MIGRATE host port key|"" db timeout [COPY] [REPLACE] [KEYS key [key ...]]
+        "MIGRATE" => match args.iter().position(|arg| arg.to_uppercase() == "KEYS") {
+            Some(position) if position > 4 => args[position + 1..].to_vec(),
+            _ => args.into_iter().skip(2).take(1).filter(|key| !key.is_empty()).collect(),
+        },
+        //sharded channels hash to slots like keys
+        "WATCH" | "SSUBSCRIBE" | "SUNSUBSCRIBE" | "SINTER" | "SUNION" | "SDIFF" | "SINTERSTORE"
+        | "SUNIONSTORE" | "SDIFFSTORE" | "PFCOUNT" | "PFMERGE" | "MGET" | "DEL" => args,
+        "SPUBLISH" => args.into_iter().take(1).collect(),
+        //BLPOP key [key ...] timeout
+        "BLPOP" | "BRPOP" | "BZPOPMIN" | "BZPOPMAX" => args.iter().take(args.len().saturating_sub(1)).cloned().collect(),
+        "BLMOVE" | "BRPOPLPUSH" | "LMOVE" | "SMOVE" | "ZRANGESTORE" => args.into_iter().take(2).collect(),
+        //ZUNIONSTORE destination numkeys key [key ...]
+        "ZUNIONSTORE" | "ZINTERSTORE" | "ZDIFFSTORE" => {
+            match args.get(1).and_then(|numkeys| numkeys.parse::<usize>().ok()) {
+                Some(numkeys) => args.iter().take(1).chain(args.iter().skip(2).take(numkeys)).cloned().collect(),
+                None => Vec::new(),
+            }
+        }
+        //SINTERCARD numkeys key [key ...] [LIMIT limit]
+        "SINTERCARD" => match args.first().and_then(|numkeys| numkeys.parse::<usize>().ok()) {
+            Some(numkeys) => args.iter().skip(1).take(numkeys).cloned().collect(),
+            None => Vec::new(),
+        },
+        //LMPOP numkeys key [key ...] LEFT|RIGHT [COUNT count]
+        "LMPOP" | "ZMPOP" | "ZUNION" | "ZINTER" | "ZDIFF" | "ZINTERCARD" => match args.first().and_then(|numkeys| numkeys.parse::<usize>().ok()) {
+            Some(numkeys) => args.iter().skip(1).take(numkeys).cloned().collect(),
+            None => Vec::new(),
+        },
+        //BLMPOP timeout numkeys key [key ...] LEFT|RIGHT [COUNT count]
+        "BLMPOP" | "BZMPOP" => match args.get(1).and_then(|numkeys| numkeys.parse::<usize>().ok()) {
+            Some(numkeys) => args.iter().skip(2).take(numkeys).cloned().collect(),
+            None => Vec::new(),
+        },
+        //XREAD [COUNT n] [BLOCK ms] STREAMS key [key ...] id [id ...]
+        "XREAD" => match args.iter().position(|arg| arg.to_uppercase() == "STREAMS") {
+            Some(position) => {
+                let streams = &args[position + 1..];
+                streams[..streams.len() / 2].to_vec()
+            }
+            None => Vec::new(),
+        },
+        _ => Vec::new(),
+    }
+}
+pub fn handle_ping(command_content: Vec<Value>, client: &mut Client) -> Result<Value> {
+    let message = match command_content.first() {
+        Some(message) => Some(unwrap_value_to_string(message)?),
+        None => None,
+    };
+    //subscribers get PING as a push like everything else they receive
+    if client.is_subscribed() {
+        return Ok(Value::Array(vec![
+            Value::BulkString("pong".to_string()),
+            Value::BulkString(message.unwrap_or_default()),
+        ]));
+    }
+    match message {
+        Some(message) => Ok(Value::BulkString(message)),
+        None => Ok(Value::SimpleString("PONG".to_string())),
+    }
 }
 pub fn handle_echo(command_content: Vec<Value>) -> Result<Value> {
     Ok(command_content.get(0).unwrap().clone())
 }
 pub async fn handle_set(
-    command: String,
-    mut command_content: Vec<Value>,
-    transaction: &mut Transaction,
-    storage: Arc<Mutex<Store>>,
+    command_content: Vec<Value>,
+    storage: StoreHandle,
 ) -> Result<Value> {
-    match transaction.put(&command, &mut command_content) {
-        Ok(Some(response)) => return Ok(response), // return from handle_set
-        Ok(None) => (),
-        Err(e) => println!(
-            "DEV_LOG: Got error when put command to queue or something: {}",
-            e
-        ),
-    }
     let mut storage = storage.lock().await;
     let key = command_content.get(0).unwrap().clone();
     let value = command_content.get(1).unwrap().clone();
@@ -110,8 +864,8 @@ pub async fn handle_set(
         "Get error when unwrap Value key {:?} to string",
         key
     ));
-    let value = unwrap_value_to_string(&value).expect(&format!(
-        "Get error when unwrap Value value {:?} to string",
+    let value = unwrap_value_to_bytes(&value).expect(&format!(
+        "Get error when unwrap Value value {:?} to bytes",
         value
     ));
 
@@ -135,6 +889,8 @@ pub async fn handle_set(
                 } else {
                     Ok(Value::NullBulkString)
                 }
+            } else if unwrap_value_to_string(px_command)?.eq_ignore_ascii_case("KEEPTTL") {
+                Ok(Value::SimpleString(storage.set_value_keep_ttl(&key, &value)?))
             } else {
                 Ok(Value::NullBulkString)
             }
@@ -144,53 +900,25 @@ pub async fn handle_set(
         )),
     }
 }
-pub async fn handle_get(
-    command: String,
-    mut command_content: Vec<Value>,
-    storage: Arc<Mutex<Store>>,
-    rdb_file: &mut RdbFile,
-    transaction: &mut Transaction,
-) -> Result<Value> {
-    match transaction.put(&command, &mut command_content) {
-        Ok(Some(response)) => return Ok(response), // return from handle_set
-        Ok(None) => (),
-        Err(e) => println!(
-            "DEV_LOG: Got error when put command to queue or something: {}",
-            e
-        ),
-    }
+pub async fn handle_get(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
     let storage = storage.lock().await;
     let key = unwrap_value_to_string(command_content.get(0).unwrap()).unwrap();
-    let rdb_file_collections: HashMap<String, String> = rdb_file
-        .map
-        .iter()
-        .map(|(key, entry)| {
-            if let Some(px) = entry.1 {
-                if px > chrono::Utc::now() {
-                    return (key.to_owned(), entry.0.clone());
-                } else {
-                    ("".to_string(), "".to_string())
-                }
-            } else {
-                return (key.to_owned(), entry.0.clone());
-            }
-        })
-        .collect::<HashMap<String, String>>();
-
+    if let Err(e) = storage.check_type(&key, "string") {
+        return Ok(Value::SimpleError(e.to_string()));
+    }
     match storage.get_value(&key) {
-        Ok(value) => Ok(Value::BulkString(value.to_string())),
-        Err(_) => {
-            if let Some(value) = rdb_file_collections.get(&key) {
-                Ok(Value::BulkString(value.to_owned()))
-            } else {
-                Ok(Value::NullBulkString)
-            }
-        }
+        Ok(value) => Ok(Value::BulkBytes(value.to_bytes().into_owned())),
+        Err(_) => Ok(Value::NullBulkString),
     }
 }
-pub fn handle_config(command_content: Vec<Value>, rdb_argument: &mut Argument) -> Result<Value> {
-    match command_content.get(0) {
-        Some(value) => match unwrap_value_to_string(value).unwrap().as_str() {
+pub async fn handle_config(
+    command_content: Vec<Value>,
+    rdb_argument: &mut Argument,
+    storage: StoreHandle,
+    replication: Arc<Mutex<Replication>>,
+) -> Result<Value> {
+    match command_content.first() {
+        Some(value) => match unwrap_value_to_string(value).unwrap().to_uppercase().as_str() {
             "GET" => {
                 if let Some(name) = command_content.get(1) {
                     if name == &Value::BulkString("dir".to_string()) {
@@ -203,6 +931,32 @@ pub fn handle_config(command_content: Vec<Value>, rdb_argument: &mut Argument) -
                             Value::BulkString("dbfilename".to_string()),
                             Value::BulkString(rdb_argument.get_dir_file_name().unwrap()),
                         ]))
+                    } else if name == &Value::BulkString("replica-serve-stale-data".to_string()) {
+                        let serve_stale_data = replication.lock().await.get_serve_stale_data();
+                        Ok(Value::Array(vec![
+                            Value::BulkString("replica-serve-stale-data".to_string()),
+                            Value::BulkString(yes_no(serve_stale_data)),
+                        ]))
+                    } else if name == &Value::BulkString("min-replicas-to-write".to_string()) {
+                        let min_replicas_to_write =
+                            replication.lock().await.get_min_replicas_to_write();
+                        Ok(Value::Array(vec![
+                            Value::BulkString("min-replicas-to-write".to_string()),
+                            Value::BulkString(min_replicas_to_write.to_string()),
+                        ]))
+                    } else if name == &Value::BulkString("min-replicas-max-lag".to_string()) {
+                        let min_replicas_max_lag =
+                            replication.lock().await.get_min_replicas_max_lag();
+                        Ok(Value::Array(vec![
+                            Value::BulkString("min-replicas-max-lag".to_string()),
+                            Value::BulkString(min_replicas_max_lag.to_string()),
+                        ]))
+                    } else if name == &Value::BulkString("notify-keyspace-events".to_string()) {
+                        let notify_flags = storage.lock().await.get_notify_flags();
+                        Ok(Value::Array(vec![
+                            Value::BulkString("notify-keyspace-events".to_string()),
+                            Value::BulkString(notify_flags_to_string(notify_flags)),
+                        ]))
                     } else {
                         Ok(Value::NullBulkString)
                     }
@@ -210,45 +964,93 @@ pub fn handle_config(command_content: Vec<Value>, rdb_argument: &mut Argument) -
                     Ok(Value::NullBulkString)
                 }
             }
-            // "SET" => {}
+            "SET" => {
+                let (name, value) = match (command_content.get(1), command_content.get(2)) {
+                    (Some(name), Some(value)) => (
+                        unwrap_value_to_string(name).unwrap(),
+                        unwrap_value_to_string(value).unwrap(),
+                    ),
+                    _ => {
+                        return Ok(Value::SimpleError(
+                            "ERR wrong number of arguments for 'config|set' command".to_string(),
+                        ))
+                    }
+                };
+                match name.as_str() {
+                    "replica-serve-stale-data" => match parse_yes_no(&value) {
+                        Some(serve_stale_data) => {
+                            replication.lock().await.set_serve_stale_data(serve_stale_data)?;
+                            Ok(Value::SimpleString("OK".to_string()))
+                        }
+                        None => Ok(invalid_config_value(&name, &value)),
+                    },
+                    "min-replicas-to-write" => match value.parse::<usize>() {
+                        Ok(min_replicas_to_write) => {
+                            replication
+                                .lock()
+                                .await
+                                .set_min_replicas_to_write(min_replicas_to_write)?;
+                            Ok(Value::SimpleString("OK".to_string()))
+                        }
+                        Err(_) => Ok(invalid_config_value(&name, &value)),
+                    },
+                    "min-replicas-max-lag" => match value.parse::<u64>() {
+                        Ok(min_replicas_max_lag) => {
+                            replication
+                                .lock()
+                                .await
+                                .set_min_replicas_max_lag(min_replicas_max_lag)?;
+                            Ok(Value::SimpleString("OK".to_string()))
+                        }
+                        Err(_) => Ok(invalid_config_value(&name, &value)),
+                    },
+                    "notify-keyspace-events" => match parse_notify_flags(&value) {
+                        Some(notify_flags) => {
+                            storage.lock().await.set_notify_flags(notify_flags);
+                            Ok(Value::SimpleString("OK".to_string()))
+                        }
+                        None => Ok(invalid_config_value(&name, &value)),
+                    },
+                    _ => Ok(Value::SimpleError(format!(
+                        "ERR Unknown option or number of arguments for CONFIG SET - '{}'",
+                        name
+                    ))),
+                }
+            }
             _ => Ok(Value::NullBulkString),
         },
         None => Ok(Value::NullBulkString),
     }
 }
-pub fn handle_key(command_content: Vec<Value>, rdb_file: &mut RdbFile) -> Result<Value> {
-    let pattern: Value = command_content.get(0).unwrap().clone();
-    let pattern: String = unwrap_value_to_string(&pattern).expect(&format!(
-        "Get error when unwrap Value pattern {:?} to string",
-        pattern
-    ));
-    match pattern.as_str() {
-        "*" => {
-            let keys = rdb_file
-                .map
-                .iter()
-                .map(|(key, entry)| {
-                    if let Some(px) = entry.1 {
-                        if px > chrono::Utc::now() {
-                            return key.to_owned();
-                        } else {
-                            String::from("")
-                        }
-                    } else {
-                        return key.to_owned();
-                    }
-                })
-                .collect::<Vec<String>>();
-
-            let keys = keys
-                .into_iter()
-                .map(|key| Value::BulkString(key))
-                .collect::<Vec<Value>>();
-            Ok(Value::Array(keys))
-        }
-        _ => Err(anyhow::anyhow!("Pattern error")),
+fn yes_no(value: bool) -> String {
+    if value { "yes" } else { "no" }.to_string()
+}
+fn parse_yes_no(value: &str) -> Option<bool> {
+    match value.to_lowercase().as_str() {
+        "yes" => Some(true),
+        "no" => Some(false),
+        _ => None,
     }
 }
+fn invalid_config_value(name: &str, value: &str) -> Value {
+    Value::SimpleError(format!(
+        "ERR CONFIG SET failed (possibly related to argument '{}') - argument couldn't be parsed into an integer or is invalid: '{}'",
+        name, value
+    ))
+}
+//KEYS pattern, every live key of the store matching the glob pattern
+pub async fn handle_key(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let pattern = unwrap_value_to_string(&command_content[0])?;
+    let keys = storage
+        .lock()
+        .await
+        .keys()
+        .into_iter()
+        .filter(|key| glob_match(&pattern, key))
+        .map(Value::BulkString)
+        .collect::<Vec<Value>>();
+    Ok(Value::Array(keys))
+}
 pub async fn handle_info(
     command_content: Vec<Value>,
     replication: Arc<Mutex<Replication>>,
@@ -256,62 +1058,615 @@ pub async fn handle_info(
     let replication = replication.lock().await;
     if let Some(arg) = command_content.get(0) {
         let arg: String = unwrap_value_to_string(arg).unwrap();
-        match arg.as_str() {
-            "replication" => Ok(replication.display_to_value().unwrap()),
-            _ => Ok(Value::BulkString("role:master".to_string())),
+        match arg.to_lowercase().as_str() {
+            "replication" | "all" | "default" | "everything" => {
+                Ok(replication.display_to_value().unwrap())
+            }
+            _ => Ok(Value::BulkString(String::new())),
         }
     } else {
-        Ok(Value::BulkString("role:master".to_string()))
+        Ok(replication.display_to_value().unwrap())
     }
 }
-pub fn handle_replconf() -> Result<Value> {
-    Ok(Value::SimpleString("OK".to_string()))
-}
-pub async fn handle_psync(replication: Arc<Mutex<Replication>>) -> Result<Value> {
-    let replication = replication.lock().await;
-    Ok(Value::SimpleString(format!(
-        "FULLRESYNC {} 0",
-        replication.get_master_replid().unwrap()
-    )))
-}
-pub async fn handle_type(command_content: Vec<Value>, storage: Arc<Mutex<Store>>) -> Result<Value> {
-    let storage = storage.lock().await;
-    let key = unwrap_value_to_string(command_content.get(0).unwrap()).unwrap();
-
-    //check entry
-    let key_type: &str = match storage.entry.check_stream_key_exist(&key) {
-        true => "stream",
-        false => {
-            //check rengular
-            match storage.get_value(&key) {
-                Ok(value_type) => {
-                    //check type
-                    match value_type {
-                        StoreValueType::String(_) => "string",
-                        StoreValueType::Interger(_) => "interger",
-                        StoreValueType::List(_) => "list",
-                    }
-                }
-                Err(_) => {
-                    // Err(anyhow::anyhow!("Can not get key {} - error {}", key, e))
-                    // eprintln!("Error when get value of {} -- error {}", key, e);
-                    "none"
-                }
+pub async fn handle_replconf(
+    command_content: Vec<Value>,
+    replication: Arc<Mutex<Replication>>,
+    client: &mut Client,
+) -> Result<Value> {
+    let option = match command_content.first() {
+        Some(option) => unwrap_value_to_string(option).unwrap().to_lowercase(),
+        None => return Ok(Value::SimpleString("OK".to_string())),
+    };
+    let argument = command_content
+        .get(1)
+        .map(|argument| unwrap_value_to_string(argument).unwrap())
+        .unwrap_or_default();
+    match option.as_str() {
+        "listening-port" => match argument.parse::<usize>() {
+            Ok(port) => {
+                client.set_listening_port(port);
+                Ok(Value::SimpleString("OK".to_string()))
+            }
+            Err(_) => Ok(Value::SimpleError(
+                "ERR value is not an integer or out of range".to_string(),
+            )),
+        },
+        //replicas never get a reply for their ACK
+        "ack" => {
+            if let Ok(offset) = argument.parse::<usize>() {
+                replication.lock().await.record_ack(client.get_id(), offset);
             }
+            Ok(Value::NoReply)
         }
-    };
-    Ok(Value::SimpleString(key_type.to_string()))
+        _ => Ok(Value::SimpleString("OK".to_string())),
+    }
 }
-
-pub async fn handle_xadd(command_content: Vec<Value>, storage: Arc<Mutex<Store>>) -> Result<Value> {
-    //parse arguments
+pub async fn handle_psync(
+    command_content: Vec<Value>,
+    storage: StoreHandle,
+    replication: Arc<Mutex<Replication>>,
+    client: &mut Client,
+) -> Result<Value> {
+    let psync_replid = command_content
+        .first()
+        .map(|replid| unwrap_value_to_string(replid).unwrap())
+        .unwrap_or("?".to_string());
+    let psync_offset = command_content
+        .get(1)
+        .and_then(|offset| unwrap_value_to_string(offset).unwrap().parse::<i64>().ok())
+        .unwrap_or(-1);
+    let writer = match client.get_writer() {
+        Some(writer) => writer,
+        None => return Ok(Value::SimpleError("ERR PSYNC not allowed here".to_string())),
+    };
+    let handler = ReplicaHandler::new(
+        client.get_id(),
+        client.get_ip(),
+        client.get_listening_port().unwrap_or_default(),
+        writer,
+    );
+
+    //reply and snapshot are written by sync_replica, nothing else goes back
+    let storage = storage.lock().await;
+    let mut replication = replication.lock().await;
+    replication
+        .sync_replica(&psync_replid, psync_offset, &storage, handler)
+        .await?;
+    Ok(Value::NoReply)
+}
+pub async fn handle_replicaof(
+    command_content: Vec<Value>,
+    rdb_argument: &mut Argument,
+    context: &ServerContext,
+) -> Result<Value> {
+    let (host, port) = match (command_content.first(), command_content.get(1)) {
+        (Some(host), Some(port)) if command_content.len() == 2 => (
+            unwrap_value_to_string(host).unwrap(),
+            unwrap_value_to_string(port).unwrap(),
+        ),
+        _ => {
+            return Ok(Value::SimpleError(
+                "ERR wrong number of arguments for 'replicaof' command".to_string(),
+            ))
+        }
+    };
+
+    let mut replication_guard = context.replication.lock().await;
+    if host.to_uppercase() == "NO" && port.to_uppercase() == "ONE" {
+        if replication_guard.get_role()? == Role::Slave {
+            replication_guard.promote_to_master()?;
+        }
+        return Ok(Value::SimpleString("OK".to_string()));
+    }
+
+    let port = match port.parse::<usize>() {
+        Ok(port) if port <= u16::MAX as usize => port,
+        _ => {
+            return Ok(Value::SimpleError(
+                "ERR Invalid master port".to_string(),
+            ))
+        }
+    };
+    if replication_guard.get_role()? == Role::Slave
+        && replication_guard.get_master_endpoint() == Some((host.clone(), port))
+    {
+        return Ok(Value::SimpleString(
+            "OK Already connected to specified master".to_string(),
+        ));
+    }
+
+    replication_guard.set_role(Role::Slave)?;
+    replication_guard.set_master_endpoint(host.clone(), port)?;
+    replication_guard.set_master_link_up(false)?;
+    let master_link = spawn_master_link(host, port, rdb_argument.clone(), context.detached());
+    replication_guard.set_master_link(Some(master_link));
+    Ok(Value::SimpleString("OK".to_string()))
+}
+pub async fn handle_cluster(
+    command_content: Vec<Value>,
+    storage: StoreHandle,
+    cluster: Arc<Mutex<Cluster>>,
+) -> Result<Value> {
+    let storage = storage.lock().await;
+    let mut cluster = cluster.lock().await;
+    if !cluster.is_enabled() {
+        return Ok(Value::SimpleError(
+            "ERR This instance has cluster support disabled".to_string(),
+        ));
+    }
+    let args = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap_or_default())
+        .collect::<Vec<String>>();
+    let subcommand = match args.first() {
+        Some(subcommand) => subcommand.to_uppercase(),
+        None => {
+            return Ok(Value::SimpleError(
+                "ERR wrong number of arguments for 'cluster' command".to_string(),
+            ))
+        }
+    };
+    match subcommand.as_str() {
+        "KEYSLOT" if args.len() == 2 => Ok(Value::SimpleInterger(key_hash_slot(&args[1]).to_string())),
+        "ADDSLOTS" if args.len() >= 2 => {
+            let mut slots = Vec::new();
+            for slot in &args[1..] {
+                match slot.parse::<usize>() {
+                    Ok(slot) => slots.push(slot),
+                    Err(_) => {
+                        return Ok(Value::SimpleError(
+                            "ERR Invalid or out of range slot".to_string(),
+                        ))
+                    }
+                }
+            }
+            Ok(cluster.add_slots(&slots))
+        }
+        "ADDSLOTSRANGE" if args.len() >= 3 && args.len() % 2 == 1 => {
+            let mut slots = Vec::new();
+            for range in args[1..].chunks(2) {
+                match parse_slot_ranges(&format!("{}-{}", range[0], range[1])) {
+                    Ok(range) => slots.extend(range),
+                    Err(_) => {
+                        return Ok(Value::SimpleError(
+                            "ERR Invalid or out of range slot".to_string(),
+                        ))
+                    }
+                }
+            }
+            Ok(cluster.add_slots(&slots))
+        }
+        "MEET" if args.len() == 3 || args.len() == 4 => match args[2].parse::<usize>() {
+            Ok(port) if port <= u16::MAX as usize => Ok(cluster.meet(args[1].clone(), port)),
+            _ => Ok(Value::SimpleError(format!("ERR Invalid base port specified: {}", args[2]))),
+        },
+        "SETSLOT" if args.len() == 3 || args.len() == 4 => {
+            let slot = match args[1].parse::<usize>() {
+                Ok(slot) if slot < CLUSTER_SLOTS => slot,
+                _ => {
+                    return Ok(Value::SimpleError(
+                        "ERR Invalid or out of range slot".to_string(),
+                    ))
+                }
+            };
+            let keys_in_slot = storage
+                .keys()
+                .iter()
+                .filter(|key| key_hash_slot(key) == slot)
+                .count();
+            Ok(cluster.set_slot(
+                slot,
+                &args[2].to_uppercase(),
+                args.get(3).map(|node_id| node_id.as_str()),
+                keys_in_slot,
+            ))
+        }
+        "GETKEYSINSLOT" | "COUNTKEYSINSLOT" if args.len() == 2 + usize::from(subcommand == "GETKEYSINSLOT") => {
+            let slot = match args[1].parse::<usize>() {
+                Ok(slot) if slot < CLUSTER_SLOTS => slot,
+                _ => {
+                    return Ok(Value::SimpleError(
+                        "ERR Invalid or out of range slot".to_string(),
+                    ))
+                }
+            };
+            let mut keys = storage
+                .keys()
+                .into_iter()
+                .filter(|key| key_hash_slot(key) == slot)
+                .collect::<Vec<String>>();
+            if subcommand == "COUNTKEYSINSLOT" {
+                return Ok(Value::SimpleInterger(keys.len().to_string()));
+            }
+            let count = match args[2].parse::<usize>() {
+                Ok(count) => count,
+                Err(_) => {
+                    return Ok(Value::SimpleError(
+                        "ERR Invalid number of keys".to_string(),
+                    ))
+                }
+            };
+            keys.sort();
+            Ok(Value::Array(
+                keys.into_iter().take(count).map(Value::BulkString).collect(),
+            ))
+        }
+        "SLOTS" => Ok(cluster.display_slots()),
+        "SHARDS" => Ok(cluster.display_shards()),
+        "NODES" => Ok(cluster.display_nodes()),
+        "INFO" => Ok(cluster.display_info()),
+        "MYID" => Ok(Value::BulkString(cluster.get_myself().id.clone())),
+        "KEYSLOT" | "ADDSLOTS" | "ADDSLOTSRANGE" | "MEET" | "SETSLOT" | "GETKEYSINSLOT"
+        | "COUNTKEYSINSLOT" => Ok(Value::SimpleError(format!(
+            "ERR wrong number of arguments for 'cluster|{}' command",
+            subcommand.to_lowercase()
+        ))),
+        _ => Ok(Value::SimpleError(format!(
+            "ERR unknown subcommand '{}'. Try CLUSTER HELP.",
+            args[0]
+        ))),
+    }
+}
+//Next command may touch a slot this node is importing
+pub fn handle_asking(client: &mut Client) -> Result<Value> {
+    client.set_asking(true);
+    Ok(Value::SimpleString("OK".to_string()))
+}
+pub async fn handle_dump(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let key = match command_content.first() {
+        Some(key) if command_content.len() == 1 => unwrap_value_to_string(key)?,
+        _ => {
+            return Ok(Value::SimpleError(
+                "ERR wrong number of arguments for 'dump' command".to_string(),
+            ))
+        }
+    };
+    match storage.lock().await.dump(&key) {
+        Some((payload, _)) => Ok(Value::BulkString(payload)),
+        None => Ok(Value::NullBulkString),
+    }
+}
+//RESTORE key ttl payload [REPLACE]
+pub async fn handle_restore(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let args = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap_or_default())
+        .collect::<Vec<String>>();
+    if args.len() < 3 {
+        return Ok(Value::SimpleError(
+            "ERR wrong number of arguments for 'restore' command".to_string(),
+        ));
+    }
+    let mut replace = false;
+    for option in &args[3..] {
+        match option.to_uppercase().as_str() {
+            "REPLACE" => replace = true,
+            _ => return Ok(Value::SimpleError("ERR syntax error".to_string())),
+        }
+    }
+    let ttl = match args[1].parse::<i64>() {
+        Ok(0) => None,
+        Ok(ttl) if ttl > 0 => Some(ttl),
+        _ => {
+            return Ok(Value::SimpleError(
+                "ERR Invalid TTL value, must be >= 0".to_string(),
+            ))
+        }
+    };
+    let payload = match parse_payload(args[2].as_bytes()) {
+        Ok(Some((payload, _))) => payload,
+        _ => {
+            return Ok(Value::SimpleError(
+                "ERR DUMP payload version or checksum are wrong".to_string(),
+            ))
+        }
+    };
+    let mut storage = storage.lock().await;
+    if !replace && storage.exists(&args[0]) {
+        return Ok(Value::SimpleError(
+            "BUSYKEY Target key name already exists.".to_string(),
+        ));
+    }
+    match storage.restore(&args[0], &payload, ttl) {
+        Ok(()) => Ok(Value::SimpleString("OK".to_string())),
+        Err(_) => Ok(Value::SimpleError(
+            "ERR Bad data format".to_string(),
+        )),
+    }
+}
+//MIGRATE host port key|"" destination-db timeout [COPY] [REPLACE] [KEYS key [key ...]]
+pub async fn handle_migrate(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let args = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap_or_default())
+        .collect::<Vec<String>>();
+    if args.len() < 5 {
+        return Ok(Value::SimpleError(
+            "ERR wrong number of arguments for 'migrate' command".to_string(),
+        ));
+    }
+    let (port, timeout_ms) = match (args[1].parse::<usize>(), args[4].parse::<u64>()) {
+        (Ok(port), Ok(timeout_ms)) => (port, timeout_ms),
+        _ => return Ok(Value::SimpleError("ERR value is not an integer or out of range".to_string())),
+    };
+    let mut copy = false;
+    let mut replace = false;
+    let mut keys = vec![args[2].clone()];
+    let mut index = 5;
+    while index < args.len() {
+        match args[index].to_uppercase().as_str() {
+            "COPY" => copy = true,
+            "REPLACE" => replace = true,
+            "KEYS" if args[2].is_empty() => {
+                keys = args[index + 1..].to_vec();
+                break;
+            }
+            _ => return Ok(Value::SimpleError("ERR syntax error".to_string())),
+        }
+        index += 1;
+    }
+
+    //ASKING lets the target accept keys of a slot it is still importing
+    let mut commands = Vec::new();
+    let mut migrated = Vec::new();
+    {
+        let storage = storage.lock().await;
+        for key in keys {
+            if let Some((payload, ttl)) = storage.dump(&key) {
+                let mut restore = vec![
+                    "RESTORE".to_string(),
+                    key.clone(),
+                    ttl.unwrap_or(0).to_string(),
+                    payload,
+                ];
+                if replace {
+                    restore.push("REPLACE".to_string());
+                }
+                commands.push(vec!["ASKING".to_string()]);
+                commands.push(restore);
+                migrated.push(key);
+            }
+        }
+    }
+    if migrated.is_empty() {
+        return Ok(Value::SimpleString("NOKEY".to_string()));
+    }
+    let timeout_ms = if timeout_ms == 0 { 1000 } else { timeout_ms };
+    let replies = match tokio::time::timeout(
+        Duration::from_millis(timeout_ms),
+        send_commands(&args[0], port, &commands),
+    )
+    .await
+    {
+        Ok(Ok(replies)) => replies,
+        _ => {
+            return Ok(Value::SimpleError(format!(
+                "IOERR error or timeout reading to target instance {}:{}",
+                args[0], port
+            )))
+        }
+    };
+    for reply in replies {
+        if let Value::SimpleError(e) = reply {
+            return Ok(Value::SimpleError(format!(
+                "ERR Target instance replied with error: {}",
+                e
+            )));
+        }
+    }
+    if !copy {
+        let mut storage = storage.lock().await;
+        for key in migrated {
+            storage.remove(&key);
+        }
+    }
+    Ok(Value::SimpleString("OK".to_string()))
+}
+//kind is the lowercase command: subscribe, psubscribe or ssubscribe
+pub async fn handle_subscribe(
+    command_content: Vec<Value>,
+    pubsub: Arc<Mutex<PubSub>>,
+    client: &mut Client,
+    kind: &str,
+) -> Result<Value> {
+    let writer = match client.get_writer() {
+        Some(writer) => writer,
+        None => return Ok(Value::NoReply),
+    };
+    for name in command_content.iter() {
+        let name = unwrap_value_to_string(name)?;
+        let mut pubsub = pubsub.lock().await;
+        match kind {
+            "psubscribe" if client.add_pattern(&name) => {
+                pubsub.psubscribe(&name, client.get_id(), writer.clone())
+            }
+            "ssubscribe" if client.add_shard_channel(&name) => {
+                pubsub.ssubscribe(&name, client.get_id(), writer.clone())
+            }
+            "subscribe" if client.add_channel(&name) => {
+                pubsub.subscribe(&name, client.get_id(), writer.clone())
+            }
+            _ => {}
+        }
+        drop(pubsub);
+        //one confirmation per channel
+        let reply = subscription_reply(kind, Value::BulkString(name), client);
+        write_value(writer.clone(), reply.serialize()).await;
+    }
+    Ok(Value::NoReply)
+}
+//kind is the lowercase command: unsubscribe, punsubscribe or sunsubscribe
+pub async fn handle_unsubscribe(
+    command_content: Vec<Value>,
+    pubsub: Arc<Mutex<PubSub>>,
+    client: &mut Client,
+    kind: &str,
+) -> Result<Value> {
+    let writer = match client.get_writer() {
+        Some(writer) => writer,
+        None => return Ok(Value::NoReply),
+    };
+    //no argument means everything this client listens to
+    let names = if command_content.is_empty() {
+        match kind {
+            "punsubscribe" => client.get_patterns(),
+            "sunsubscribe" => client.get_shard_channels(),
+            _ => client.get_channels(),
+        }
+    } else {
+        command_content
+            .iter()
+            .map(|name| unwrap_value_to_string(name).unwrap_or_default())
+            .collect()
+    };
+    if names.is_empty() {
+        return Ok(subscription_reply(kind, Value::NullBulkString, client));
+    }
+    for name in names {
+        let mut pubsub = pubsub.lock().await;
+        match kind {
+            "punsubscribe" if client.remove_pattern(&name) => pubsub.punsubscribe(&name, client.get_id()),
+            "sunsubscribe" if client.remove_shard_channel(&name) => {
+                pubsub.sunsubscribe(&name, client.get_id())
+            }
+            "unsubscribe" if client.remove_channel(&name) => pubsub.unsubscribe(&name, client.get_id()),
+            _ => {}
+        }
+        drop(pubsub);
+        let reply = subscription_reply(kind, Value::BulkString(name), client);
+        write_value(writer.clone(), reply.serialize()).await;
+    }
+    Ok(Value::NoReply)
+}
+fn subscription_reply(kind: &str, name: Value, client: &Client) -> Value {
+    let count = match kind {
+        "ssubscribe" | "sunsubscribe" => client.count_shard_subscriptions(),
+        _ => client.count_subscriptions(),
+    };
+    Value::Array(vec![
+        Value::BulkString(kind.to_string()),
+        name,
+        Value::SimpleInterger(count.to_string()),
+    ])
+}
+pub async fn handle_publish(
+    command_content: Vec<Value>,
+    replication: Arc<Mutex<Replication>>,
+    cluster: Arc<Mutex<Cluster>>,
+    pubsub: Arc<Mutex<PubSub>>,
+) -> Result<Value> {
+    let channel = unwrap_value_to_string(&command_content[0])?;
+    let message = unwrap_value_to_string(&command_content[1])?;
+    let receivers = publish(pubsub, &channel, &message).await;
+    //messages replicated from our master were already sent around the cluster
+    if replication.lock().await.get_role()? == Role::Master {
+        let cluster = cluster.lock().await;
+        if cluster.is_enabled() {
+            broadcast_publish(cluster.bus_peers(), &channel, &message);
+        }
+    }
+    Ok(Value::SimpleInterger(receivers.to_string()))
+}
+//Sharded messages stay in the shard, replicas get them through the replication stream
+pub async fn handle_spublish(command_content: Vec<Value>, pubsub: Arc<Mutex<PubSub>>) -> Result<Value> {
+    let channel = unwrap_value_to_string(&command_content[0])?;
+    let message = unwrap_value_to_string(&command_content[1])?;
+    let receivers = spublish(pubsub, &channel, &message).await;
+    Ok(Value::SimpleInterger(receivers.to_string()))
+}
+pub async fn handle_pubsub(command_content: Vec<Value>, pubsub: Arc<Mutex<PubSub>>) -> Result<Value> {
+    let args = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap_or_default())
+        .collect::<Vec<String>>();
+    let pubsub = pubsub.lock().await;
+    match args[0].to_uppercase().as_str() {
+        "CHANNELS" if args.len() <= 2 => Ok(Value::Array(
+            pubsub
+                .get_channels(args.get(1).map(|pattern| pattern.as_str()))
+                .into_iter()
+                .map(Value::BulkString)
+                .collect(),
+        )),
+        "NUMSUB" => Ok(Value::Array(
+            args[1..]
+                .iter()
+                .flat_map(|channel| {
+                    [
+                        Value::BulkString(channel.clone()),
+                        Value::SimpleInterger(pubsub.count_subscribers(channel).to_string()),
+                    ]
+                })
+                .collect(),
+        )),
+        "NUMPAT" if args.len() == 1 => Ok(Value::SimpleInterger(pubsub.count_patterns().to_string())),
+        "SHARDCHANNELS" if args.len() <= 2 => Ok(Value::Array(
+            pubsub
+                .get_shard_channels(args.get(1).map(|pattern| pattern.as_str()))
+                .into_iter()
+                .map(Value::BulkString)
+                .collect(),
+        )),
+        "SHARDNUMSUB" => Ok(Value::Array(
+            args[1..]
+                .iter()
+                .flat_map(|channel| {
+                    [
+                        Value::BulkString(channel.clone()),
+                        Value::SimpleInterger(pubsub.count_shard_subscribers(channel).to_string()),
+                    ]
+                })
+                .collect(),
+        )),
+        "CHANNELS" | "NUMPAT" | "SHARDCHANNELS" => Ok(Value::SimpleError(format!(
+            "ERR wrong number of arguments for 'pubsub|{}' command",
+            args[0].to_lowercase()
+        ))),
+        _ => Ok(Value::SimpleError(format!(
+            "ERR unknown subcommand '{}'. Try PUBSUB HELP.",
+            args[0]
+        ))),
+    }
+}
+pub async fn handle_type(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let storage = storage.lock().await;
+    let key = unwrap_value_to_string(command_content.get(0).unwrap()).unwrap();
+    let key_type = storage.get_type(&key).unwrap_or("none");
+    Ok(Value::SimpleString(key_type.to_string()))
+}
+//OBJECT ENCODING key
+pub async fn handle_object(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let args = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    match (args[0].to_uppercase().as_str(), args.get(1)) {
+        ("ENCODING", Some(key)) if args.len() == 2 => {
+            let storage = storage.lock().await;
+            match storage.get_encoding(key) {
+                Some(encoding) => Ok(Value::BulkString(encoding.to_string())),
+                None => Ok(Value::NullBulkString),
+            }
+        }
+        ("ENCODING", _) => Ok(Value::SimpleError(
+            "ERR wrong number of arguments for 'object|encoding' command".to_string(),
+        )),
+        _ => Ok(Value::SimpleError(format!(
+            "ERR unknown subcommand '{}'. Try OBJECT HELP.",
+            args[0]
+        ))),
+    }
+}
+
+pub async fn handle_xadd(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    //parse arguments
     let stream_key = unwrap_value_to_string(command_content.get(0).unwrap()).unwrap();
     let mut stream_id = unwrap_value_to_string(command_content.get(1).unwrap()).unwrap();
 
     let mut storage = storage.lock().await;
     //get stream by key stream and stream id to add new key value pairs
-    if !storage.entry.check_stream_key_exist(&stream_key) {
-        storage.entry.add_new_stream_key(&stream_key).unwrap();
+    if let Err(e) = storage.add_stream_key(&stream_key) {
+        return Ok(Value::SimpleError(e.to_string()));
     }
     if !storage.entry.check_stream_id_exist(&stream_key, &stream_id) {
         match storage.entry.add_stream(&stream_key, &stream_id) {
@@ -337,6 +1692,9 @@ pub async fn handle_xadd(command_content: Vec<Value>, storage: Arc<Mutex<Store>>
                 .unwrap();
             index += 2;
         }
+        storage.touch(&stream_key);
+        storage.notify(NOTIFY_STREAM, "xadd", &stream_key);
+        storage.signal_stream(&stream_key);
         Ok(Value::BulkString(stream_id.to_string()))
     } else {
         Ok(Value::SimpleError(
@@ -346,12 +1704,15 @@ pub async fn handle_xadd(command_content: Vec<Value>, storage: Arc<Mutex<Store>>
 }
 pub async fn handle_xrange(
     command_content: Vec<Value>,
-    storage: Arc<Mutex<Store>>,
+    storage: StoreHandle,
 ) -> Result<Value> {
     let storage = storage.lock().await;
     let stream_key = unwrap_value_to_string(command_content.get(0).unwrap()).unwrap();
     let start = unwrap_value_to_string(command_content.get(1).unwrap()).unwrap();
     let end = unwrap_value_to_string(command_content.get(2).unwrap()).unwrap();
+    if let Err(e) = storage.check_type(&stream_key, "stream") {
+        return Ok(Value::SimpleError(e.to_string()));
+    }
 
     let (st_time, st_seq) = if start.contains('-') && start.len() > 1 {
         let mut start = start.split('-');
@@ -403,88 +1764,62 @@ pub async fn handle_xrange(
     Ok(Value::Array(streams))
 }
 pub async fn handle_xread(
-    mut command_content: Vec<Value>,
-    storage: Arc<Mutex<Store>>,
+    command_content: Vec<Value>,
+    storage: StoreHandle,
+    may_block: bool,
 ) -> Result<Value> {
     let first_arg = unwrap_value_to_string(command_content.get(0).unwrap()).unwrap();
-    let mut stream_keys_argument_start = 1;
-    let mut stream_keys;
-    let mut stream_id_index;
-
-    if first_arg == "streams" {
-        stream_keys_argument_start = 1;
-    } else if first_arg == "block" {
-        stream_keys_argument_start = 3;
-
-        stream_keys = command_content[stream_keys_argument_start
-            ..=((command_content.len() + stream_keys_argument_start + 1) / 2 - 1)]
-            .iter()
-            .map(|c| unwrap_value_to_string(c).unwrap())
-            .collect::<Vec<String>>();
-
-        stream_id_index = (command_content.len() + stream_keys_argument_start + 1) / 2;
-
-        //check is stream id is $ -> change $ to latest stream id for stream key coresponding
-        for i in stream_id_index..(stream_id_index + stream_keys.len()) {
-            if command_content[i] == Value::BulkString("$".to_string()) {
-                let stream_key =
-                    unwrap_value_to_string(&command_content[i - stream_keys.len()].to_owned())
-                        .unwrap();
-                let storage_guard = storage.lock().await;
-                let last_stream_id = storage_guard.entry.get_last(&stream_key).unwrap();
-                command_content[i] = Value::BulkString(last_stream_id);
-            }
-        }
-
+    let (stream_keys_argument_start, block_time) = if first_arg == "block" {
         let block_time = unwrap_value_to_string(command_content.get(1).unwrap())
             .unwrap()
             .parse::<u64>()
             .unwrap();
-        if block_time == 0 {
-            //save current status of storage
-            let storage_guard = storage.lock().await;
-            let current_len = {
-                let mut len = 0;
-                for stream_key in &stream_keys {
-                    len += storage_guard.entry.get_len(&stream_key).unwrap();
-                }
-                len
-            };
-            drop(storage_guard);
-            loop {
-                sleep(Duration::from_millis(500)).await;
-                let storage_guard = storage.lock().await;
-                //check if it differ with previous status then return else we sleep again
-                let next_len = {
-                    let mut len = 0;
-                    for stream_key in &stream_keys {
-                        len += storage_guard.entry.get_len(&stream_key).unwrap();
-                    }
-                    len
-                };
-                drop(storage_guard);
-                if next_len > current_len {
-                    break;
-                }
-            }
-        } else if block_time > 0 {
-            sleep(Duration::from_millis(block_time)).await;
+        (3, Some(block_time))
+    } else {
+        (1, None)
+    };
+    let stream_id_index = (command_content.len() + stream_keys_argument_start + 1) / 2;
+    let stream_keys = command_content[stream_keys_argument_start..stream_id_index]
+        .iter()
+        .map(|c| unwrap_value_to_string(c).unwrap())
+        .collect::<Vec<String>>();
+
+    //$ means only entries added after this call -> change $ to latest stream id for stream key coresponding
+    let storage_guard = storage.lock().await;
+    for stream_key in stream_keys.iter() {
+        if let Err(e) = storage_guard.check_type(stream_key, "stream") {
+            return Ok(Value::SimpleError(e.to_string()));
         }
     }
-
-    stream_keys = command_content[stream_keys_argument_start
-        ..=((command_content.len() + stream_keys_argument_start + 1) / 2 - 1)]
+    let start_ids = command_content[stream_id_index..]
         .iter()
-        .map(|c| unwrap_value_to_string(c).unwrap())
+        .zip(stream_keys.iter())
+        .map(|(start, stream_key)| match unwrap_value_to_string(start).unwrap().as_str() {
+            "$" => storage_guard.entry.get_last(stream_key).unwrap(),
+            start => start.to_string(),
+        })
         .collect::<Vec<String>>();
+    drop(storage_guard);
 
-    stream_id_index = (command_content.len() + stream_keys_argument_start + 1) / 2;
-    let storage = storage.lock().await;
+    //inside EXEC the read answers right away
+    let timeout = match block_time {
+        Some(0) if may_block => None,
+        Some(block_time) if may_block => Some(Duration::from_millis(block_time)),
+        _ => Some(Duration::ZERO),
+    };
+    let result = block_on_keys(storage, &stream_keys, timeout, false, |storage, _| {
+        Ok(read_streams(storage, &stream_keys, &start_ids))
+    })
+    .await?;
+    Ok(result.unwrap_or(Value::NullBulkString))
+}
+//Entries after the given ids, None when no stream has any
+fn read_streams(storage: &Store, stream_keys: &[String], start_ids: &[String]) -> Option<Value> {
     let mut result = Vec::new();
-    for stream_key in stream_keys {
-        let mut array_stream_key: Vec<Value> = Vec::new();
-        array_stream_key.push(Value::BulkString(stream_key.clone()));
-        let start = unwrap_value_to_string(command_content.get(stream_id_index).unwrap()).unwrap();
+    for (stream_key, start) in stream_keys.iter().zip(start_ids.iter()) {
+        if !storage.entry.check_stream_key_exist(stream_key) {
+            continue;
+        }
         //get time and sequence from start value
         let (st_time, st_seq) = if start.contains('-') && start.len() > 1 {
             let mut start = start.split('-');
@@ -497,11 +1832,11 @@ pub async fn handle_xread(
         };
         let streams = storage
             .entry
-            .get_streams_from_start(&stream_key, st_time, st_seq);
+            .get_streams_from_start(stream_key, st_time, st_seq);
 
-        //if cannot get any streams by condition
+        //skip streams without new entries
         if streams.len() == 0 {
-            return Ok(Value::NullBulkString);
+            continue;
         }
 
         let mut streams_array = Vec::new();
@@ -517,188 +1852,2696 @@ pub async fn handle_xread(
             stream_array.push(Value::Array(pair_array));
             streams_array.push(Value::Array(stream_array));
         }
-        array_stream_key.push(Value::Array(streams_array));
-        result.push(Value::Array(array_stream_key));
-        stream_id_index += 1;
+        result.push(Value::Array(vec![
+            Value::BulkString(stream_key.clone()),
+            Value::Array(streams_array),
+        ]));
+    }
+    if result.is_empty() {
+        None
+    } else {
+        Some(Value::Array(result))
     }
-    Ok(Value::Array(result))
 }
-pub async fn handle_incr(
-    command: String,
-    mut command_content: Vec<Value>,
-    storage: Arc<Mutex<Store>>,
-    transaction: &mut Transaction,
+//INCR, DECR, INCRBY and DECRBY
+pub async fn handle_incrby(
+    command_content: Vec<Value>,
+    storage: StoreHandle,
+    command: &str,
 ) -> Result<Value> {
-    match transaction.put(&command, &mut command_content) {
-        Ok(Some(response)) => return Ok(response), // return from handle_set
-        Ok(None) => (),
-        Err(e) => println!(
-            "DEV_LOG: Got error when put command to queue or something: {}",
-            e
-        ),
-    }
     let key = unwrap_value_to_string(command_content.get(0).unwrap()).unwrap();
-    let mut storage = storage.lock().await;
-    if storage.increase(&key).is_err() {
-        return Ok(Value::SimpleError(
-            "ERR value is not an integer or out of range".to_string(),
-        ));
+    let increment = match command_content.get(1).map(|value| unwrap_value_to_string(value).unwrap().parse::<i64>()) {
+        Some(Ok(increment)) => increment,
+        Some(Err(_)) => return Ok(not_an_integer()),
+        None => 1,
     };
-    let value = storage.get_value(&key).unwrap();
-    Ok(Value::SimpleInterger(value.to_string()))
-}
-pub async fn handle_multi(transaction: &mut Transaction) -> Result<Value> {
-    transaction
-        .push_back(&Value::BulkString("MULTI".to_string()))
-        .unwrap();
-    Ok(Value::SimpleString("OK".to_string()))
-}
-pub fn handle_discard(transaction: &mut Transaction) -> Result<Value> {
-    if let Some(value) = transaction.get_font_value() {
-        if value == Value::BulkString("MULTI".to_string()) {
-            return Ok(Value::SimpleString("OK".to_string()));
-        }
-        return Ok(Value::SimpleError("ERR DISCARD without MULTI".to_string()));
-    } else {
-        Ok(Value::SimpleError("ERR DISCARD without MULTI".to_string()))
+    let increment = match command {
+        "DECR" | "DECRBY" => match increment.checked_neg() {
+            Some(increment) => increment,
+            None => return Ok(Value::SimpleError("ERR decrement would overflow".to_string())),
+        },
+        _ => increment,
+    };
+    let mut storage = storage.lock().await;
+    match storage.increase_by(&key, increment) {
+        Ok(value) => Ok(Value::SimpleInterger(value.to_string())),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
     }
 }
-pub async fn handle_rpush(
-    command_content: Vec<Value>,
-    storage: Arc<Mutex<Store>>,
-) -> Result<Value> {
+pub async fn handle_incrbyfloat(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let key = unwrap_value_to_string(&command_content[0])?;
+    let increment = match unwrap_value_to_string(&command_content[1])?.parse::<f64>() {
+        Ok(increment) if increment.is_finite() => increment,
+        _ => return Ok(Value::SimpleError("ERR value is not a valid float".to_string())),
+    };
     let mut storage = storage.lock().await;
-    let key = unwrap_value_to_string(command_content.get(0).unwrap()).unwrap();
-    for value in command_content.iter().skip(1) {
-        let value = unwrap_value_to_string(value).unwrap();
-        storage.push(&key, &value).unwrap();
+    match storage.increase_by_float(&key, increment) {
+        Ok(value) => Ok(Value::BulkString(value)),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
     }
-    let list_size = storage.get_list_size(&key).unwrap();
-    Ok(Value::SimpleInterger(list_size.to_string()))
 }
-pub async fn handle_lrange(
-    command_content: Vec<Value>,
-    storage: Arc<Mutex<Store>>,
-) -> Result<Value> {
-    let storage = storage.lock().await;
-    let key = unwrap_value_to_string(command_content.get(0).unwrap()).unwrap();
-    let start = unwrap_value_to_string(command_content.get(1).unwrap())
-        .unwrap()
-        .parse::<i64>()
-        .unwrap();
-    let end = unwrap_value_to_string(command_content.get(2).unwrap())
-        .unwrap()
-        .parse::<i64>()
-        .unwrap();
-    let list = storage.get_list_range(&key, start, end).unwrap();
-    let list = list
+//Reply for a string key, nil when it does not exist
+fn string_reply(bytes: Option<std::borrow::Cow<'_, [u8]>>) -> Value {
+    match bytes {
+        Some(bytes) => Value::BulkBytes(bytes.into_owned()),
+        None => Value::NullBulkString,
+    }
+}
+//MGET key [key ...], keys holding another type are nil
+pub async fn handle_mget(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let keys = command_content
         .iter()
-        .map(|value| Value::BulkString(value.to_owned()))
-        .collect::<Vec<Value>>();
-    Ok(Value::Array(list))
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    let storage = storage.lock().await;
+    Ok(Value::Array(
+        keys.iter()
+            .map(|key| string_reply(storage.get_string_bytes(key).ok().flatten()))
+            .collect(),
+    ))
 }
-pub async fn handle_lpush(
-    command_content: Vec<Value>,
-    storage: Arc<Mutex<Store>>,
-) -> Result<Value> {
+//MSET and MSETNX key value [key value ...], MSETNX sets nothing if one key exists
+pub async fn handle_mset(command_content: Vec<Value>, storage: StoreHandle, command: &str) -> Result<Value> {
+    if !command_content.len().is_multiple_of(2) {
+        return Ok(Value::SimpleError(format!(
+            "ERR wrong number of arguments for '{}' command",
+            command.to_lowercase()
+        )));
+    }
+    let mut pairs = Vec::new();
+    for pair in command_content.chunks(2) {
+        pairs.push((unwrap_value_to_string(&pair[0])?, unwrap_value_to_bytes(&pair[1])?));
+    }
     let mut storage = storage.lock().await;
-    let key = unwrap_value_to_string(command_content.get(0).unwrap()).unwrap();
-    for value in command_content.iter().skip(1) {
-        let value = unwrap_value_to_string(value).unwrap();
-        storage.push_head(&key, &value).unwrap();
+    if command == "MSETNX" && pairs.iter().any(|(key, _)| storage.exists(key)) {
+        return Ok(Value::SimpleInterger("0".to_string()));
+    }
+    for (key, value) in pairs.iter() {
+        storage.set_value(key, value, None)?;
+    }
+    match command {
+        "MSETNX" => Ok(Value::SimpleInterger("1".to_string())),
+        _ => Ok(Value::SimpleString("OK".to_string())),
     }
-    let list_size = storage.get_list_size(&key).unwrap();
-    Ok(Value::SimpleInterger(list_size.to_string()))
 }
-pub async fn handle_llen(command_content: Vec<Value>, storage: Arc<Mutex<Store>>) -> Result<Value> {
-    let key = unwrap_value_to_string(command_content.get(0).unwrap()).unwrap();
-    let storage = storage.lock().await;
-    let list_size = storage.get_list_size(&key).unwrap();
-    Ok(Value::SimpleInterger(list_size.to_string()))
+pub async fn handle_getset(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let key = unwrap_value_to_string(&command_content[0])?;
+    let value = unwrap_value_to_bytes(&command_content[1])?;
+    let mut storage = storage.lock().await;
+    let old = match storage.get_string_bytes(&key) {
+        Ok(old) => string_reply(old),
+        Err(e) => return Ok(Value::SimpleError(e.to_string())),
+    };
+    storage.set_value(&key, &value, None)?;
+    Ok(old)
 }
-pub async fn handle_lpop(command_content: Vec<Value>, storage: Arc<Mutex<Store>>) -> Result<Value> {
-    let key = unwrap_value_to_string(command_content.get(0).unwrap()).unwrap();
-    let number = unwrap_value_to_string(
-        command_content
-            .get(1)
-            .unwrap_or(&Value::BulkString("1".to_string())),
-    )
-    .unwrap();
-
+pub async fn handle_getdel(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let key = unwrap_value_to_string(&command_content[0])?;
     let mut storage = storage.lock().await;
-    match storage.pop_front_list(&key, number.parse::<usize>().unwrap()) {
-        Ok(None) => Ok(Value::NullBulkString),
-        Ok(value) => {
-            let value = value.unwrap();
-            if value.len() == 0 {
-                return Ok(Value::NullBulkString);
-            } else if value.len() == 1 {
-                return Ok(Value::BulkString(value[0].to_owned()));
-            } else {
-                return Ok(Value::Array(
-                    value
-                        .iter()
-                        .map(|v| Value::BulkString(v.to_owned()))
-                        .collect::<Vec<Value>>(),
-                ));
+    let value = match storage.get_string_bytes(&key) {
+        Ok(value) => string_reply(value),
+        Err(e) => return Ok(Value::SimpleError(e.to_string())),
+    };
+    storage.remove(&key);
+    Ok(value)
+}
+//DEL key [key ...], the number of keys that existed
+pub async fn handle_del(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let mut storage = storage.lock().await;
+    let mut removed = 0;
+    for key in command_content.iter() {
+        if storage.remove(&unwrap_value_to_string(key)?) {
+            removed += 1;
+        }
+    }
+    Ok(Value::SimpleInterger(removed.to_string()))
+}
+//Absolute expire time for EX, PX, EXAT and PXAT, None when it is out of range
+fn parse_expire_time(option: &str, time: i64) -> Option<chrono::DateTime<chrono::Utc>> {
+    if time <= 0 {
+        return None;
+    }
+    match option {
+        "EX" => chrono::Utc::now().checked_add_signed(chrono::Duration::try_seconds(time)?),
+        "PX" => chrono::Utc::now().checked_add_signed(chrono::Duration::try_milliseconds(time)?),
+        "EXAT" => chrono::DateTime::from_timestamp(time, 0),
+        _ => chrono::DateTime::from_timestamp_millis(time),
+    }
+}
+//GETEX key [EX seconds | PX milliseconds | EXAT unix-time-seconds | PXAT unix-time-milliseconds | PERSIST]
+pub async fn handle_getex(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let args = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    let options = args[1..].iter().map(|arg| arg.to_uppercase()).collect::<Vec<String>>();
+    //Some(None) drops the ttl
+    let expiry = match options.as_slice() {
+        [] => None,
+        [persist] if persist == "PERSIST" => Some(None),
+        [option, time] if matches!(option.as_str(), "EX" | "PX" | "EXAT" | "PXAT") => {
+            let time = match time.parse::<i64>() {
+                Ok(time) => time,
+                Err(_) => return Ok(not_an_integer()),
+            };
+            match parse_expire_time(option, time) {
+                Some(expiry) => Some(Some(expiry)),
+                None => {
+                    return Ok(Value::SimpleError(
+                        "ERR invalid expire time in 'getex' command".to_string(),
+                    ))
+                }
             }
         }
-        Err(e) => Err(anyhow::anyhow!("Got error when handle lpop {}", e)),
+        _ => return Ok(Value::SimpleError("ERR syntax error".to_string())),
+    };
+    let mut storage = storage.lock().await;
+    let value = match storage.get_string_bytes(&args[0]) {
+        Ok(value) => string_reply(value),
+        Err(e) => return Ok(Value::SimpleError(e.to_string())),
+    };
+    if let Some(expiry) = expiry {
+        storage.set_expiry(&args[0], expiry);
     }
+    Ok(value)
 }
-pub async fn handle_blpop(
+pub async fn handle_setnx(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let key = unwrap_value_to_string(&command_content[0])?;
+    let value = unwrap_value_to_bytes(&command_content[1])?;
+    let mut storage = storage.lock().await;
+    if storage.exists(&key) {
+        return Ok(Value::SimpleInterger("0".to_string()));
+    }
+    storage.set_value(&key, &value, None)?;
+    Ok(Value::SimpleInterger("1".to_string()))
+}
+//SETEX key seconds value and PSETEX key milliseconds value
+pub async fn handle_setex(command_content: Vec<Value>, storage: StoreHandle, command: &str) -> Result<Value> {
+    let key = unwrap_value_to_string(&command_content[0])?;
+    let time = match unwrap_value_to_string(&command_content[1])?.parse::<i64>() {
+        Ok(time) => time,
+        Err(_) => return Ok(not_an_integer()),
+    };
+    let value = unwrap_value_to_bytes(&command_content[2])?;
+    let option = if command == "SETEX" { "EX" } else { "PX" };
+    let milliseconds = match parse_expire_time(option, time) {
+        Some(expiry) => (expiry - chrono::Utc::now()).num_milliseconds().max(1),
+        None => {
+            return Ok(Value::SimpleError(format!(
+                "ERR invalid expire time in '{}' command",
+                command.to_lowercase()
+            )))
+        }
+    };
+    let mut storage = storage.lock().await;
+    storage.set_value(&key, &value, Some(&milliseconds.to_string()))?;
+    Ok(Value::SimpleString("OK".to_string()))
+}
+pub async fn handle_append(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let key = unwrap_value_to_string(&command_content[0])?;
+    let value = unwrap_value_to_bytes(&command_content[1])?;
+    let mut storage = storage.lock().await;
+    match storage.update_string(&key, "append", |bytes| {
+        bytes.extend_from_slice(&value);
+        bytes.len()
+    }) {
+        Ok(len) => Ok(Value::SimpleInterger(len.to_string())),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+pub async fn handle_strlen(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let key = unwrap_value_to_string(&command_content[0])?;
+    let storage = storage.lock().await;
+    match storage.get_string_bytes(&key) {
+        Ok(bytes) => Ok(Value::SimpleInterger(bytes.map_or(0, |bytes| bytes.len()).to_string())),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+//GETRANGE key start end, inclusive and negative offsets count from the end
+pub async fn handle_getrange(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let args = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    let (start, end) = match (args[1].parse::<i64>(), args[2].parse::<i64>()) {
+        (Ok(start), Ok(end)) => (start, end),
+        _ => return Ok(not_an_integer()),
+    };
+    let storage = storage.lock().await;
+    let bytes = match storage.get_string_bytes(&args[0]) {
+        Ok(bytes) => bytes.unwrap_or_default(),
+        Err(e) => return Ok(Value::SimpleError(e.to_string())),
+    };
+    match bitmap::resolve_range(bytes.len() as i64, start, end) {
+        Some((start, end)) => Ok(Value::BulkBytes(bytes[start as usize..=end as usize].to_vec())),
+        None => Ok(Value::BulkString("".to_string())),
+    }
+}
+//SETRANGE key offset value, the string is padded with zero bytes up to the offset
+pub async fn handle_setrange(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let key = unwrap_value_to_string(&command_content[0])?;
+    let offset = match unwrap_value_to_string(&command_content[1])?.parse::<i64>() {
+        Ok(offset) if offset >= 0 => offset as u64,
+        Ok(_) => return Ok(Value::SimpleError("ERR offset is out of range".to_string())),
+        Err(_) => return Ok(not_an_integer()),
+    };
+    let value = unwrap_value_to_bytes(&command_content[2])?;
+    let mut storage = storage.lock().await;
+    //nothing to write, a missing key is not created
+    if value.is_empty() {
+        return match storage.get_string_bytes(&key) {
+            Ok(bytes) => Ok(Value::SimpleInterger(bytes.map_or(0, |bytes| bytes.len()).to_string())),
+            Err(e) => Ok(Value::SimpleError(e.to_string())),
+        };
+    }
+    if offset + value.len() as u64 > (BITMAP_MAX_OFFSET + 1) / 8 {
+        return Ok(Value::SimpleError(
+            "ERR string exceeds maximum allowed size (proto-max-bulk-len)".to_string(),
+        ));
+    }
+    let offset = offset as usize;
+    match storage.update_string(&key, "setrange", |bytes| {
+        if bytes.len() < offset + value.len() {
+            bytes.resize(offset + value.len(), 0);
+        }
+        bytes[offset..offset + value.len()].copy_from_slice(&value);
+        bytes.len()
+    }) {
+        Ok(len) => Ok(Value::SimpleInterger(len.to_string())),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+//LCS key1 key2 [LEN] [IDX] [MINMATCHLEN min-match-len] [WITHMATCHLEN]
+pub async fn handle_lcs(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let args = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    let (mut len, mut idx, mut with_match_len, mut min_match_len) = (false, false, false, 0usize);
+    let mut index = 2;
+    while let Some(option) = args.get(index) {
+        match option.to_uppercase().as_str() {
+            "LEN" => len = true,
+            "IDX" => idx = true,
+            "WITHMATCHLEN" => with_match_len = true,
+            "MINMATCHLEN" if index + 1 < args.len() => {
+                min_match_len = match args[index + 1].parse::<i64>() {
+                    Ok(min) => min.max(0) as usize,
+                    Err(_) => return Ok(not_an_integer()),
+                };
+                index += 1;
+            }
+            _ => return Ok(Value::SimpleError("ERR syntax error".to_string())),
+        }
+        index += 1;
+    }
+    if len && idx {
+        return Ok(Value::SimpleError(
+            "ERR If you want both the length and indexes, please just use IDX.".to_string(),
+        ));
+    }
+    let storage = storage.lock().await;
+    let (a, b) = match (storage.get_string_bytes(&args[0]), storage.get_string_bytes(&args[1])) {
+        (Ok(a), Ok(b)) => (a.unwrap_or_default(), b.unwrap_or_default()),
+        _ => {
+            return Ok(Value::SimpleError(
+                "ERR The specified keys must contain string values.".to_string(),
+            ))
+        }
+    };
+    if (a.len() + 1).checked_mul(b.len() + 1).is_none_or(|cells| cells > lcs::LCS_MAX_CELLS) {
+        return Ok(Value::SimpleError(
+            "ERR Insufficient memory, transient memory for LCS exceeds proto-max-bulk-len".to_string(),
+        ));
+    }
+    let (subsequence, matches) = lcs::lcs(&a, &b);
+    if len {
+        return Ok(Value::SimpleInterger(subsequence.len().to_string()));
+    }
+    if !idx {
+        return Ok(Value::BulkBytes(subsequence));
+    }
+    let range = |(start, end): (usize, usize)| {
+        Value::Array(vec![
+            Value::SimpleInterger(start.to_string()),
+            Value::SimpleInterger(end.to_string()),
+        ])
+    };
+    let matches = matches
+        .iter()
+        .filter(|run| run.len() >= min_match_len)
+        .map(|run| {
+            let mut reply = vec![range(run.a), range(run.b)];
+            if with_match_len {
+                reply.push(Value::SimpleInterger(run.len().to_string()));
+            }
+            Value::Array(reply)
+        })
+        .collect();
+    Ok(Value::Array(vec![
+        Value::BulkString("matches".to_string()),
+        Value::Array(matches),
+        Value::BulkString("len".to_string()),
+        Value::SimpleInterger(subsequence.len().to_string()),
+    ]))
+}
+pub async fn handle_multi(transaction: &mut Transaction) -> Result<Value> {
+    if transaction.is_in_multi() {
+        return Ok(Value::SimpleError(
+            "ERR MULTI calls can not be nested".to_string(),
+        ));
+    }
+    transaction.begin();
+    Ok(Value::SimpleString("OK".to_string()))
+}
+pub fn handle_discard(transaction: &mut Transaction) -> Result<Value> {
+    if !transaction.is_in_multi() {
+        return Ok(Value::SimpleError("ERR DISCARD without MULTI".to_string()));
+    }
+    transaction.take_queue();
+    transaction.unwatch();
+    Ok(Value::SimpleString("OK".to_string()))
+}
+pub async fn handle_watch(
+    command_content: Vec<Value>,
+    storage: StoreHandle,
+    transaction: &mut Transaction,
+) -> Result<Value> {
+    if command_content.is_empty() {
+        return Ok(Value::SimpleError(
+            "ERR wrong number of arguments for 'watch' command".to_string(),
+        ));
+    }
+    if transaction.is_in_multi() {
+        return Ok(Value::SimpleError(
+            "ERR WATCH inside MULTI is not allowed".to_string(),
+        ));
+    }
+    let storage = storage.lock().await;
+    for key in command_content.iter() {
+        transaction.watch(&unwrap_value_to_string(key)?, &storage);
+    }
+    Ok(Value::SimpleString("OK".to_string()))
+}
+pub fn handle_unwatch(transaction: &mut Transaction) -> Result<Value> {
+    transaction.unwatch();
+    Ok(Value::SimpleString("OK".to_string()))
+}
+//Leave subscriber mode and MULTI and forget WATCH, the connection stays open
+pub async fn handle_reset(
+    pubsub: Arc<Mutex<PubSub>>,
+    transaction: &mut Transaction,
+    client: &mut Client,
+) -> Result<Value> {
+    pubsub.lock().await.remove_client(client.get_id());
+    client.reset();
+    transaction.take_queue();
+    transaction.unwatch();
+    Ok(Value::SimpleString("RESET".to_string()))
+}
+pub async fn handle_rpush(
+    command_content: Vec<Value>,
+    storage: StoreHandle,
+) -> Result<Value> {
+    let mut storage = storage.lock().await;
+    let key = unwrap_value_to_string(command_content.get(0).unwrap()).unwrap();
+    let values = command_content
+        .iter()
+        .skip(1)
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    match storage.push(&key, &values) {
+        Ok(list_size) => Ok(Value::SimpleInterger(list_size.to_string())),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+pub async fn handle_lrange(
+    command_content: Vec<Value>,
+    storage: StoreHandle,
+) -> Result<Value> {
+    let storage = storage.lock().await;
+    let key = unwrap_value_to_string(command_content.get(0).unwrap()).unwrap();
+    let start = unwrap_value_to_string(command_content.get(1).unwrap())
+        .unwrap()
+        .parse::<i64>()
+        .unwrap();
+    let end = unwrap_value_to_string(command_content.get(2).unwrap())
+        .unwrap()
+        .parse::<i64>()
+        .unwrap();
+    let list = match storage.get_list_range(&key, start, end) {
+        Ok(list) => list,
+        Err(e) => return Ok(Value::SimpleError(e.to_string())),
+    };
+    let list = list
+        .iter()
+        .map(|value| Value::BulkString(value.to_owned()))
+        .collect::<Vec<Value>>();
+    Ok(Value::Array(list))
+}
+pub async fn handle_lpush(
     command_content: Vec<Value>,
-    storage: Arc<Mutex<Store>>,
+    storage: StoreHandle,
 ) -> Result<Value> {
+    let mut storage = storage.lock().await;
+    let key = unwrap_value_to_string(command_content.get(0).unwrap()).unwrap();
+    let values = command_content
+        .iter()
+        .skip(1)
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    match storage.push_head(&key, &values) {
+        Ok(list_size) => Ok(Value::SimpleInterger(list_size.to_string())),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+pub async fn handle_llen(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let key = unwrap_value_to_string(command_content.get(0).unwrap()).unwrap();
+    let storage = storage.lock().await;
+    match storage.get_list_size(&key) {
+        Ok(list_size) => Ok(Value::SimpleInterger(list_size.to_string())),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+//LPOP and RPOP key [count], with a count the reply is always an array
+pub async fn handle_lpop(command_content: Vec<Value>, storage: StoreHandle, head: bool) -> Result<Value> {
     let key = unwrap_value_to_string(command_content.get(0).unwrap()).unwrap();
-    let timeout = unwrap_value_to_string(command_content.get(1).unwrap()).unwrap().parse::<f64>().unwrap();
-    if timeout == 0f64{
-        loop {
-            let mut storage = storage.lock().await;
-            let response = storage.pop_front_list(&key, 1);
-            drop(storage);
-            match response {
-                Ok(value) => {
-                    if let Some(value) = value {
-                        if value.len() == 0 {
-                            sleep(Duration::from_millis(500)).await;
-                        } else {
+    let count = match command_content.get(1) {
+        Some(count) => match unwrap_value_to_string(count)?.parse::<usize>() {
+            Ok(count) => Some(count),
+            Err(_) => {
+                return Ok(Value::SimpleError(
+                    "ERR value is out of range, must be positive".to_string(),
+                ))
+            }
+        },
+        None => None,
+    };
 
-                            // return Ok(Value::Array(value.iter().map(|v|Value::BulkString(v.to_owned())).collect()));
-                            return Ok(Value::Array(vec![
-                                Value::BulkString(key),
-                                Value::BulkString(value[0].to_owned())
-                            ]));
-                        }
-                    } else {
-                        sleep(Duration::from_millis(500)).await;
-                    }
+    let mut storage = storage.lock().await;
+    match storage.pop_list(&key, count.unwrap_or(1), head) {
+        Ok(None) => Ok(if count.is_some() { Value::NullArray } else { Value::NullBulkString }),
+        Ok(Some(values)) => match count {
+            Some(_) => Ok(Value::Array(values.into_iter().map(Value::BulkString).collect())),
+            None => Ok(values
+                .into_iter()
+                .next()
+                .map(Value::BulkString)
+                .unwrap_or(Value::NullBulkString)),
+        },
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+pub async fn handle_lindex(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let key = unwrap_value_to_string(&command_content[0])?;
+    let index = match unwrap_value_to_string(&command_content[1])?.parse::<i64>() {
+        Ok(index) => index,
+        Err(_) => return Ok(not_an_integer()),
+    };
+    let storage = storage.lock().await;
+    match storage.get_list_index(&key, index) {
+        Ok(value) => Ok(value.map(Value::BulkString).unwrap_or(Value::NullBulkString)),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+pub async fn handle_lset(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let key = unwrap_value_to_string(&command_content[0])?;
+    let index = match unwrap_value_to_string(&command_content[1])?.parse::<i64>() {
+        Ok(index) => index,
+        Err(_) => return Ok(not_an_integer()),
+    };
+    let value = unwrap_value_to_string(&command_content[2])?;
+    let mut storage = storage.lock().await;
+    match storage.set_list_index(&key, index, &value) {
+        Ok(()) => Ok(Value::SimpleString("OK".to_string())),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+//LINSERT key BEFORE|AFTER pivot element
+pub async fn handle_linsert(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let args = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    let before = match args[1].to_uppercase().as_str() {
+        "BEFORE" => true,
+        "AFTER" => false,
+        _ => return Ok(Value::SimpleError("ERR syntax error".to_string())),
+    };
+    let mut storage = storage.lock().await;
+    match storage.insert_list(&args[0], before, &args[2], &args[3]) {
+        Ok(size) => Ok(Value::SimpleInterger(size.to_string())),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+//LREM key count element
+pub async fn handle_lrem(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let key = unwrap_value_to_string(&command_content[0])?;
+    let count = match unwrap_value_to_string(&command_content[1])?.parse::<i64>() {
+        Ok(count) => count,
+        Err(_) => return Ok(not_an_integer()),
+    };
+    let value = unwrap_value_to_string(&command_content[2])?;
+    let mut storage = storage.lock().await;
+    match storage.remove_list(&key, count, &value) {
+        Ok(removed) => Ok(Value::SimpleInterger(removed.to_string())),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+pub async fn handle_ltrim(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let key = unwrap_value_to_string(&command_content[0])?;
+    let (start, end) = match (
+        unwrap_value_to_string(&command_content[1])?.parse::<i64>(),
+        unwrap_value_to_string(&command_content[2])?.parse::<i64>(),
+    ) {
+        (Ok(start), Ok(end)) => (start, end),
+        _ => return Ok(not_an_integer()),
+    };
+    let mut storage = storage.lock().await;
+    match storage.trim_list(&key, start, end) {
+        Ok(()) => Ok(Value::SimpleString("OK".to_string())),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+//LPOS key element [RANK rank] [COUNT num-matches] [MAXLEN len]
+pub async fn handle_lpos(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let args = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    let (mut rank, mut count, mut maxlen) = (1i64, None, 0i64);
+    for option in args[2..].chunks(2) {
+        let number = match option.get(1).map(|number| number.parse::<i64>()) {
+            Some(Ok(number)) => number,
+            Some(Err(_)) => return Ok(not_an_integer()),
+            None => return Ok(Value::SimpleError("ERR syntax error".to_string())),
+        };
+        match option[0].to_uppercase().as_str() {
+            "RANK" => rank = number,
+            "COUNT" => count = Some(number),
+            "MAXLEN" => maxlen = number,
+            _ => return Ok(Value::SimpleError("ERR syntax error".to_string())),
+        }
+    }
+    if rank == 0 {
+        return Ok(Value::SimpleError(
+            "ERR RANK can't be zero: use 1 to start from the first match, 2 from the second ... or use negative to start from the end of the list".to_string(),
+        ));
+    }
+    if count.is_some_and(|count| count < 0) {
+        return Ok(Value::SimpleError("ERR COUNT can't be negative".to_string()));
+    }
+    if maxlen < 0 {
+        return Ok(Value::SimpleError("ERR MAXLEN can't be negative".to_string()));
+    }
+    let storage = storage.lock().await;
+    let positions = match storage.get_list_positions(
+        &args[0],
+        &args[1],
+        rank,
+        count.unwrap_or(1) as usize,
+        maxlen as usize,
+    ) {
+        Ok(positions) => positions,
+        Err(e) => return Ok(Value::SimpleError(e.to_string())),
+    };
+    match count {
+        Some(_) => Ok(Value::Array(
+            positions
+                .into_iter()
+                .map(|position| Value::SimpleInterger(position.to_string()))
+                .collect(),
+        )),
+        None => Ok(positions
+            .first()
+            .map(|position| Value::SimpleInterger(position.to_string()))
+            .unwrap_or(Value::NullBulkString)),
+    }
+}
+//RPUSHX and LPUSHX only push on a list that already exists
+pub async fn handle_pushx(command_content: Vec<Value>, storage: StoreHandle, head: bool) -> Result<Value> {
+    let key = unwrap_value_to_string(&command_content[0])?;
+    let values = command_content
+        .iter()
+        .skip(1)
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    let mut storage = storage.lock().await;
+    if !storage.exists(&key) {
+        return Ok(Value::SimpleInterger("0".to_string()));
+    }
+    let size = if head { storage.push_head(&key, &values) } else { storage.push(&key, &values) };
+    match size {
+        Ok(size) => Ok(Value::SimpleInterger(size.to_string())),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+//LMOVE is BLMOVE that never waits
+pub async fn handle_lmove(mut command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    command_content.push(Value::BulkString("0".to_string()));
+    handle_blmove(command_content, storage, false, false).await
+}
+//LMPOP is BLMPOP that never waits
+pub async fn handle_lmpop(mut command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    command_content.insert(0, Value::BulkString("0".to_string()));
+    handle_blmpop(command_content, storage, false).await
+}
+fn not_an_integer() -> Value {
+    Value::SimpleError("ERR value is not an integer or out of range".to_string())
+}
+//BLPOP and BRPOP key [key ...] timeout, the first key with items wins
+pub async fn handle_blpop(
+    command_content: Vec<Value>,
+    storage: StoreHandle,
+    may_block: bool,
+    head: bool,
+) -> Result<Value> {
+    let mut keys = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    let timeout = match parse_block_timeout(&keys.pop().unwrap(), may_block) {
+        Ok(timeout) => timeout,
+        Err(error) => return Ok(error),
+    };
+    let result = block_on_keys(storage, &keys, timeout, true, |storage, ready| {
+        for key in ready {
+            match storage.pop_list(key, 1, head) {
+                Ok(Some(values)) if !values.is_empty() => {
+                    return Ok(Some(Value::Array(vec![
+                        Value::BulkString(key.clone()),
+                        Value::BulkString(values[0].clone()),
+                    ])));
                 }
-                Err(e) => return Err(anyhow::anyhow!("Got error when handle lpop {}", e)),
+                Ok(_) => {}
+                Err(e) => return Ok(Some(Value::SimpleError(e.to_string()))),
             }
         }
-    }
-    else{
-        sleep(Duration::from_millis((timeout * 1000f64) as u64)).await;
-        let mut storage = storage.lock().await;
-        let response = storage.pop_front_list(&key, 1);
-        match response {
-            Ok(value) => {
-                if let Some(value) = value {
-                    if value.len() == 0 {
-                        Ok(Value::NullBulkString)
-                    } else {
-                        // return Ok(Value::Array(value.iter().map(|v|Value::BulkString(v.to_owned())).collect()));
-                        return Ok(Value::Array(vec![
-                            Value::BulkString(key),
-                            Value::BulkString(value[0].to_owned())
-                        ]));
-                    }
-                } else {
-                    Ok(Value::NullBulkString)
+        Ok(None)
+    })
+    .await?;
+    Ok(result.unwrap_or(Value::NullArray))
+}
+//BLMOVE source destination LEFT|RIGHT LEFT|RIGHT timeout, BRPOPLPUSH source destination timeout
+pub async fn handle_blmove(
+    command_content: Vec<Value>,
+    storage: StoreHandle,
+    may_block: bool,
+    rpoplpush: bool,
+) -> Result<Value> {
+    let args = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    let (source, destination) = (args[0].clone(), args[1].clone());
+    let (from_head, to_head, timeout) = if rpoplpush {
+        (false, true, &args[2])
+    } else {
+        match (parse_list_side(&args[2]), parse_list_side(&args[3])) {
+            (Some(from_head), Some(to_head)) => (from_head, to_head, &args[4]),
+            _ => return Ok(Value::SimpleError("ERR syntax error".to_string())),
+        }
+    };
+    let timeout = match parse_block_timeout(timeout, may_block) {
+        Ok(timeout) => timeout,
+        Err(error) => return Ok(error),
+    };
+    let result = block_on_keys(storage, std::slice::from_ref(&source), timeout, true, |storage, ready| {
+        if ready.is_empty() {
+            return Ok(None);
+        }
+        match storage.move_list(&source, &destination, from_head, to_head) {
+            Ok(value) => Ok(value.map(Value::BulkString)),
+            Err(e) => Ok(Some(Value::SimpleError(e.to_string()))),
+        }
+    })
+    .await?;
+    Ok(result.unwrap_or(Value::NullBulkString))
+}
+//BLMPOP timeout numkeys key [key ...] LEFT|RIGHT [COUNT count]
+pub async fn handle_blmpop(
+    command_content: Vec<Value>,
+    storage: StoreHandle,
+    may_block: bool,
+) -> Result<Value> {
+    let args = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    let numkeys = match args[1].parse::<usize>() {
+        Ok(0) => {
+            return Ok(Value::SimpleError(
+                "ERR numkeys should be greater than 0".to_string(),
+            ))
+        }
+        Ok(numkeys) if numkeys + 3 <= args.len() => numkeys,
+        _ => return Ok(Value::SimpleError("ERR syntax error".to_string())),
+    };
+    let keys = args[2..2 + numkeys].to_vec();
+    let head = match parse_list_side(&args[2 + numkeys]) {
+        Some(head) => head,
+        None => return Ok(Value::SimpleError("ERR syntax error".to_string())),
+    };
+    let count = match &args[3 + numkeys..] {
+        [] => 1,
+        [option, count] if option.to_uppercase() == "COUNT" => match count.parse::<usize>() {
+            Ok(count) if count > 0 => count,
+            _ => {
+                return Ok(Value::SimpleError(
+                    "ERR count should be greater than 0".to_string(),
+                ))
+            }
+        },
+        _ => return Ok(Value::SimpleError("ERR syntax error".to_string())),
+    };
+    let timeout = match parse_block_timeout(&args[0], may_block) {
+        Ok(timeout) => timeout,
+        Err(error) => return Ok(error),
+    };
+    let result = block_on_keys(storage, &keys, timeout, true, |storage, ready| {
+        for key in ready {
+            match storage.pop_list(key, count, head) {
+                Ok(Some(values)) if !values.is_empty() => {
+                    return Ok(Some(Value::Array(vec![
+                        Value::BulkString(key.clone()),
+                        Value::Array(values.into_iter().map(Value::BulkString).collect()),
+                    ])));
                 }
+                Ok(_) => {}
+                Err(e) => return Ok(Some(Value::SimpleError(e.to_string()))),
             }
-            Err(e) => return Err(anyhow::anyhow!("Got error when handle lpop {}", e)),
         }
+        Ok(None)
+    })
+    .await?;
+    Ok(result.unwrap_or(Value::NullArray))
+}
+//Seconds with fractions, None blocks forever, inside EXEC nothing blocks
+fn parse_block_timeout(timeout: &str, may_block: bool) -> std::result::Result<Option<Duration>, Value> {
+    let timeout = match timeout.parse::<f64>() {
+        Ok(timeout) if timeout.is_finite() => timeout,
+        _ => {
+            return Err(Value::SimpleError(
+                "ERR timeout is not a float or out of range".to_string(),
+            ))
+        }
+    };
+    if timeout < 0f64 {
+        return Err(Value::SimpleError("ERR timeout is negative".to_string()));
+    }
+    if !may_block {
+        Ok(Some(Duration::ZERO))
+    } else if timeout == 0f64 {
+        Ok(None)
+    } else {
+        Ok(Some(Duration::from_secs_f64(timeout)))
+    }
+}
+//LEFT is the head of the list
+fn parse_list_side(side: &str) -> Option<bool> {
+    match side.to_uppercase().as_str() {
+        "LEFT" => Some(true),
+        "RIGHT" => Some(false),
+        _ => None,
+    }
+}
+//HSET key field value [field value ...], HMSET is the same but answers OK
+pub async fn handle_hset(command_content: Vec<Value>, storage: StoreHandle, hmset: bool) -> Result<Value> {
+    let args = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    if args.len() % 2 == 0 {
+        return Ok(Value::SimpleError(format!(
+            "ERR wrong number of arguments for '{}' command",
+            if hmset { "hmset" } else { "hset" }
+        )));
+    }
+    let pairs = args[1..]
+        .chunks(2)
+        .map(|pair| (pair[0].clone(), pair[1].clone()))
+        .collect::<Vec<(String, String)>>();
+    let mut storage = storage.lock().await;
+    match storage.set_hash(&args[0], &pairs, false) {
+        Ok(_) if hmset => Ok(Value::SimpleString("OK".to_string())),
+        Ok(added) => Ok(Value::SimpleInterger(added.to_string())),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+pub async fn handle_hsetnx(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let key = unwrap_value_to_string(&command_content[0])?;
+    let pair = (
+        unwrap_value_to_string(&command_content[1])?,
+        unwrap_value_to_string(&command_content[2])?,
+    );
+    let mut storage = storage.lock().await;
+    match storage.set_hash(&key, &[pair], true) {
+        Ok(added) => Ok(Value::SimpleInterger(added.to_string())),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+pub async fn handle_hget(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let key = unwrap_value_to_string(&command_content[0])?;
+    let field = unwrap_value_to_string(&command_content[1])?;
+    let storage = storage.lock().await;
+    match storage.get_hash_field(&key, &field) {
+        Ok(value) => Ok(value.map(Value::BulkString).unwrap_or(Value::NullBulkString)),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+pub async fn handle_hmget(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let key = unwrap_value_to_string(&command_content[0])?;
+    let storage = storage.lock().await;
+    let mut values = Vec::new();
+    for field in command_content.iter().skip(1) {
+        match storage.get_hash_field(&key, &unwrap_value_to_string(field)?) {
+            Ok(value) => values.push(value.map(Value::BulkString).unwrap_or(Value::NullBulkString)),
+            Err(e) => return Ok(Value::SimpleError(e.to_string())),
+        }
+    }
+    Ok(Value::Array(values))
+}
+pub async fn handle_hdel(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let args = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    let mut storage = storage.lock().await;
+    match storage.remove_hash_fields(&args[0], &args[1..]) {
+        Ok(removed) => Ok(Value::SimpleInterger(removed.to_string())),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+pub async fn handle_hexists(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let key = unwrap_value_to_string(&command_content[0])?;
+    let field = unwrap_value_to_string(&command_content[1])?;
+    let storage = storage.lock().await;
+    match storage.get_hash_field(&key, &field) {
+        Ok(value) => Ok(Value::SimpleInterger(usize::from(value.is_some()).to_string())),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+pub async fn handle_hlen(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let key = unwrap_value_to_string(&command_content[0])?;
+    let storage = storage.lock().await;
+    match storage.get_hash_size(&key) {
+        Ok(size) => Ok(Value::SimpleInterger(size.to_string())),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+//HGETALL, HKEYS and HVALS, the same walk keeping fields, values or both
+pub async fn handle_hgetall(
+    command_content: Vec<Value>,
+    storage: StoreHandle,
+    fields: bool,
+    values: bool,
+) -> Result<Value> {
+    let key = unwrap_value_to_string(&command_content[0])?;
+    let storage = storage.lock().await;
+    let pairs = match storage.get_hash_pairs(&key) {
+        Ok(pairs) => pairs,
+        Err(e) => return Ok(Value::SimpleError(e.to_string())),
+    };
+    let mut result = Vec::new();
+    for (field, value) in pairs {
+        if fields {
+            result.push(Value::BulkString(field));
+        }
+        if values {
+            result.push(Value::BulkString(value));
+        }
+    }
+    Ok(Value::Array(result))
+}
+pub async fn handle_hstrlen(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let key = unwrap_value_to_string(&command_content[0])?;
+    let field = unwrap_value_to_string(&command_content[1])?;
+    let storage = storage.lock().await;
+    match storage.get_hash_field(&key, &field) {
+        Ok(value) => Ok(Value::SimpleInterger(value.map_or(0, |value| value.len()).to_string())),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+pub async fn handle_hincrby(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let key = unwrap_value_to_string(&command_content[0])?;
+    let field = unwrap_value_to_string(&command_content[1])?;
+    let increment = match unwrap_value_to_string(&command_content[2])?.parse::<i64>() {
+        Ok(increment) => increment,
+        Err(_) => return Ok(not_an_integer()),
+    };
+    let mut storage = storage.lock().await;
+    match storage.increase_hash(&key, &field, increment) {
+        Ok(value) => Ok(Value::SimpleInterger(value.to_string())),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+pub async fn handle_hincrbyfloat(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let key = unwrap_value_to_string(&command_content[0])?;
+    let field = unwrap_value_to_string(&command_content[1])?;
+    let increment = match unwrap_value_to_string(&command_content[2])?.parse::<f64>() {
+        Ok(increment) if increment.is_finite() => increment,
+        _ => return Ok(Value::SimpleError("ERR value is not a valid float".to_string())),
+    };
+    let mut storage = storage.lock().await;
+    match storage.increase_hash_float(&key, &field, increment) {
+        Ok(value) => Ok(Value::BulkString(value)),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+//HRANDFIELD key [count [WITHVALUES]], a negative count may return the same field twice
+pub async fn handle_hrandfield(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let args = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    let count = match args.get(1).map(|count| count.parse::<i64>()) {
+        Some(Ok(count)) => Some(count),
+        Some(Err(_)) => return Ok(not_an_integer()),
+        None => None,
+    };
+    let with_values = match &args[1..] {
+        [] | [_] => false,
+        [_, option] if option.to_uppercase() == "WITHVALUES" => true,
+        _ => return Ok(Value::SimpleError("ERR syntax error".to_string())),
+    };
+    let storage = storage.lock().await;
+    let mut pairs = match storage.get_hash_pairs(&args[0]) {
+        Ok(pairs) => pairs,
+        Err(e) => return Ok(Value::SimpleError(e.to_string())),
+    };
+    let count = match count {
+        Some(count) => count,
+        None if pairs.is_empty() => return Ok(Value::NullBulkString),
+        None => return Ok(Value::BulkString(pairs.swap_remove(random_index(pairs.len())).0)),
+    };
+    let mut picked = Vec::new();
+    if count < 0 {
+        for _ in 0..(if pairs.is_empty() { 0 } else { count.unsigned_abs() }) {
+            picked.push(pairs[random_index(pairs.len())].clone());
+        }
+    } else {
+        while picked.len() < count as usize && !pairs.is_empty() {
+            picked.push(pairs.swap_remove(random_index(pairs.len())));
+        }
+    }
+    let mut result = Vec::new();
+    for (field, value) in picked {
+        result.push(Value::BulkString(field));
+        if with_values {
+            result.push(Value::BulkString(value));
+        }
+    }
+    Ok(Value::Array(result))
+}
+//HSCAN key cursor [MATCH pattern] [COUNT count] [NOVALUES], the cursor is an offset in the sorted fields
+pub async fn handle_hscan(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let args = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    let cursor = match args[1].parse::<usize>() {
+        Ok(cursor) => cursor,
+        Err(_) => return Ok(Value::SimpleError("ERR invalid cursor".to_string())),
+    };
+    let (mut pattern, mut count, mut no_values) = (None, 10usize, false);
+    let mut options = args[2..].iter();
+    while let Some(option) = options.next() {
+        match (option.to_uppercase().as_str(), options.next()) {
+            ("MATCH", Some(value)) => pattern = Some(value.clone()),
+            ("COUNT", Some(value)) => match value.parse::<usize>() {
+                Ok(value) if value > 0 => count = value,
+                _ => return Ok(Value::SimpleError("ERR syntax error".to_string())),
+            },
+            ("NOVALUES", next) => {
+                no_values = true;
+                if next.is_some() {
+                    return Ok(Value::SimpleError("ERR syntax error".to_string()));
+                }
+            }
+            _ => return Ok(Value::SimpleError("ERR syntax error".to_string())),
+        }
+    }
+    let storage = storage.lock().await;
+    let mut pairs = match storage.get_hash_pairs(&args[0]) {
+        Ok(pairs) => pairs,
+        Err(e) => return Ok(Value::SimpleError(e.to_string())),
+    };
+    pairs.sort();
+    let end = cursor.saturating_add(count).min(pairs.len());
+    let next_cursor = if end >= pairs.len() { 0 } else { end };
+    let mut result = Vec::new();
+    for (field, value) in pairs.into_iter().take(end).skip(cursor) {
+        if pattern.as_ref().is_some_and(|pattern| !glob_match(pattern, &field)) {
+            continue;
+        }
+        result.push(Value::BulkString(field));
+        if !no_values {
+            result.push(Value::BulkString(value));
+        }
+    }
+    Ok(Value::Array(vec![
+        Value::BulkString(next_cursor.to_string()),
+        Value::Array(result),
+    ]))
+}
+//FIELDS numfields field [field ...], the tail every per-field ttl command ends with
+fn parse_hash_fields(args: &[String]) -> std::result::Result<Vec<String>, Value> {
+    if args.first().is_none_or(|fields| fields.to_uppercase() != "FIELDS") {
+        return Err(Value::SimpleError(
+            "ERR Mandatory argument FIELDS is missing or not at the right position".to_string(),
+        ));
+    }
+    match args.get(1).map(|numfields| numfields.parse::<usize>()) {
+        Some(Ok(0)) => Err(Value::SimpleError(
+            "ERR Parameter `numFields` should be greater than 0".to_string(),
+        )),
+        Some(Ok(numfields)) if numfields == args.len() - 2 => Ok(args[2..].to_vec()),
+        Some(Ok(_)) => Err(Value::SimpleError(
+            "ERR The `numfields` parameter must match the number of arguments".to_string(),
+        )),
+        _ => Err(not_an_integer()),
+    }
+}
+fn hash_field_replies(replies: Vec<i64>) -> Value {
+    Value::Array(
+        replies
+            .into_iter()
+            .map(|reply| Value::SimpleInterger(reply.to_string()))
+            .collect(),
+    )
+}
+//HEXPIRE key seconds [NX | XX | GT | LT] FIELDS numfields field [field ...]
+pub async fn handle_hexpire(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let args = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    let seconds = match args[1].parse::<i64>() {
+        Ok(seconds) if (0..=1i64 << 48).contains(&seconds) => seconds,
+        Ok(_) => {
+            return Ok(Value::SimpleError(
+                "ERR invalid expire time, must be >= 0 and <= 2^48".to_string(),
+            ))
+        }
+        Err(_) => return Ok(not_an_integer()),
+    };
+    let condition = args[2].to_uppercase();
+    let (condition, rest) = match condition.as_str() {
+        "NX" | "XX" | "GT" | "LT" => (Some(condition.as_str()), &args[3..]),
+        _ => (None, &args[2..]),
+    };
+    let fields = match parse_hash_fields(rest) {
+        Ok(fields) => fields,
+        Err(error) => return Ok(error),
+    };
+    let expire = chrono::Utc::now() + chrono::Duration::seconds(seconds);
+    let mut storage = storage.lock().await;
+    match storage.expire_hash_fields(&args[0], &fields, expire, condition) {
+        Ok(replies) => Ok(hash_field_replies(replies)),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+//HTTL key FIELDS numfields field [field ...], seconds left or -1 / -2
+pub async fn handle_httl(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let args = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    let fields = match parse_hash_fields(&args[1..]) {
+        Ok(fields) => fields,
+        Err(error) => return Ok(error),
+    };
+    let storage = storage.lock().await;
+    match storage.get_hash_ttls(&args[0], &fields) {
+        Ok(ttls) => Ok(hash_field_replies(
+            ttls.into_iter()
+                .map(|ttl| if ttl < 0 { ttl } else { (ttl + 500) / 1000 })
+                .collect(),
+        )),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+//HPERSIST key FIELDS numfields field [field ...]
+pub async fn handle_hpersist(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let args = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    let fields = match parse_hash_fields(&args[1..]) {
+        Ok(fields) => fields,
+        Err(error) => return Ok(error),
+    };
+    let mut storage = storage.lock().await;
+    match storage.persist_hash_fields(&args[0], &fields) {
+        Ok(replies) => Ok(hash_field_replies(replies)),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+fn set_operation(command: &str) -> SetOperation {
+    match command {
+        "SINTER" | "SINTERSTORE" => SetOperation::Inter,
+        "SUNION" | "SUNIONSTORE" => SetOperation::Union,
+        _ => SetOperation::Diff,
+    }
+}
+fn members_reply(members: Vec<String>) -> Value {
+    Value::Array(members.into_iter().map(Value::BulkString).collect())
+}
+pub async fn handle_sadd(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let args = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    let mut storage = storage.lock().await;
+    match storage.add_set(&args[0], &args[1..]) {
+        Ok(added) => Ok(Value::SimpleInterger(added.to_string())),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+pub async fn handle_srem(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let args = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    let mut storage = storage.lock().await;
+    match storage.remove_set(&args[0], &args[1..]) {
+        Ok(removed) => Ok(Value::SimpleInterger(removed.to_string())),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+pub async fn handle_sismember(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let key = unwrap_value_to_string(&command_content[0])?;
+    let member = unwrap_value_to_string(&command_content[1])?;
+    let storage = storage.lock().await;
+    match storage.is_set_member(&key, &member) {
+        Ok(is_member) => Ok(Value::SimpleInterger(usize::from(is_member).to_string())),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+pub async fn handle_smismember(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let key = unwrap_value_to_string(&command_content[0])?;
+    let storage = storage.lock().await;
+    let mut replies = Vec::new();
+    for member in command_content.iter().skip(1) {
+        match storage.is_set_member(&key, &unwrap_value_to_string(member)?) {
+            Ok(is_member) => replies.push(Value::SimpleInterger(usize::from(is_member).to_string())),
+            Err(e) => return Ok(Value::SimpleError(e.to_string())),
+        }
+    }
+    Ok(Value::Array(replies))
+}
+pub async fn handle_smembers(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let key = unwrap_value_to_string(&command_content[0])?;
+    let storage = storage.lock().await;
+    match storage.get_set_members(&key) {
+        Ok(members) => Ok(members_reply(members)),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+pub async fn handle_scard(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let key = unwrap_value_to_string(&command_content[0])?;
+    let storage = storage.lock().await;
+    match storage.get_set_size(&key) {
+        Ok(size) => Ok(Value::SimpleInterger(size.to_string())),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+//SPOP key [count]
+pub async fn handle_spop(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let key = unwrap_value_to_string(&command_content[0])?;
+    let count = match command_content.get(1) {
+        Some(count) => match unwrap_value_to_string(count)?.parse::<usize>() {
+            Ok(count) => Some(count),
+            Err(_) => {
+                return Ok(Value::SimpleError(
+                    "ERR value is out of range, must be positive".to_string(),
+                ))
+            }
+        },
+        None => None,
+    };
+    if command_content.len() > 2 {
+        return Ok(Value::SimpleError("ERR syntax error".to_string()));
+    }
+    let mut storage = storage.lock().await;
+    match storage.pop_set(&key, count.unwrap_or(1)) {
+        Ok(popped) if count.is_some() => Ok(members_reply(popped)),
+        Ok(popped) => Ok(popped
+            .into_iter()
+            .next()
+            .map(Value::BulkString)
+            .unwrap_or(Value::NullBulkString)),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+//SRANDMEMBER key [count], a negative count may return the same member twice
+pub async fn handle_srandmember(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let key = unwrap_value_to_string(&command_content[0])?;
+    let count = match command_content.get(1) {
+        Some(count) => match unwrap_value_to_string(count)?.parse::<i64>() {
+            Ok(count) => Some(count),
+            Err(_) => return Ok(not_an_integer()),
+        },
+        None => None,
+    };
+    if command_content.len() > 2 {
+        return Ok(Value::SimpleError("ERR syntax error".to_string()));
+    }
+    let storage = storage.lock().await;
+    let mut members = match storage.get_set_members(&key) {
+        Ok(members) => members,
+        Err(e) => return Ok(Value::SimpleError(e.to_string())),
+    };
+    let count = match count {
+        Some(count) => count,
+        None if members.is_empty() => return Ok(Value::NullBulkString),
+        None => return Ok(Value::BulkString(members.swap_remove(random_index(members.len())))),
+    };
+    let mut picked = Vec::new();
+    if count < 0 {
+        for _ in 0..(if members.is_empty() { 0 } else { count.unsigned_abs() }) {
+            picked.push(members[random_index(members.len())].clone());
+        }
+    } else {
+        while picked.len() < count as usize && !members.is_empty() {
+            picked.push(members.swap_remove(random_index(members.len())));
+        }
+    }
+    Ok(members_reply(picked))
+}
+pub async fn handle_smove(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let args = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    let mut storage = storage.lock().await;
+    match storage.move_set(&args[0], &args[1], &args[2]) {
+        Ok(moved) => Ok(Value::SimpleInterger(usize::from(moved).to_string())),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+//SINTER, SUNION and SDIFF key [key ...]
+pub async fn handle_sinter(
+    command_content: Vec<Value>,
+    storage: StoreHandle,
+    operation: SetOperation,
+) -> Result<Value> {
+    let keys = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    let storage = storage.lock().await;
+    match storage.combine_sets(&keys, operation) {
+        Ok(members) => Ok(members_reply(members)),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+//SINTERSTORE, SUNIONSTORE and SDIFFSTORE destination key [key ...]
+pub async fn handle_sinterstore(
+    command_content: Vec<Value>,
+    storage: StoreHandle,
+    operation: SetOperation,
+    event: &str,
+) -> Result<Value> {
+    let args = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    let mut storage = storage.lock().await;
+    match storage.combine_sets(&args[1..], operation) {
+        Ok(members) => Ok(Value::SimpleInterger(
+            storage.store_set(&args[0], &members, event).to_string(),
+        )),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+//SINTERCARD numkeys key [key ...] [LIMIT limit], limit 0 means no limit
+pub async fn handle_sintercard(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let args = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    let numkeys = match args[0].parse::<usize>() {
+        Ok(0) => {
+            return Ok(Value::SimpleError(
+                "ERR numkeys should be greater than 0".to_string(),
+            ))
+        }
+        Ok(numkeys) if numkeys < args.len() => numkeys,
+        Ok(_) => {
+            return Ok(Value::SimpleError(
+                "ERR Number of keys can't be greater than number of args".to_string(),
+            ))
+        }
+        Err(_) => return Ok(not_an_integer()),
+    };
+    let limit = match &args[1 + numkeys..] {
+        [] => 0,
+        [option, limit] if option.to_uppercase() == "LIMIT" => match limit.parse::<usize>() {
+            Ok(limit) => limit,
+            Err(_) => return Ok(Value::SimpleError("ERR LIMIT can't be negative".to_string())),
+        },
+        _ => return Ok(Value::SimpleError("ERR syntax error".to_string())),
+    };
+    let storage = storage.lock().await;
+    match storage.combine_sets(&args[1..1 + numkeys], SetOperation::Inter) {
+        Ok(members) if limit > 0 => Ok(Value::SimpleInterger(members.len().min(limit).to_string())),
+        Ok(members) => Ok(Value::SimpleInterger(members.len().to_string())),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+//Members, each followed by its score when with_scores
+fn scored_reply(pairs: Vec<(String, f64)>, with_scores: bool) -> Value {
+    let mut result = Vec::new();
+    for (member, score) in pairs {
+        result.push(Value::BulkString(member));
+        if with_scores {
+            result.push(Value::BulkString(format_score(score)));
+        }
+    }
+    Value::Array(result)
+}
+fn not_a_float() -> Value {
+    Value::SimpleError("ERR value is not a valid float".to_string())
+}
+fn parse_score(score: &str) -> Option<f64> {
+    score.parse::<f64>().ok().filter(|score| !score.is_nan())
+}
+//ZADD key [NX | XX] [GT | LT] [CH] [INCR] score member [score member ...]
+pub async fn handle_zadd(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let args = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    let mut options = ZAddOptions::default();
+    let mut changed = false;
+    let mut index = 1;
+    while let Some(option) = args.get(index) {
+        match option.to_uppercase().as_str() {
+            "NX" => options.nx = true,
+            "XX" => options.xx = true,
+            "GT" => options.gt = true,
+            "LT" => options.lt = true,
+            "CH" => changed = true,
+            "INCR" => options.incr = true,
+            _ => break,
+        }
+        index += 1;
+    }
+    let elements = &args[index..];
+    if elements.is_empty() || elements.len() % 2 == 1 {
+        return Ok(Value::SimpleError("ERR syntax error".to_string()));
+    }
+    if options.nx && options.xx {
+        return Ok(Value::SimpleError(
+            "ERR XX and NX options at the same time are not compatible".to_string(),
+        ));
+    }
+    if (options.gt && options.lt) || (options.nx && (options.gt || options.lt)) {
+        return Ok(Value::SimpleError(
+            "ERR GT, LT, and/or NX options at the same time are not compatible".to_string(),
+        ));
+    }
+    if options.incr && elements.len() > 2 {
+        return Ok(Value::SimpleError(
+            "ERR INCR option supports a single increment-element pair".to_string(),
+        ));
+    }
+    let mut pairs = Vec::new();
+    for pair in elements.chunks(2) {
+        match parse_score(&pair[0]) {
+            Some(score) => pairs.push((score, pair[1].clone())),
+            None => return Ok(not_a_float()),
+        }
+    }
+    let mut storage = storage.lock().await;
+    let results = match storage.add_sorted_set(&args[0], &pairs, &options) {
+        Ok(results) => results,
+        Err(e) => return Ok(Value::SimpleError(e.to_string())),
+    };
+    if options.incr {
+        return Ok(match results[0] {
+            ZAddResult::Added(score) | ZAddResult::Updated(score) | ZAddResult::Unchanged(score) => {
+                Value::BulkString(format_score(score))
+            }
+            ZAddResult::Skipped => Value::NullBulkString,
+        });
+    }
+    let count = results
+        .iter()
+        .filter(|result| match result {
+            ZAddResult::Added(_) => true,
+            ZAddResult::Updated(_) => changed,
+            _ => false,
+        })
+        .count();
+    Ok(Value::SimpleInterger(count.to_string()))
+}
+//ZINCRBY key increment member
+pub async fn handle_zincrby(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let key = unwrap_value_to_string(&command_content[0])?;
+    let increment = match parse_score(&unwrap_value_to_string(&command_content[1])?) {
+        Some(increment) => increment,
+        None => return Ok(not_a_float()),
+    };
+    let member = unwrap_value_to_string(&command_content[2])?;
+    let options = ZAddOptions {
+        incr: true,
+        ..ZAddOptions::default()
+    };
+    let mut storage = storage.lock().await;
+    match storage.add_sorted_set(&key, &[(increment, member)], &options) {
+        Ok(results) => match results[0] {
+            ZAddResult::Added(score) | ZAddResult::Updated(score) | ZAddResult::Unchanged(score) => {
+                Ok(Value::BulkString(format_score(score)))
+            }
+            ZAddResult::Skipped => Ok(Value::NullBulkString),
+        },
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+pub async fn handle_zrem(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let args = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    let mut storage = storage.lock().await;
+    match storage.remove_sorted_set(&args[0], &args[1..]) {
+        Ok(removed) => Ok(Value::SimpleInterger(removed.to_string())),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+pub async fn handle_zscore(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let key = unwrap_value_to_string(&command_content[0])?;
+    let member = unwrap_value_to_string(&command_content[1])?;
+    let storage = storage.lock().await;
+    match storage.get_sorted_set_score(&key, &member) {
+        Ok(score) => Ok(score
+            .map(|score| Value::BulkString(format_score(score)))
+            .unwrap_or(Value::NullBulkString)),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+pub async fn handle_zmscore(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let key = unwrap_value_to_string(&command_content[0])?;
+    let storage = storage.lock().await;
+    let mut scores = Vec::new();
+    for member in command_content.iter().skip(1) {
+        match storage.get_sorted_set_score(&key, &unwrap_value_to_string(member)?) {
+            Ok(score) => scores.push(
+                score
+                    .map(|score| Value::BulkString(format_score(score)))
+                    .unwrap_or(Value::NullBulkString),
+            ),
+            Err(e) => return Ok(Value::SimpleError(e.to_string())),
+        }
+    }
+    Ok(Value::Array(scores))
+}
+pub async fn handle_zcard(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let key = unwrap_value_to_string(&command_content[0])?;
+    let storage = storage.lock().await;
+    match storage.get_sorted_set_size(&key) {
+        Ok(size) => Ok(Value::SimpleInterger(size.to_string())),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+//ZCOUNT key min max
+pub async fn handle_zcount(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let key = unwrap_value_to_string(&command_content[0])?;
+    let (min, max) = match (
+        ScoreBound::parse(&unwrap_value_to_string(&command_content[1])?),
+        ScoreBound::parse(&unwrap_value_to_string(&command_content[2])?),
+    ) {
+        (Some(min), Some(max)) => (min, max),
+        _ => return Ok(not_a_score_range()),
+    };
+    let storage = storage.lock().await;
+    match storage.count_sorted_set(&key, &min, &max) {
+        Ok(count) => Ok(Value::SimpleInterger(count.to_string())),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+fn not_a_score_range() -> Value {
+    Value::SimpleError("ERR min or max is not a float".to_string())
+}
+fn not_a_lex_range() -> Value {
+    Value::SimpleError("ERR min or max not valid string range item".to_string())
+}
+//ZRANK and ZREVRANK key member [WITHSCORE]
+pub async fn handle_zrank(command_content: Vec<Value>, storage: StoreHandle, reverse: bool) -> Result<Value> {
+    let args = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    let with_score = match &args[2..] {
+        [] => false,
+        [option] if option.to_uppercase() == "WITHSCORE" => true,
+        _ => return Ok(Value::SimpleError("ERR syntax error".to_string())),
+    };
+    let storage = storage.lock().await;
+    match storage.get_sorted_set_rank(&args[0], &args[1], reverse) {
+        Ok(None) => Ok(Value::NullBulkString),
+        Ok(Some((rank, score))) if with_score => Ok(Value::Array(vec![
+            Value::SimpleInterger(rank.to_string()),
+            Value::BulkString(format_score(score)),
+        ])),
+        Ok(Some((rank, _))) => Ok(Value::SimpleInterger(rank.to_string())),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+//Parsed ZRANGE arguments after the key
+struct ZRangeQuery {
+    spec: ZRangeSpec,
+    reverse: bool,
+    offset: usize,
+    limit: Option<usize>,
+    with_scores: bool,
+}
+//start stop [BYSCORE | BYLEX] [REV] [LIMIT offset count] [WITHSCORES], shared by ZRANGE and ZRANGESTORE
+fn parse_zrange(args: &[String]) -> std::result::Result<ZRangeQuery, Value> {
+    let syntax_error = |message: &str| Value::SimpleError(format!("ERR syntax error{}", message));
+    let (mut by_score, mut by_lex, mut reverse, mut with_scores) = (false, false, false, false);
+    let mut limit = None;
+    let mut options = args[2..].iter();
+    while let Some(option) = options.next() {
+        match option.to_uppercase().as_str() {
+            "BYSCORE" => by_score = true,
+            "BYLEX" => by_lex = true,
+            "REV" => reverse = true,
+            "WITHSCORES" => with_scores = true,
+            "LIMIT" => match (options.next(), options.next()) {
+                (Some(offset), Some(count)) => match (offset.parse::<i64>(), count.parse::<i64>()) {
+                    (Ok(offset), Ok(count)) => limit = Some((offset, count)),
+                    _ => return Err(not_an_integer()),
+                },
+                _ => return Err(syntax_error("")),
+            },
+            _ => return Err(syntax_error("")),
+        }
+    }
+    if by_score && by_lex {
+        return Err(syntax_error(""));
+    }
+    if limit.is_some() && !by_score && !by_lex {
+        return Err(syntax_error(
+            ", LIMIT is only supported in combination with either BYSCORE or BYLEX",
+        ));
+    }
+    if with_scores && by_lex {
+        return Err(syntax_error(", WITHSCORES not supported in combination with BYLEX"));
+    }
+    //REV takes the bounds as max then min
+    let (min, max) = if reverse && (by_score || by_lex) {
+        (&args[1], &args[0])
+    } else {
+        (&args[0], &args[1])
+    };
+    let spec = if by_score {
+        match (ScoreBound::parse(min), ScoreBound::parse(max)) {
+            (Some(min), Some(max)) => ZRangeSpec::Score(min, max),
+            _ => return Err(not_a_score_range()),
+        }
+    } else if by_lex {
+        match (LexBound::parse(min), LexBound::parse(max)) {
+            (Some(min), Some(max)) => ZRangeSpec::Lex(min, max),
+            _ => return Err(not_a_lex_range()),
+        }
+    } else {
+        match (min.parse::<i64>(), max.parse::<i64>()) {
+            (Ok(start), Ok(stop)) => ZRangeSpec::Rank(start, stop),
+            _ => return Err(not_an_integer()),
+        }
+    };
+    //a negative offset selects nothing, a negative count means no limit
+    let (offset, limit) = match limit {
+        Some((offset, _)) if offset < 0 => (0, Some(0)),
+        Some((offset, count)) => (offset as usize, usize::try_from(count).ok()),
+        None => (0, None),
+    };
+    Ok(ZRangeQuery {
+        spec,
+        reverse,
+        offset,
+        limit,
+        with_scores,
+    })
+}
+pub async fn handle_zrange(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let args = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    let query = match parse_zrange(&args[1..]) {
+        Ok(query) => query,
+        Err(error) => return Ok(error),
+    };
+    let storage = storage.lock().await;
+    match storage.range_sorted_set(&args[0], &query.spec, query.reverse, query.offset, query.limit) {
+        Ok(pairs) => Ok(scored_reply(pairs, query.with_scores)),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+//ZRANGESTORE destination source min max [BYSCORE | BYLEX] [REV] [LIMIT offset count]
+pub async fn handle_zrangestore(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let args = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    let query = match parse_zrange(&args[2..]) {
+        Ok(query) if query.with_scores => return Ok(Value::SimpleError("ERR syntax error".to_string())),
+        Ok(query) => query,
+        Err(error) => return Ok(error),
+    };
+    let mut storage = storage.lock().await;
+    let pairs = match storage.range_sorted_set(&args[1], &query.spec, query.reverse, query.offset, query.limit) {
+        Ok(pairs) => pairs,
+        Err(e) => return Ok(Value::SimpleError(e.to_string())),
+    };
+    match storage.store_sorted_set(&args[0], &pairs, "zrangestore") {
+        Ok(size) => Ok(Value::SimpleInterger(size.to_string())),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+//ZREMRANGEBYRANK key start stop, ZREMRANGEBYSCORE key min max, ZREMRANGEBYLEX key min max
+pub async fn handle_zremrange(command_content: Vec<Value>, storage: StoreHandle, command: &str) -> Result<Value> {
+    let args = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    let spec = match command {
+        "ZREMRANGEBYSCORE" => match (ScoreBound::parse(&args[1]), ScoreBound::parse(&args[2])) {
+            (Some(min), Some(max)) => ZRangeSpec::Score(min, max),
+            _ => return Ok(not_a_score_range()),
+        },
+        "ZREMRANGEBYLEX" => match (LexBound::parse(&args[1]), LexBound::parse(&args[2])) {
+            (Some(min), Some(max)) => ZRangeSpec::Lex(min, max),
+            _ => return Ok(not_a_lex_range()),
+        },
+        _ => match (args[1].parse::<i64>(), args[2].parse::<i64>()) {
+            (Ok(start), Ok(stop)) => ZRangeSpec::Rank(start, stop),
+            _ => return Ok(not_an_integer()),
+        },
+    };
+    let mut storage = storage.lock().await;
+    match storage.remove_range_sorted_set(&args[0], &spec, &command.to_lowercase()) {
+        Ok(removed) => Ok(Value::SimpleInterger(removed.to_string())),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+//ZPOPMIN and ZPOPMAX key [count]
+pub async fn handle_zpopmin(command_content: Vec<Value>, storage: StoreHandle, max: bool) -> Result<Value> {
+    let key = unwrap_value_to_string(&command_content[0])?;
+    let count = match command_content.get(1) {
+        Some(count) => match unwrap_value_to_string(count)?.parse::<usize>() {
+            Ok(count) => count,
+            Err(_) => {
+                return Ok(Value::SimpleError(
+                    "ERR value is out of range, must be positive".to_string(),
+                ))
+            }
+        },
+        None => 1,
+    };
+    if command_content.len() > 2 {
+        return Ok(Value::SimpleError("ERR syntax error".to_string()));
+    }
+    let mut storage = storage.lock().await;
+    match storage.pop_sorted_set(&key, count, max) {
+        Ok(popped) => Ok(scored_reply(popped, true)),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+//BZPOPMIN and BZPOPMAX key [key ...] timeout, the first key with members wins
+pub async fn handle_bzpopmin(
+    command_content: Vec<Value>,
+    storage: StoreHandle,
+    may_block: bool,
+    max: bool,
+) -> Result<Value> {
+    let mut keys = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    let timeout = match parse_block_timeout(&keys.pop().unwrap(), may_block) {
+        Ok(timeout) => timeout,
+        Err(error) => return Ok(error),
+    };
+    let result = block_on_keys(storage, &keys, timeout, true, |storage, ready| {
+        for key in ready {
+            match storage.pop_sorted_set(key, 1, max) {
+                Ok(popped) if !popped.is_empty() => {
+                    let (member, score) = popped[0].clone();
+                    return Ok(Some(Value::Array(vec![
+                        Value::BulkString(key.clone()),
+                        Value::BulkString(member),
+                        Value::BulkString(format_score(score)),
+                    ])));
+                }
+                Ok(_) => {}
+                Err(e) => return Ok(Some(Value::SimpleError(e.to_string()))),
+            }
+        }
+        Ok(None)
+    })
+    .await?;
+    Ok(result.unwrap_or(Value::NullArray))
+}
+//Parsed numkeys key [key ...] [WEIGHTS weight ...] [AGGREGATE SUM|MIN|MAX] [WITHSCORES]
+struct ZAlgebraQuery {
+    keys: Vec<String>,
+    weights: Vec<f64>,
+    aggregate: Aggregate,
+    with_scores: bool,
+}
+//ZDIFF takes no WEIGHTS or AGGREGATE and the *STORE commands no WITHSCORES
+fn parse_zalgebra(args: &[String], command: &str) -> std::result::Result<ZAlgebraQuery, Value> {
+    let syntax_error = || Value::SimpleError("ERR syntax error".to_string());
+    let numkeys = match args[0].parse::<usize>() {
+        Ok(0) => {
+            return Err(Value::SimpleError(format!(
+                "ERR at least 1 input key is needed for '{}' command",
+                command.to_lowercase()
+            )))
+        }
+        Ok(numkeys) if numkeys < args.len() => numkeys,
+        Ok(_) => return Err(syntax_error()),
+        Err(_) => return Err(not_an_integer()),
+    };
+    let is_diff = command.starts_with("ZDIFF");
+    let is_store = command.ends_with("STORE");
+    let mut query = ZAlgebraQuery {
+        keys: args[1..1 + numkeys].to_vec(),
+        weights: Vec::new(),
+        aggregate: Aggregate::Sum,
+        with_scores: false,
+    };
+    let mut index = 1 + numkeys;
+    while let Some(option) = args.get(index) {
+        match option.to_uppercase().as_str() {
+            "WEIGHTS" if !is_diff && index + numkeys < args.len() => {
+                for weight in &args[index + 1..index + 1 + numkeys] {
+                    match parse_score(weight) {
+                        Some(weight) => query.weights.push(weight),
+                        None => {
+                            return Err(Value::SimpleError(
+                                "ERR weight value is not a float".to_string(),
+                            ))
+                        }
+                    }
+                }
+                index += numkeys;
+            }
+            "AGGREGATE" if !is_diff => {
+                match args.get(index + 1).and_then(|aggregate| Aggregate::parse(aggregate)) {
+                    Some(aggregate) => query.aggregate = aggregate,
+                    None => return Err(syntax_error()),
+                }
+                index += 1;
+            }
+            "WITHSCORES" if !is_store => query.with_scores = true,
+            _ => return Err(syntax_error()),
+        }
+        index += 1;
+    }
+    Ok(query)
+}
+//ZUNION, ZINTER, ZDIFF numkeys key [key ...] ... and their *STORE destination variants
+pub async fn handle_zunion(command_content: Vec<Value>, storage: StoreHandle, command: &str) -> Result<Value> {
+    let args = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    let operation = match &command[..5] {
+        "ZUNIO" => SetOperation::Union,
+        "ZINTE" => SetOperation::Inter,
+        _ => SetOperation::Diff,
+    };
+    let destination = command.ends_with("STORE").then(|| args[0].clone());
+    let query = match parse_zalgebra(&args[usize::from(destination.is_some())..], command) {
+        Ok(query) => query,
+        Err(error) => return Ok(error),
+    };
+    let mut storage = storage.lock().await;
+    let pairs = match storage.combine_sorted_sets(&query.keys, &query.weights, query.aggregate, operation) {
+        Ok(pairs) => pairs,
+        Err(e) => return Ok(Value::SimpleError(e.to_string())),
+    };
+    match destination {
+        Some(destination) => match storage.store_sorted_set(&destination, &pairs, &command.to_lowercase()) {
+            Ok(size) => Ok(Value::SimpleInterger(size.to_string())),
+            Err(e) => Ok(Value::SimpleError(e.to_string())),
+        },
+        None => Ok(scored_reply(pairs, query.with_scores)),
+    }
+}
+//ZINTERCARD numkeys key [key ...] [LIMIT limit], limit 0 means no limit
+pub async fn handle_zintercard(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let args = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    let numkeys = match args[0].parse::<usize>() {
+        Ok(0) => {
+            return Ok(Value::SimpleError(
+                "ERR numkeys should be greater than 0".to_string(),
+            ))
+        }
+        Ok(numkeys) if numkeys < args.len() => numkeys,
+        Ok(_) => {
+            return Ok(Value::SimpleError(
+                "ERR Number of keys can't be greater than number of args".to_string(),
+            ))
+        }
+        Err(_) => return Ok(not_an_integer()),
+    };
+    let limit = match &args[1 + numkeys..] {
+        [] => 0,
+        [option, limit] if option.to_uppercase() == "LIMIT" => match limit.parse::<usize>() {
+            Ok(limit) => limit,
+            Err(_) => return Ok(Value::SimpleError("ERR LIMIT can't be negative".to_string())),
+        },
+        _ => return Ok(Value::SimpleError("ERR syntax error".to_string())),
+    };
+    let storage = storage.lock().await;
+    match storage.combine_sorted_sets(&args[1..1 + numkeys], &[], Aggregate::Sum, SetOperation::Inter) {
+        Ok(pairs) if limit > 0 => Ok(Value::SimpleInterger(pairs.len().min(limit).to_string())),
+        Ok(pairs) => Ok(Value::SimpleInterger(pairs.len().to_string())),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+//ZRANDMEMBER key [count [WITHSCORES]], a negative count may return the same member twice
+pub async fn handle_zrandmember(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let args = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    let count = match args.get(1).map(|count| count.parse::<i64>()) {
+        Some(Ok(count)) => Some(count),
+        Some(Err(_)) => return Ok(not_an_integer()),
+        None => None,
+    };
+    let with_scores = match &args[1..] {
+        [] | [_] => false,
+        [_, option] if option.to_uppercase() == "WITHSCORES" => true,
+        _ => return Ok(Value::SimpleError("ERR syntax error".to_string())),
+    };
+    let storage = storage.lock().await;
+    let mut pairs = match storage.range_sorted_set(&args[0], &ZRangeSpec::Rank(0, -1), false, 0, None) {
+        Ok(pairs) => pairs,
+        Err(e) => return Ok(Value::SimpleError(e.to_string())),
+    };
+    let count = match count {
+        Some(count) => count,
+        None if pairs.is_empty() => return Ok(Value::NullBulkString),
+        None => return Ok(Value::BulkString(pairs.swap_remove(random_index(pairs.len())).0)),
+    };
+    let mut picked = Vec::new();
+    if count < 0 {
+        for _ in 0..(if pairs.is_empty() { 0 } else { count.unsigned_abs() }) {
+            picked.push(pairs[random_index(pairs.len())].clone());
+        }
+    } else {
+        while picked.len() < count as usize && !pairs.is_empty() {
+            picked.push(pairs.swap_remove(random_index(pairs.len())));
+        }
+    }
+    Ok(scored_reply(picked, with_scores))
+}
+//ZMPOP is BZMPOP that never waits
+pub async fn handle_zmpop(mut command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    command_content.insert(0, Value::BulkString("0".to_string()));
+    handle_bzmpop(command_content, storage, false).await
+}
+//BZMPOP timeout numkeys key [key ...] MIN|MAX [COUNT count]
+pub async fn handle_bzmpop(
+    command_content: Vec<Value>,
+    storage: StoreHandle,
+    may_block: bool,
+) -> Result<Value> {
+    let args = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    let numkeys = match args[1].parse::<usize>() {
+        Ok(0) => {
+            return Ok(Value::SimpleError(
+                "ERR numkeys should be greater than 0".to_string(),
+            ))
+        }
+        Ok(numkeys) if numkeys + 3 <= args.len() => numkeys,
+        _ => return Ok(Value::SimpleError("ERR syntax error".to_string())),
+    };
+    let keys = args[2..2 + numkeys].to_vec();
+    let max = match args[2 + numkeys].to_uppercase().as_str() {
+        "MIN" => false,
+        "MAX" => true,
+        _ => return Ok(Value::SimpleError("ERR syntax error".to_string())),
+    };
+    let count = match &args[3 + numkeys..] {
+        [] => 1,
+        [option, count] if option.to_uppercase() == "COUNT" => match count.parse::<usize>() {
+            Ok(count) if count > 0 => count,
+            _ => {
+                return Ok(Value::SimpleError(
+                    "ERR count should be greater than 0".to_string(),
+                ))
+            }
+        },
+        _ => return Ok(Value::SimpleError("ERR syntax error".to_string())),
+    };
+    let timeout = match parse_block_timeout(&args[0], may_block) {
+        Ok(timeout) => timeout,
+        Err(error) => return Ok(error),
+    };
+    let result = block_on_keys(storage, &keys, timeout, true, |storage, ready| {
+        for key in ready {
+            match storage.pop_sorted_set(key, count, max) {
+                Ok(popped) if !popped.is_empty() => {
+                    let popped = popped
+                        .into_iter()
+                        .map(|(member, score)| {
+                            Value::Array(vec![
+                                Value::BulkString(member),
+                                Value::BulkString(format_score(score)),
+                            ])
+                        })
+                        .collect();
+                    return Ok(Some(Value::Array(vec![
+                        Value::BulkString(key.clone()),
+                        Value::Array(popped),
+                    ])));
+                }
+                Ok(_) => {}
+                Err(e) => return Ok(Some(Value::SimpleError(e.to_string()))),
+            }
+        }
+        Ok(None)
+    })
+    .await?;
+    Ok(result.unwrap_or(Value::NullArray))
+}
+//GEOADD key [NX | XX] [CH] longitude latitude member [longitude latitude member ...]
+pub async fn handle_geoadd(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let args = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    let mut options = ZAddOptions::default();
+    let mut changed = false;
+    let mut index = 1;
+    while let Some(option) = args.get(index) {
+        match option.to_uppercase().as_str() {
+            "NX" => options.nx = true,
+            "XX" => options.xx = true,
+            "CH" => changed = true,
+            _ => break,
+        }
+        index += 1;
+    }
+    let elements = &args[index..];
+    if elements.is_empty() || elements.len() % 3 != 0 {
+        return Ok(Value::SimpleError(
+            "ERR syntax error. Try GEOADD key [x1] [y1] [name1] [x2] [y2] [name2] ... ".to_string(),
+        ));
+    }
+    if options.nx && options.xx {
+        return Ok(Value::SimpleError(
+            "ERR XX and NX options at the same time are not compatible".to_string(),
+        ));
+    }
+    let mut pairs = Vec::new();
+    for element in elements.chunks(3) {
+        let (longitude, latitude) = match (parse_score(&element[0]), parse_score(&element[1])) {
+            (Some(longitude), Some(latitude)) => (longitude, latitude),
+            _ => return Ok(not_a_float()),
+        };
+        if !geo::is_valid_coord(longitude, latitude) {
+            return Ok(invalid_coord(longitude, latitude));
+        }
+        pairs.push((geo::encode(longitude, latitude), element[2].clone()));
+    }
+    let mut storage = storage.lock().await;
+    let results = match storage.add_sorted_set(&args[0], &pairs, &options) {
+        Ok(results) => results,
+        Err(e) => return Ok(Value::SimpleError(e.to_string())),
+    };
+    let count = results
+        .iter()
+        .filter(|result| match result {
+            ZAddResult::Added(_) => true,
+            ZAddResult::Updated(_) => changed,
+            _ => false,
+        })
+        .count();
+    Ok(Value::SimpleInterger(count.to_string()))
+}
+fn invalid_coord(longitude: f64, latitude: f64) -> Value {
+    Value::SimpleError(format!(
+        "ERR invalid longitude,latitude pair {:.6},{:.6}",
+        longitude, latitude
+    ))
+}
+fn unsupported_unit() -> Value {
+    Value::SimpleError("ERR unsupported unit provided. please use M, KM, FT, MI".to_string())
+}
+fn coord_reply(coord: (f64, f64)) -> Value {
+    Value::Array(vec![
+        Value::BulkString(coord.0.to_string()),
+        Value::BulkString(coord.1.to_string()),
+    ])
+}
+//GEOPOS key [member [member ...]]
+pub async fn handle_geopos(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let args = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    let storage = storage.lock().await;
+    let mut positions = Vec::new();
+    for member in &args[1..] {
+        match storage.get_sorted_set_score(&args[0], member) {
+            Ok(Some(score)) => positions.push(coord_reply(geo::decode(score))),
+            Ok(None) => positions.push(Value::NullArray),
+            Err(e) => return Ok(Value::SimpleError(e.to_string())),
+        }
+    }
+    Ok(Value::Array(positions))
+}
+//GEODIST key member1 member2 [M | KM | FT | MI]
+pub async fn handle_geodist(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let args = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    let unit = match &args[3..] {
+        [] => 1f64,
+        [unit] => match geo::parse_unit(unit) {
+            Some(unit) => unit,
+            None => return Ok(unsupported_unit()),
+        },
+        _ => return Ok(Value::SimpleError("ERR syntax error".to_string())),
+    };
+    let storage = storage.lock().await;
+    let scores = (
+        storage.get_sorted_set_score(&args[0], &args[1]),
+        storage.get_sorted_set_score(&args[0], &args[2]),
+    );
+    match scores {
+        (Ok(Some(from)), Ok(Some(to))) => {
+            let distance = geo::distance(geo::decode(from), geo::decode(to));
+            Ok(Value::BulkString(format!("{:.4}", distance / unit)))
+        }
+        (Err(e), _) | (_, Err(e)) => Ok(Value::SimpleError(e.to_string())),
+        _ => Ok(Value::NullBulkString),
+    }
+}
+//GEOHASH key [member [member ...]]
+pub async fn handle_geohash(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let args = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    let storage = storage.lock().await;
+    let mut hashes = Vec::new();
+    for member in &args[1..] {
+        match storage.get_sorted_set_score(&args[0], member) {
+            Ok(Some(score)) => hashes.push(Value::BulkString(geo::hash_string(score))),
+            Ok(None) => hashes.push(Value::NullBulkString),
+            Err(e) => return Ok(Value::SimpleError(e.to_string())),
+        }
+    }
+    Ok(Value::Array(hashes))
+}
+//Where GEOSEARCH starts from
+enum GeoOrigin {
+    Member(String),
+    Coord(f64, f64),
+}
+//GEOSEARCH key FROMMEMBER member | FROMLONLAT longitude latitude
+//  BYRADIUS radius unit | BYBOX width height unit [ASC | DESC] [COUNT count [ANY]]
+//  [WITHCOORD] [WITHDIST] [WITHHASH], GEOSEARCHSTORE destination source ... [STOREDIST]
+pub async fn handle_geosearch(command_content: Vec<Value>, storage: StoreHandle, store: bool) -> Result<Value> {
+    let args = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    let syntax_error = || Ok(Value::SimpleError("ERR syntax error".to_string()));
+    let (destination, key) = match store {
+        true => (Some(args[0].clone()), args[1].clone()),
+        false => (None, args[0].clone()),
+    };
+    let mut origin = None;
+    let mut shape = None;
+    let mut unit = 1f64;
+    let mut ascending = None;
+    let mut count = None;
+    let mut any = false;
+    let (mut with_coord, mut with_dist, mut with_hash, mut store_dist) = (false, false, false, false);
+    let mut index = if store { 2 } else { 1 };
+    while let Some(option) = args.get(index) {
+        match option.to_uppercase().as_str() {
+            "FROMMEMBER" if origin.is_none() && index + 1 < args.len() => {
+                origin = Some(GeoOrigin::Member(args[index + 1].clone()));
+                index += 1;
+            }
+            "FROMLONLAT" if origin.is_none() && index + 2 < args.len() => {
+                let (longitude, latitude) = match (parse_score(&args[index + 1]), parse_score(&args[index + 2])) {
+                    (Some(longitude), Some(latitude)) => (longitude, latitude),
+                    _ => return Ok(not_a_float()),
+                };
+                if !geo::is_valid_coord(longitude, latitude) {
+                    return Ok(invalid_coord(longitude, latitude));
+                }
+                origin = Some(GeoOrigin::Coord(longitude, latitude));
+                index += 2;
+            }
+            "BYRADIUS" if shape.is_none() && index + 2 < args.len() => {
+                let radius = match parse_score(&args[index + 1]) {
+                    Some(radius) if radius >= 0f64 => radius,
+                    Some(_) => return Ok(Value::SimpleError("ERR radius cannot be negative".to_string())),
+                    None => return Ok(not_a_float()),
+                };
+                unit = match geo::parse_unit(&args[index + 2]) {
+                    Some(unit) => unit,
+                    None => return Ok(unsupported_unit()),
+                };
+                shape = Some(GeoShape::Radius(radius * unit));
+                index += 2;
+            }
+            "BYBOX" if shape.is_none() && index + 3 < args.len() => {
+                let (width, height) = match (parse_score(&args[index + 1]), parse_score(&args[index + 2])) {
+                    (Some(width), Some(height)) if width >= 0f64 && height >= 0f64 => (width, height),
+                    (Some(_), Some(_)) => {
+                        return Ok(Value::SimpleError(
+                            "ERR height or width cannot be negative".to_string(),
+                        ))
+                    }
+                    _ => return Ok(not_a_float()),
+                };
+                unit = match geo::parse_unit(&args[index + 3]) {
+                    Some(unit) => unit,
+                    None => return Ok(unsupported_unit()),
+                };
+                shape = Some(GeoShape::Box(width * unit, height * unit));
+                index += 3;
+            }
+            "ASC" => ascending = Some(true),
+            "DESC" => ascending = Some(false),
+            "COUNT" if index + 1 < args.len() => {
+                match args[index + 1].parse::<i64>() {
+                    Ok(value) if value > 0 => count = Some(value as usize),
+                    Ok(_) => return Ok(Value::SimpleError("ERR COUNT must be > 0".to_string())),
+                    Err(_) => return Ok(not_an_integer()),
+                }
+                index += 1;
+                if args.get(index + 1).is_some_and(|option| option.to_uppercase() == "ANY") {
+                    any = true;
+                    index += 1;
+                }
+            }
+            "WITHCOORD" if !store => with_coord = true,
+            "WITHDIST" if !store => with_dist = true,
+            "WITHHASH" if !store => with_hash = true,
+            "STOREDIST" if store => store_dist = true,
+            "FROMMEMBER" | "FROMLONLAT" if origin.is_some() => {
+                return Ok(Value::SimpleError(
+                    "ERR exactly one of FROMMEMBER or FROMLONLAT can be specified for geosearch".to_string(),
+                ))
+            }
+            "BYRADIUS" | "BYBOX" if shape.is_some() => {
+                return Ok(Value::SimpleError(
+                    "ERR exactly one of BYRADIUS and BYBOX can be specified for geosearch".to_string(),
+                ))
+            }
+            _ => return syntax_error(),
+        }
+        index += 1;
+    }
+    let origin = match origin {
+        Some(origin) => origin,
+        None => {
+            return Ok(Value::SimpleError(
+                "ERR exactly one of FROMMEMBER or FROMLONLAT can be specified for geosearch".to_string(),
+            ))
+        }
+    };
+    let shape = match shape {
+        Some(shape) => shape,
+        None => {
+            return Ok(Value::SimpleError(
+                "ERR exactly one of BYRADIUS and BYBOX can be specified for geosearch".to_string(),
+            ))
+        }
+    };
+    if any && count.is_none() {
+        return Ok(Value::SimpleError(
+            "ERR the ANY argument requires COUNT argument".to_string(),
+        ));
+    }
+    let mut storage = storage.lock().await;
+    let pairs = match storage.range_sorted_set(&key, &ZRangeSpec::Rank(0, -1), false, 0, None) {
+        Ok(pairs) => pairs,
+        Err(e) => return Ok(Value::SimpleError(e.to_string())),
+    };
+    let center = match origin {
+        _ if pairs.is_empty() => None,
+        GeoOrigin::Member(member) => match pairs.iter().find(|(name, _)| *name == member) {
+            Some((_, score)) => Some(geo::decode(*score)),
+            None => {
+                return Ok(Value::SimpleError(
+                    "ERR could not decode requested zset member".to_string(),
+                ))
+            }
+        },
+        GeoOrigin::Coord(longitude, latitude) => Some((longitude, latitude)),
+    };
+    let mut points = match center {
+        Some(center) => geo::search(pairs, center, shape, count.filter(|_| any)),
+        None => Vec::new(),
+    };
+    //COUNT without ANY has to see every match to keep the nearest ones
+    match ascending.or(count.map(|_| true)) {
+        Some(true) => points.sort_by(|a, b| a.distance.total_cmp(&b.distance)),
+        Some(false) => points.sort_by(|a, b| b.distance.total_cmp(&a.distance)),
+        None => {}
+    }
+    if let Some(count) = count {
+        points.truncate(count);
+    }
+    if let Some(destination) = destination {
+        let pairs = points
+            .into_iter()
+            .map(|point| match store_dist {
+                true => (point.member, point.distance / unit),
+                false => (point.member, point.score),
+            })
+            .collect::<Vec<(String, f64)>>();
+        return match storage.store_sorted_set(&destination, &pairs, "geosearchstore") {
+            Ok(size) => Ok(Value::SimpleInterger(size.to_string())),
+            Err(e) => Ok(Value::SimpleError(e.to_string())),
+        };
+    }
+    let replies = points
+        .into_iter()
+        .map(|point| {
+            if !(with_dist || with_hash || with_coord) {
+                return Value::BulkString(point.member);
+            }
+            let mut reply = vec![Value::BulkString(point.member)];
+            if with_dist {
+                reply.push(Value::BulkString(format!("{:.4}", point.distance / unit)));
+            }
+            if with_hash {
+                reply.push(Value::SimpleInterger((point.score as u64).to_string()));
+            }
+            if with_coord {
+                reply.push(coord_reply(point.coord));
+            }
+            Value::Array(reply)
+        })
+        .collect();
+    Ok(Value::Array(replies))
+}
+//PFADD key [element [element ...]]
+pub async fn handle_pfadd(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let args = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    let mut storage = storage.lock().await;
+    match storage.add_hyperloglog(&args[0], &args[1..]) {
+        Ok(updated) => Ok(Value::SimpleInterger(i64::from(updated).to_string())),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+//PFCOUNT key [key ...]
+pub async fn handle_pfcount(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let keys = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    let mut storage = storage.lock().await;
+    match storage.count_hyperloglog(&keys) {
+        Ok(count) => Ok(Value::SimpleInterger(count.to_string())),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+//PFMERGE destkey [sourcekey [sourcekey ...]]
+pub async fn handle_pfmerge(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let args = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    let mut storage = storage.lock().await;
+    match storage.merge_hyperloglog(&args[0], &args[1..]) {
+        Ok(()) => Ok(Value::SimpleString("OK".to_string())),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+//PFDEBUG GETREG | DECODE | ENCODING | TODENSE key
+pub async fn handle_pfdebug(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let subcommand = unwrap_value_to_string(&command_content[0])?;
+    let key = unwrap_value_to_string(&command_content[1])?;
+    let mut storage = storage.lock().await;
+    let no_such_key = || Ok(Value::SimpleError("ERR The specified key does not exist".to_string()));
+    //GETREG and TODENSE leave the key dense, like redis
+    let converted = match subcommand.to_uppercase().as_str() {
+        "GETREG" | "TODENSE" => match storage.hyperloglog_to_dense(&key) {
+            Ok(Some(converted)) => converted,
+            Ok(None) => return no_such_key(),
+            Err(e) => return Ok(Value::SimpleError(e.to_string())),
+        },
+        _ => false,
+    };
+    let hll = match storage.get_hyperloglog(&key) {
+        Ok(Some(hll)) => hll,
+        Ok(None) => return no_such_key(),
+        Err(e) => return Ok(Value::SimpleError(e.to_string())),
+    };
+    match subcommand.to_uppercase().as_str() {
+        "GETREG" => match hll.registers() {
+            Ok(registers) => Ok(Value::Array(
+                registers
+                    .into_iter()
+                    .map(|register| Value::SimpleInterger(register.to_string()))
+                    .collect(),
+            )),
+            Err(e) => Ok(Value::SimpleError(e.to_string())),
+        },
+        "DECODE" if !hll.is_sparse() => Ok(Value::SimpleError("ERR HLL encoding is not sparse".to_string())),
+        "DECODE" => match hll.decode() {
+            Ok(decoded) => Ok(Value::SimpleString(decoded)),
+            Err(e) => Ok(Value::SimpleError(e.to_string())),
+        },
+        "ENCODING" if hll.is_sparse() => Ok(Value::SimpleString("sparse".to_string())),
+        "ENCODING" => Ok(Value::SimpleString("dense".to_string())),
+        "TODENSE" => Ok(Value::SimpleInterger(i64::from(converted).to_string())),
+        _ => Ok(Value::SimpleError(format!(
+            "ERR Unknown PFDEBUG subcommand '{}'",
+            subcommand
+        ))),
+    }
+}
+//PFSELFTEST
+pub fn handle_pfselftest() -> Result<Value> {
+    match hyperloglog::self_test() {
+        Ok(()) => Ok(Value::SimpleString("OK".to_string())),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+fn bit_offset_error() -> Value {
+    Value::SimpleError("ERR bit offset is not an integer or out of range".to_string())
+}
+fn parse_bit_offset(offset: &str) -> Option<u64> {
+    offset.parse::<u64>().ok().filter(|offset| *offset <= BITMAP_MAX_OFFSET)
+}
+//SETBIT key offset value
+pub async fn handle_setbit(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let args = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    let offset = match parse_bit_offset(&args[1]) {
+        Some(offset) => offset,
+        None => return Ok(bit_offset_error()),
+    };
+    let bit = match args[2].as_str() {
+        "0" => 0,
+        "1" => 1,
+        _ => return Ok(Value::SimpleError("ERR bit is not an integer or out of range".to_string())),
+    };
+    let mut storage = storage.lock().await;
+    match storage.update_string(&args[0], "setbit", |bytes| bitmap::set_bit(bytes, offset, bit)) {
+        Ok(old) => Ok(Value::SimpleInterger(old.to_string())),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+//GETBIT key offset
+pub async fn handle_getbit(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let key = unwrap_value_to_string(&command_content[0])?;
+    let offset = match parse_bit_offset(&unwrap_value_to_string(&command_content[1])?) {
+        Some(offset) => offset,
+        None => return Ok(bit_offset_error()),
+    };
+    let storage = storage.lock().await;
+    match storage.get_string_bytes(&key) {
+        Ok(bytes) => Ok(Value::SimpleInterger(
+            bitmap::get_bit(&bytes.unwrap_or_default(), offset).to_string(),
+        )),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
+    }
+}
+//Start and end of BITCOUNT and BITPOS, true when they are bit offsets
+fn parse_bit_range(start: &str, end: &str, unit: Option<&String>) -> std::result::Result<(i64, i64, bool), Value> {
+    let bit = match unit.map(|unit| unit.to_uppercase()).as_deref() {
+        None | Some("BYTE") => false,
+        Some("BIT") => true,
+        _ => return Err(Value::SimpleError("ERR syntax error".to_string())),
+    };
+    match (start.parse::<i64>(), end.parse::<i64>()) {
+        (Ok(start), Ok(end)) => Ok((start, end, bit)),
+        _ => Err(not_an_integer()),
+    }
+}
+//BITCOUNT key [start end [BYTE | BIT]]
+pub async fn handle_bitcount(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let args = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    let range = match &args[1..] {
+        [] => None,
+        [start, end] | [start, end, _] => match parse_bit_range(start, end, args.get(3)) {
+            Ok(range) => Some(range),
+            Err(error) => return Ok(error),
+        },
+        _ => return Ok(Value::SimpleError("ERR syntax error".to_string())),
+    };
+    let storage = storage.lock().await;
+    let bytes = match storage.get_string_bytes(&args[0]) {
+        Ok(Some(bytes)) => bytes,
+        Ok(None) => return Ok(Value::SimpleInterger("0".to_string())),
+        Err(e) => return Ok(Value::SimpleError(e.to_string())),
+    };
+    let len = bytes.len() as i64;
+    let bits = match range {
+        None => bitmap::resolve_range(len * 8, 0, -1),
+        Some((start, end, true)) => bitmap::resolve_range(len * 8, start, end),
+        Some((start, end, false)) => {
+            bitmap::resolve_range(len, start, end).map(|(start, end)| (start * 8, end * 8 + 7))
+        }
+    };
+    let count = bits.map_or(0, |(start, end)| bitmap::count_bits(&bytes, start, end));
+    Ok(Value::SimpleInterger(count.to_string()))
+}
+//BITPOS key bit [start [end [BYTE | BIT]]]
+pub async fn handle_bitpos(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let args = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    let bit = match args[1].parse::<i64>() {
+        Ok(bit @ (0 | 1)) => bit as u8,
+        Ok(_) => return Ok(Value::SimpleError("ERR The bit argument must be 1 or 0.".to_string())),
+        Err(_) => return Ok(not_an_integer()),
+    };
+    let (start, end, in_bits) = match &args[2..] {
+        [] => (0, -1, false),
+        [start] => match start.parse::<i64>() {
+            Ok(start) => (start, -1, false),
+            Err(_) => return Ok(not_an_integer()),
+        },
+        [start, end] | [start, end, _] => match parse_bit_range(start, end, args.get(4)) {
+            Ok(range) => range,
+            Err(error) => return Ok(error),
+        },
+        _ => return Ok(Value::SimpleError("ERR syntax error".to_string())),
+    };
+    let end_given = args.len() > 3;
+    let storage = storage.lock().await;
+    let bytes = match storage.get_string_bytes(&args[0]) {
+        Ok(Some(bytes)) => bytes,
+        Ok(None) => return Ok(Value::SimpleInterger(if bit == 1 { "-1" } else { "0" }.to_string())),
+        Err(e) => return Ok(Value::SimpleError(e.to_string())),
+    };
+    let len = bytes.len() as i64;
+    let bits = match in_bits {
+        true => bitmap::resolve_range(len * 8, start, end),
+        false => bitmap::resolve_range(len, start, end).map(|(start, end)| (start * 8, end * 8 + 7)),
+    };
+    let position = match bits {
+        Some((start, end)) => match bitmap::bit_position(&bytes, bit, start, end) {
+            Some(position) => position as i64,
+            //without an end the string is seen as padded with zeros
+            None if bit == 0 && !end_given => end as i64 + 1,
+            None => -1,
+        },
+        None => -1,
+    };
+    Ok(Value::SimpleInterger(position.to_string()))
+}
+//BITOP AND | OR | XOR | NOT | DIFF destkey key [key ...]
+pub async fn handle_bitop(command_content: Vec<Value>, storage: StoreHandle) -> Result<Value> {
+    let args = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    let operation = match BitOperation::parse(&args[0]) {
+        Some(operation) => operation,
+        None => return Ok(Value::SimpleError("ERR syntax error".to_string())),
+    };
+    let keys = &args[2..];
+    if operation == BitOperation::Not && keys.len() != 1 {
+        return Ok(Value::SimpleError(
+            "ERR BITOP NOT must be called with a single source key.".to_string(),
+        ));
+    }
+    if operation == BitOperation::Diff && keys.len() < 2 {
+        return Ok(Value::SimpleError(
+            "ERR BITOP DIFF must be called with at least two source keys.".to_string(),
+        ));
+    }
+    let mut storage = storage.lock().await;
+    let mut sources = Vec::new();
+    for key in keys {
+        match storage.get_string_bytes(key) {
+            Ok(bytes) => sources.push(bytes.map(|bytes| bytes.into_owned()).unwrap_or_default()),
+            Err(e) => return Ok(Value::SimpleError(e.to_string())),
+        }
+    }
+    let result = bitmap::bit_operation(operation, &sources);
+    let len = storage.store_string(&args[1], result, "set");
+    Ok(Value::SimpleInterger(len.to_string()))
+}
+//One BITFIELD subcommand
+enum BitfieldOp {
+    Get(BitfieldType, u64),
+    Set(BitfieldType, u64, i64, Overflow),
+    IncrBy(BitfieldType, u64, i64, Overflow),
+}
+//BITFIELD key [GET encoding offset | [OVERFLOW WRAP | SAT | FAIL]
+//  SET encoding offset value | INCRBY encoding offset increment ...], BITFIELD_RO only takes GET
+pub async fn handle_bitfield(command_content: Vec<Value>, storage: StoreHandle, read_only: bool) -> Result<Value> {
+    let args = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap())
+        .collect::<Vec<String>>();
+    let syntax_error = || Ok(Value::SimpleError("ERR syntax error".to_string()));
+    let mut ops = Vec::new();
+    let mut overflow = Overflow::Wrap;
+    let mut index = 1;
+    while let Some(subcommand) = args.get(index) {
+        let subcommand = subcommand.to_uppercase();
+        if read_only && subcommand != "GET" {
+            return Ok(Value::SimpleError(
+                "ERR BITFIELD_RO only supports the GET subcommand".to_string(),
+            ));
+        }
+        if subcommand == "OVERFLOW" {
+            overflow = match args.get(index + 1).and_then(|overflow| Overflow::parse(overflow)) {
+                Some(overflow) => overflow,
+                None if index + 1 < args.len() => {
+                    return Ok(Value::SimpleError("ERR Invalid OVERFLOW type specified".to_string()))
+                }
+                None => return syntax_error(),
+            };
+            index += 2;
+            continue;
+        }
+        let operands = match subcommand.as_str() {
+            "GET" => 2,
+            "SET" | "INCRBY" => 3,
+            _ => return syntax_error(),
+        };
+        if index + operands >= args.len() {
+            return syntax_error();
+        }
+        let field = match BitfieldType::parse(&args[index + 1]) {
+            Some(field) => field,
+            None => {
+                return Ok(Value::SimpleError(
+                    "ERR Invalid bitfield type. Use something like i16 u8. Note that u64 is not supported but i64 is."
+                        .to_string(),
+                ))
+            }
+        };
+        //#N is the Nth field of that width
+        let offset = match args[index + 2].strip_prefix('#') {
+            Some(nth) => nth.parse::<u64>().ok().and_then(|nth| nth.checked_mul(field.bits as u64)),
+            None => args[index + 2].parse::<u64>().ok(),
+        };
+        let offset = match offset.filter(|offset| offset + field.bits as u64 - 1 <= BITMAP_MAX_OFFSET) {
+            Some(offset) => offset,
+            None => return Ok(bit_offset_error()),
+        };
+        let value = match operands {
+            3 => match args[index + 3].parse::<i64>() {
+                Ok(value) => value,
+                Err(_) => return Ok(not_an_integer()),
+            },
+            _ => 0,
+        };
+        ops.push(match subcommand.as_str() {
+            "GET" => BitfieldOp::Get(field, offset),
+            "SET" => BitfieldOp::Set(field, offset, value, overflow),
+            _ => BitfieldOp::IncrBy(field, offset, value, overflow),
+        });
+        index += operands + 1;
+    }
+    let run = |bytes: &mut Vec<u8>| {
+        ops.iter()
+            .map(|op| match *op {
+                BitfieldOp::Get(field, offset) => Value::SimpleInterger(bitmap::get_field(bytes, offset, field).to_string()),
+                BitfieldOp::Set(field, offset, value, overflow) => {
+                    //unsigned fields see the value as its 64 bits, like redis
+                    let value = if field.signed { value as i128 } else { value as u64 as i128 };
+                    match overflow.apply(value, field) {
+                        Some(value) => {
+                            let old = bitmap::get_field(bytes, offset, field);
+                            bitmap::set_field(bytes, offset, field, value);
+                            Value::SimpleInterger(old.to_string())
+                        }
+                        None => Value::NullBulkString,
+                    }
+                }
+                BitfieldOp::IncrBy(field, offset, increment, overflow) => {
+                    let old = bitmap::get_field(bytes, offset, field);
+                    match overflow.apply(old as i128 + increment as i128, field) {
+                        Some(value) => {
+                            bitmap::set_field(bytes, offset, field, value);
+                            Value::SimpleInterger(value.to_string())
+                        }
+                        None => Value::NullBulkString,
+                    }
+                }
+            })
+            .collect::<Vec<Value>>()
+    };
+    let mut storage = storage.lock().await;
+    //a write grows the string up to the furthest field even when FAIL refuses it
+    let grow_to = ops
+        .iter()
+        .filter_map(|op| match op {
+            BitfieldOp::Get(..) => None,
+            BitfieldOp::Set(field, offset, ..) | BitfieldOp::IncrBy(field, offset, ..) => {
+                Some(((offset + field.bits as u64 - 1) >> 3) as usize + 1)
+            }
+        })
+        .max();
+    let replies = match grow_to {
+        Some(grow_to) => storage.update_string(&args[0], "setbit", |bytes| {
+            if bytes.len() < grow_to {
+                bytes.resize(grow_to, 0);
+            }
+            run(bytes)
+        }),
+        None => storage
+            .get_string_bytes(&args[0])
+            .map(|bytes| run(&mut bytes.map(|bytes| bytes.into_owned()).unwrap_or_default())),
+    };
+    match replies {
+        Ok(replies) => Ok(Value::Array(replies)),
+        Err(e) => Ok(Value::SimpleError(e.to_string())),
     }
 }
@@ -0,0 +1,47 @@
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use crate::{
+    cluster::state::Cluster,
+    pubsub::state::PubSub,
+    rdb::replication::Replication,
+    store::{handle::StoreHandle, store::Store},
+};
+
+//Server state every connection shares, what command handlers reach the rest of the node through
+#[derive(Clone)]
+pub struct ServerContext {
+    pub storage: StoreHandle,
+    pub replication: Arc<Mutex<Replication>>,
+    pub cluster: Arc<Mutex<Cluster>>,
+    pub pubsub: Arc<Mutex<PubSub>>,
+}
+impl ServerContext {
+    pub fn new(
+        storage: Arc<Mutex<Store>>,
+        replication: Arc<Mutex<Replication>>,
+        cluster: Arc<Mutex<Cluster>>,
+        pubsub: Arc<Mutex<PubSub>>,
+    ) -> Self {
+        ServerContext {
+            storage: storage.into(),
+            replication,
+            cluster,
+            pubsub,
+        }
+    }
+    //Same state with the store locked until the returned context and its clones are gone
+    pub async fn hold_store(&self) -> Self {
+        ServerContext {
+            storage: self.storage.hold().await,
+            ..self.clone()
+        }
+    }
+    //Same state with the store unlocked, for tasks that outlive the command
+    pub fn detached(&self) -> Self {
+        ServerContext {
+            storage: self.storage.shared().into(),
+            ..self.clone()
+        }
+    }
+}
@@ -1 +1,3 @@
-pub mod command_handler;
\ No newline at end of file
+pub mod client;
+pub mod command_handler;
+pub mod context;
\ No newline at end of file
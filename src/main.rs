@@ -1,25 +1,44 @@
+mod cluster;
 mod command_handler;
+mod pubsub;
 mod rdb;
 mod resp;
+mod sentinel;
 mod store;
 //sys
-use core::panic;
-use std::{env::args, sync::Arc};
+use std::{env::args, sync::Arc, time::Duration};
 use tokio::{
-    io::{split, AsyncReadExt, AsyncWriteExt},
+    io::split,
     net::{TcpListener, TcpStream},
     sync::Mutex,
+    time::interval,
 };
 //module
 use crate::{
-    command_handler::command_handler::{command_handler, handle_info},
-    rdb::argument::flags_handler,
-    resp::{
-        resp::{
-            extract_command, read_value, read_without_parse, unwrap_value_to_string, write_value,
+    cluster::{
+        bus::run_cluster_bus,
+        state::{Cluster, CLUSTER_BUS_PORT_OFFSET},
+    },
+    command_handler::{
+        client::Client,
+        command_handler::{
+            command_handler, exec_transaction, get_command_keys, is_blocking_command, replicated_command,
         },
+        context::ServerContext,
+    },
+    pubsub::{
+        notify::{parse_notify_flags, spawn_notifier},
+        state::PubSub,
+    },
+    rdb::{
+        argument::{flags_handler, Argument},
+        master_link::spawn_master_link,
+    },
+    resp::{
+        resp::{extract_command, write_value, RespReader},
         value::Value,
     },
+    sentinel::state::{run_sentinel, Sentinel},
     store::{store::Store, transaction::Transaction},
 };
 
@@ -29,306 +48,211 @@ async fn main() {
 
     let (rdb_argument, rdb_file, replication) =
         flags_handler(args.into_iter().skip(1).collect()).unwrap();
+    let master_endpoint = replication.get_master_endpoint();
     let replication = Arc::new(Mutex::new(replication));
 
-    let (master_address, master_port) = rdb_argument.get_master_endpoint().unwrap();
-
     let storage = Arc::new(Mutex::new(Store::new()));
+    let cluster = Arc::new(Mutex::new(Cluster::from_argument(&rdb_argument).unwrap()));
+    let pubsub = Arc::new(Mutex::new(PubSub::new()));
 
-    let storage_clone = storage.clone();
-    if master_port != 0 {
-        //listenning new connections
-        let listener = TcpListener::bind(format!("127.0.0.1:{}", rdb_argument.get_port().unwrap()))
+    //keyspace notifications go through pub/sub
+    {
+        let mut storage = storage.lock().await;
+        storage.set_notifier(spawn_notifier(pubsub.clone()));
+        let notify_keyspace_events = rdb_argument.get_notify_keyspace_events().unwrap();
+        let notify_flags = parse_notify_flags(&notify_keyspace_events).unwrap_or_else(|| {
+            eprintln!("Invalid notify-keyspace-events: {}", notify_keyspace_events);
+            std::process::exit(1);
+        });
+        storage.set_notify_flags(notify_flags);
+    }
+    //the store is the only place keys are served from, the rdb file is not kept
+    storage
+        .lock()
+        .await
+        .load_rdb_file(&rdb_file)
+        .expect("Error when load rdb file");
+    spawn_active_expire(storage.clone());
+
+    //listenning new connections
+    let listener = TcpListener::bind(format!("127.0.0.1:{}", rdb_argument.get_port().unwrap()))
+        .await
+        .unwrap_or_else(|e| {
+            eprintln!("Failed to bind to address: {}", e);
+            std::process::exit(1);
+        });
+
+    if rdb_argument.is_sentinel() {
+        let sentinel = Sentinel::from_argument(&rdb_argument).unwrap();
+        run_sentinel(listener, sentinel).await;
+        return;
+    }
+
+    if cluster.lock().await.is_enabled() {
+        let bus_port = rdb_argument.get_port().unwrap() + CLUSTER_BUS_PORT_OFFSET;
+        let bus_listener = TcpListener::bind(format!("127.0.0.1:{}", bus_port))
             .await
             .unwrap_or_else(|e| {
-                eprintln!("Failed to bind to address: {}", e);
+                eprintln!("Failed to bind cluster bus to port {}: {}", bus_port, e);
                 std::process::exit(1);
             });
-        //slave side
-        let stream_to_master = TcpStream::connect(format!("{}:{}", master_address, master_port))
-            .await
-            .unwrap();
-        let (mut master_reader, mut master_writer) = split(stream_to_master);
-
-        //=====================Hand sake=========================//
-        let payload_step_1: String =
-            Value::serialize(&Value::Array(vec![Value::BulkString("PING".to_string())]));
-        let payload_step_2_once: String = Value::serialize(&Value::Array(vec![
-            Value::BulkString("REPLCONF".to_string()),
-            Value::BulkString("listening-port".to_string()),
-            Value::BulkString(format!("{}", rdb_argument.get_port().unwrap())),
-        ]));
-        let payload_step_2_twice: String = Value::serialize(&Value::Array(vec![
-            Value::BulkString("REPLCONF".to_string()),
-            Value::BulkString("capa".to_string()),
-            Value::BulkString("psync2".to_string()),
-        ]));
+        tokio::spawn(run_cluster_bus(bus_listener, cluster.clone(), pubsub.clone()));
+    }
 
-        let payload_step_3: String = Value::serialize(&Value::Array(vec![
-            Value::BulkString("PSYNC".to_string()),
-            Value::BulkString("?".to_string()),
-            Value::BulkString("-1".to_string()),
-        ]));
+    let context = ServerContext::new(storage, replication.clone(), cluster, pubsub);
 
-        let payloads = vec![
-            payload_step_1,
-            payload_step_2_once,
-            payload_step_2_twice,
-            payload_step_3,
-        ];
-        for payload in payloads {
-            master_writer.write_all(payload.as_bytes()).await.unwrap();
-            read_without_parse(&mut master_reader).await.unwrap();
-        }
-        //======================End handsake====================================//
+    //slave side
+    if let Some((master_address, master_port)) = master_endpoint {
+        let master_link = spawn_master_link(
+            master_address,
+            master_port,
+            rdb_argument.clone(),
+            context.clone(),
+        );
+        replication.lock().await.set_master_link(Some(master_link));
+    }
 
-        //reciev table empty file
-        read_without_parse(&mut master_reader).await.unwrap();
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                //clone for loop
+                let context = context.clone();
+                let rdb_argument = rdb_argument.clone();
 
-        tokio::spawn(async move {
-            let mut buffer: [u8; 1024] = [0; 1024];
-            loop {
-                master_reader.read(&mut buffer).await.unwrap();
-                let mut asize = 0;
-                let mut last = 0;
+                tokio::spawn(async move {
+                    handle_connection(stream, context, rdb_argument).await;
+                });
+            }
+            Err(e) => eprintln!("Got error when listenning... --- error: {}", e),
+        }
+    }
+}
 
-                for i in 0..buffer.len() {
-                    if buffer[i] == b'\n' && buffer[i - 1] == b'\r' {
-                        asize = String::from_utf8_lossy(&buffer[1..i - 1]).parse().unwrap();
-                        last = i + 1;
-                        break;
-                    }
+async fn handle_connection(
+    stream: TcpStream,
+    context: ServerContext,
+    mut rdb_argument: Argument,
+) {
+    let ServerContext {
+        storage,
+        replication,
+        cluster,
+        pubsub,
+    } = context.clone();
+    let address = stream
+        .peer_addr()
+        .map(|address| address.to_string())
+        .unwrap_or_default();
+    let (reader, writer) = split(stream);
+    let mut reader = RespReader::new(reader);
+    let writer = Arc::new(Mutex::new(writer));
+    let mut client = Client::new(address, Some(writer.clone()));
+    let mut transaction = Transaction::new();
+    loop {
+        match reader.read_value().await {
+            Ok(Some((response, _))) => {
+                let (command, command_content) = extract_command(response).unwrap();
+                if command == "QUIT" {
+                    write_value(writer.clone(), Value::SimpleString("OK".to_string()).serialize()).await;
+                    break;
                 }
-
-                let mut result: Vec<String> = Vec::new();
-
-                for _ in 0..asize {
-                    let mut len_next_command = 0;
-                    for i in last..buffer.len() {
-                        if buffer[i] == b'\n' && buffer[i - 1] == b'\r' {
-                            len_next_command = String::from_utf8_lossy(&buffer[last + 1..i - 1])
-                                .parse()
-                                .unwrap();
-                            last = i + 1;
-                            break;
-                        }
-                    }
-                    result.push(
-                        String::from_utf8_lossy(&buffer[last..last + len_next_command]).to_string(),
-                    );
-                    last = last + len_next_command + 2;
+                //a subscribed connection only takes subscription commands
+                if client.is_subscribed()
+                    && !matches!(
+                        command.as_str(),
+                        "SUBSCRIBE"
+                            | "UNSUBSCRIBE"
+                            | "PSUBSCRIBE"
+                            | "PUNSUBSCRIBE"
+                            | "SSUBSCRIBE"
+                            | "SUNSUBSCRIBE"
+                            | "PING"
+                            | "RESET"
+                    )
+                {
+                    let error = Value::SimpleError(format!(
+                        "ERR Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context",
+                        command.to_lowercase()
+                    ));
+                    write_value(writer.clone(), error.serialize()).await;
+                    continue;
                 }
-                match result[0].as_str() {
-                    "SET" => {
-                        storage_clone
+                let mut refused = replication.lock().await.check_client_command(&command);
+                let keys = get_command_keys(&command, &command_content);
+                if !keys.is_empty() {
+                    let mut storage = storage.lock().await;
+                    //expired keys go away before the command sees them
+                    storage.expire_keys(&keys);
+                    if refused.is_none() {
+                        //route to the node serving the keys
+                        refused = cluster
                             .lock()
                             .await
-                            .set_value(&result[1], &result[2], None)
-                            .unwrap();
+                            .check_keys(&keys, client.is_asking(), &storage);
                     }
-                    "REPLCONF GETACK *" => {
-                        if result[1].as_str() == "GETACK" && result[2] == "*" {
-                            let payload = Value::Array(vec![
-                                Value::BulkString("REPLCONF".to_string()),
-                                Value::BulkString("ACK".to_string()),
-                                Value::BulkString("0".to_string()),
-                            ]);
-                            master_writer
-                                .write_all(payload.serialize().as_bytes())
-                                .await
-                                .unwrap();
-                        }
+                }
+                let result = if let Some(error) = refused {
+                    //a refused command poisons the transaction it was sent in
+                    if transaction.is_in_multi() && command != "EXEC" {
+                        transaction.abort();
                     }
-                    _ => {
-                        println!("Slave can not handle this command {}", result[0])
+                    error
+                } else if command == "EXEC" {
+                    exec_transaction(&context, &mut rdb_argument, &mut transaction, &mut client, true).await
+                } else {
+                    let handled = command_handler(
+                        command.clone(),
+                        command_content.clone(),
+                        &context,
+                        &mut rdb_argument,
+                        &mut transaction,
+                        &mut client,
+                    );
+                    if is_blocking_command(&command) {
+                        //a client that goes away while blocked gives up its place in line
+                        tokio::select! {
+                            result = handled => result,
+                            _ = reader.closed() => break,
+                        }
+                    } else {
+                        handled.await
                     }
+                };
+
+                write_value(writer.clone(), Value::serialize(&result)).await;
+                //ASKING only holds for the command right after it
+                if command != "ASKING" {
+                    client.set_asking(false);
                 }
-            }
-        });
-        loop {
-            match listener.accept().await {
-                Ok((stream, _)) => {
-                    //read client stream
-                    let (mut reader, mut writer) = split(stream);
-                    loop {
-                        match read_value(&mut reader).await {
-                            Ok(Some(response)) => {
-                                // sleep(Duration::from_millis(2000)).await;
-                                let (command, command_content) = extract_command(response).unwrap();
-                                let result = match command.as_str() {
-                                    "INFO" => handle_info(command_content, replication.clone())
-                                        .await
-                                        .expect("Error when handle KEY"),
-                                    "GET" => {
-                                        let key =
-                                            unwrap_value_to_string(command_content.get(0).unwrap())
-                                                .unwrap();
-                                        let storage = storage.lock().await;
 
-                                        let mut value = Value::NullBulkString;
-                                        for _ in 1..100 {
-                                            if let Ok(get_value) = storage.get_value(&key) {
-                                                value = Value::BulkString(get_value.to_string());
-                                                break;
-                                            }
-                                        }
-                                        if value == Value::NullBulkString {
-                                            panic!("Fail get value of key {}", key)
-                                        } else {
-                                            value
-                                        }
-                                    }
-                                    _ => Value::NullBulkString,
-                                };
-                                writer
-                                    .write_all(result.serialize().as_bytes())
-                                    .await
-                                    .unwrap();
-                            }
-                            Ok(None) => {
-                                println!("Got nothing from client");
-                                break;
-                            }
-                            Err(e) => println!("Got error from slave: {}", e),
-                        }
+                //propagate writes to replicas
+                if result != Value::SimpleString("QUEUED".to_string()) {
+                    if let Some(payload) = replicated_command(&command, &command_content, &result) {
+                        let mut replication = replication.lock().await;
+                        replication.propagate(&Value::Array(payload)).await.unwrap();
                     }
                 }
-                Err(e) => println!("Connection error: {}", e),
+            }
+            Ok(None) => {
+                break;
+            }
+            Err(e) => {
+                eprint!("Got error when read value : {}", e);
+                break;
             }
         }
-    } else {
-        //master side
-        let listener = TcpListener::bind(format!("127.0.0.1:{}", rdb_argument.get_port().unwrap()))
-            .await
-            .unwrap_or_else(|e| {
-                eprintln!("Failed to bind to address: {}", e);
-                std::process::exit(1);
-            });
+    }
+    replication.lock().await.remove_repl_handler(client.get_id());
+    pubsub.lock().await.remove_client(client.get_id());
+}
 
-        let storage = Arc::new(Mutex::new(Store::new()));
+//Drop expired keys nobody asks for
+fn spawn_active_expire(storage: Arc<Mutex<Store>>) {
+    tokio::spawn(async move {
+        let mut cron = interval(Duration::from_millis(100));
         loop {
-            match listener.accept().await {
-                Ok((stream, _)) => {
-                    let (mut reader, writer) = split(stream);
-                    let writer = Arc::new(Mutex::new(writer));
-
-                    //clone for loop
-                    let storage = storage.clone();
-                    let mut rdb_argument = rdb_argument.clone();
-                    let mut rdb_file = rdb_file.clone();
-                    let replication = replication.clone();
-
-                    tokio::spawn(async move {
-                        let mut transaction = Transaction::new();
-                        loop {
-                            match read_value(&mut reader).await {
-                                Ok(Some(response)) => {
-                                    let (command, command_content) =
-                                        extract_command(response).unwrap();
-                                    let result = if command == "EXEC" {
-                                        match transaction.get_font_value() {
-                                            Some(value) => {
-                                                if value == Value::BulkString("MULTI".to_string())
-                                                    && transaction.len() == 0
-                                                {
-                                                    Value::Array(Vec::new());
-                                                }
-                                                let mut result: Vec<Value> = Vec::new();
-                                                let num_cmd = transaction.len();
-                                                for _ in 1..=num_cmd {
-                                                    let value =
-                                                        transaction.get_font_value().unwrap();
-                                                    let (cmd, cmd_content) =
-                                                        extract_command(value).unwrap();
-                                                    result.push(
-                                                        command_handler(
-                                                            cmd,
-                                                            cmd_content,
-                                                            storage.clone(),
-                                                            &mut rdb_argument,
-                                                            &mut rdb_file,
-                                                            replication.clone(),
-                                                            &mut transaction,
-                                                        )
-                                                        .await,
-                                                    );
-                                                }
-                                                Value::Array(result)
-                                            }
-                                            None => Value::SimpleError(
-                                                "ERR EXEC without MULTI".to_string(),
-                                            ),
-                                        }
-                                    } else {
-                                        command_handler(
-                                            command.clone(),
-                                            command_content.clone(),
-                                            storage.clone(),
-                                            &mut rdb_argument,
-                                            &mut rdb_file,
-                                            replication.clone(),
-                                            &mut transaction,
-                                        )
-                                        .await
-                                    };
-
-                                    write_value(writer.clone(), Value::serialize(&result)).await;
-
-                                    // handle second time
-                                    if command == "PSYNC" {
-                                        let empty_rdb_file = "524544495330303131fa0972656469732d76657205372e322e30fa0a72656469732d62697473c040fa056374696d65c26d08bc65fa08757365642d6d656dc2b0c41000fa08616f662d62617365c000fff06e3bfec0ff5aa2";
-                                        let rdb_bytes = hex::decode(empty_rdb_file).unwrap();
-                                        println!(
-                                            "LOG_FROM_handle_second_time --- rdb_bytes: {:?}",
-                                            rdb_bytes
-                                        );
-                                        let header = format!("${}\r\n", rdb_bytes.len());
-
-                                        let mut writer_guard = writer.lock().await;
-                                        writer_guard.write_all(header.as_bytes()).await.unwrap();
-                                        writer_guard.write_all(&rdb_bytes).await.unwrap();
-                                        writer_guard.flush().await.expect("Failed to flush stream");
-
-                                        let mut replication = replication.lock().await;
-                                        replication.add_repl_handler(writer.clone()).unwrap();
-                                    } else if command == "SET" {
-                                        let mut repls = replication.lock().await;
-                                        let key =
-                                            unwrap_value_to_string(command_content.get(0).unwrap())
-                                                .unwrap();
-                                        let value =
-                                            unwrap_value_to_string(command_content.get(1).unwrap())
-                                                .unwrap();
-                                        let payload = Value::Array(vec![
-                                            Value::BulkString("SET".to_string()),
-                                            Value::BulkString(key),
-                                            Value::BulkString(value),
-                                        ]);
-
-                                        for repl_writer in &mut repls.replication_handlers {
-                                            repl_writer
-                                                .lock()
-                                                .await
-                                                .write_all(payload.serialize().as_bytes())
-                                                .await
-                                                .unwrap();
-                                            repl_writer.lock().await.flush().await.unwrap();
-                                        }
-                                    }
-                                }
-                                Ok(None) => {
-                                    break;
-                                }
-                                Err(e) => {
-                                    eprint!("Got error when read value : {}", e);
-                                    break;
-                                }
-                            }
-                        }
-                    });
-                }
-                Err(e) => eprintln!("Got error when listenning... --- error: {}", e),
-            }
+            cron.tick().await;
+            storage.lock().await.active_expire_cycle();
         }
-    }
+    });
 }
@@ -0,0 +1,3 @@
+pub mod glob;
+pub mod notify;
+pub mod state;
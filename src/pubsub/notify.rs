@@ -0,0 +1,117 @@
+use std::sync::Arc;
+use tokio::sync::{
+    mpsc::{unbounded_channel, UnboundedSender},
+    Mutex,
+};
+
+use crate::pubsub::state::{publish, PubSub};
+
+//Classes of notify-keyspace-events, same letters as redis
+pub const NOTIFY_KEYSPACE: u32 = 1 << 0; //K
+pub const NOTIFY_KEYEVENT: u32 = 1 << 1; //E
+pub const NOTIFY_GENERIC: u32 = 1 << 2; //g
+pub const NOTIFY_STRING: u32 = 1 << 3; //$
+pub const NOTIFY_LIST: u32 = 1 << 4; //l
+pub const NOTIFY_SET: u32 = 1 << 5; //s
+pub const NOTIFY_HASH: u32 = 1 << 6; //h
+pub const NOTIFY_ZSET: u32 = 1 << 7; //z
+pub const NOTIFY_EXPIRED: u32 = 1 << 8; //x
+//accepted like redis, there is no maxmemory here so nothing is ever evicted
+pub const NOTIFY_EVICTED: u32 = 1 << 9; //e
+pub const NOTIFY_STREAM: u32 = 1 << 10; //t
+pub const NOTIFY_KEY_MISS: u32 = 1 << 11; //m
+pub const NOTIFY_MODULE: u32 = 1 << 12; //d
+pub const NOTIFY_NEW: u32 = 1 << 13; //n
+pub const NOTIFY_ALL: u32 = NOTIFY_GENERIC
+    | NOTIFY_STRING
+    | NOTIFY_LIST
+    | NOTIFY_SET
+    | NOTIFY_HASH
+    | NOTIFY_ZSET
+    | NOTIFY_EXPIRED
+    | NOTIFY_EVICTED
+    | NOTIFY_STREAM
+    | NOTIFY_MODULE;
+
+const CLASSES: [(char, u32); 10] = [
+    ('g', NOTIFY_GENERIC),
+    ('$', NOTIFY_STRING),
+    ('l', NOTIFY_LIST),
+    ('s', NOTIFY_SET),
+    ('h', NOTIFY_HASH),
+    ('z', NOTIFY_ZSET),
+    ('x', NOTIFY_EXPIRED),
+    ('e', NOTIFY_EVICTED),
+    ('t', NOTIFY_STREAM),
+    ('d', NOTIFY_MODULE),
+];
+
+//(channel, message) waiting to be published
+pub type Notifier = UnboundedSender<(String, String)>;
+
+//None when the string has an unknown letter
+pub fn parse_notify_flags(flags: &str) -> Option<u32> {
+    let mut result = 0;
+    for flag in flags.chars() {
+        result |= match flag {
+            'A' => NOTIFY_ALL,
+            'K' => NOTIFY_KEYSPACE,
+            'E' => NOTIFY_KEYEVENT,
+            'm' => NOTIFY_KEY_MISS,
+            'n' => NOTIFY_NEW,
+            flag => CLASSES.iter().find(|(letter, _)| *letter == flag)?.1,
+        };
+    }
+    Some(result)
+}
+
+//Canonical form shown by CONFIG GET
+pub fn notify_flags_to_string(flags: u32) -> String {
+    let mut result = String::new();
+    if flags & NOTIFY_ALL == NOTIFY_ALL {
+        result.push('A');
+    } else {
+        for (letter, class) in CLASSES {
+            if flags & class != 0 {
+                result.push(letter);
+            }
+        }
+    }
+    for (letter, class) in [
+        ('K', NOTIFY_KEYSPACE),
+        ('E', NOTIFY_KEYEVENT),
+        ('m', NOTIFY_KEY_MISS),
+        ('n', NOTIFY_NEW),
+    ] {
+        if flags & class != 0 {
+            result.push(letter);
+        }
+    }
+    result
+}
+
+//Messages for one event, empty when the class or both channel kinds are off
+pub fn keyspace_messages(flags: u32, class: u32, event: &str, key: &str) -> Vec<(String, String)> {
+    let mut messages = Vec::new();
+    if flags & class == 0 {
+        return messages;
+    }
+    if flags & NOTIFY_KEYSPACE != 0 {
+        messages.push((format!("__keyspace@0__:{}", key), event.to_string()));
+    }
+    if flags & NOTIFY_KEYEVENT != 0 {
+        messages.push((format!("__keyevent@0__:{}", event), key.to_string()));
+    }
+    messages
+}
+
+//Publish notifications in the order the store produced them
+pub fn spawn_notifier(pubsub: Arc<Mutex<PubSub>>) -> Notifier {
+    let (sender, mut receiver) = unbounded_channel::<(String, String)>();
+    tokio::spawn(async move {
+        while let Some((channel, message)) = receiver.recv().await {
+            publish(pubsub.clone(), &channel, &message).await;
+        }
+    });
+    sender
+}
@@ -0,0 +1,173 @@
+use std::{collections::HashMap, sync::Arc};
+use tokio::{io::WriteHalf, net::TcpStream, sync::Mutex};
+
+use crate::{
+    pubsub::glob::glob_match,
+    resp::{resp::write_value, value::Value},
+};
+
+pub type SubscriberWriter = Arc<Mutex<WriteHalf<TcpStream>>>;
+
+//Who listens on which channel or pattern, keyed by client id
+pub struct PubSub {
+    channels: HashMap<String, HashMap<u64, SubscriberWriter>>,
+    patterns: HashMap<String, HashMap<u64, SubscriberWriter>>,
+    //sharded channels live in the slot of their name, like keys
+    shard_channels: HashMap<String, HashMap<u64, SubscriberWriter>>,
+}
+impl PubSub {
+    pub fn new() -> Self {
+        PubSub {
+            channels: HashMap::new(),
+            patterns: HashMap::new(),
+            shard_channels: HashMap::new(),
+        }
+    }
+    pub fn subscribe(&mut self, channel: &str, client_id: u64, writer: SubscriberWriter) {
+        self.channels
+            .entry(channel.to_string())
+            .or_default()
+            .insert(client_id, writer);
+    }
+    pub fn unsubscribe(&mut self, channel: &str, client_id: u64) {
+        remove_subscriber(&mut self.channels, channel, client_id);
+    }
+    pub fn psubscribe(&mut self, pattern: &str, client_id: u64, writer: SubscriberWriter) {
+        self.patterns
+            .entry(pattern.to_string())
+            .or_default()
+            .insert(client_id, writer);
+    }
+    pub fn punsubscribe(&mut self, pattern: &str, client_id: u64) {
+        remove_subscriber(&mut self.patterns, pattern, client_id);
+    }
+    pub fn ssubscribe(&mut self, channel: &str, client_id: u64, writer: SubscriberWriter) {
+        self.shard_channels
+            .entry(channel.to_string())
+            .or_default()
+            .insert(client_id, writer);
+    }
+    pub fn sunsubscribe(&mut self, channel: &str, client_id: u64) {
+        remove_subscriber(&mut self.shard_channels, channel, client_id);
+    }
+    //Every writer the message goes to, with the push each one receives
+    pub fn receivers(&self, channel: &str, message: &str) -> Vec<(SubscriberWriter, Value)> {
+        let mut receivers = Vec::new();
+        if let Some(subscribers) = self.channels.get(channel) {
+            let push = Value::Array(vec![
+                Value::BulkString("message".to_string()),
+                Value::BulkString(channel.to_string()),
+                Value::BulkString(message.to_string()),
+            ]);
+            for writer in subscribers.values() {
+                receivers.push((writer.clone(), push.clone()));
+            }
+        }
+        for (pattern, subscribers) in self.patterns.iter() {
+            if !glob_match(pattern, channel) {
+                continue;
+            }
+            let push = Value::Array(vec![
+                Value::BulkString("pmessage".to_string()),
+                Value::BulkString(pattern.clone()),
+                Value::BulkString(channel.to_string()),
+                Value::BulkString(message.to_string()),
+            ]);
+            for writer in subscribers.values() {
+                receivers.push((writer.clone(), push.clone()));
+            }
+        }
+        receivers
+    }
+    //Sharded channels never match patterns
+    pub fn shard_receivers(&self, channel: &str, message: &str) -> Vec<(SubscriberWriter, Value)> {
+        let push = Value::Array(vec![
+            Value::BulkString("smessage".to_string()),
+            Value::BulkString(channel.to_string()),
+            Value::BulkString(message.to_string()),
+        ]);
+        self.shard_channels
+            .get(channel)
+            .map(|subscribers| {
+                subscribers
+                    .values()
+                    .map(|writer| (writer.clone(), push.clone()))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+    //Channels with at least one subscriber, optionally filtered by a glob
+    pub fn get_channels(&self, pattern: Option<&str>) -> Vec<String> {
+        filter_names(&self.channels, pattern)
+    }
+    pub fn get_shard_channels(&self, pattern: Option<&str>) -> Vec<String> {
+        filter_names(&self.shard_channels, pattern)
+    }
+    pub fn count_subscribers(&self, channel: &str) -> usize {
+        self.channels.get(channel).map_or(0, |subscribers| subscribers.len())
+    }
+    pub fn count_shard_subscribers(&self, channel: &str) -> usize {
+        self.shard_channels
+            .get(channel)
+            .map_or(0, |subscribers| subscribers.len())
+    }
+    pub fn count_patterns(&self) -> usize {
+        self.patterns.len()
+    }
+    //Drop every subscription of a closed connection
+    pub fn remove_client(&mut self, client_id: u64) {
+        for subscriptions in [&mut self.channels, &mut self.patterns, &mut self.shard_channels] {
+            subscriptions.retain(|_, subscribers| {
+                subscribers.remove(&client_id);
+                !subscribers.is_empty()
+            });
+        }
+    }
+}
+
+//Deliver a message to local subscribers, returns how many received it
+pub async fn publish(pubsub: Arc<Mutex<PubSub>>, channel: &str, message: &str) -> usize {
+    //write outside the lock so a slow subscriber does not hold everyone
+    let receivers = pubsub.lock().await.receivers(channel, message);
+    deliver(receivers).await
+}
+
+//Deliver a message to local subscribers of a sharded channel
+pub async fn spublish(pubsub: Arc<Mutex<PubSub>>, channel: &str, message: &str) -> usize {
+    let receivers = pubsub.lock().await.shard_receivers(channel, message);
+    deliver(receivers).await
+}
+
+async fn deliver(receivers: Vec<(SubscriberWriter, Value)>) -> usize {
+    let count = receivers.len();
+    for (writer, push) in receivers {
+        write_value(writer, push.serialize()).await;
+    }
+    count
+}
+
+fn filter_names(
+    subscriptions: &HashMap<String, HashMap<u64, SubscriberWriter>>,
+    pattern: Option<&str>,
+) -> Vec<String> {
+    let mut names = subscriptions
+        .keys()
+        .filter(|name| pattern.is_none_or(|pattern| glob_match(pattern, name)))
+        .cloned()
+        .collect::<Vec<String>>();
+    names.sort();
+    names
+}
+
+fn remove_subscriber(
+    subscriptions: &mut HashMap<String, HashMap<u64, SubscriberWriter>>,
+    name: &str,
+    client_id: u64,
+) {
+    if let Some(subscribers) = subscriptions.get_mut(name) {
+        subscribers.remove(&client_id);
+        if subscribers.is_empty() {
+            subscriptions.remove(name);
+        }
+    }
+}
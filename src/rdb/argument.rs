@@ -7,8 +7,18 @@ pub struct Argument{
     dir: String,
     db_file_name:String,
     port: usize,
-    master_endpoint: String,
-    
+    //sentinel mode
+    sentinel: bool,
+    sentinel_monitors: Vec<String>,
+    sentinel_peers: Vec<String>,
+    down_after_milliseconds: u64,
+    failover_timeout: u64,
+    //cluster mode
+    cluster_enabled: bool,
+    cluster_nodes: Vec<String>,
+    cluster_node_timeout: u64,
+    //keyspace notifications, letters like notify-keyspace-events
+    notify_keyspace_events: String,
 }
 impl Argument{
     pub fn new() -> Self{
@@ -16,7 +26,15 @@ impl Argument{
             dir: String::new(), 
             db_file_name: String::new(),
             port: 6379,
-            master_endpoint: String::from("127.0.0.1:0")
+            sentinel: false,
+            sentinel_monitors: Vec::new(),
+            sentinel_peers: Vec::new(),
+            down_after_milliseconds: 30000,
+            failover_timeout: 180000,
+            cluster_enabled: false,
+            cluster_nodes: Vec::new(),
+            cluster_node_timeout: 15000,
+            notify_keyspace_events: String::new(),
         }
     }
     pub fn set_dir(&mut self, dir: String) -> Result<()>{
@@ -42,16 +60,35 @@ impl Argument{
     pub fn get_port(&self) -> Result<usize>{
         Ok(self.port)
     }
-    pub fn get_master_endpoint(&self) -> Result<(String, usize)>{
-        let mut master_endpoint = self.master_endpoint.split(":");
-        let address = master_endpoint.next().unwrap().to_string();
-        let port = master_endpoint.next().unwrap().parse::<usize>().unwrap();
-        Ok((address, port))
+    pub fn is_sentinel(&self) -> bool {
+        self.sentinel
     }
-    pub fn set_master_endpoint(&mut self, address: String, port: usize) -> Result<()>{
-        println!("LOG_FROM_set_master_endpoint -- address: {}, port: {}", address, port);
-        self.master_endpoint = format!("{}:{}", address, port);
-        Ok(())
+    //"<name> <host> <port> <quorum>"
+    pub fn get_sentinel_monitors(&self) -> Result<Vec<String>> {
+        Ok(self.sentinel_monitors.clone())
+    }
+    //"<host> <port>" of the other sentinels
+    pub fn get_sentinel_peers(&self) -> Result<Vec<String>> {
+        Ok(self.sentinel_peers.clone())
+    }
+    pub fn get_down_after_milliseconds(&self) -> Result<u64> {
+        Ok(self.down_after_milliseconds)
+    }
+    pub fn get_failover_timeout(&self) -> Result<u64> {
+        Ok(self.failover_timeout)
+    }
+    pub fn is_cluster_enabled(&self) -> bool {
+        self.cluster_enabled
+    }
+    //"<host> <port> <slots>" of the other cluster nodes
+    pub fn get_cluster_nodes(&self) -> Result<Vec<String>> {
+        Ok(self.cluster_nodes.clone())
+    }
+    pub fn get_cluster_node_timeout(&self) -> Result<u64> {
+        Ok(self.cluster_node_timeout)
+    }
+    pub fn get_notify_keyspace_events(&self) -> Result<String> {
+        Ok(self.notify_keyspace_events.clone())
     }
 }
 
@@ -64,6 +101,51 @@ pub fn flags_handler<'a>(flags: Vec<String>) -> Result<(Argument, RdbFile, Repli
     while index < flags.len() {
         let flag_name = flags[index].clone();
         match flag_name.as_str() {
+            //the only flag without value
+            "--sentinel" => {
+                rdb_argument.sentinel = true;
+                index += 1;
+                continue;
+            }
+            "--sentinel-monitor" => match flags.get(index + 1) {
+                Some(monitor) => rdb_argument.sentinel_monitors.push(monitor.to_owned()),
+                None => panic!("Need <name> <host> <port> <quorum>"),
+            },
+            "--sentinel-peer" => match flags.get(index + 1) {
+                Some(peer) => rdb_argument.sentinel_peers.push(peer.to_owned()),
+                None => panic!("Need <host> <port> of a sentinel"),
+            },
+            "--down-after-milliseconds" => match flags.get(index + 1) {
+                Some(milliseconds) => {
+                    rdb_argument.down_after_milliseconds = milliseconds.parse::<u64>().expect("Error when parse down-after-milliseconds");
+                }
+                None => panic!("Need a number of milliseconds"),
+            },
+            "--failover-timeout" => match flags.get(index + 1) {
+                Some(milliseconds) => {
+                    rdb_argument.failover_timeout = milliseconds.parse::<u64>().expect("Error when parse failover-timeout");
+                }
+                None => panic!("Need a number of milliseconds"),
+            },
+            "--cluster-enabled" => match flags.get(index + 1).map(|v| v.as_str()) {
+                Some("yes") => rdb_argument.cluster_enabled = true,
+                Some("no") => rdb_argument.cluster_enabled = false,
+                _ => panic!("Need yes or no"),
+            },
+            "--cluster-node" => match flags.get(index + 1) {
+                Some(node) => rdb_argument.cluster_nodes.push(node.to_owned()),
+                None => panic!("Need <host> <port> <slots> of a cluster node"),
+            },
+            "--cluster-node-timeout" => match flags.get(index + 1) {
+                Some(milliseconds) => {
+                    rdb_argument.cluster_node_timeout = milliseconds.parse::<u64>().expect("Error when parse cluster-node-timeout");
+                }
+                None => panic!("Need a number of milliseconds"),
+            },
+            "--notify-keyspace-events" => match flags.get(index + 1) {
+                Some(events) => rdb_argument.notify_keyspace_events = events.to_owned(),
+                None => panic!("Need keyspace event classes like KEA"),
+            },
             "--dir" => match flags.get(index + 1) {
                 Some(dir) => {
                     let _ = rdb_argument.set_dir(dir.to_owned());
@@ -87,13 +169,33 @@ pub fn flags_handler<'a>(flags: Vec<String>) -> Result<(Argument, RdbFile, Repli
                     replication.set_role(Role::Slave).expect("Error when set role in replication");
 
                     let mut m_endpoint = master_endpoint.split_whitespace();
-                    let _ = rdb_argument.set_master_endpoint(
-                        m_endpoint.next().unwrap().to_string(), 
-                        m_endpoint.next().unwrap().parse::<usize>().unwrap()
-                    );
+                    let address = m_endpoint.next().unwrap().to_string();
+                    let port = m_endpoint.next().unwrap().parse::<usize>().unwrap();
+                    let _ = replication.set_master_endpoint(address, port);
                 } 
                 None => panic!("Need a file name"),
             }
+            "--replica-serve-stale-data" => match flags.get(index + 1).map(|v| v.as_str()) {
+                Some("yes") => {
+                    let _ = replication.set_serve_stale_data(true);
+                }
+                Some("no") => {
+                    let _ = replication.set_serve_stale_data(false);
+                }
+                _ => panic!("Need yes or no"),
+            },
+            "--min-replicas-to-write" => match flags.get(index + 1) {
+                Some(number) => {
+                    let _ = replication.set_min_replicas_to_write(number.parse::<usize>().expect("Error when parse min-replicas-to-write"));
+                }
+                None => panic!("Need a number of replicas"),
+            },
+            "--min-replicas-max-lag" => match flags.get(index + 1) {
+                Some(seconds) => {
+                    let _ = replication.set_min_replicas_max_lag(seconds.parse::<u64>().expect("Error when parse min-replicas-max-lag"));
+                }
+                None => panic!("Need a number of seconds"),
+            },
             _ => panic!("Invalid flags name: {}", flag_name),
         }
         index += 2;
@@ -0,0 +1,146 @@
+use nom::{
+    bytes::complete::take,
+    number::complete::{le_i16, le_i24, le_i32, le_i64, le_u16, le_u32, le_u8},
+    IResult, Parser,
+};
+
+//Listpack blob: total bytes and element count as header, the elements, then 0xFF
+//Redis 7 keeps small lists, sets, hashes, sorted sets and stream nodes in them
+const LISTPACK_HEADER_SIZE: usize = 6;
+const LISTPACK_END: u8 = 0xFF;
+
+//One element to write, listpacks store small integers without their digits
+pub enum ListpackElement<'a> {
+    Integer(i64),
+    String(&'a [u8]),
+}
+
+pub fn write_listpack(elements: &[ListpackElement]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for element in elements {
+        let entry = match element {
+            ListpackElement::Integer(value) => encode_integer(*value),
+            ListpackElement::String(value) => encode_string(value),
+        };
+        body.extend(&entry);
+        body.extend(encode_backlen(entry.len()));
+    }
+    let total = LISTPACK_HEADER_SIZE + body.len() + 1;
+    let mut output = (total as u32).to_le_bytes().to_vec();
+    //past u16::MAX the count is unknown and readers walk the whole blob
+    output.extend((elements.len().min(u16::MAX as usize) as u16).to_le_bytes());
+    output.extend(body);
+    output.push(LISTPACK_END);
+    output
+}
+fn encode_integer(value: i64) -> Vec<u8> {
+    if (0..=127).contains(&value) {
+        vec![value as u8]
+    } else if (-4096..4096).contains(&value) {
+        let value = value as u16 & 0x1fff;
+        vec![0xc0 | (value >> 8) as u8, value as u8]
+    } else if i16::try_from(value).is_ok() {
+        let mut entry = vec![0xf1];
+        entry.extend((value as i16).to_le_bytes());
+        entry
+    } else if (-(1 << 23)..(1 << 23)).contains(&value) {
+        let mut entry = vec![0xf2];
+        entry.extend(&(value as i32).to_le_bytes()[..3]);
+        entry
+    } else if i32::try_from(value).is_ok() {
+        let mut entry = vec![0xf3];
+        entry.extend((value as i32).to_le_bytes());
+        entry
+    } else {
+        let mut entry = vec![0xf4];
+        entry.extend(value.to_le_bytes());
+        entry
+    }
+}
+fn encode_string(value: &[u8]) -> Vec<u8> {
+    let mut entry = if value.len() < 64 {
+        vec![0x80 | value.len() as u8]
+    } else if value.len() < 4096 {
+        vec![0xe0 | (value.len() >> 8) as u8, value.len() as u8]
+    } else {
+        let mut header = vec![0xf0];
+        header.extend((value.len() as u32).to_le_bytes());
+        header
+    };
+    entry.extend(value);
+    entry
+}
+//Length of the entry written backward, 7 bits per byte, so the blob can be walked from the tail
+fn encode_backlen(len: usize) -> Vec<u8> {
+    let size = backlen_size(len);
+    (0..size)
+        .map(|index| {
+            let shift = 7 * (size - 1 - index);
+            let byte = ((len >> shift) & 127) as u8;
+            if index == 0 {
+                byte
+            } else {
+                byte | 128
+            }
+        })
+        .collect()
+}
+fn backlen_size(len: usize) -> usize {
+    match len {
+        0..=127 => 1,
+        128..=16382 => 2,
+        16383..=2097150 => 3,
+        2097151..=268435454 => 4,
+        _ => 5,
+    }
+}
+
+//Every element as a string, integers printed in decimal
+pub fn parse_listpack(blob: &[u8]) -> IResult<&[u8], Vec<String>> {
+    let (mut input, (_total, _count)) = (le_u32, le_u16).parse(blob)?;
+    let mut elements = Vec::new();
+    loop {
+        let (rest, first_byte) = le_u8(input)?;
+        if first_byte == LISTPACK_END {
+            return Ok((rest, elements));
+        }
+        let (rest, element) = parse_element(input)?;
+        let (rest, _) = take(backlen_size(input.len() - rest.len()))(rest)?;
+        elements.push(element);
+        input = rest;
+    }
+}
+fn parse_element(input: &[u8]) -> IResult<&[u8], String> {
+    let (rest, first_byte) = le_u8(input)?;
+    let string = |input, len: usize| -> IResult<&[u8], String> {
+        let (input, value) = take(len)(input)?;
+        Ok((input, String::from_utf8_lossy(value).to_string()))
+    };
+    match first_byte {
+        0x00..=0x7f => Ok((rest, first_byte.to_string())),
+        0x80..=0xbf => string(rest, (first_byte & 0x3f) as usize),
+        0xc0..=0xdf => {
+            let (rest, low) = le_u8(rest)?;
+            let value = (((first_byte as u16 & 0x1f) << 8) | low as u16) as i16;
+            //13 bits two's complement
+            let value = if value >= 1 << 12 { value - (1 << 13) } else { value };
+            Ok((rest, value.to_string()))
+        }
+        0xe0..=0xef => {
+            let (rest, low) = le_u8(rest)?;
+            string(rest, ((first_byte as usize & 0x0f) << 8) | low as usize)
+        }
+        0xf0 => {
+            let (rest, len) = le_u32(rest)?;
+            string(rest, len as usize)
+        }
+        0xf1 => le_i16.map(|value| value.to_string()).parse(rest),
+        0xf2 => le_i24.map(|value| value.to_string()).parse(rest),
+        0xf3 => le_i32.map(|value| value.to_string()).parse(rest),
+        0xf4 => le_i64.map(|value| value.to_string()).parse(rest),
+        _ => Err(nom::Err::Error(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::Fail,
+        ))),
+    }
+}
@@ -0,0 +1,229 @@
+use anyhow::Result;
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    io::{split, AsyncWriteExt, WriteHalf},
+    net::TcpStream,
+    sync::Mutex,
+    task::JoinHandle,
+    time::{interval, sleep},
+};
+
+use crate::{
+    command_handler::{
+        client::Client,
+        command_handler::{command_handler, exec_transaction},
+        context::ServerContext,
+    },
+    rdb::{
+        argument::Argument,
+        parse_rdb::parse_rdb_file,
+        replication::Replication,
+    },
+    resp::{
+        resp::{extract_command, unwrap_value_to_string, RespReader},
+        value::Value,
+    },
+    store::{store::Store, transaction::Transaction},
+};
+
+const MIN_RECONNECT_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(5);
+//Replicas report their offset this often so master can compute lag
+const ACK_INTERVAL: Duration = Duration::from_secs(1);
+
+//Keep this node in sync with its master, reconnecting with backoff when the link drops
+pub fn spawn_master_link(
+    master_address: String,
+    master_port: usize,
+    rdb_argument: Argument,
+    context: ServerContext,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut backoff = MIN_RECONNECT_BACKOFF;
+        loop {
+            match connect_to_master(
+                &master_address,
+                master_port,
+                &rdb_argument,
+                context.storage.shared(),
+                context.replication.clone(),
+            )
+            .await
+            {
+                Ok((reader, writer)) => {
+                    backoff = MIN_RECONNECT_BACKOFF;
+                    if let Err(e) = stream_from_master(
+                        reader,
+                        writer,
+                        rdb_argument.clone(),
+                        &context,
+                    )
+                    .await
+                    {
+                        eprintln!("Lost link with master {}:{} --- error: {}", master_address, master_port, e);
+                    }
+                }
+                Err(e) => eprintln!(
+                    "Can not sync with master {}:{} --- error: {}",
+                    master_address, master_port, e
+                ),
+            }
+            context.replication.lock().await.set_master_link_up(false).unwrap();
+            sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+        }
+    })
+}
+
+async fn connect_to_master(
+    master_address: &str,
+    master_port: usize,
+    rdb_argument: &Argument,
+    storage: Arc<Mutex<Store>>,
+    replication: Arc<Mutex<Replication>>,
+) -> Result<(RespReader, WriteHalf<TcpStream>)> {
+    let stream_to_master = TcpStream::connect(format!("{}:{}", master_address, master_port)).await?;
+    let (master_reader, mut master_writer) = split(stream_to_master);
+    let mut master_reader = RespReader::new(master_reader);
+
+    //=====================Hand shake=========================//
+    let (psync_replid, psync_offset) = {
+        let replication = replication.lock().await;
+        //with a replication history we can ask for a partial resync
+        if replication.has_backlog() {
+            (
+                replication.get_master_replid()?,
+                format!("{}", replication.get_master_repl_offset()? + 1),
+            )
+        } else {
+            ("?".to_string(), "-1".to_string())
+        }
+    };
+    let payloads = vec![
+        vec!["PING".to_string()],
+        vec![
+            "REPLCONF".to_string(),
+            "listening-port".to_string(),
+            format!("{}", rdb_argument.get_port()?),
+        ],
+        vec!["REPLCONF".to_string(), "capa".to_string(), "psync2".to_string()],
+        vec!["PSYNC".to_string(), psync_replid, psync_offset],
+    ];
+    let mut psync_response = String::new();
+    for payload in payloads {
+        let payload = Value::Array(payload.into_iter().map(Value::BulkString).collect());
+        master_writer.write_all(&payload.serialize()).await?;
+        psync_response = match master_reader.read_value().await? {
+            Some((Value::SimpleString(response), _)) => response,
+            Some((Value::SimpleError(e), _)) => return Err(anyhow::anyhow!("Master replied error: {}", e)),
+            Some((value, _)) => return Err(anyhow::anyhow!("Unexpected reply from master: {:?}", value)),
+            None => return Err(anyhow::anyhow!("Master closed connection during handshake")),
+        };
+    }
+    //======================End handshake====================================//
+
+    let mut psync_response = psync_response.split_whitespace();
+    match psync_response.next() {
+        Some("FULLRESYNC") => {
+            let master_replid = psync_response.next().unwrap_or_default().to_string();
+            let master_offset = psync_response.next().unwrap_or("0").parse::<usize>()?;
+            replication.lock().await.set_master_sync_in_progress(true);
+            let rdb_bytes = master_reader.read_rdb_file().await;
+            replication.lock().await.set_master_sync_in_progress(false);
+            let rdb_bytes = rdb_bytes?;
+            //a snapshot we can not read is not loaded as empty, the link drops and syncs again
+            let (_, rdb_file) = parse_rdb_file(&rdb_bytes)
+                .map_err(|e| anyhow::anyhow!("Can not parse rdb file from master --- error: {}", e))?;
+            let mut storage = storage.lock().await;
+            storage.flush();
+            storage.load_rdb_file(&rdb_file)?;
+
+            //sub replicas hold data from our old history, make them sync again
+            let mut replication = replication.lock().await;
+            replication.disconnect_replicas().await;
+            replication.set_master_replid(&master_replid)?;
+            replication.set_master_repl_offset(master_offset)?;
+            replication.reset_backlog();
+        }
+        Some("CONTINUE") => {
+            if let Some(master_replid) = psync_response.next() {
+                replication.lock().await.shift_replid(master_replid);
+            }
+        }
+        _ => return Err(anyhow::anyhow!("Unexpected PSYNC reply from master")),
+    }
+
+    let mut replication = replication.lock().await;
+    replication.set_master_link_up(true)?;
+    replication.touch_master_io();
+    Ok((master_reader, master_writer))
+}
+
+//Apply every command master sends, only REPLCONF GETACK gets a reply
+//and the stream is forwarded as is to our own replicas
+async fn stream_from_master(
+    mut master_reader: RespReader,
+    mut master_writer: WriteHalf<TcpStream>,
+    mut rdb_argument: Argument,
+    context: &ServerContext,
+) -> Result<()> {
+    let replication = &context.replication;
+    let mut transaction = Transaction::new();
+    //commands from master never block, they replay pops that already happened
+    transaction.set_executing(true);
+    let mut client = Client::new(String::new(), None);
+    let mut ack_interval = interval(ACK_INTERVAL);
+    loop {
+        let (value, raw_value) = tokio::select! {
+            value = master_reader.read_raw_value() => match value? {
+                Some(value) => value,
+                None => return Ok(()),
+            },
+            _ = ack_interval.tick() => {
+                send_ack(&mut master_writer, replication.clone()).await?;
+                continue;
+            }
+        };
+        replication.lock().await.touch_master_io();
+        let (command, command_content) = extract_command(value)?;
+
+        let is_getack = command.to_uppercase() == "REPLCONF"
+            && command_content
+                .first()
+                .map(|arg| unwrap_value_to_string(arg).unwrap_or_default().to_uppercase() == "GETACK")
+                .unwrap_or(false);
+        if is_getack {
+            send_ack(&mut master_writer, replication.clone()).await?;
+        } else if command.to_uppercase() == "EXEC" {
+            //master wraps a transaction's writes in MULTI/EXEC, apply them all at once
+            exec_transaction(context, &mut rdb_argument, &mut transaction, &mut client, false).await;
+        } else {
+            command_handler(
+                command,
+                command_content,
+                context,
+                &mut rdb_argument,
+                &mut transaction,
+                &mut client,
+            )
+            .await;
+        }
+
+        //advance our offset and forward to sub replicas
+        replication.lock().await.propagate_raw(&raw_value).await?;
+    }
+}
+
+async fn send_ack(
+    master_writer: &mut WriteHalf<TcpStream>,
+    replication: Arc<Mutex<Replication>>,
+) -> Result<()> {
+    let offset = replication.lock().await.get_master_repl_offset()?;
+    let payload = Value::Array(vec![
+        Value::BulkString("REPLCONF".to_string()),
+        Value::BulkString("ACK".to_string()),
+        Value::BulkString(offset.to_string()),
+    ]);
+    master_writer.write_all(&payload.serialize()).await?;
+    Ok(())
+}
@@ -1,3 +1,6 @@
 pub mod argument;
+pub mod listpack;
+pub mod master_link;
 pub mod parse_rdb;
-pub mod replication;
\ No newline at end of file
+pub mod replication;
+pub mod write_rdb;
\ No newline at end of file
@@ -2,14 +2,15 @@ use std::{collections::HashMap};
 
 use chrono::{DateTime, TimeZone, Utc};
 use nom::{
-    branch::alt,
-    bytes::complete::{tag, take_while_m_n},
+    bytes::complete::{tag, take, take_while_m_n},
     combinator::{peek},
-    multi::{many0},
-    number::complete::{le_u16, le_u32, le_u64, le_u8},
+    multi::{count},
+    number::complete::{be_u32, be_u64, le_i16, le_i32, le_f64, le_i64, le_u16, le_u32, le_u64, le_u8},
     IResult, Parser,
 };
 
+use crate::rdb::listpack::parse_listpack;
+
 #[derive(Debug, Clone)]
 pub struct Entry(pub String, pub Option<DateTime<Utc>>);
 impl<T: Into<String>> From<T> for Entry {
@@ -31,29 +32,165 @@ impl Entry {
         self
     }
 }
+//A set key, intset tells which of the two set encodings it is saved with
+#[derive(Debug, Clone)]
+pub struct SetEntry {
+    pub members: Vec<String>,
+    pub intset: bool,
+    pub expiry: Option<DateTime<Utc>>,
+}
+//A string that is not utf-8, e.g. a bitmap or a HyperLogLog, kept as raw bytes
+#[derive(Debug, Clone)]
+pub struct BinaryEntry {
+    pub bytes: Vec<u8>,
+    pub expiry: Option<DateTime<Utc>>,
+}
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListEntry {
+    pub values: Vec<String>,
+    pub expiry: Option<DateTime<Utc>>,
+}
+//Field, value and the field's own expire time (HEXPIRE)
+pub type HashField = (String, String, Option<DateTime<Utc>>);
+#[derive(Debug, Clone, PartialEq)]
+pub struct HashEntry {
+    pub fields: Vec<HashField>,
+    pub expiry: Option<DateTime<Utc>>,
+}
+//Members in score order, geo sets are sorted sets too
+#[derive(Debug, Clone, PartialEq)]
+pub struct SortedSetEntry {
+    pub members: Vec<(String, f64)>,
+    pub expiry: Option<DateTime<Utc>>,
+}
+//Stream entry id with its field/value pairs
+pub type StreamRecord = (String, Vec<(String, String)>);
+//Stream entries in id order
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamEntry {
+    pub entries: Vec<StreamRecord>,
+}
 #[derive(Debug, Clone)]
 pub struct RdbFile {
     pub map: HashMap<String, Entry>,
+    pub sets: HashMap<String, SetEntry>,
+    pub binaries: HashMap<String, BinaryEntry>,
+    pub lists: HashMap<String, ListEntry>,
+    pub hashes: HashMap<String, HashEntry>,
+    pub sorted_sets: HashMap<String, SortedSetEntry>,
+    pub streams: HashMap<String, StreamEntry>,
 }
 impl RdbFile{
     pub fn new() -> Self{
-        RdbFile { map: HashMap::new() }
+        RdbFile {
+            map: HashMap::new(),
+            sets: HashMap::new(),
+            binaries: HashMap::new(),
+            lists: HashMap::new(),
+            hashes: HashMap::new(),
+            sorted_sets: HashMap::new(),
+            streams: HashMap::new(),
+        }
+    }
+    //Keys and keys with an expire time, for the resize db opcode
+    pub fn sizes(&self) -> (usize, usize) {
+        let expiries = [
+            self.map.values().map(|entry| entry.1).collect::<Vec<_>>(),
+            self.sets.values().map(|entry| entry.expiry).collect(),
+            self.binaries.values().map(|entry| entry.expiry).collect(),
+            self.lists.values().map(|entry| entry.expiry).collect(),
+            self.hashes.values().map(|entry| entry.expiry).collect(),
+            self.sorted_sets.values().map(|entry| entry.expiry).collect(),
+            self.streams.values().map(|_| None).collect(),
+        ]
+        .concat();
+        (expiries.len(), expiries.iter().filter(|expiry| expiry.is_some()).count())
+    }
+    fn insert(&mut self, key: String, object: RdbObject, expiry: Option<DateTime<Utc>>) {
+        match object {
+            RdbObject::String(value) => {
+                self.map.insert(key, Entry::from(value).with_exp(expiry));
+            }
+            RdbObject::Set(members, intset) => {
+                self.sets.insert(key, SetEntry { members, intset, expiry });
+            }
+            RdbObject::Binary(bytes) => {
+                self.binaries.insert(key, BinaryEntry { bytes, expiry });
+            }
+            RdbObject::List(values) => {
+                self.lists.insert(key, ListEntry { values, expiry });
+            }
+            RdbObject::Hash(fields) => {
+                self.hashes.insert(key, HashEntry { fields, expiry });
+            }
+            RdbObject::SortedSet(members) => {
+                self.sorted_sets.insert(key, SortedSetEntry { members, expiry });
+            }
+            //streams have no ttl here
+            RdbObject::Stream(entries) => {
+                self.streams.insert(key, StreamEntry { entries });
+            }
+        }
     }
 }
-pub fn parse_rdb_file(input: &[u8]) -> IResult<&[u8], RdbFile>{
-    let (input, _) = (tag(&b"REDIS"[..]), take_while_m_n(4, 4, |c: u8| c.is_ascii_digit())).parse(input)?;
-    let (input, _) = many0(alt((parse_metadata_section, parse_database_section))).parse(input)?;
+enum RdbObject {
+    String(String),
+    Set(Vec<String>, bool),
+    Binary(Vec<u8>),
+    List(Vec<String>),
+    Hash(Vec<HashField>),
+    SortedSet(Vec<(String, f64)>),
+    Stream(Vec<StreamRecord>),
+}
 
-    // let (input, (key, val)) = parse_key_value(input)?;
-    // let mut map = HashMap::new();
-    // map.insert(key, val);
-    let (input, collecs) = many0(parse_key_value).parse(input)?;
-    let map = collecs.iter().map(|(key, entry)|{
-        return (key.to_owned(), entry.to_owned());
-    }).collect::<HashMap<String, Entry>>();
+//Value type bytes, the numbering redis uses
+pub const RDB_TYPE_STRING: u8 = 0;
+pub const RDB_TYPE_LIST: u8 = 1;
+pub const RDB_TYPE_SET: u8 = 2;
+const RDB_TYPE_ZSET: u8 = 3;
+pub const RDB_TYPE_HASH: u8 = 4;
+pub const RDB_TYPE_ZSET_2: u8 = 5;
+pub const RDB_TYPE_SET_INTSET: u8 = 11;
+pub const RDB_TYPE_STREAM_LISTPACKS: u8 = 15;
+const RDB_TYPE_HASH_LISTPACK: u8 = 16;
+const RDB_TYPE_ZSET_LISTPACK: u8 = 17;
+const RDB_TYPE_LIST_QUICKLIST_2: u8 = 18;
+const RDB_TYPE_STREAM_LISTPACKS_2: u8 = 19;
+const RDB_TYPE_SET_LISTPACK: u8 = 20;
+const RDB_TYPE_STREAM_LISTPACKS_3: u8 = 21;
+//hashes with field expire times, since redis 7.4
+pub const RDB_TYPE_HASH_METADATA: u8 = 24;
+const RDB_TYPE_HASH_LISTPACK_EX: u8 = 25;
 
-    Ok((input, RdbFile { map }))
+//Quicklist node that holds one big element instead of a listpack
+const QUICKLIST_NODE_CONTAINER_PLAIN: usize = 1;
+//Stream entry flags
+pub const STREAM_ITEM_FLAG_DELETED: i64 = 1;
+pub const STREAM_ITEM_FLAG_SAMEFIELDS: i64 = 2;
 
+//Opcodes in front of a record
+const RDB_OPCODE_AUX: u8 = 0xFA;
+const RDB_OPCODE_SELECTDB: u8 = 0xFE;
+const RDB_OPCODE_EOF: u8 = 0xFF;
+
+//A record the parser can not read is an error, the rest of the file is never skipped
+pub fn parse_rdb_file(input: &[u8]) -> IResult<&[u8], RdbFile>{
+    let (mut input, _) = (tag(&b"REDIS"[..]), take_while_m_n(4, 4, |c: u8| c.is_ascii_digit())).parse(input)?;
+    let mut rdb_file = RdbFile::new();
+    loop {
+        match input.first() {
+            //the checksum after EOF is not verified
+            None | Some(&RDB_OPCODE_EOF) => break,
+            Some(&RDB_OPCODE_AUX) => (input, _) = parse_metadata_section(input)?,
+            Some(&RDB_OPCODE_SELECTDB) => (input, _) = parse_database_section(input)?,
+            Some(_) => {
+                let (rest, (key, object, expiry)) = parse_key_value(input)?;
+                rdb_file.insert(key, object, expiry);
+                input = rest;
+            }
+        }
+    }
+    Ok((input, rdb_file))
 }
 fn parse_metadata_section(input: &[u8]) -> IResult<&[u8], ()> {
     let (input, _) = tag(&b"\xFA"[..])(input)?;
@@ -91,12 +228,268 @@ fn parse_expiry(input: &[u8]) -> IResult<&[u8], Option<DateTime<Utc>>>{
     }
    }
 }
-fn parse_key_value(input: &[u8]) -> IResult<&[u8], (String, Entry)> {
+//Key, value and expire time of one record
+type KeyValue = (String, RdbObject, Option<DateTime<Utc>>);
+fn parse_key_value(input: &[u8]) -> IResult<&[u8], KeyValue> {
     let (input, expiry) = parse_expiry(input)?;
-    let (input, _) = tag(&b"\x00"[..])(input)?; // expect string type
-    let (input, key) = parse_string(input)?;
-    let (input, value) = parse_string(input)?;
-    Ok((input, (key, Entry::from(value).with_exp(expiry))))
+    let (type_input, value_type) = le_u8(input)?;
+    let (input, key) = parse_string(type_input)?;
+    let (input, object) = match value_type {
+        RDB_TYPE_STRING => match parse_raw_string(input) {
+            Ok((input, bytes)) if std::str::from_utf8(bytes).is_err() => (input, RdbObject::Binary(bytes.to_vec())),
+            _ => {
+                let (input, value) = parse_string(input)?;
+                (input, RdbObject::String(value))
+            }
+        },
+        RDB_TYPE_LIST => {
+            let (input, length) = parse_length(input)?;
+            let (input, values) = count(parse_string, length).parse(input)?;
+            (input, RdbObject::List(values))
+        }
+        RDB_TYPE_LIST_QUICKLIST_2 => {
+            let (input, values) = parse_quicklist(input)?;
+            (input, RdbObject::List(values))
+        }
+        RDB_TYPE_SET => {
+            let (input, length) = parse_length(input)?;
+            let (input, members) = count(parse_string, length).parse(input)?;
+            (input, RdbObject::Set(members, false))
+        }
+        RDB_TYPE_SET_INTSET => {
+            let (input, members) = parse_intset(input)?;
+            (input, RdbObject::Set(members, true))
+        }
+        RDB_TYPE_SET_LISTPACK => {
+            let (input, members) = parse_listpack_string(input)?;
+            (input, RdbObject::Set(members, false))
+        }
+        RDB_TYPE_ZSET | RDB_TYPE_ZSET_2 => {
+            let (input, length) = parse_length(input)?;
+            let score = if value_type == RDB_TYPE_ZSET { parse_string_score } else { parse_binary_score };
+            let (input, members) = count((parse_string, score), length).parse(input)?;
+            (input, RdbObject::SortedSet(members))
+        }
+        RDB_TYPE_ZSET_LISTPACK => {
+            let (input, elements) = parse_listpack_string(input)?;
+            let members = elements
+                .chunks_exact(2)
+                .map(|pair| Some((pair[0].clone(), pair[1].parse::<f64>().ok()?)))
+                .collect::<Option<Vec<(String, f64)>>>()
+                .ok_or_else(|| nom::Err::Failure(nom::error::Error::new(input, nom::error::ErrorKind::Float)))?;
+            (input, RdbObject::SortedSet(members))
+        }
+        RDB_TYPE_HASH => {
+            let (input, length) = parse_length(input)?;
+            let (input, pairs) = count((parse_string, parse_string), length).parse(input)?;
+            let fields = pairs.into_iter().map(|(field, value)| (field, value, None)).collect();
+            (input, RdbObject::Hash(fields))
+        }
+        RDB_TYPE_HASH_LISTPACK => {
+            let (input, elements) = parse_listpack_string(input)?;
+            let fields = elements
+                .chunks_exact(2)
+                .map(|pair| (pair[0].clone(), pair[1].clone(), None))
+                .collect();
+            (input, RdbObject::Hash(fields))
+        }
+        RDB_TYPE_HASH_METADATA => {
+            let (input, fields) = parse_hash_metadata(input)?;
+            (input, RdbObject::Hash(fields))
+        }
+        RDB_TYPE_HASH_LISTPACK_EX => {
+            //the smallest field ttl comes first, the listpack holds absolute ones
+            let (input, (_min_expire, elements)) = (le_u64, parse_listpack_string).parse(input)?;
+            let fields = elements
+                .chunks_exact(3)
+                .map(|field| {
+                    let expire = field[2].parse::<i64>().ok().filter(|expire| *expire > 0);
+                    (field[0].clone(), field[1].clone(), expire.and_then(DateTime::from_timestamp_millis))
+                })
+                .collect();
+            (input, RdbObject::Hash(fields))
+        }
+        RDB_TYPE_STREAM_LISTPACKS | RDB_TYPE_STREAM_LISTPACKS_2 | RDB_TYPE_STREAM_LISTPACKS_3 => {
+            let (input, entries) = parse_stream(input, value_type)?;
+            (input, RdbObject::Stream(entries))
+        }
+        //point at the type byte, that is what can not be read
+        _ => {
+            return Err(nom::Err::Failure(nom::error::Error::new(
+                type_input,
+                nom::error::ErrorKind::Switch,
+            )))
+        }
+    };
+    Ok((input, (key, object, expiry)))
+}
+//A listpack saved as one string
+fn parse_listpack_string(input: &[u8]) -> IResult<&[u8], Vec<String>> {
+    let (input, blob) = parse_raw_string(input)?;
+    let (_, elements) = parse_listpack(blob)?;
+    Ok((input, elements))
+}
+//Score as a double in 8 bytes, little endian
+fn parse_binary_score(input: &[u8]) -> IResult<&[u8], f64> {
+    le_f64(input)
+}
+//Score as text after a one byte length, with three lengths kept for nan and the infinities
+fn parse_string_score(input: &[u8]) -> IResult<&[u8], f64> {
+    let (input, length) = le_u8(input)?;
+    match length {
+        253 => Ok((input, f64::NAN)),
+        254 => Ok((input, f64::INFINITY)),
+        255 => Ok((input, f64::NEG_INFINITY)),
+        _ => {
+            let (rest, score) = take(length)(input)?;
+            match std::str::from_utf8(score).ok().and_then(|score| score.parse::<f64>().ok()) {
+                Some(score) => Ok((rest, score)),
+                None => Err(nom::Err::Failure(nom::error::Error::new(input, nom::error::ErrorKind::Float))),
+            }
+        }
+    }
+}
+//Smallest field ttl, then every field's ttl relative to it (0 when it has none), field and value
+fn parse_hash_metadata(input: &[u8]) -> IResult<&[u8], Vec<HashField>> {
+    let (input, min_expire) = le_u64(input)?;
+    let (mut input, length) = parse_length(input)?;
+    let mut fields = Vec::new();
+    for _ in 0..length {
+        let (rest, (ttl, field, value)) = (parse_length, parse_string, parse_string).parse(input)?;
+        let expire = match ttl {
+            0 => None,
+            ttl => DateTime::from_timestamp_millis((min_expire + ttl as u64 - 1) as i64),
+        };
+        fields.push((field, value, expire));
+        input = rest;
+    }
+    Ok((input, fields))
+}
+//Quicklist nodes: container kind, then a listpack or a single plain element
+fn parse_quicklist(input: &[u8]) -> IResult<&[u8], Vec<String>> {
+    let (mut input, nodes) = parse_length(input)?;
+    let mut values = Vec::new();
+    for _ in 0..nodes {
+        let (rest, container) = parse_length(input)?;
+        let (rest, node) = if container == QUICKLIST_NODE_CONTAINER_PLAIN {
+            parse_string.map(|value| vec![value]).parse(rest)?
+        } else {
+            parse_listpack_string(rest)?
+        };
+        values.extend(node);
+        input = rest;
+    }
+    Ok((input, values))
+}
+//Stream id saved as 16 raw bytes, milliseconds then sequence, big endian
+fn parse_raw_stream_id(input: &[u8]) -> IResult<&[u8], (u64, u64)> {
+    (be_u64, be_u64).parse(input)
+}
+fn parse_stream_element(elements: &[String], index: &mut usize) -> Option<i64> {
+    let value = elements.get(*index)?.parse::<i64>().ok()?;
+    *index += 1;
+    Some(value)
+}
+//Listpack nodes keyed by their master id, then the stream metadata and consumer groups.
+//Groups are read past, the store has none
+fn parse_stream(input: &[u8], value_type: u8) -> IResult<&[u8], Vec<StreamRecord>> {
+    let fail = |input| nom::Err::Failure(nom::error::Error::new(input, nom::error::ErrorKind::Verify));
+    let (mut input, nodes) = parse_length(input)?;
+    let mut entries = Vec::new();
+    for _ in 0..nodes {
+        let (rest, master_id) = parse_raw_string(input)?;
+        let (_, (master_ms, master_seq)) = parse_raw_stream_id(master_id)?;
+        let (rest, elements) = parse_listpack_string(rest)?;
+        //master entry: count, deleted, master field count, master fields, 0
+        let mut index = 2;
+        let master_fields_count = parse_stream_element(&elements, &mut index).ok_or_else(|| fail(input))? as usize;
+        let master_fields = elements
+            .get(index..index + master_fields_count)
+            .ok_or_else(|| fail(input))?
+            .to_vec();
+        index += master_fields_count + 1;
+        while index < elements.len() {
+            let flags = parse_stream_element(&elements, &mut index).ok_or_else(|| fail(input))?;
+            let ms = parse_stream_element(&elements, &mut index).ok_or_else(|| fail(input))?;
+            let seq = parse_stream_element(&elements, &mut index).ok_or_else(|| fail(input))?;
+            let fields = if flags & STREAM_ITEM_FLAG_SAMEFIELDS != 0 {
+                master_fields.clone()
+            } else {
+                let fields_count = parse_stream_element(&elements, &mut index).ok_or_else(|| fail(input))? as usize;
+                (0..fields_count)
+                    .map(|field| elements.get(index + field * 2).cloned().ok_or_else(|| fail(input)))
+                    .collect::<Result<Vec<String>, _>>()?
+            };
+            let mut pairs = Vec::new();
+            for field in fields.iter() {
+                if flags & STREAM_ITEM_FLAG_SAMEFIELDS == 0 {
+                    index += 1;
+                }
+                pairs.push((field.clone(), elements.get(index).cloned().ok_or_else(|| fail(input))?));
+                index += 1;
+            }
+            //lp-count closes every entry
+            index += 1;
+            if flags & STREAM_ITEM_FLAG_DELETED == 0 {
+                let id = format!("{}-{}", master_ms.wrapping_add(ms as u64), master_seq.wrapping_add(seq as u64));
+                entries.push((id, pairs));
+            }
+        }
+        input = rest;
+    }
+    //length and last id
+    let (mut input, _) = count(parse_length, 3).parse(input)?;
+    if value_type >= RDB_TYPE_STREAM_LISTPACKS_2 {
+        //first id, max deleted id and entries added
+        (input, _) = count(parse_length, 5).parse(input)?;
+    }
+    let (mut input, groups) = parse_length(input)?;
+    for _ in 0..groups {
+        let (rest, _name) = parse_raw_string(input)?;
+        let (mut rest, _last_id) = count(parse_length, 2).parse(rest)?;
+        if value_type >= RDB_TYPE_STREAM_LISTPACKS_2 {
+            (rest, _) = parse_length(rest)?;
+        }
+        //pending entries: id, delivery time and delivery count
+        let (mut rest, pending) = parse_length(rest)?;
+        for _ in 0..pending {
+            (rest, _) = (take(16usize), le_u64, parse_length).parse(rest)?;
+        }
+        let (mut rest, consumers) = parse_length(rest)?;
+        for _ in 0..consumers {
+            (rest, _) = (parse_raw_string, le_u64).parse(rest)?;
+            if value_type >= RDB_TYPE_STREAM_LISTPACKS_3 {
+                (rest, _) = le_u64(rest)?;
+            }
+            let (next, pending) = parse_length(rest)?;
+            (rest, _) = take(16 * pending)(next)?;
+        }
+        input = rest;
+    }
+    Ok((input, entries))
+}
+//Intset blob: encoding (bytes per value), length, then the sorted values, all little endian
+fn parse_intset(input: &[u8]) -> IResult<&[u8], Vec<String>> {
+    let (input, blob) = parse_raw_string(input)?;
+    let (blob, encoding) = le_u32(blob)?;
+    let (mut blob, length) = le_u32(blob)?;
+    let mut members = Vec::new();
+    for _ in 0..length {
+        let (rest, value) = match encoding {
+            2 => le_i16.map(i64::from).parse(blob)?,
+            4 => le_i32.map(i64::from).parse(blob)?,
+            8 => le_i64(blob)?,
+            _ => {
+                return Err(nom::Err::Error(nom::error::Error::new(
+                    blob,
+                    nom::error::ErrorKind::Fail,
+                )))
+            }
+        };
+        members.push(value.to_string());
+        blob = rest;
+    }
+    Ok((input, members))
 }
 fn parse_string(input: &[u8]) -> IResult<&[u8], String> {
     let (input, first_byte) = peek(le_u8).parse(input)?;
@@ -121,10 +514,13 @@ fn parse_string(input: &[u8]) -> IResult<&[u8], String> {
             ))),
         };
     }
-    let (input, len) = parse_length(input)?;
-    let (input, s) = nom::bytes::complete::take(len).parse(input)?;
+    let (input, s) = parse_raw_string(input)?;
     Ok((input, String::from_utf8_lossy(s).to_string()))
 }
+fn parse_raw_string(input: &[u8]) -> IResult<&[u8], &[u8]> {
+    let (input, len) = parse_length(input)?;
+    nom::bytes::complete::take(len).parse(input)
+}
 fn parse_length(input: &[u8]) -> IResult<&[u8], usize> {
     let (input, first_byte) = le_u8(input)?;
     let first_byte = first_byte as usize;
@@ -134,8 +530,13 @@ fn parse_length(input: &[u8]) -> IResult<&[u8], usize> {
             let (input, second_byte) = le_u8(input)?;
             Ok((input, ((first_byte & 0b111111) << 8) | second_byte as usize))
         }
+        0b10 if first_byte == 0x81 => {
+            let (input, next_eight_byte) = be_u64(input)?;
+            Ok((input, next_eight_byte as usize))
+        }
         0b10 => {
-            let (input, next_four_byte) = le_u32(input)?;
+            //32 bits length is stored in big endian
+            let (input, next_four_byte) = be_u32(input)?;
             Ok((input, next_four_byte as usize))
         }
         _ => Err(nom::Err::Error(nom::error::Error::new(
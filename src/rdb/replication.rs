@@ -1,16 +1,77 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::collections::VecDeque;
 use std::sync::{Arc};
+use std::time::Instant;
 use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
 use tokio::{sync::Mutex};
 use anyhow::Result;
-use tokio::io::WriteHalf;
+use tokio::io::{AsyncWriteExt, WriteHalf};
 
-use crate::{resp::{value::Value}};
+use crate::{
+    command_handler::command_handler::is_write_command,
+    rdb::write_rdb::write_rdb_file,
+    resp::value::Value,
+    store::store::Store,
+};
+
+//Commands a replica still serves while the link with its master is down
+//and replica-serve-stale-data is set to no
+const STALE_COMMANDS: [&str; 7] = ["INFO", "REPLICAOF", "SLAVEOF", "PING", "CONFIG", "REPLCONF", "PSYNC"];
+const DEFAULT_BACKLOG_SIZE: usize = 1024 * 1024;
+const DEFAULT_MIN_REPLICAS_MAX_LAG: u64 = 10;
+
+//A replica connected to this node
+pub struct ReplicaHandler {
+    client_id: u64,
+    ip: String,
+    listening_port: usize,
+    ack_offset: usize,
+    //None until the first REPLCONF ACK, such a replica is not good yet
+    last_ack: Option<Instant>,
+    writer: Arc<Mutex<WriteHalf<TcpStream>>>,
+}
+impl ReplicaHandler {
+    pub fn new(
+        client_id: u64,
+        ip: String,
+        listening_port: usize,
+        writer: Arc<Mutex<WriteHalf<TcpStream>>>,
+    ) -> Self {
+        ReplicaHandler {
+            client_id,
+            ip,
+            listening_port,
+            ack_offset: 0,
+            last_ack: None,
+            writer,
+        }
+    }
+}
 
 pub struct Replication{
     role: Role,
     master_replid: String,
     master_repl_offset: usize,
-    pub replication_handlers: Vec<Arc<Mutex<WriteHalf<TcpStream>>>>
+    //previous history, replicas of our old master can still partially resync up to second_repl_offset
+    master_replid2: String,
+    second_repl_offset: i64,
+    master_endpoint: Option<(String, usize)>,
+    master_link_up: bool,
+    master_last_io: Option<Instant>,
+    master_link_down_since: Option<Instant>,
+    master_sync_in_progress: bool,
+    master_link: Option<JoinHandle<()>>,
+    serve_stale_data: bool,
+    //bytes of the replication stream kept for partial resync
+    backlog: VecDeque<u8>,
+    backlog_size: usize,
+    backlog_active: bool,
+    //master refuses writes when fewer replicas acked within max lag seconds
+    min_replicas_to_write: usize,
+    min_replicas_max_lag: u64,
+    replication_handlers: Vec<ReplicaHandler>
 }
 #[derive(Clone, PartialEq, Eq)]
 pub enum Role {
@@ -30,8 +91,23 @@ impl Replication  {
     pub fn new() -> Self {
         Replication {
             role: Role::Master,
-            master_replid: String::from("8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb"), //random alphanumeric 40 characters
+            //a new history on every start, PSYNC must not continue one from a previous run
+            master_replid: generate_replid(),
             master_repl_offset: 0,
+            master_replid2: "0".repeat(40),
+            second_repl_offset: -1,
+            master_endpoint: None,
+            master_link_up: false,
+            master_last_io: None,
+            master_link_down_since: None,
+            master_sync_in_progress: false,
+            master_link: None,
+            serve_stale_data: true,
+            backlog: VecDeque::new(),
+            backlog_size: DEFAULT_BACKLOG_SIZE,
+            backlog_active: false,
+            min_replicas_to_write: 0,
+            min_replicas_max_lag: DEFAULT_MIN_REPLICAS_MAX_LAG,
             replication_handlers: Vec::new()
         }
     }
@@ -39,23 +115,314 @@ impl Replication  {
         self.role = role;
         Ok(())
     }
-    // pub fn get_role(&self) -> Result<Role> {
-    //     Ok(self.role.clone())
-    // }
+    pub fn get_role(&self) -> Result<Role> {
+        Ok(self.role.clone())
+    }
     pub fn display_to_value(&self) -> Result<Value> {
-        Ok(Value::BulkString(format!(
-            "role:{}\r\nmaster_replid:{}\r\nmaster_repl_offset:{}",
-            self.role.to_string(),
-            self.master_replid,
-            self.master_repl_offset
-        )))
+        let mut info = vec![
+            "# Replication".to_string(),
+            format!("role:{}", self.role.to_string()),
+        ];
+        if let (Role::Slave, Some((master_address, master_port))) = (&self.role, &self.master_endpoint) {
+            let last_io = match (self.master_link_up, self.master_last_io) {
+                (true, Some(last_io)) => last_io.elapsed().as_secs() as i64,
+                _ => -1,
+            };
+            info.push(format!("master_host:{}", master_address));
+            info.push(format!("master_port:{}", master_port));
+            info.push(format!(
+                "master_link_status:{}",
+                if self.master_link_up { "up" } else { "down" }
+            ));
+            info.push(format!("master_last_io_seconds_ago:{}", last_io));
+            info.push(format!("master_sync_in_progress:{}", self.master_sync_in_progress as u8));
+            info.push(format!("slave_read_repl_offset:{}", self.master_repl_offset));
+            info.push(format!("slave_repl_offset:{}", self.master_repl_offset));
+            if let Some(down_since) = self.master_link_down_since {
+                info.push(format!(
+                    "master_link_down_since_seconds:{}",
+                    down_since.elapsed().as_secs()
+                ));
+            }
+            info.push("slave_priority:100".to_string());
+            info.push("slave_read_only:1".to_string());
+            info.push("replica_announced:1".to_string());
+        }
+        info.push(format!("connected_slaves:{}", self.replication_handlers.len()));
+        for (index, replica) in self.replication_handlers.iter().enumerate() {
+            info.push(format!(
+                "slave{}:ip={},port={},state=online,offset={},lag={}",
+                index,
+                replica.ip,
+                replica.listening_port,
+                replica.ack_offset,
+                replica.last_ack.map_or(-1, |last_ack| last_ack.elapsed().as_secs() as i64)
+            ));
+        }
+        if self.min_replicas_to_write > 0 {
+            info.push(format!("min_slaves_good_slaves:{}", self.count_good_replicas()));
+        }
+        info.push(format!("master_replid:{}", self.master_replid));
+        info.push(format!("master_replid2:{}", self.master_replid2));
+        info.push(format!("master_repl_offset:{}", self.master_repl_offset));
+        info.push(format!("second_repl_offset:{}", self.second_repl_offset));
+        info.push(format!("repl_backlog_active:{}", self.backlog_active as u8));
+        info.push(format!("repl_backlog_size:{}", self.backlog_size));
+        info.push(format!(
+            "repl_backlog_first_byte_offset:{}",
+            self.get_backlog_first_byte_offset()
+        ));
+        info.push(format!("repl_backlog_histlen:{}", self.backlog.len()));
+        Ok(Value::BulkString(info.join("\r\n")))
     }
     pub fn get_master_replid(&self) -> Result<String> {
         Ok(self.master_replid.clone())
     }
-    pub fn add_repl_handler(&mut self, handler: Arc<Mutex<WriteHalf<TcpStream>>>) -> Result<()>{
+    pub fn set_master_replid(&mut self, master_replid: &str) -> Result<()> {
+        self.master_replid = master_replid.to_string();
+        Ok(())
+    }
+    //Master changed its replid but our history is still valid until current offset
+    pub fn shift_replid(&mut self, master_replid: &str) {
+        if self.master_replid == master_replid {
+            return;
+        }
+        self.master_replid2 = std::mem::replace(&mut self.master_replid, master_replid.to_string());
+        self.second_repl_offset = self.master_repl_offset as i64 + 1;
+    }
+    pub fn get_master_repl_offset(&self) -> Result<usize> {
+        Ok(self.master_repl_offset)
+    }
+    pub fn set_master_repl_offset(&mut self, offset: usize) -> Result<()> {
+        self.master_repl_offset = offset;
+        Ok(())
+    }
+    pub fn get_master_endpoint(&self) -> Option<(String, usize)> {
+        self.master_endpoint.clone()
+    }
+    pub fn set_master_endpoint(&mut self, address: String, port: usize) -> Result<()> {
+        self.master_endpoint = Some((address, port));
+        Ok(())
+    }
+    pub fn set_serve_stale_data(&mut self, serve_stale_data: bool) -> Result<()> {
+        self.serve_stale_data = serve_stale_data;
+        Ok(())
+    }
+    pub fn get_serve_stale_data(&self) -> bool {
+        self.serve_stale_data
+    }
+    pub fn set_min_replicas_to_write(&mut self, min_replicas_to_write: usize) -> Result<()> {
+        self.min_replicas_to_write = min_replicas_to_write;
+        Ok(())
+    }
+    pub fn get_min_replicas_to_write(&self) -> usize {
+        self.min_replicas_to_write
+    }
+    pub fn set_min_replicas_max_lag(&mut self, min_replicas_max_lag: u64) -> Result<()> {
+        self.min_replicas_max_lag = min_replicas_max_lag;
+        Ok(())
+    }
+    pub fn get_min_replicas_max_lag(&self) -> u64 {
+        self.min_replicas_max_lag
+    }
+    //Replicas whose last ACK is not older than min-replicas-max-lag
+    fn count_good_replicas(&self) -> usize {
+        self.replication_handlers
+            .iter()
+            .filter(|replica| {
+                replica
+                    .last_ack
+                    .is_some_and(|last_ack| last_ack.elapsed().as_secs() <= self.min_replicas_max_lag)
+            })
+            .count()
+    }
+    pub fn set_master_link_up(&mut self, up: bool) -> Result<()> {
+        if up {
+            self.master_link_down_since = None;
+        } else if self.master_link_up || self.master_link_down_since.is_none() {
+            self.master_link_down_since = Some(Instant::now());
+        }
+        self.master_link_up = up;
+        Ok(())
+    }
+    pub fn set_master_sync_in_progress(&mut self, in_progress: bool) {
+        self.master_sync_in_progress = in_progress;
+    }
+    //Called every time some bytes come from the master
+    pub fn touch_master_io(&mut self) {
+        self.master_last_io = Some(Instant::now());
+    }
+    //Replace the task that talks with master, the old one is stopped
+    pub fn set_master_link(&mut self, master_link: Option<JoinHandle<()>>) {
+        if let Some(old_link) = self.master_link.take() {
+            old_link.abort();
+        }
+        self.master_link = master_link;
+    }
+    //Turn this replica into a master with a new replication history
+    pub fn promote_to_master(&mut self) -> Result<()> {
+        self.set_master_link(None);
+        self.role = Role::Master;
+        self.master_endpoint = None;
+        self.master_link_up = false;
+        self.master_last_io = None;
+        self.master_link_down_since = None;
+        self.master_sync_in_progress = false;
+        self.shift_replid(&generate_replid());
+        Ok(())
+    }
+    //Error to reply when a client command is not allowed on this node right now
+    pub fn check_client_command(&self, command: &str) -> Option<Value> {
+        if self.role != Role::Slave {
+            if self.min_replicas_to_write > 0
+                && is_write_command(command)
+                && self.count_good_replicas() < self.min_replicas_to_write
+            {
+                return Some(Value::SimpleError(
+                    "NOREPLICAS Not enough good replicas to write.".to_string(),
+                ));
+            }
+            return None;
+        }
+        if !self.master_link_up && !self.serve_stale_data && !STALE_COMMANDS.contains(&command) {
+            return Some(Value::SimpleError(
+                "MASTERDOWN Link with MASTER is down and replica-serve-stale-data is set to 'no'."
+                    .to_string(),
+            ));
+        }
+        if is_write_command(command) {
+            return Some(Value::SimpleError(
+                "READONLY You can't write against a read only replica.".to_string(),
+            ));
+        }
+        None
+    }
+    pub fn add_repl_handler(&mut self, handler: ReplicaHandler) -> Result<()>{
         // println!("Say hi from add_repl_handler --- handler: {:#?}", handler);
+        self.backlog_active = true;
         self.replication_handlers.push(handler);
         Ok(())
     }
+    pub fn remove_repl_handler(&mut self, client_id: u64) {
+        self.replication_handlers
+            .retain(|replica| replica.client_id != client_id);
+    }
+    //Replica told us how much of the stream it has processed
+    pub fn record_ack(&mut self, client_id: u64, offset: usize) {
+        if let Some(replica) = self
+            .replication_handlers
+            .iter_mut()
+            .find(|replica| replica.client_id == client_id)
+        {
+            replica.ack_offset = offset;
+            replica.last_ack = Some(Instant::now());
+        }
+    }
+    fn get_backlog_first_byte_offset(&self) -> usize {
+        if self.backlog.is_empty() {
+            0
+        } else {
+            self.master_repl_offset - self.backlog.len() + 1
+        }
+    }
+    fn feed_backlog(&mut self, payload: &[u8]) {
+        if !self.backlog_active {
+            return;
+        }
+        self.backlog.extend(payload);
+        if self.backlog.len() > self.backlog_size {
+            let overflow = self.backlog.len() - self.backlog_size;
+            self.backlog.drain(..overflow);
+        }
+    }
+    pub fn has_backlog(&self) -> bool {
+        self.backlog_active
+    }
+    //Start a new history after a full sync with our master
+    pub fn reset_backlog(&mut self) {
+        self.backlog.clear();
+        self.backlog_active = true;
+    }
+    fn can_partial_sync(&self, psync_replid: &str, psync_offset: i64) -> bool {
+        if !self.backlog_active || psync_offset < 1 {
+            return false;
+        }
+        let same_history = psync_replid == self.master_replid
+            || (psync_replid == self.master_replid2 && psync_offset <= self.second_repl_offset);
+        let backlog_start = self.master_repl_offset + 1 - self.backlog.len();
+        same_history
+            && psync_offset as usize >= backlog_start
+            && psync_offset as usize <= self.master_repl_offset + 1
+    }
+    //Answer PSYNC with +CONTINUE and the missing backlog, or +FULLRESYNC and a snapshot,
+    //then start streaming to the replica
+    pub async fn sync_replica(
+        &mut self,
+        psync_replid: &str,
+        psync_offset: i64,
+        storage: &Store,
+        handler: ReplicaHandler,
+    ) -> Result<()> {
+        let payload = if self.can_partial_sync(psync_replid, psync_offset) {
+            let backlog_start = self.master_repl_offset + 1 - self.backlog.len();
+            let mut payload = format!("+CONTINUE {}\r\n", self.master_replid).into_bytes();
+            payload.extend(self.backlog.range(psync_offset as usize - backlog_start..));
+            payload
+        } else {
+            let rdb_bytes = write_rdb_file(&storage.to_rdb_file());
+            let mut payload = format!(
+                "+FULLRESYNC {} {}\r\n${}\r\n",
+                self.master_replid,
+                self.master_repl_offset,
+                rdb_bytes.len()
+            )
+            .into_bytes();
+            payload.extend(rdb_bytes);
+            payload
+        };
+        {
+            let mut writer = handler.writer.lock().await;
+            writer.write_all(&payload).await?;
+            writer.flush().await?;
+        }
+        self.add_repl_handler(handler)
+    }
+    //Close every replica connection so they sync again with our new history
+    pub async fn disconnect_replicas(&mut self) {
+        for replica in self.replication_handlers.drain(..) {
+            let _ = replica.writer.lock().await.shutdown().await;
+        }
+    }
+    //Send a write command to every replica, replicas that can not be reached are dropped
+    pub async fn propagate(&mut self, command: &Value) -> Result<()> {
+        self.propagate_raw(&command.serialize()).await
+    }
+    //Bytes are forwarded untouched so sub replicas share our replid and offset
+    pub async fn propagate_raw(&mut self, payload: &[u8]) -> Result<()> {
+        self.master_repl_offset += payload.len();
+        self.feed_backlog(payload);
+
+        let mut alive_handlers = Vec::new();
+        for replica in self.replication_handlers.drain(..) {
+            let mut writer = replica.writer.lock().await;
+            if writer.write_all(payload).await.is_ok() && writer.flush().await.is_ok() {
+                drop(writer);
+                alive_handlers.push(replica);
+            }
+        }
+        self.replication_handlers = alive_handlers;
+        Ok(())
+    }
+}
+
+//Random 40 characters hex string
+pub fn generate_replid() -> String {
+    let mut replid = String::new();
+    while replid.len() < 40 {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default() as u128);
+        replid += &format!("{:016x}", hasher.finish());
+    }
+    replid.truncate(40);
+    replid
 }
@@ -0,0 +1,322 @@
+use chrono::{DateTime, Utc};
+
+use crate::rdb::{
+    listpack::{write_listpack, ListpackElement},
+    parse_rdb::{
+        HashField, RdbFile, RDB_TYPE_HASH, RDB_TYPE_HASH_METADATA, RDB_TYPE_LIST, RDB_TYPE_SET, RDB_TYPE_SET_INTSET, RDB_TYPE_STREAM_LISTPACKS,
+        RDB_TYPE_STRING, RDB_TYPE_ZSET_2, StreamRecord,
+    },
+};
+
+//Serialize keys to rdb version 11, checksum is left as 0 which means disabled
+pub fn write_rdb_file(rdb_file: &RdbFile) -> Vec<u8> {
+    //version 12 has the hash field expire types
+    let mut output = b"REDIS0012".to_vec();
+
+    output.push(0xFE);
+    write_length(&mut output, 0);
+    output.push(0xFB);
+    let (keys, expires) = rdb_file.sizes();
+    write_length(&mut output, keys);
+    write_length(&mut output, expires);
+
+    for (key, entry) in rdb_file.map.iter() {
+        write_record_header(&mut output, key, RDB_TYPE_STRING, entry.1);
+        write_string(&mut output, &entry.0);
+    }
+    for (key, entry) in rdb_file.sets.iter() {
+        if entry.intset {
+            write_record_header(&mut output, key, RDB_TYPE_SET_INTSET, entry.expiry);
+            write_intset(&mut output, &entry.members);
+        } else {
+            write_record_header(&mut output, key, RDB_TYPE_SET, entry.expiry);
+            write_length(&mut output, entry.members.len());
+            for member in entry.members.iter() {
+                write_string(&mut output, member);
+            }
+        }
+    }
+
+    for (key, entry) in rdb_file.binaries.iter() {
+        write_record_header(&mut output, key, RDB_TYPE_STRING, entry.expiry);
+        write_bytes(&mut output, &entry.bytes);
+    }
+    for (key, entry) in rdb_file.lists.iter() {
+        write_record_header(&mut output, key, RDB_TYPE_LIST, entry.expiry);
+        write_length(&mut output, entry.values.len());
+        for value in entry.values.iter() {
+            write_string(&mut output, value);
+        }
+    }
+    for (key, entry) in rdb_file.hashes.iter() {
+        let min_expire = entry.fields.iter().filter_map(|(_, _, expire)| *expire).min();
+        match min_expire {
+            Some(min_expire) => {
+                write_record_header(&mut output, key, RDB_TYPE_HASH_METADATA, entry.expiry);
+                write_hash_metadata(&mut output, &entry.fields, min_expire.timestamp_millis());
+            }
+            None => {
+                write_record_header(&mut output, key, RDB_TYPE_HASH, entry.expiry);
+                write_length(&mut output, entry.fields.len());
+                for (field, value, _) in entry.fields.iter() {
+                    write_string(&mut output, field);
+                    write_string(&mut output, value);
+                }
+            }
+        }
+    }
+    for (key, entry) in rdb_file.sorted_sets.iter() {
+        write_record_header(&mut output, key, RDB_TYPE_ZSET_2, entry.expiry);
+        write_length(&mut output, entry.members.len());
+        for (member, score) in entry.members.iter() {
+            write_string(&mut output, member);
+            output.extend(score.to_le_bytes());
+        }
+    }
+    for (key, entry) in rdb_file.streams.iter() {
+        write_record_header(&mut output, key, RDB_TYPE_STREAM_LISTPACKS, None);
+        write_stream(&mut output, &entry.entries);
+    }
+
+    output.push(0xFF);
+    output.extend([0u8; 8]);
+    output
+}
+
+//Expire time if any, value type and key
+fn write_record_header(output: &mut Vec<u8>, key: &str, value_type: u8, expiry: Option<DateTime<Utc>>) {
+    if let Some(exp) = expiry {
+        output.push(0xFC);
+        output.extend((exp.timestamp_millis() as u64).to_le_bytes());
+    }
+    output.push(value_type);
+    write_string(output, key);
+}
+
+fn write_string(output: &mut Vec<u8>, value: &str) {
+    write_bytes(output, value.as_bytes());
+}
+fn write_bytes(output: &mut Vec<u8>, value: &[u8]) {
+    write_length(output, value.len());
+    output.extend(value);
+}
+
+//Sorted values with the smallest width that fits all of them, stored as one string
+fn write_intset(output: &mut Vec<u8>, members: &[String]) {
+    let mut values = members
+        .iter()
+        .filter_map(|member| member.parse::<i64>().ok())
+        .collect::<Vec<i64>>();
+    values.sort_unstable();
+    let encoding: usize = if values.iter().all(|value| i16::try_from(*value).is_ok()) {
+        2
+    } else if values.iter().all(|value| i32::try_from(*value).is_ok()) {
+        4
+    } else {
+        8
+    };
+    let mut blob = (encoding as u32).to_le_bytes().to_vec();
+    blob.extend((values.len() as u32).to_le_bytes());
+    for value in values {
+        blob.extend(&value.to_le_bytes()[..encoding]);
+    }
+    write_length(output, blob.len());
+    output.extend(blob);
+}
+
+//Field ttls are saved relative to the smallest one, 0 is a field without ttl
+fn write_hash_metadata(output: &mut Vec<u8>, fields: &[HashField], min_expire: i64) {
+    output.extend((min_expire as u64).to_le_bytes());
+    write_length(output, fields.len());
+    for (field, value, expire) in fields.iter() {
+        let ttl = expire.map_or(0, |expire| (expire.timestamp_millis() - min_expire) as usize + 1);
+        write_length(output, ttl);
+        write_string(output, field);
+        write_string(output, value);
+    }
+}
+//Stream ids split into milliseconds and sequence
+fn split_stream_id(id: &str) -> (u64, u64) {
+    let (ms, seq) = id.split_once('-').unwrap_or((id, "0"));
+    (ms.parse().unwrap_or_default(), seq.parse().unwrap_or_default())
+}
+//One listpack node holding every entry, each entry with its own fields, no consumer groups
+fn write_stream(output: &mut Vec<u8>, entries: &[StreamRecord]) {
+    let ids = entries.iter().map(|(id, _)| split_stream_id(id)).collect::<Vec<(u64, u64)>>();
+    if let Some(&(master_ms, master_seq)) = ids.first() {
+        write_length(output, 1);
+        let mut master_id = master_ms.to_be_bytes().to_vec();
+        master_id.extend(master_seq.to_be_bytes());
+        write_bytes(output, &master_id);
+        //count, deleted, no master fields, master entry terminator
+        let mut elements = [entries.len() as i64, 0, 0, 0]
+            .map(ListpackElement::Integer)
+            .into_iter()
+            .collect::<Vec<ListpackElement>>();
+        for ((_, fields), (ms, seq)) in entries.iter().zip(ids.iter()) {
+            elements.push(ListpackElement::Integer(0));
+            elements.push(ListpackElement::Integer((ms - master_ms) as i64));
+            elements.push(ListpackElement::Integer(seq.wrapping_sub(master_seq) as i64));
+            elements.push(ListpackElement::Integer(fields.len() as i64));
+            for (field, value) in fields.iter() {
+                elements.push(ListpackElement::String(field.as_bytes()));
+                elements.push(ListpackElement::String(value.as_bytes()));
+            }
+            //lp-count: flags, ids, field count and the pairs
+            elements.push(ListpackElement::Integer(fields.len() as i64 * 2 + 4));
+        }
+        write_bytes(output, &write_listpack(&elements));
+    } else {
+        write_length(output, 0);
+    }
+    let (last_ms, last_seq) = ids.last().copied().unwrap_or((0, 0));
+    write_length(output, entries.len());
+    write_length(output, last_ms as usize);
+    write_length(output, last_seq as usize);
+    //consumer groups
+    write_length(output, 0);
+}
+
+fn write_length(output: &mut Vec<u8>, length: usize) {
+    if length < 1 << 6 {
+        output.push(length as u8);
+    } else if length < 1 << 14 {
+        output.push(0b0100_0000 | (length >> 8) as u8);
+        output.push(length as u8);
+    } else if length <= u32::MAX as usize {
+        output.push(0b1000_0000);
+        output.extend((length as u32).to_be_bytes());
+    } else {
+        output.push(0b1000_0001);
+        output.extend((length as u64).to_be_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rdb::parse_rdb::{parse_rdb_file, HashEntry, ListEntry, SortedSetEntry, StreamEntry};
+
+    fn round_trip(rdb_file: &RdbFile) -> RdbFile {
+        let bytes = write_rdb_file(rdb_file);
+        let (rest, parsed) = parse_rdb_file(&bytes).expect("rdb file should parse back");
+        //EOF and the 8 checksum bytes are left
+        assert_eq!(rest.len(), 9);
+        parsed
+    }
+
+    #[test]
+    fn list_round_trip() {
+        let mut rdb_file = RdbFile::new();
+        let expiry = DateTime::from_timestamp_millis(4_102_444_800_000);
+        let values = (0..100).map(|index| format!("item-{}", index)).collect::<Vec<String>>();
+        rdb_file.lists.insert("list".to_string(), ListEntry { values, expiry });
+        rdb_file.lists.insert(
+            "short".to_string(),
+            ListEntry {
+                values: vec!["a".to_string(), "".to_string(), "c".repeat(20000)],
+                expiry: None,
+            },
+        );
+        let parsed = round_trip(&rdb_file);
+        assert_eq!(parsed.lists, rdb_file.lists);
+        assert_eq!(parsed.sizes(), (2, 1));
+    }
+
+    #[test]
+    fn hash_round_trip() {
+        let mut rdb_file = RdbFile::new();
+        let expire = |ms| DateTime::from_timestamp_millis(ms);
+        rdb_file.hashes.insert(
+            "plain".to_string(),
+            HashEntry {
+                fields: vec![
+                    ("a".to_string(), "1".to_string(), None),
+                    ("b".to_string(), "x".repeat(100), None),
+                ],
+                expiry: expire(4_102_444_800_000),
+            },
+        );
+        rdb_file.hashes.insert(
+            "field-ttl".to_string(),
+            HashEntry {
+                fields: vec![
+                    ("a".to_string(), "1".to_string(), expire(4_102_444_800_500)),
+                    ("b".to_string(), "2".to_string(), None),
+                    ("c".to_string(), "3".to_string(), expire(4_102_444_800_000)),
+                ],
+                expiry: None,
+            },
+        );
+        let parsed = round_trip(&rdb_file);
+        assert_eq!(parsed.hashes, rdb_file.hashes);
+    }
+
+    #[test]
+    fn sorted_set_round_trip() {
+        let mut rdb_file = RdbFile::new();
+        rdb_file.sorted_sets.insert(
+            "zset".to_string(),
+            SortedSetEntry {
+                members: vec![
+                    ("low".to_string(), f64::NEG_INFINITY),
+                    ("a".to_string(), -1.5),
+                    ("b".to_string(), 0.1),
+                    ("high".to_string(), f64::INFINITY),
+                ],
+                expiry: None,
+            },
+        );
+        //a geo set, scores are 52 bit geohashes
+        rdb_file.sorted_sets.insert(
+            "geo".to_string(),
+            SortedSetEntry {
+                members: vec![
+                    ("Palermo".to_string(), 3479099956230698.0),
+                    ("Catania".to_string(), 3479447370796909.0),
+                ],
+                expiry: DateTime::from_timestamp_millis(4_102_444_800_000),
+            },
+        );
+        let parsed = round_trip(&rdb_file);
+        assert_eq!(parsed.sorted_sets, rdb_file.sorted_sets);
+    }
+
+    #[test]
+    fn stream_round_trip() {
+        let mut rdb_file = RdbFile::new();
+        let entries = vec![
+            ("1526919030474-55".to_string(), vec![("temperature".to_string(), "36".to_string())]),
+            (
+                "1526919030474-56".to_string(),
+                vec![("a".to_string(), "1".to_string()), ("b".to_string(), "x".repeat(300))],
+            ),
+            //sequence lower than the master entry, stored as a negative delta
+            ("1526919030999-0".to_string(), vec![("c".to_string(), "-70000".to_string())]),
+            ("18446744073709551615-3".to_string(), vec![("d".to_string(), "".to_string())]),
+        ];
+        rdb_file.streams.insert("stream".to_string(), StreamEntry { entries });
+        rdb_file.streams.insert("empty".to_string(), StreamEntry { entries: Vec::new() });
+        let parsed = round_trip(&rdb_file);
+        assert_eq!(parsed.streams, rdb_file.streams);
+    }
+
+    #[test]
+    fn unknown_type_is_an_error() {
+        let mut rdb_file = RdbFile::new();
+        rdb_file.lists.insert(
+            "list".to_string(),
+            ListEntry {
+                values: vec!["a".to_string()],
+                expiry: None,
+            },
+        );
+        let mut bytes = write_rdb_file(&rdb_file);
+        let type_position = bytes
+            .iter()
+            .position(|byte| *byte == RDB_TYPE_LIST)
+            .expect("list type byte");
+        bytes[type_position] = 0x7F;
+        assert!(parse_rdb_file(&bytes).is_err());
+    }
+}
@@ -1,96 +1,107 @@
 use crate::resp::value::Value;
 use anyhow::Result;
+use bytes::{Buf, BytesMut};
 use std::sync::Arc;
-use std::usize;
 use tokio::sync::Mutex;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf},
+    io::{split, AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf},
     net::TcpStream,
 };
-// #[derive(Debug)]
-// pub struct RespHandler {
-//     pub stream: Arc<Mutex<TcpStream>>,
-//     pub buffer: [u8; 1024],
-// }
 
-// impl RespHandler {
-//     pub fn new(stream: Arc<Mutex<TcpStream>>) -> Self {
-//         RespHandler {
-//             stream,
-//             buffer: [0; 1024],
-//         }
-//     }
-//     pub async fn read_value(&mut self) -> Result<Option<Value>> {
-//         self.buffer = [0;1024];
-
-//         let mut stream = self.stream.lock().await;
-//         let (mut reader, _) = stream.split();
-//         let read_size: usize = reader.read(&mut self.buffer).await?;
-//         println!("LOG_FROM_read_value: {}", String::from_utf8_lossy(&self.buffer));
-
-//         if read_size == 0 {
-//             return Ok(None);
-//         }
+pub struct RespReader {
+    reader: ReadHalf<TcpStream>,
+    buffer: BytesMut,
+}
 
-//         match parse_payload(&self.buffer[..read_size]) {
-//             Ok((value, _)) => Ok(Some(value)),
-//             Err(e) => Err(anyhow::anyhow!(
-//                 "Got Error when parse value from stream: {}",
-//                 e
-//             )),
-//         }
-//     }
-//     pub async fn write_value(&mut self, payload: String) {
-//         // println!("LOG_FROM_write_value -- payload: {}", payload);
-//         let mut stream = self.stream.lock().await;
-//         let (_, mut writer) = stream.split();
-//         match writer.write_all(payload.as_bytes()).await{
-//             Ok(_) => {
-//                 writer.flush().await.expect("Failed to flush stream");
-//             }
-//             Err(e) => {
-//                 println!("Failed to send response : {}", e);
-//             }
-//         }
-//     }
-//     pub async fn read_without_parse(&mut self) -> Result<([u8; 1024], usize)>{
-//         self.buffer = [0;1024];
-//         let mut stream = self.stream.lock().await;
-//         let (mut reader, _) = stream.split();
-//         let read_size: usize = reader.read(&mut self.buffer).await.unwrap();
-//         // println!("LOG_FROM_read_withou_parse: buffer: {:?}", &self.buffer[..read_size]);
-//         Ok((self.buffer, read_size))
-//     }
-// }
+impl RespReader {
+    pub fn new(reader: ReadHalf<TcpStream>) -> Self {
+        RespReader {
+            reader,
+            buffer: BytesMut::with_capacity(1024),
+        }
+    }
+    //Return the next value together with the number of bytes it took on the wire
+    pub async fn read_value(&mut self) -> Result<Option<(Value, usize)>> {
+        loop {
+            if let Some((value, size)) = parse_payload(&self.buffer)? {
+                self.buffer.advance(size);
+                return Ok(Some((value, size)));
+            }
+            if self.reader.read_buf(&mut self.buffer).await? == 0 {
+                return Ok(None);
+            }
+        }
+    }
+    //Resolves once the peer closed the connection, bytes sent meanwhile stay buffered for read_value
+    pub async fn closed(&mut self) {
+        loop {
+            match self.reader.read_buf(&mut self.buffer).await {
+                Ok(0) | Err(_) => return,
+                Ok(_) => {}
+            }
+        }
+    }
+    //Same as read_value but keep the exact bytes, used to forward the replication stream
+    pub async fn read_raw_value(&mut self) -> Result<Option<(Value, Vec<u8>)>> {
+        loop {
+            if let Some((value, size)) = parse_payload(&self.buffer)? {
+                return Ok(Some((value, self.buffer.split_to(size).to_vec())));
+            }
+            if self.reader.read_buf(&mut self.buffer).await? == 0 {
+                return Ok(None);
+            }
+        }
+    }
+    //Rdb file after FULLRESYNC is sent as $<len>\r\n<bytes> without trailing \r\n
+    pub async fn read_rdb_file(&mut self) -> Result<Vec<u8>> {
+        loop {
+            if let Some((header, header_size)) = read_until_crlf(&self.buffer) {
+                if header.first() != Some(&b'$') {
+                    return Err(anyhow::anyhow!(
+                        "Invalid rdb header: {}",
+                        bytes_to_string(header)
+                    ));
+                }
+                let rdb_size = String::from_utf8_lossy(&header[1..]).parse::<usize>()?;
+                if self.buffer.len() >= header_size + rdb_size {
+                    self.buffer.advance(header_size);
+                    return Ok(self.buffer.split_to(rdb_size).to_vec());
+                }
+            }
+            if self.reader.read_buf(&mut self.buffer).await? == 0 {
+                return Err(anyhow::anyhow!("Connection closed while reading rdb file"));
+            }
+        }
+    }
+}
 
-pub async fn read_without_parse(reader: &mut ReadHalf<TcpStream>) -> Result<([u8; 1024], usize)> {
-    let mut buffer: [u8; 1024] = [0; 1024];
-    let read_size: usize = reader.read(&mut buffer).await.unwrap();
-    Ok((buffer, read_size))
+//Open a connection, send one command and wait for its reply
+pub async fn send_command(address: &str, port: usize, command: &[String]) -> Result<Value> {
+    let mut replies = send_commands(address, port, &[command.to_vec()]).await?;
+    Ok(replies.remove(0))
 }
-pub async fn read_value(reader: &mut ReadHalf<TcpStream>) -> Result<Option<Value>> {
-    let mut buffer: [u8; 1024] = [0; 1024];
-    let read_size: usize = reader.read(&mut buffer).await?;
-    // println!(
-    //     "LOG_FROM_read_value buff:{}",
-    //     String::from_utf8_lossy(&buffer[..read_size])
-    // );
-    if read_size == 0 {
-        return Ok(None);
-    }
 
-    match parse_payload(&buffer[..read_size]) {
-        Ok((value, _)) => Ok(Some(value)),
-        Err(e) => Err(anyhow::anyhow!(
-            "Got Error when parse value from stream: {}",
-            e
-        )),
+//Send commands one after another on the same connection, e.g. ASKING then RESTORE
+pub async fn send_commands(address: &str, port: usize, commands: &[Vec<String>]) -> Result<Vec<Value>> {
+    let stream = TcpStream::connect(format!("{}:{}", address, port)).await?;
+    let (reader, mut writer) = split(stream);
+    let mut reader = RespReader::new(reader);
+    let mut replies = Vec::new();
+    for command in commands {
+        let payload = Value::Array(command.iter().cloned().map(Value::BulkString).collect());
+        writer.write_all(&payload.serialize()).await?;
+        match reader.read_value().await? {
+            Some((value, _)) => replies.push(value),
+            None => return Err(anyhow::anyhow!("Connection closed before reply")),
+        }
     }
+    Ok(replies)
 }
-pub async fn write_value(writer: Arc<Mutex<WriteHalf<TcpStream>>>, payload: String) {
+
+pub async fn write_value(writer: Arc<Mutex<WriteHalf<TcpStream>>>, payload: Vec<u8>) {
     // println!("LOG_FROM_write_value -- payload: {}", payload);
     let mut writer = writer.lock().await;
-    match writer.write_all(payload.as_bytes()).await {
+    match writer.write_all(&payload).await {
         Ok(_) => {
             writer.flush().await.expect("Failed to flush stream");
         }
@@ -100,86 +111,71 @@ pub async fn write_value(writer: Arc<Mutex<WriteHalf<TcpStream>>>, payload: Stri
     }
 }
 
-pub fn parse_payload(payload: &[u8]) -> Result<(Value, usize)> {
+//Return None when payload does not contain a complete value yet
+pub fn parse_payload(payload: &[u8]) -> Result<Option<(Value, usize)>> {
+    if payload.is_empty() {
+        return Ok(None);
+    }
     match payload[0] as char {
-        '+'  => parse_simple_string(payload),
-        '-'  => parse_simple_string(payload),
-        ':'  => parse_simple_string(payload),
+        '+' => parse_simple(payload, Value::SimpleString),
+        '-' => parse_simple(payload, Value::SimpleError),
+        ':' => parse_simple(payload, Value::SimpleInterger),
         '$' => parse_bulk_string(payload),
         '*' => parse_array(payload),
         _ => Err(anyhow::anyhow!(
             "Invalid sign in string given: {}",
-            bytes_to_string(&payload)
+            bytes_to_string(payload)
         )),
     }
 }
 
-fn parse_simple_string(payload: &[u8]) -> Result<(Value, usize)> {
-    if let Some((buffer, buff_size)) = read_until_crlf(&payload[1..]) {
-        return Ok((
-            Value::SimpleString(String::from_utf8_lossy(buffer).trim().to_string()),
-            buff_size,
-        ));
-    }
-    Err(anyhow::anyhow!(
-        "Invalid when parse simple string: {:#?}",
-        payload
-    ))
+fn parse_simple(payload: &[u8], to_value: fn(String) -> Value) -> Result<Option<(Value, usize)>> {
+    match read_until_crlf(&payload[1..]) {
+        Some((buffer, buff_size)) => Ok(Some((to_value(bytes_to_string(buffer)), buff_size + 1))),
+        None => Ok(None),
+    }
 }
 
-fn parse_bulk_string(payload: &[u8]) -> Result<(Value, usize)> {
+fn parse_bulk_string(payload: &[u8]) -> Result<Option<(Value, usize)>> {
     //$4\r\nPING\r\n
-    // println!("LOG_FROM parse_bulk_string --- payload: {}", bytes_to_string(payload));
-    if let Some((payload_size, _)) = read_until_crlf(&payload[1..]) {
-        //Size of bulk string
-        let payload_size: usize = String::from_utf8_lossy(payload_size)
-            .parse::<usize>()
-            .unwrap();
-
-        let format_prev_bulk_string: String = format!("${}\r\n", payload_size);
-
-        if let Some((buffer, _)) = read_until_crlf(&payload[format_prev_bulk_string.len()..]) {
-            return Ok((
-                Value::BulkString(String::from_utf8_lossy(buffer).trim().to_string()),
-                payload_size,
-            ));
-        }
+    let (payload_size, header_size) = match read_until_crlf(&payload[1..]) {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+    let payload_size = String::from_utf8_lossy(payload_size).parse::<i64>()?;
+    let start = header_size + 1;
+    if payload_size < 0 {
+        return Ok(Some((Value::NullBulkString, start)));
     }
-    Err(anyhow::anyhow!(
-        "Invalid when parse simple string: {:#?}",
-        payload
-    ))
+    let end = start + payload_size as usize;
+    if payload.len() < end + 2 {
+        return Ok(None);
+    }
+    let value = match String::from_utf8(payload[start..end].to_vec()) {
+        Ok(value) => Value::BulkString(value),
+        Err(e) => Value::BulkBytes(e.into_bytes()),
+    };
+    Ok(Some((value, end + 2)))
 }
 
-fn parse_array(payload: &[u8]) -> Result<(Value, usize)> {
-    let (array_size, last) = read_until_crlf(&payload[1..]).unwrap();
-    let array_size: usize = String::from_utf8_lossy(array_size)
-        .trim()
-        .to_string()
-        .parse::<usize>()
-        .unwrap();
-    let mut array_parsed: Vec<Value> = Vec::new();
-    let mut start = last + 1;
+fn parse_array(payload: &[u8]) -> Result<Option<(Value, usize)>> {
+    let (array_size, header_size) = match read_until_crlf(&payload[1..]) {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+    let array_size = String::from_utf8_lossy(array_size).parse::<usize>()?;
+    let mut array_parsed: Vec<Value> = Vec::with_capacity(array_size);
+    let mut start = header_size + 1;
     for _ in 0..array_size {
-        match parse_payload(&payload[start..]) {
-            Ok((buffer, buf_size)) => {
-                let format_prev_bulk_string = format!(
-                    "${}\r\n{}\r\n",
-                    buf_size,
-                    unwrap_value_to_string(&buffer).unwrap()
-                );
-                // println!("LOG_FROM_parse_array --- format: {}", format_prev_bulk_string);
-                start += format_prev_bulk_string.len();
-                array_parsed.push(buffer);
+        match parse_payload(&payload[start..])? {
+            Some((value, size)) => {
+                start += size;
+                array_parsed.push(value);
             }
-            Err(e) => return Err(anyhow::anyhow!("Got error when parse inside array: {}", e)),
+            None => return Ok(None),
         }
     }
-    // if array_parsed.len() == array_size {
-    return Ok((Value::Array(array_parsed), array_size));
-    // }
-
-    // Err(anyhow::anyhow!("Mismatch array lenght: {:#?}", payload))
+    Ok(Some((Value::Array(array_parsed), start)))
 }
 
 //Read until \r\n
@@ -211,12 +207,21 @@ pub fn unwrap_value_to_string(value: &Value) -> Result<String> {
     match value {
         Value::BulkString(value) => Ok(value.to_owned()),
         Value::SimpleString(value) => Ok(value.to_owned()),
+        Value::BulkBytes(value) => Ok(bytes_to_string(value)),
         _ => Err(anyhow::anyhow!(
             "Got error unwrap_value_to_string type mismatch"
         )),
     }
 }
 
+//Bytes of an argument, binary values come through untouched
+pub fn unwrap_value_to_bytes(value: &Value) -> Result<Vec<u8>> {
+    match value {
+        Value::BulkBytes(value) => Ok(value.to_owned()),
+        _ => Ok(unwrap_value_to_string(value)?.into_bytes()),
+    }
+}
+
 fn bytes_to_string(buff: &[u8]) -> String {
     String::from_utf8_lossy(buff).to_string()
 }
@@ -4,22 +4,35 @@ pub enum Value {
     SimpleError(String),
     SimpleInterger(String),
     BulkString(String),
+    //Bulk string that is not utf-8, e.g. a bitmap or a HyperLogLog
+    BulkBytes(Vec<u8>),
     Array(Vec<Value>),
     NullBulkString,
+    NullArray,
+    //Nothing is written back, e.g. REPLCONF ACK
+    NoReply,
 }
 impl Value {
-    pub fn serialize(&self) -> String {
+    pub fn serialize(&self) -> Vec<u8> {
         match self {
-            Value::SimpleString(s) => format!("+{}\r\n", s),
-            Value::SimpleError(s) => format!("-{}\r\n", s),
-            Value::SimpleInterger(s) => format!(":{}\r\n", s),
-            Value::BulkString(s) => format!("${}\r\n{}\r\n", s.len(), s),
-            Value::NullBulkString => String::from("$-1\r\n"),
+            Value::SimpleString(s) => format!("+{}\r\n", s).into_bytes(),
+            Value::SimpleError(s) => format!("-{}\r\n", s).into_bytes(),
+            Value::SimpleInterger(s) => format!(":{}\r\n", s).into_bytes(),
+            Value::BulkString(s) => format!("${}\r\n{}\r\n", s.len(), s).into_bytes(),
+            Value::BulkBytes(b) => {
+                let mut result = format!("${}\r\n", b.len()).into_bytes();
+                result.extend(b);
+                result.extend(b"\r\n");
+                result
+            }
+            Value::NullBulkString => b"$-1\r\n".to_vec(),
+            Value::NullArray => b"*-1\r\n".to_vec(),
+            Value::NoReply => Vec::new(),
             Value::Array(a) => {
-               let mut result = format!("*{}\r\n", a.len());
+               let mut result = format!("*{}\r\n", a.len()).into_bytes();
                for value in a{
                     let value = value.serialize();
-                    result += &value;
+                    result.extend(value);
                }
                result
             } 
@@ -0,0 +1,398 @@
+use anyhow::Result;
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    hash::{BuildHasher, Hasher},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::Mutex,
+    task::JoinHandle,
+    time::{sleep, timeout},
+};
+
+use crate::{
+    resp::{resp::send_command, value::Value},
+    sentinel::state::Sentinel,
+};
+
+const PING_PERIOD: Duration = Duration::from_millis(250);
+const INFO_PERIOD: Duration = Duration::from_secs(1);
+const REPLY_TIMEOUT: Duration = Duration::from_millis(500);
+//random wait before asking for votes so sentinels do not split the election
+const MAX_ELECTION_DELAY_MS: u64 = 500;
+
+pub fn spawn_monitor(name: String, sentinel: Arc<Mutex<Sentinel>>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut last_info: Option<Instant> = None;
+        loop {
+            sleep(PING_PERIOD).await;
+            if let Err(e) = monitor_once(&name, sentinel.clone(), &mut last_info).await {
+                eprintln!("Sentinel monitor of {} --- error: {}", name, e);
+            }
+        }
+    })
+}
+
+async fn request(host: &str, port: usize, command: &[&str]) -> Option<Value> {
+    let command = command.iter().map(|arg| arg.to_string()).collect::<Vec<String>>();
+    timeout(REPLY_TIMEOUT, send_command(host, port, &command))
+        .await
+        .ok()?
+        .ok()
+}
+
+async fn ping(host: &str, port: usize) -> bool {
+    matches!(request(host, port, &["PING"]).await, Some(Value::SimpleString(_)))
+}
+
+async fn info_replication(host: &str, port: usize) -> Option<HashMap<String, String>> {
+    match request(host, port, &["INFO", "replication"]).await {
+        Some(Value::BulkString(info)) => Some(
+            info.lines()
+                .filter_map(|line| line.split_once(':'))
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        ),
+        _ => None,
+    }
+}
+
+//slaveN:ip=127.0.0.1,port=6380,state=online,offset=0,lag=0
+fn parse_replica_line(line: &str) -> Option<(String, usize)> {
+    let fields = line
+        .split(',')
+        .filter_map(|field| field.split_once('='))
+        .collect::<HashMap<&str, &str>>();
+    Some((fields.get("ip")?.to_string(), fields.get("port")?.parse().ok()?))
+}
+
+fn random_delay() -> Duration {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_i64(chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default());
+    Duration::from_millis(hasher.finish() % MAX_ELECTION_DELAY_MS)
+}
+
+//One round of PING/INFO to the master and its replicas, then failure detection
+async fn monitor_once(
+    name: &str,
+    sentinel: Arc<Mutex<Sentinel>>,
+    last_info: &mut Option<Instant>,
+) -> Result<()> {
+    let (host, port, replicas) = {
+        let sentinel = sentinel.lock().await;
+        let master = sentinel.masters.get(name).unwrap();
+        (
+            master.host.clone(),
+            master.port,
+            master
+                .replicas
+                .iter()
+                .map(|replica| (replica.host.clone(), replica.port))
+                .collect::<Vec<(String, usize)>>(),
+        )
+    };
+    let refresh_info = last_info.is_none_or(|last_info| last_info.elapsed() >= INFO_PERIOD);
+    if refresh_info {
+        *last_info = Some(Instant::now());
+    }
+
+    let master_ok = ping(&host, port).await;
+    let master_info = if refresh_info && master_ok {
+        info_replication(&host, port).await
+    } else {
+        None
+    };
+    let mut replica_states = Vec::new();
+    for (replica_host, replica_port) in replicas {
+        let replica_ok = ping(&replica_host, replica_port).await;
+        let replica_info = if refresh_info && replica_ok {
+            info_replication(&replica_host, replica_port).await
+        } else {
+            None
+        };
+        replica_states.push((replica_host, replica_port, replica_ok, replica_info));
+    }
+
+    let mut stale_masters = Vec::new();
+    {
+        let mut sentinel = sentinel.lock().await;
+        let current_epoch = sentinel.get_current_epoch();
+        let master = sentinel.masters.get_mut(name).unwrap();
+        //master changed while we were asking
+        if master.host != host || master.port != port {
+            return Ok(());
+        }
+        if master_ok {
+            master.last_ok = Instant::now();
+        }
+        if let Some(info) = master_info {
+            if info.get("role").map(|role| role.as_str()) == Some("master") {
+                for (key, value) in info.iter() {
+                    if key.starts_with("slave") && key[5..].parse::<usize>().is_ok() {
+                        if let Some((replica_host, replica_port)) = parse_replica_line(value) {
+                            master.add_replica(&replica_host, replica_port);
+                        }
+                    }
+                }
+            }
+        }
+        for (replica_host, replica_port, replica_ok, replica_info) in replica_states {
+            let replica = match master
+                .replicas
+                .iter_mut()
+                .find(|replica| replica.host == replica_host && replica.port == replica_port)
+            {
+                Some(replica) => replica,
+                None => continue,
+            };
+            if replica_ok {
+                replica.last_ok = Some(Instant::now());
+            }
+            if let Some(info) = replica_info {
+                replica.reports_master = info.get("role").map(|role| role.as_str()) == Some("master");
+                if !replica.reports_master {
+                    replica.needs_reconfigure = false;
+                }
+                replica.priority = info
+                    .get("slave_priority")
+                    .and_then(|priority| priority.parse().ok())
+                    .unwrap_or(100);
+                replica.offset = info
+                    .get("slave_repl_offset")
+                    .or(info.get("master_repl_offset"))
+                    .and_then(|offset| offset.parse().ok())
+                    .unwrap_or(0);
+            }
+        }
+
+        let down_after = master.down_after;
+        let promoted = master
+            .replicas
+            .iter()
+            .find(|replica| {
+                replica.reports_master
+                    && !replica.needs_reconfigure
+                    && replica
+                        .last_ok
+                        .is_some_and(|last_ok| last_ok.elapsed() <= down_after)
+            })
+            .map(|replica| (replica.host.clone(), replica.port));
+        if let Some((promoted_host, promoted_port)) = promoted {
+            if master.is_sdown() && !master.failover_in_progress {
+                //another sentinel already promoted this replica
+                master.switch_master(&promoted_host, promoted_port, current_epoch);
+                return Ok(());
+            }
+        }
+        //an old master came back, turn it into a replica
+        if !master.is_sdown() {
+            stale_masters = master
+                .replicas
+                .iter()
+                .filter(|replica| replica.reports_master && replica.needs_reconfigure)
+                .map(|replica| (replica.host.clone(), replica.port))
+                .collect();
+        }
+    }
+    let port = port.to_string();
+    for (stale_host, stale_port) in stale_masters {
+        request(&stale_host, stale_port, &["REPLICAOF", &host, &port]).await;
+    }
+
+    check_down(name, sentinel).await
+}
+
+//Agree on objective down state with other sentinels and start a failover when needed
+async fn check_down(name: &str, sentinel: Arc<Mutex<Sentinel>>) -> Result<()> {
+    let (sdown, forced, host, port, quorum, peers) = {
+        let sentinel = sentinel.lock().await;
+        let master = sentinel.masters.get(name).unwrap();
+        (
+            master.is_sdown(),
+            master.force_failover,
+            master.host.clone(),
+            master.port,
+            master.quorum,
+            sentinel.get_peers(),
+        )
+    };
+    if !sdown && !forced {
+        sentinel.lock().await.masters.get_mut(name).unwrap().odown = false;
+        return Ok(());
+    }
+
+    let mut odown = false;
+    if sdown {
+        let port = port.to_string();
+        let mut down_votes = 1;
+        for (peer_host, peer_port) in peers.iter() {
+            let reply = request(
+                peer_host,
+                *peer_port,
+                &["SENTINEL", "is-master-down-by-addr", &host, &port, "0", "*"],
+            )
+            .await;
+            if let Some(Value::Array(reply)) = reply {
+                if reply.first() == Some(&Value::SimpleInterger("1".to_string())) {
+                    down_votes += 1;
+                }
+            }
+        }
+        odown = down_votes >= quorum;
+    }
+
+    let start_failover = {
+        let mut sentinel = sentinel.lock().await;
+        let master = sentinel.masters.get_mut(name).unwrap();
+        master.odown = odown;
+        let can_retry = master
+            .last_failover_attempt
+            .is_none_or(|last_attempt| last_attempt.elapsed() > master.failover_timeout * 2);
+        let start = (forced || (odown && can_retry)) && !master.failover_in_progress;
+        if start {
+            master.failover_in_progress = true;
+            master.last_failover_attempt = Some(Instant::now());
+        }
+        start
+    };
+    if start_failover {
+        if let Err(e) = run_failover(name, sentinel.clone(), forced).await {
+            eprintln!("Failover of {} aborted --- error: {}", name, e);
+            let mut sentinel = sentinel.lock().await;
+            let master = sentinel.masters.get_mut(name).unwrap();
+            master.failover_in_progress = false;
+            master.force_failover = false;
+        }
+    }
+    Ok(())
+}
+
+async fn run_failover(name: &str, sentinel: Arc<Mutex<Sentinel>>, forced: bool) -> Result<()> {
+    let epoch = if forced {
+        sentinel.lock().await.next_epoch()
+    } else {
+        let leader_epoch_before = sentinel.lock().await.masters.get(name).unwrap().leader_epoch;
+        sleep(random_delay()).await;
+        {
+            let sentinel = sentinel.lock().await;
+            let master = sentinel.masters.get(name).unwrap();
+            if master.leader_epoch > leader_epoch_before {
+                return Err(anyhow::anyhow!("Voted for another sentinel"));
+            }
+            if !master.is_sdown() {
+                return Err(anyhow::anyhow!("Master is reachable again"));
+            }
+        }
+        match elect_leader(name, sentinel.clone()).await {
+            Some(epoch) => epoch,
+            None => return Err(anyhow::anyhow!("Not elected as leader")),
+        }
+    };
+    promote_best_replica(name, sentinel, epoch).await
+}
+
+//Ask every peer to vote for us in a new epoch
+async fn elect_leader(name: &str, sentinel: Arc<Mutex<Sentinel>>) -> Option<u64> {
+    let (epoch, runid, peers, host, port, quorum) = {
+        let mut sentinel = sentinel.lock().await;
+        let epoch = sentinel.next_epoch();
+        let runid = sentinel.get_runid();
+        let peers = sentinel.get_peers();
+        let master = sentinel.masters.get_mut(name).unwrap();
+        master.leader = Some(runid.clone());
+        master.leader_epoch = epoch;
+        (epoch, runid, peers, master.host.clone(), master.port, master.quorum)
+    };
+    let epoch_string = epoch.to_string();
+    let port = port.to_string();
+    let mut votes = 1;
+    for (peer_host, peer_port) in peers.iter() {
+        let reply = request(
+            peer_host,
+            *peer_port,
+            &["SENTINEL", "is-master-down-by-addr", &host, &port, &epoch_string, &runid],
+        )
+        .await;
+        if let Some(Value::Array(reply)) = reply {
+            if reply.get(1) == Some(&Value::BulkString(runid.clone()))
+                && reply.get(2) == Some(&Value::SimpleInterger(epoch_string.clone()))
+            {
+                votes += 1;
+            }
+        }
+    }
+    //majority of every sentinel monitoring the master, the peers and us
+    let sentinels = peers.len() + 1;
+    let majority = sentinels / 2 + 1;
+    if votes >= quorum.max(majority) {
+        Some(epoch)
+    } else {
+        None
+    }
+}
+
+//Promote the replica with best priority then offset, and point the others to it
+async fn promote_best_replica(name: &str, sentinel: Arc<Mutex<Sentinel>>, epoch: u64) -> Result<()> {
+    let (mut candidates, failover_timeout) = {
+        let sentinel = sentinel.lock().await;
+        let master = sentinel.masters.get(name).unwrap();
+        let candidates = master
+            .replicas
+            .iter()
+            .filter(|replica| {
+                replica.priority > 0
+                    && replica
+                        .last_ok
+                        .is_some_and(|last_ok| last_ok.elapsed() <= master.down_after)
+            })
+            .map(|replica| (replica.priority, replica.offset, replica.host.clone(), replica.port))
+            .collect::<Vec<(u64, usize, String, usize)>>();
+        (candidates, master.failover_timeout)
+    };
+    candidates.sort_by(|a, b| {
+        a.0.cmp(&b.0)
+            .then(b.1.cmp(&a.1))
+            .then((&a.2, a.3).cmp(&(&b.2, b.3)))
+    });
+    let (_, _, promoted_host, promoted_port) = match candidates.first() {
+        Some(candidate) => candidate.clone(),
+        None => return Err(anyhow::anyhow!("No suitable replica to promote")),
+    };
+
+    request(&promoted_host, promoted_port, &["REPLICAOF", "NO", "ONE"]).await;
+    let started = Instant::now();
+    loop {
+        let info = info_replication(&promoted_host, promoted_port).await;
+        if info.and_then(|info| info.get("role").cloned()) == Some("master".to_string()) {
+            break;
+        }
+        if started.elapsed() > failover_timeout {
+            return Err(anyhow::anyhow!("Replica was not promoted in time"));
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    let (others, peers) = {
+        let mut sentinel = sentinel.lock().await;
+        let peers = sentinel.get_peers();
+        let master = sentinel.masters.get_mut(name).unwrap();
+        let others = master
+            .replicas
+            .iter()
+            .filter(|replica| !(replica.host == promoted_host && replica.port == promoted_port))
+            .map(|replica| (replica.host.clone(), replica.port))
+            .collect::<Vec<(String, usize)>>();
+        master.switch_master(&promoted_host, promoted_port, epoch);
+        (others, peers)
+    };
+    let port = promoted_port.to_string();
+    let epoch = epoch.to_string();
+    for (peer_host, peer_port) in peers {
+        request(&peer_host, peer_port, &["SENTINEL", "hello", name, &promoted_host, &port, &epoch]).await;
+    }
+    for (replica_host, replica_port) in others {
+        request(&replica_host, replica_port, &["REPLICAOF", &promoted_host, &port]).await;
+    }
+    Ok(())
+}
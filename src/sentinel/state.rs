@@ -0,0 +1,457 @@
+use anyhow::Result;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::split,
+    net::{TcpListener, TcpStream},
+    sync::Mutex,
+};
+
+use crate::{
+    rdb::{argument::Argument, replication::generate_replid},
+    resp::{
+        resp::{extract_command, unwrap_value_to_string, write_value, RespReader},
+        value::Value,
+    },
+    sentinel::monitor::spawn_monitor,
+};
+
+//A replica of a monitored master as seen from INFO replication
+pub struct SentinelReplica {
+    pub host: String,
+    pub port: usize,
+    pub last_ok: Option<Instant>,
+    pub priority: u64,
+    pub offset: usize,
+    //replica answered INFO with role:master
+    pub reports_master: bool,
+    //old master replaced by a failover, sent REPLICAOF once it is back
+    pub needs_reconfigure: bool,
+}
+impl SentinelReplica {
+    pub fn new(host: String, port: usize) -> Self {
+        SentinelReplica {
+            host,
+            port,
+            last_ok: None,
+            priority: 100,
+            offset: 0,
+            reports_master: false,
+            needs_reconfigure: false,
+        }
+    }
+}
+
+pub struct MonitoredMaster {
+    pub name: String,
+    pub host: String,
+    pub port: usize,
+    pub quorum: usize,
+    pub down_after: Duration,
+    pub failover_timeout: Duration,
+    pub last_ok: Instant,
+    pub odown: bool,
+    pub replicas: Vec<SentinelReplica>,
+    pub config_epoch: u64,
+    //who we voted for as failover leader and in which epoch
+    pub leader: Option<String>,
+    pub leader_epoch: u64,
+    pub failover_in_progress: bool,
+    pub last_failover_attempt: Option<Instant>,
+    pub force_failover: bool,
+}
+impl MonitoredMaster {
+    //"<name> <host> <port> <quorum>"
+    pub fn parse(monitor: &str, down_after: Duration, failover_timeout: Duration) -> Result<Self> {
+        let parts = monitor.split_whitespace().collect::<Vec<&str>>();
+        if parts.len() != 4 {
+            return Err(anyhow::anyhow!(
+                "Sentinel monitor needs <name> <host> <port> <quorum>, got {}",
+                monitor
+            ));
+        }
+        Ok(MonitoredMaster {
+            name: parts[0].to_string(),
+            host: parts[1].to_string(),
+            port: parts[2].parse::<usize>()?,
+            quorum: parts[3].parse::<usize>()?,
+            down_after,
+            failover_timeout,
+            last_ok: Instant::now(),
+            odown: false,
+            replicas: Vec::new(),
+            config_epoch: 0,
+            leader: None,
+            leader_epoch: 0,
+            failover_in_progress: false,
+            last_failover_attempt: None,
+            force_failover: false,
+        })
+    }
+    //Subjectively down: no valid reply for down-after-milliseconds
+    pub fn is_sdown(&self) -> bool {
+        self.last_ok.elapsed() > self.down_after
+    }
+    pub fn add_replica(&mut self, host: &str, port: usize) {
+        let known = self
+            .replicas
+            .iter()
+            .any(|replica| replica.host == host && replica.port == port);
+        let is_self = self.host == host && self.port == port;
+        if !known && !is_self {
+            self.replicas.push(SentinelReplica::new(host.to_string(), port));
+        }
+    }
+    //Make a replica the new master, the old master is kept as a replica to reconfigure later
+    pub fn switch_master(&mut self, host: &str, port: usize, config_epoch: u64) {
+        let old_host = std::mem::replace(&mut self.host, host.to_string());
+        let old_port = std::mem::replace(&mut self.port, port);
+        self.replicas
+            .retain(|replica| !(replica.host == host && replica.port == port));
+        self.add_replica(&old_host, old_port);
+        if let Some(old_master) = self
+            .replicas
+            .iter_mut()
+            .find(|replica| replica.host == old_host && replica.port == old_port)
+        {
+            old_master.needs_reconfigure = true;
+        }
+        self.config_epoch = config_epoch;
+        self.last_ok = Instant::now();
+        self.odown = false;
+        self.failover_in_progress = false;
+        self.force_failover = false;
+    }
+    fn flags(&self) -> String {
+        let mut flags = vec!["master"];
+        if self.is_sdown() {
+            flags.push("s_down");
+        }
+        if self.odown {
+            flags.push("o_down");
+        }
+        if self.failover_in_progress {
+            flags.push("failover_in_progress");
+        }
+        flags.join(",")
+    }
+    fn status(&self) -> &str {
+        if self.odown {
+            "odown"
+        } else if self.is_sdown() {
+            "sdown"
+        } else {
+            "ok"
+        }
+    }
+}
+
+pub struct Sentinel {
+    runid: String,
+    current_epoch: u64,
+    peers: Vec<(String, usize)>,
+    pub masters: HashMap<String, MonitoredMaster>,
+}
+impl Sentinel {
+    pub fn from_argument(rdb_argument: &Argument) -> Result<Self> {
+        let down_after = Duration::from_millis(rdb_argument.get_down_after_milliseconds()?);
+        let failover_timeout = Duration::from_millis(rdb_argument.get_failover_timeout()?);
+        let mut masters = HashMap::new();
+        for monitor in rdb_argument.get_sentinel_monitors()? {
+            let master = MonitoredMaster::parse(&monitor, down_after, failover_timeout)?;
+            masters.insert(master.name.clone(), master);
+        }
+        let mut peers = Vec::new();
+        for peer in rdb_argument.get_sentinel_peers()? {
+            let mut peer = peer.split_whitespace();
+            match (peer.next(), peer.next().map(|port| port.parse::<usize>())) {
+                (Some(host), Some(Ok(port))) => peers.push((host.to_string(), port)),
+                _ => return Err(anyhow::anyhow!("Sentinel peer needs <host> <port>")),
+            }
+        }
+        Ok(Sentinel {
+            runid: generate_replid(),
+            current_epoch: 0,
+            peers,
+            masters,
+        })
+    }
+    pub fn get_runid(&self) -> String {
+        self.runid.clone()
+    }
+    pub fn get_peers(&self) -> Vec<(String, usize)> {
+        self.peers.clone()
+    }
+    pub fn get_current_epoch(&self) -> u64 {
+        self.current_epoch
+    }
+    //Start a new epoch to run an election in
+    pub fn next_epoch(&mut self) -> u64 {
+        self.current_epoch += 1;
+        self.current_epoch
+    }
+    //Reply to SENTINEL is-master-down-by-addr, voting for runid when it is not *
+    fn vote(&mut self, host: &str, port: usize, epoch: u64, runid: &str) -> Value {
+        if epoch > self.current_epoch {
+            self.current_epoch = epoch;
+        }
+        let own_runid = self.runid.clone();
+        let master = match self
+            .masters
+            .values_mut()
+            .find(|master| master.host == host && master.port == port)
+        {
+            Some(master) => master,
+            None => {
+                return Value::Array(vec![
+                    Value::SimpleInterger("0".to_string()),
+                    Value::BulkString("*".to_string()),
+                    Value::SimpleInterger("0".to_string()),
+                ])
+            }
+        };
+        if runid != "*" && master.leader_epoch < epoch {
+            master.leader = Some(runid.to_string());
+            master.leader_epoch = epoch;
+            //give the leader time to finish before trying ourselves
+            if runid != own_runid {
+                master.last_failover_attempt = Some(Instant::now());
+            }
+        }
+        let (leader, leader_epoch) = if runid == "*" {
+            ("*".to_string(), 0)
+        } else {
+            (
+                master.leader.clone().unwrap_or("*".to_string()),
+                master.leader_epoch,
+            )
+        };
+        Value::Array(vec![
+            Value::SimpleInterger((master.is_sdown() as u8).to_string()),
+            Value::BulkString(leader),
+            Value::SimpleInterger(leader_epoch.to_string()),
+        ])
+    }
+    fn display_master(&self, master: &MonitoredMaster) -> Value {
+        let fields = vec![
+            ("name", master.name.clone()),
+            ("ip", master.host.clone()),
+            ("port", master.port.to_string()),
+            ("runid", String::new()),
+            ("flags", master.flags()),
+            ("num-slaves", master.replicas.len().to_string()),
+            ("num-other-sentinels", self.peers.len().to_string()),
+            ("quorum", master.quorum.to_string()),
+            ("down-after-milliseconds", master.down_after.as_millis().to_string()),
+            ("failover-timeout", master.failover_timeout.as_millis().to_string()),
+            ("config-epoch", master.config_epoch.to_string()),
+        ];
+        flat_fields(fields)
+    }
+    fn display_info(&self) -> Value {
+        let mut info = vec![
+            "# Sentinel".to_string(),
+            format!("sentinel_masters:{}", self.masters.len()),
+            format!("sentinel_current_epoch:{}", self.current_epoch),
+        ];
+        for (index, master) in self.masters.values().enumerate() {
+            info.push(format!(
+                "master{}:name={},status={},address={}:{},slaves={},sentinels={}",
+                index,
+                master.name,
+                master.status(),
+                master.host,
+                master.port,
+                master.replicas.len(),
+                self.peers.len() + 1
+            ));
+        }
+        Value::BulkString(info.join("\r\n"))
+    }
+}
+
+fn flat_fields(fields: Vec<(&str, String)>) -> Value {
+    Value::Array(
+        fields
+            .into_iter()
+            .flat_map(|(name, value)| [Value::BulkString(name.to_string()), Value::BulkString(value)])
+            .collect(),
+    )
+}
+
+//Serve sentinel commands and monitor every configured master
+pub async fn run_sentinel(listener: TcpListener, sentinel: Sentinel) {
+    let names = sentinel.masters.keys().cloned().collect::<Vec<String>>();
+    let sentinel = Arc::new(Mutex::new(sentinel));
+    for name in names {
+        spawn_monitor(name, sentinel.clone());
+    }
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let sentinel = sentinel.clone();
+                tokio::spawn(async move {
+                    handle_sentinel_connection(stream, sentinel).await;
+                });
+            }
+            Err(e) => eprintln!("Got error when listenning... --- error: {}", e),
+        }
+    }
+}
+
+async fn handle_sentinel_connection(stream: TcpStream, sentinel: Arc<Mutex<Sentinel>>) {
+    let (reader, writer) = split(stream);
+    let mut reader = RespReader::new(reader);
+    let writer = Arc::new(Mutex::new(writer));
+    loop {
+        match reader.read_value().await {
+            Ok(Some((value, _))) => {
+                let result = match extract_command(value) {
+                    Ok((command, command_content)) => {
+                        let mut sentinel = sentinel.lock().await;
+                        sentinel_command_handler(&command, command_content, &mut sentinel)
+                    }
+                    Err(e) => Value::SimpleError(format!("ERR {}", e)),
+                };
+                write_value(writer.clone(), result.serialize()).await;
+            }
+            Ok(None) => break,
+            Err(e) => {
+                eprintln!("Got error when read value : {}", e);
+                break;
+            }
+        }
+    }
+}
+
+fn sentinel_command_handler(command: &str, command_content: Vec<Value>, sentinel: &mut Sentinel) -> Value {
+    let args = command_content
+        .iter()
+        .map(|value| unwrap_value_to_string(value).unwrap_or_default())
+        .collect::<Vec<String>>();
+    match command.to_uppercase().as_str() {
+        "PING" => Value::SimpleString("PONG".to_string()),
+        "INFO" => sentinel.display_info(),
+        "SENTINEL" => handle_sentinel(args, sentinel),
+        c => Value::SimpleError(format!("ERR unknown command '{}'", c)),
+    }
+}
+
+fn handle_sentinel(args: Vec<String>, sentinel: &mut Sentinel) -> Value {
+    let subcommand = args.first().map(|arg| arg.to_lowercase()).unwrap_or_default();
+    let no_such_master = Value::SimpleError("ERR No such master with that name".to_string());
+    match subcommand.as_str() {
+        "myid" => Value::BulkString(sentinel.runid.clone()),
+        "masters" => Value::Array(
+            sentinel
+                .masters
+                .values()
+                .map(|master| sentinel.display_master(master))
+                .collect(),
+        ),
+        "master" => match args.get(1).and_then(|name| sentinel.masters.get(name)) {
+            Some(master) => sentinel.display_master(master),
+            None => no_such_master,
+        },
+        "get-master-addr-by-name" => match args.get(1).and_then(|name| sentinel.masters.get(name)) {
+            Some(master) => Value::Array(vec![
+                Value::BulkString(master.host.clone()),
+                Value::BulkString(master.port.to_string()),
+            ]),
+            None => Value::NullBulkString,
+        },
+        "replicas" | "slaves" => match args.get(1).and_then(|name| sentinel.masters.get(name)) {
+            Some(master) => Value::Array(
+                master
+                    .replicas
+                    .iter()
+                    .map(|replica| {
+                        let down = replica
+                            .last_ok
+                            .map(|last_ok| last_ok.elapsed() > master.down_after)
+                            .unwrap_or(true);
+                        flat_fields(vec![
+                            ("name", format!("{}:{}", replica.host, replica.port)),
+                            ("ip", replica.host.clone()),
+                            ("port", replica.port.to_string()),
+                            ("flags", if down { "slave,s_down" } else { "slave" }.to_string()),
+                            ("slave-priority", replica.priority.to_string()),
+                            ("slave-repl-offset", replica.offset.to_string()),
+                        ])
+                    })
+                    .collect(),
+            ),
+            None => no_such_master,
+        },
+        "sentinels" => match args.get(1).and_then(|name| sentinel.masters.get(name)) {
+            Some(_) => Value::Array(
+                sentinel
+                    .peers
+                    .iter()
+                    .map(|(host, port)| {
+                        flat_fields(vec![
+                            ("name", format!("{}:{}", host, port)),
+                            ("ip", host.clone()),
+                            ("port", port.to_string()),
+                        ])
+                    })
+                    .collect(),
+            ),
+            None => no_such_master,
+        },
+        "is-master-down-by-addr" => {
+            if args.len() != 5 {
+                return Value::SimpleError(
+                    "ERR wrong number of arguments for 'sentinel|is-master-down-by-addr' command"
+                        .to_string(),
+                );
+            }
+            match (args[2].parse::<usize>(), args[3].parse::<u64>()) {
+                (Ok(port), Ok(epoch)) => sentinel.vote(&args[1], port, epoch, &args[4]),
+                _ => Value::SimpleError("ERR value is not an integer or out of range".to_string()),
+            }
+        }
+        //new configuration announced by the sentinel that ran a failover
+        "hello" => {
+            if args.len() != 5 {
+                return Value::SimpleError(
+                    "ERR wrong number of arguments for 'sentinel|hello' command".to_string(),
+                );
+            }
+            match (
+                sentinel.masters.get_mut(&args[1]),
+                args[3].parse::<usize>(),
+                args[4].parse::<u64>(),
+            ) {
+                (Some(master), Ok(port), Ok(config_epoch)) => {
+                    if config_epoch > master.config_epoch {
+                        master.switch_master(&args[2], port, config_epoch);
+                    }
+                    if config_epoch > sentinel.current_epoch {
+                        sentinel.current_epoch = config_epoch;
+                    }
+                    Value::SimpleString("OK".to_string())
+                }
+                (None, _, _) => no_such_master,
+                _ => Value::SimpleError("ERR value is not an integer or out of range".to_string()),
+            }
+        }
+        "failover" => match args.get(1).and_then(|name| sentinel.masters.get_mut(name)) {
+            Some(master) if master.failover_in_progress => {
+                Value::SimpleError("INPROG Failover already in progress".to_string())
+            }
+            Some(master) => {
+                master.force_failover = true;
+                Value::SimpleString("OK".to_string())
+            }
+            None => no_such_master,
+        },
+        _ => Value::SimpleError(format!(
+            "ERR unknown subcommand '{}'. Try SENTINEL HELP.",
+            subcommand
+        )),
+    }
+}
@@ -0,0 +1,325 @@
+//Bit helpers over string bytes, bit 0 is the most significant bit of the first byte like redis
+
+//SETBIT and BITFIELD offsets stop at the 512MB string limit
+pub const BITMAP_MAX_OFFSET: u64 = (512 * 1024 * 1024 * 8) - 1;
+
+pub fn get_bit(bytes: &[u8], offset: u64) -> u8 {
+    match bytes.get((offset >> 3) as usize) {
+        Some(byte) => (byte >> (7 - (offset & 7))) & 1,
+        None => 0,
+    }
+}
+//Set one bit growing the string with zeros, returns the previous bit
+pub fn set_bit(bytes: &mut Vec<u8>, offset: u64, bit: u8) -> u8 {
+    let index = (offset >> 3) as usize;
+    if bytes.len() <= index {
+        bytes.resize(index + 1, 0);
+    }
+    let mask = 1 << (7 - (offset & 7));
+    let old = u8::from(bytes[index] & mask != 0);
+    if bit == 1 {
+        bytes[index] |= mask;
+    } else {
+        bytes[index] &= !mask;
+    }
+    old
+}
+
+//Inclusive range of BITCOUNT, BITPOS and GETRANGE, negative values count from the end
+pub fn resolve_range(len: i64, start: i64, end: i64) -> Option<(u64, u64)> {
+    let start = if start < 0 { (len + start).max(0) } else { start };
+    let end = if end < 0 { (len + end).max(0) } else { end.min(len - 1) };
+    if len == 0 || start > end {
+        return None;
+    }
+    Some((start as u64, end as u64))
+}
+//Set bits between two bit offsets, both included
+pub fn count_bits(bytes: &[u8], start: u64, end: u64) -> u64 {
+    let (first, last) = ((start >> 3) as usize, (end >> 3) as usize);
+    if first == last {
+        return (first_bits(bytes[first], start & 7) & last_bits(end & 7)).count_ones() as u64;
+    }
+    let middle = bytes[first + 1..last]
+        .iter()
+        .map(|byte| byte.count_ones() as u64)
+        .sum::<u64>();
+    middle
+        + first_bits(bytes[first], start & 7).count_ones() as u64
+        + (bytes[last] & last_bits(end & 7)).count_ones() as u64
+}
+//Byte with the bits before the start offset cleared
+fn first_bits(byte: u8, start: u64) -> u8 {
+    byte & (0xffu8 >> start)
+}
+//Mask keeping bits up to the end offset
+fn last_bits(end: u64) -> u8 {
+    0xffu8 << (7 - end)
+}
+//First bit equal to the given one between two bit offsets, both included
+pub fn bit_position(bytes: &[u8], bit: u8, start: u64, end: u64) -> Option<u64> {
+    //bytes made only of the bits we are not looking for are skipped whole
+    let skip = if bit == 1 { 0x00 } else { 0xff };
+    let mut offset = start;
+    while offset <= end {
+        if offset & 7 == 0 && offset + 7 <= end && bytes[(offset >> 3) as usize] == skip {
+            offset += 8;
+            continue;
+        }
+        if get_bit(bytes, offset) == bit {
+            return Some(offset);
+        }
+        offset += 1;
+    }
+    None
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BitOperation {
+    And,
+    Or,
+    Xor,
+    Not,
+    //bits of the first key that are set in none of the others
+    Diff,
+}
+impl BitOperation {
+    pub fn parse(operation: &str) -> Option<Self> {
+        match operation.to_uppercase().as_str() {
+            "AND" => Some(BitOperation::And),
+            "OR" => Some(BitOperation::Or),
+            "XOR" => Some(BitOperation::Xor),
+            "NOT" => Some(BitOperation::Not),
+            "DIFF" => Some(BitOperation::Diff),
+            _ => None,
+        }
+    }
+}
+//BITOP result, shorter sources are padded with zeros up to the longest one
+pub fn bit_operation(operation: BitOperation, sources: &[Vec<u8>]) -> Vec<u8> {
+    let len = sources.iter().map(|source| source.len()).max().unwrap_or(0);
+    let byte = |source: &Vec<u8>, index: usize| source.get(index).copied().unwrap_or(0);
+    (0..len)
+        .map(|index| match operation {
+            BitOperation::And => sources.iter().fold(0xff, |result, source| result & byte(source, index)),
+            BitOperation::Or => sources.iter().fold(0, |result, source| result | byte(source, index)),
+            BitOperation::Xor => sources.iter().fold(0, |result, source| result ^ byte(source, index)),
+            BitOperation::Not => !byte(&sources[0], index),
+            BitOperation::Diff => {
+                let others = sources[1..].iter().fold(0, |result, source| result | byte(source, index));
+                byte(&sources[0], index) & !others
+            }
+        })
+        .collect()
+}
+
+//BITFIELD type such as i5 or u8, u64 is not supported because replies are signed
+#[derive(Clone, Copy, Debug)]
+pub struct BitfieldType {
+    pub signed: bool,
+    pub bits: u32,
+}
+impl BitfieldType {
+    pub fn parse(encoding: &str) -> Option<Self> {
+        let signed = match encoding.chars().next()? {
+            'i' | 'I' => true,
+            'u' | 'U' => false,
+            _ => return None,
+        };
+        let bits = encoding[1..].parse::<u32>().ok()?;
+        match (signed, bits) {
+            (_, 0) | (true, 65..) | (false, 64..) => None,
+            _ => Some(BitfieldType { signed, bits }),
+        }
+    }
+    fn min(&self) -> i128 {
+        if self.signed {
+            -(1i128 << (self.bits - 1))
+        } else {
+            0
+        }
+    }
+    fn max(&self) -> i128 {
+        if self.signed {
+            (1i128 << (self.bits - 1)) - 1
+        } else {
+            (1i128 << self.bits) - 1
+        }
+    }
+    //Keep the low bits, sign extended for signed types
+    fn wrap(&self, value: i128) -> i64 {
+        let modulo = 1i128 << self.bits;
+        let value = value.rem_euclid(modulo);
+        if self.signed && value > self.max() {
+            (value - modulo) as i64
+        } else {
+            value as i64
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Overflow {
+    Wrap,
+    Sat,
+    Fail,
+}
+impl Overflow {
+    pub fn parse(overflow: &str) -> Option<Self> {
+        match overflow.to_uppercase().as_str() {
+            "WRAP" => Some(Overflow::Wrap),
+            "SAT" => Some(Overflow::Sat),
+            "FAIL" => Some(Overflow::Fail),
+            _ => None,
+        }
+    }
+    //Value the field ends up with, None when FAIL refuses the write
+    pub fn apply(&self, value: i128, field: BitfieldType) -> Option<i64> {
+        if (field.min()..=field.max()).contains(&value) {
+            return Some(value as i64);
+        }
+        match self {
+            Overflow::Wrap => Some(field.wrap(value)),
+            Overflow::Sat => Some(value.clamp(field.min(), field.max()) as i64),
+            Overflow::Fail => None,
+        }
+    }
+}
+
+pub fn get_field(bytes: &[u8], offset: u64, field: BitfieldType) -> i64 {
+    let mut value = 0u64;
+    for index in 0..field.bits as u64 {
+        value = (value << 1) | get_bit(bytes, offset + index) as u64;
+    }
+    if field.signed && field.bits < 64 && value >> (field.bits - 1) == 1 {
+        //sign extend
+        value |= u64::MAX << field.bits;
+    }
+    value as i64
+}
+pub fn set_field(bytes: &mut Vec<u8>, offset: u64, field: BitfieldType, value: i64) {
+    for index in 0..field.bits as u64 {
+        let bit = ((value as u64) >> (field.bits as u64 - 1 - index)) & 1;
+        set_bit(bytes, offset + index, bit as u8);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(encoding: &str) -> BitfieldType {
+        BitfieldType::parse(encoding).unwrap()
+    }
+
+    #[test]
+    fn widths() {
+        for encoding in ["i1", "u1", "i64", "u63", "I8", "U16"] {
+            assert!(BitfieldType::parse(encoding).is_some(), "{}", encoding);
+        }
+        for encoding in ["i0", "u0", "i65", "u64", "x8", "i", "u-1"] {
+            assert!(BitfieldType::parse(encoding).is_none(), "{}", encoding);
+        }
+        assert_eq!((field("i64").min(), field("i64").max()), (i64::MIN as i128, i64::MAX as i128));
+        assert_eq!((field("u63").min(), field("u63").max()), (0, i64::MAX as i128));
+        assert_eq!((field("i1").min(), field("i1").max()), (-1, 0));
+        assert_eq!((field("u1").min(), field("u1").max()), (0, 1));
+    }
+
+    #[test]
+    fn fields_are_big_endian_at_any_offset() {
+        let mut bytes = Vec::new();
+        //BITFIELD k SET u4 0 15
+        set_field(&mut bytes, 0, field("u4"), 15);
+        assert_eq!(bytes, vec![0xf0]);
+        for (encoding, value) in [
+            ("i64", i64::MIN),
+            ("i64", i64::MAX),
+            ("i64", -1),
+            ("u63", i64::MAX),
+            ("u63", 1),
+            ("i5", -16),
+            ("u1", 1),
+        ] {
+            for offset in [0, 5, 64] {
+                let mut bytes = Vec::new();
+                set_field(&mut bytes, offset, field(encoding), value);
+                assert_eq!(get_field(&bytes, offset, field(encoding)), value, "{} at {}", encoding, offset);
+                //nothing before the field is touched
+                assert!((0..offset).all(|bit| get_bit(&bytes, bit) == 0));
+            }
+        }
+        //the same bits read back differently as signed and unsigned
+        let mut bytes = Vec::new();
+        set_field(&mut bytes, 3, field("i8"), -1);
+        assert_eq!(get_field(&bytes, 3, field("u8")), 255);
+        assert_eq!(get_field(&bytes, 3, field("i8")), -1);
+    }
+
+    #[test]
+    fn wrap_overflow() {
+        let wrap = |value: i128, encoding| Overflow::Wrap.apply(value, field(encoding));
+        assert_eq!(wrap(127 + 1, "i8"), Some(-128));
+        assert_eq!(wrap(-128 - 1, "i8"), Some(127));
+        assert_eq!(wrap(300, "i8"), Some(44));
+        assert_eq!(wrap(255 + 1, "u8"), Some(0));
+        assert_eq!(wrap(-1, "u8"), Some(255));
+        //BITFIELD k SET u8 0 -1, the value is seen as its 64 bits
+        assert_eq!(wrap(-1i64 as u64 as i128, "u8"), Some(255));
+        assert_eq!(wrap(i64::MAX as i128 + 1, "i64"), Some(i64::MIN));
+        assert_eq!(wrap(i64::MIN as i128 - 1, "i64"), Some(i64::MAX));
+        assert_eq!(wrap(i64::MAX as i128 + 1, "u63"), Some(0));
+        assert_eq!(wrap(-1, "u63"), Some(i64::MAX));
+        assert_eq!(wrap(1, "i1"), Some(-1));
+        //in range values are left alone
+        assert_eq!(wrap(-5, "i64"), Some(-5));
+    }
+
+    #[test]
+    fn sat_overflow() {
+        let sat = |value: i128, encoding| Overflow::Sat.apply(value, field(encoding));
+        assert_eq!(sat(127 + 1, "i8"), Some(127));
+        assert_eq!(sat(-128 - 1, "i8"), Some(-128));
+        assert_eq!(sat(255 + 100, "u8"), Some(255));
+        assert_eq!(sat(-1, "u8"), Some(0));
+        assert_eq!(sat(i64::MAX as i128 + 1, "i64"), Some(i64::MAX));
+        assert_eq!(sat(i64::MIN as i128 - 1, "i64"), Some(i64::MIN));
+        assert_eq!(sat(i64::MAX as i128 + 1, "u63"), Some(i64::MAX));
+        assert_eq!(sat(-1, "u63"), Some(0));
+    }
+
+    #[test]
+    fn fail_overflow() {
+        let fail = |value: i128, encoding| Overflow::Fail.apply(value, field(encoding));
+        assert_eq!(fail(127 + 1, "i8"), None);
+        assert_eq!(fail(-128 - 1, "i8"), None);
+        assert_eq!(fail(256, "u8"), None);
+        assert_eq!(fail(-1, "u8"), None);
+        assert_eq!(fail(i64::MAX as i128 + 1, "i64"), None);
+        assert_eq!(fail(i64::MIN as i128 - 1, "i64"), None);
+        assert_eq!(fail(i64::MAX as i128 + 1, "u63"), None);
+        assert_eq!(fail(i64::MAX as i128, "u63"), Some(i64::MAX));
+        assert_eq!(fail(i64::MIN as i128, "i64"), Some(i64::MIN));
+    }
+
+    #[test]
+    fn incrby_like_redis() {
+        //BITFIELD k INCRBY u2 100 1 OVERFLOW SAT INCRBY u2 102 1, sent four times
+        let mut bytes = Vec::new();
+        let mut replies = Vec::new();
+        for _ in 0..4 {
+            let mut reply = Vec::new();
+            for (offset, overflow) in [(100, Overflow::Wrap), (102, Overflow::Sat)] {
+                let old = get_field(&bytes, offset, field("u2"));
+                let value = overflow.apply(old as i128 + 1, field("u2")).unwrap();
+                set_field(&mut bytes, offset, field("u2"), value);
+                reply.push(value);
+            }
+            replies.push(reply);
+        }
+        assert_eq!(replies, vec![vec![1, 1], vec![2, 2], vec![3, 3], vec![0, 3]]);
+        //OVERFLOW FAIL INCRBY u2 102 1 leaves the saturated field alone
+        assert_eq!(Overflow::Fail.apply(get_field(&bytes, 102, field("u2")) as i128 + 1, field("u2")), None);
+        assert_eq!(get_field(&bytes, 102, field("u2")), 3);
+    }
+}
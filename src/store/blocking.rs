@@ -0,0 +1,164 @@
+use anyhow::Result;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Weak},
+    time::Duration,
+};
+use tokio::{
+    sync::Notify,
+    time::{timeout_at, Instant},
+};
+
+use crate::{
+    resp::value::Value,
+    store::{handle::StoreHandle, store::Store},
+};
+
+//Clients blocked on keys, in the order they started waiting.
+//The queue only holds a weak handle, a client that disconnected while blocked is skipped
+#[derive(Clone)]
+pub struct BlockingKeys {
+    next_id: u64,
+    queues: HashMap<String, VecDeque<(u64, Weak<Notify>)>>,
+}
+impl BlockingKeys {
+    pub fn new() -> Self {
+        BlockingKeys {
+            next_id: 1,
+            queues: HashMap::new(),
+        }
+    }
+    //Queue a waiter behind everyone already blocked on these keys
+    pub fn register(&mut self, keys: &[String]) -> (u64, Arc<Notify>) {
+        let id = self.next_id;
+        self.next_id += 1;
+        let notify = Arc::new(Notify::new());
+        for key in keys {
+            let queue = self.queues.entry(key.clone()).or_default();
+            queue.retain(|(_, waiter)| waiter.strong_count() > 0);
+            queue.push_back((id, Arc::downgrade(&notify)));
+        }
+        (id, notify)
+    }
+    pub fn unregister(&mut self, id: u64, keys: &[String]) {
+        for key in keys {
+            if let Some(queue) = self.queues.get_mut(key) {
+                queue.retain(|(waiter, _)| *waiter != id);
+                if queue.is_empty() {
+                    self.queues.remove(key);
+                }
+            }
+        }
+    }
+    //Longest waiting client that is still connected
+    fn first_alive(&self, key: &str) -> Option<(u64, Arc<Notify>)> {
+        self.queues
+            .get(key)?
+            .iter()
+            .find_map(|(id, waiter)| Some((*id, waiter.upgrade()?)))
+    }
+    //Nobody ahead of this waiter on the key, None is a client not blocked yet
+    pub fn is_turn(&self, key: &str, id: Option<u64>) -> bool {
+        match self.first_alive(key) {
+            Some((first, _)) => Some(first) == id,
+            None => true,
+        }
+    }
+    //Wake the longest waiting client, for data only one of them can take
+    pub fn signal(&self, key: &str) {
+        if let Some((_, notify)) = self.first_alive(key) {
+            notify.notify_one();
+        }
+    }
+    //Wake every client, for data all of them can read
+    pub fn signal_all(&self, key: &str) {
+        if let Some(queue) = self.queues.get(key) {
+            for notify in queue.iter().filter_map(|(_, waiter)| waiter.upgrade()) {
+                notify.notify_one();
+            }
+        }
+    }
+}
+
+//A blocked client's place in the queues, dropping it without finish (the connection went away
+//while the command waited) still leaves the queues and hands the data to the next client
+struct Waiter {
+    storage: StoreHandle,
+    keys: Vec<String>,
+    id: u64,
+    notify: Arc<Notify>,
+    finished: bool,
+}
+impl Waiter {
+    fn finish(mut self, store: &mut Store) {
+        store.unblock(self.id, &self.keys);
+        self.finished = true;
+    }
+}
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        let (storage, keys, id) = (self.storage.clone(), std::mem::take(&mut self.keys), self.id);
+        tokio::spawn(async move {
+            storage.lock().await.unblock(id, &keys);
+        });
+    }
+}
+
+//Retry serve every time one of the keys is signalled, until it gives a value or the timeout runs out.
+//fifo keeps the data for the client blocked first, serve only gets keys where it is our turn.
+//None timeout waits forever, a zero timeout only tries once.
+pub async fn block_on_keys<F>(
+    storage: StoreHandle,
+    keys: &[String],
+    timeout: Option<Duration>,
+    fifo: bool,
+    mut serve: F,
+) -> Result<Option<Value>>
+where
+    F: FnMut(&mut Store, &[String]) -> Result<Option<Value>>,
+{
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let mut waiter: Option<Waiter> = None;
+    loop {
+        let mut store = storage.lock().await;
+        let id = waiter.as_ref().map(|waiter| waiter.id);
+        let ready = keys
+            .iter()
+            .filter(|key| !fifo || store.blocking.is_turn(key, id))
+            .cloned()
+            .collect::<Vec<String>>();
+        let served = serve(&mut store, &ready);
+        let timed_out = deadline.is_some_and(|deadline| deadline <= Instant::now());
+        if !matches!(served, Ok(None)) || timed_out {
+            if let Some(waiter) = waiter {
+                waiter.finish(&mut store);
+            }
+            return served;
+        }
+        let notify = match &waiter {
+            Some(waiter) => waiter.notify.clone(),
+            None => {
+                let (id, notify) = store.blocking.register(keys);
+                waiter = Some(Waiter {
+                    storage: storage.clone(),
+                    keys: keys.to_vec(),
+                    id,
+                    notify: notify.clone(),
+                    finished: false,
+                });
+                notify
+            }
+        };
+        drop(store);
+        match deadline {
+            Some(deadline) => {
+                //the next round sees the deadline passed and gives up
+                let _ = timeout_at(deadline, notify.notified()).await;
+            }
+            None => notify.notified().await,
+        }
+    }
+}
@@ -209,6 +209,18 @@ impl Entry {
     pub fn check_stream_key_exist(&self, stream_key: &str) -> bool {
         self.collection.contains_key(stream_key)
     }
+    pub fn get_stream_keys(&self) -> Vec<String> {
+        self.collection.keys().cloned().collect()
+    }
+    pub fn remove_stream_key(&mut self, stream_key: &str) -> bool {
+        self.collection.remove(stream_key).is_some()
+    }
+    pub fn get_streams(&self, stream_key: &str) -> Vec<&StreamType> {
+        match self.collection.get(stream_key) {
+            Some(streams) => streams.iter().collect(),
+            None => Vec::new(),
+        }
+    }
     pub fn get_streams_in_range(
         &self,
         stream_key: &str,
@@ -259,12 +271,12 @@ impl Entry {
         }
         result
     }
-    pub fn get_len(&self, stream_key: &str) -> Result<usize>{
-        Ok(self.collection.get(stream_key).unwrap().len())
-    }
+    //0-0 for a missing or empty stream, anything added later is newer
     pub fn get_last(&self, stream_key: &str) -> Result<String>{
-        let last = self.collection.get(stream_key).unwrap().last().unwrap();
-        Ok(last.stream_id.clone())
+        match self.collection.get(stream_key).and_then(|streams| streams.last()) {
+            Some(last) => Ok(last.stream_id.clone()),
+            None => Ok("0-0".to_string()),
+        }
     }
 }
 
@@ -0,0 +1,246 @@
+//Geo members live in a sorted set, scored by a 52-bit interleaved geohash like redis
+const GEO_STEP: u32 = 26;
+const GEO_LONG_MIN: f64 = -180f64;
+const GEO_LONG_MAX: f64 = 180f64;
+//web mercator limits, the poles are left out
+const GEO_LAT_MIN: f64 = -85.05112878;
+const GEO_LAT_MAX: f64 = 85.05112878;
+const EARTH_RADIUS_IN_METERS: f64 = 6372797.560856;
+const GEOHASH_ALPHABET: &[u8] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+//What GEOSEARCH looks inside, sizes in meters
+#[derive(Clone, Copy, Debug)]
+pub enum GeoShape {
+    Radius(f64),
+    Box(f64, f64),
+}
+impl GeoShape {
+    //Distance to the center when the point is inside the shape
+    pub fn contains(&self, center: (f64, f64), point: (f64, f64)) -> Option<f64> {
+        match self {
+            GeoShape::Radius(radius) => Some(distance(center, point)).filter(|distance| distance <= radius),
+            GeoShape::Box(width, height) => {
+                if lat_distance(center.1, point.1) > height / 2f64
+                    || distance((center.0, point.1), point) > width / 2f64
+                {
+                    return None;
+                }
+                Some(distance(center, point))
+            }
+        }
+    }
+}
+
+//One GEOSEARCH match
+#[derive(Clone, Debug)]
+pub struct GeoPoint {
+    pub member: String,
+    pub distance: f64,
+    pub score: f64,
+    pub coord: (f64, f64),
+}
+
+//Meters in one unit, for GEODIST and GEOSEARCH
+pub fn parse_unit(unit: &str) -> Option<f64> {
+    match unit.to_lowercase().as_str() {
+        "m" => Some(1f64),
+        "km" => Some(1000f64),
+        "ft" => Some(0.3048),
+        "mi" => Some(1609.34),
+        _ => None,
+    }
+}
+
+pub fn is_valid_coord(longitude: f64, latitude: f64) -> bool {
+    (GEO_LONG_MIN..=GEO_LONG_MAX).contains(&longitude) && (GEO_LAT_MIN..=GEO_LAT_MAX).contains(&latitude)
+}
+
+//Longitude bits go to the odd positions and latitude bits to the even ones
+fn interleave(latitude: u32, longitude: u32) -> u64 {
+    let spread = |value: u32| {
+        let mut value = value as u64;
+        value = (value | (value << 16)) & 0x0000FFFF0000FFFF;
+        value = (value | (value << 8)) & 0x00FF00FF00FF00FF;
+        value = (value | (value << 4)) & 0x0F0F0F0F0F0F0F0F;
+        value = (value | (value << 2)) & 0x3333333333333333;
+        (value | (value << 1)) & 0x5555555555555555
+    };
+    spread(latitude) | (spread(longitude) << 1)
+}
+fn deinterleave(bits: u64) -> (u32, u32) {
+    let squash = |value: u64| {
+        let mut value = value & 0x5555555555555555;
+        value = (value | (value >> 1)) & 0x3333333333333333;
+        value = (value | (value >> 2)) & 0x0F0F0F0F0F0F0F0F;
+        value = (value | (value >> 4)) & 0x00FF00FF00FF00FF;
+        value = (value | (value >> 8)) & 0x0000FFFF0000FFFF;
+        ((value | (value >> 16)) & 0x00000000FFFFFFFF) as u32
+    };
+    (squash(bits), squash(bits >> 1))
+}
+
+fn encode_with(longitude: f64, latitude: f64, lat_min: f64, lat_max: f64) -> u64 {
+    let scale = (1u64 << GEO_STEP) as f64;
+    let lat_offset = (latitude - lat_min) / (lat_max - lat_min) * scale;
+    let long_offset = (longitude - GEO_LONG_MIN) / (GEO_LONG_MAX - GEO_LONG_MIN) * scale;
+    interleave(lat_offset as u32, long_offset as u32)
+}
+//Score GEOADD stores for a position
+pub fn encode(longitude: f64, latitude: f64) -> f64 {
+    encode_with(longitude, latitude, GEO_LAT_MIN, GEO_LAT_MAX) as f64
+}
+//Center of the cell a score stands for, as (longitude, latitude)
+pub fn decode(score: f64) -> (f64, f64) {
+    let (lat_bits, long_bits) = deinterleave(score as u64);
+    let scale = (1u64 << GEO_STEP) as f64;
+    let cell = |bits: u32, min: f64, max: f64| {
+        let low = min + (bits as f64 / scale) * (max - min);
+        let high = min + ((bits as f64 + 1f64) / scale) * (max - min);
+        ((low + high) / 2f64).clamp(min, max)
+    };
+    (
+        cell(long_bits, GEO_LONG_MIN, GEO_LONG_MAX),
+        cell(lat_bits, GEO_LAT_MIN, GEO_LAT_MAX),
+    )
+}
+//Standard 11 character geohash, computed over -90..90 like GEOHASH does
+pub fn hash_string(score: f64) -> String {
+    let (longitude, latitude) = decode(score);
+    let bits = encode_with(longitude, latitude, -90f64, 90f64);
+    (0..11)
+        .map(|index| {
+            //only 52 bits are known, the last character is always '0'
+            let position = if index == 10 { 0 } else { (bits >> (52 - (index + 1) * 5)) & 0x1f };
+            GEOHASH_ALPHABET[position as usize] as char
+        })
+        .collect()
+}
+
+fn lat_distance(latitude1: f64, latitude2: f64) -> f64 {
+    EARTH_RADIUS_IN_METERS * (latitude2.to_radians() - latitude1.to_radians()).abs()
+}
+//Haversine distance in meters between two (longitude, latitude) points
+pub fn distance(from: (f64, f64), to: (f64, f64)) -> f64 {
+    let (long1, lat1) = (from.0.to_radians(), from.1.to_radians());
+    let (long2, lat2) = (to.0.to_radians(), to.1.to_radians());
+    let v = ((long2 - long1) / 2f64).sin();
+    if v == 0f64 {
+        return lat_distance(from.1, to.1);
+    }
+    let u = ((lat2 - lat1) / 2f64).sin();
+    let a = u * u + lat1.cos() * lat2.cos() * v * v;
+    2f64 * EARTH_RADIUS_IN_METERS * a.sqrt().asin()
+}
+
+//Members of a geo sorted set inside the shape, in score order, ANY stops at the first count matches
+pub fn search(pairs: Vec<(String, f64)>, center: (f64, f64), shape: GeoShape, any: Option<usize>) -> Vec<GeoPoint> {
+    let mut points = Vec::new();
+    for (member, score) in pairs {
+        if any.is_some_and(|count| points.len() >= count) {
+            break;
+        }
+        let coord = decode(score);
+        if let Some(distance) = shape.contains(center, coord) {
+            points.push(GeoPoint {
+                member,
+                distance,
+                score,
+                coord,
+            });
+        }
+    }
+    points
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //GEOADD Sicily 13.361389 38.115556 Palermo 15.087269 37.502669 Catania
+    const PALERMO: (f64, f64) = (13.361389, 38.115556);
+    const CATANIA: (f64, f64) = (15.087269, 37.502669);
+
+    fn sicily() -> Vec<(String, f64)> {
+        let mut pairs = vec![
+            ("Palermo", PALERMO),
+            ("Catania", CATANIA),
+            ("edge1", (12.758489, 38.788135)),
+            ("edge2", (17.241510, 38.788135)),
+        ]
+        .into_iter()
+        .map(|(member, (longitude, latitude))| (member.to_string(), encode(longitude, latitude)))
+        .collect::<Vec<(String, f64)>>();
+        pairs.sort_by(|a, b| a.1.total_cmp(&b.1));
+        pairs
+    }
+
+    #[test]
+    fn scores_and_hashes_match_redis() {
+        //ZSCORE Sicily Palermo, GEOHASH Sicily Palermo Catania
+        assert_eq!(encode(PALERMO.0, PALERMO.1), 3479099956230698f64);
+        assert_eq!(encode(CATANIA.0, CATANIA.1), 3479447370796909f64);
+        assert_eq!(hash_string(encode(PALERMO.0, PALERMO.1)), "sqc8b49rny0");
+        assert_eq!(hash_string(encode(CATANIA.0, CATANIA.1)), "sqdtr74hyu0");
+    }
+
+    #[test]
+    fn positions_match_redis() {
+        //GEOPOS Sicily Palermo Catania
+        for (coord, expected) in [
+            (PALERMO, ("13.36138933897018433", "38.11555639549629859")),
+            (CATANIA, ("15.08726745843887329", "37.50266842333162032")),
+        ] {
+            let (longitude, latitude) = decode(encode(coord.0, coord.1));
+            assert_eq!(format!("{:.17}", longitude), expected.0);
+            assert_eq!(format!("{:.17}", latitude), expected.1);
+        }
+    }
+
+    #[test]
+    fn distances_match_redis() {
+        //GEODIST works on the stored positions, not the ones given to GEOADD
+        let palermo = decode(encode(PALERMO.0, PALERMO.1));
+        let catania = decode(encode(CATANIA.0, CATANIA.1));
+        assert_eq!(format!("{:.4}", distance(palermo, catania)), "166274.1516");
+        assert_eq!(format!("{:.4}", distance(palermo, catania) / parse_unit("km").unwrap()), "166.2742");
+        assert_eq!(distance(palermo, palermo), 0f64);
+    }
+
+    #[test]
+    fn search_matches_redis() {
+        let km = parse_unit("km").unwrap();
+        let found = |shape, ascending: bool| {
+            let mut points = search(sicily(), (15f64, 37f64), shape, None);
+            if ascending {
+                points.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+            }
+            points
+                .into_iter()
+                .map(|point| (point.member, format!("{:.4}", point.distance / km)))
+                .collect::<Vec<(String, String)>>()
+        };
+        let expected = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(member, distance)| (member.to_string(), distance.to_string()))
+                .collect::<Vec<(String, String)>>()
+        };
+        //GEOSEARCH Sicily FROMLONLAT 15 37 BYRADIUS 200 km WITHDIST, in score order
+        assert_eq!(
+            found(GeoShape::Radius(200f64 * km), false),
+            expected(&[("Palermo", "190.4424"), ("Catania", "56.4413")])
+        );
+        //GEOSEARCH Sicily FROMLONLAT 15 37 BYBOX 400 400 km ASC WITHDIST
+        assert_eq!(
+            found(GeoShape::Box(400f64 * km, 400f64 * km), true),
+            expected(&[
+                ("Catania", "56.4413"),
+                ("Palermo", "190.4424"),
+                ("edge2", "279.7403"),
+                ("edge1", "279.7405"),
+            ])
+        );
+        //ANY stops at the first match
+        assert_eq!(search(sicily(), (15f64, 37f64), GeoShape::Radius(200f64 * km), Some(1)).len(), 1);
+    }
+}
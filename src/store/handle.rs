@@ -0,0 +1,67 @@
+use std::{
+    ops::{Deref, DerefMut},
+    sync::Arc,
+};
+use tokio::sync::{Mutex, MutexGuard, OwnedMutexGuard};
+
+use crate::store::store::Store;
+
+//What command handlers lock the store through.
+//EXEC holds the store for the whole transaction, its commands lock the guard it keeps
+//instead of waiting on the store they would never get
+#[derive(Clone)]
+pub enum StoreHandle {
+    Shared(Arc<Mutex<Store>>),
+    Held(Arc<Mutex<Store>>, Arc<Mutex<OwnedMutexGuard<Store>>>),
+}
+impl StoreHandle {
+    pub async fn lock(&self) -> StoreGuard<'_> {
+        match self {
+            StoreHandle::Shared(storage) => StoreGuard::Shared(storage.lock().await),
+            StoreHandle::Held(_, guard) => StoreGuard::Held(guard.lock().await),
+        }
+    }
+    //Lock the store until the last clone of the returned handle is dropped
+    pub async fn hold(&self) -> StoreHandle {
+        match self {
+            StoreHandle::Shared(storage) => StoreHandle::Held(
+                storage.clone(),
+                Arc::new(Mutex::new(storage.clone().lock_owned().await)),
+            ),
+            StoreHandle::Held(..) => self.clone(),
+        }
+    }
+    //The store without the guard, for tasks that outlive the command like a master link
+    pub fn shared(&self) -> Arc<Mutex<Store>> {
+        match self {
+            StoreHandle::Shared(storage) | StoreHandle::Held(storage, _) => storage.clone(),
+        }
+    }
+}
+impl From<Arc<Mutex<Store>>> for StoreHandle {
+    fn from(storage: Arc<Mutex<Store>>) -> Self {
+        StoreHandle::Shared(storage)
+    }
+}
+
+pub enum StoreGuard<'a> {
+    Shared(MutexGuard<'a, Store>),
+    Held(MutexGuard<'a, OwnedMutexGuard<Store>>),
+}
+impl Deref for StoreGuard<'_> {
+    type Target = Store;
+    fn deref(&self) -> &Store {
+        match self {
+            StoreGuard::Shared(store) => store,
+            StoreGuard::Held(store) => store,
+        }
+    }
+}
+impl DerefMut for StoreGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Store {
+        match self {
+            StoreGuard::Shared(store) => store,
+            StoreGuard::Held(store) => store,
+        }
+    }
+}
@@ -0,0 +1,126 @@
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+
+//Past either limit a listpack hash becomes a hashtable, like hash-max-listpack-*
+pub const HASH_MAX_LISTPACK_ENTRIES: usize = 128;
+pub const HASH_MAX_LISTPACK_VALUE: usize = 64;
+
+#[derive(Clone, Debug)]
+enum HashEncoding {
+    //small hashes keep their pairs in insertion order and scan them
+    Listpack(Vec<(String, String)>),
+    Hashtable(HashMap<String, String>),
+}
+
+//Hash value with optional per-field expire times (HEXPIRE)
+#[derive(Clone, Debug)]
+pub struct Hash {
+    encoding: HashEncoding,
+    expires: HashMap<String, DateTime<Utc>>,
+}
+impl Hash {
+    pub fn new() -> Self {
+        Hash {
+            encoding: HashEncoding::Listpack(Vec::new()),
+            expires: HashMap::new(),
+        }
+    }
+
+    fn is_alive(&self, field: &str) -> bool {
+        self.expires
+            .get(field)
+            .is_none_or(|expire| *expire >= Utc::now())
+    }
+    pub fn get(&self, field: &str) -> Option<&String> {
+        let value = match &self.encoding {
+            HashEncoding::Listpack(pairs) => pairs.iter().find(|(name, _)| name == field).map(|(_, value)| value),
+            HashEncoding::Hashtable(table) => table.get(field),
+        };
+        value.filter(|_| self.is_alive(field))
+    }
+    pub fn contains(&self, field: &str) -> bool {
+        self.get(field).is_some()
+    }
+    pub fn len(&self) -> usize {
+        self.pairs().len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    pub fn is_listpack(&self) -> bool {
+        matches!(self.encoding, HashEncoding::Listpack(_))
+    }
+    //Live field/value pairs, in insertion order while the hash is a listpack
+    pub fn pairs(&self) -> Vec<(&String, &String)> {
+        let pairs: Vec<(&String, &String)> = match &self.encoding {
+            HashEncoding::Listpack(pairs) => pairs.iter().map(|(field, value)| (field, value)).collect(),
+            HashEncoding::Hashtable(table) => table.iter().collect(),
+        };
+        pairs.into_iter().filter(|(field, _)| self.is_alive(field)).collect()
+    }
+
+    //True when the field is new, writing a field drops its ttl
+    pub fn set(&mut self, field: &str, value: &str) -> bool {
+        let is_new = !self.contains(field);
+        self.expires.remove(field);
+        if let HashEncoding::Listpack(pairs) = &mut self.encoding {
+            if pairs.len() + usize::from(is_new) > HASH_MAX_LISTPACK_ENTRIES
+                || field.len() > HASH_MAX_LISTPACK_VALUE
+                || value.len() > HASH_MAX_LISTPACK_VALUE
+            {
+                //never converted back, like redis
+                let table = pairs.drain(..).collect();
+                self.encoding = HashEncoding::Hashtable(table);
+            }
+        }
+        match &mut self.encoding {
+            HashEncoding::Listpack(pairs) => match pairs.iter_mut().find(|(name, _)| name == field) {
+                Some((_, old)) => *old = value.to_string(),
+                None => pairs.push((field.to_string(), value.to_string())),
+            },
+            HashEncoding::Hashtable(table) => {
+                table.insert(field.to_string(), value.to_string());
+            }
+        }
+        is_new
+    }
+    pub fn remove(&mut self, field: &str) -> bool {
+        let existed = self.contains(field);
+        self.expires.remove(field);
+        match &mut self.encoding {
+            HashEncoding::Listpack(pairs) => pairs.retain(|(name, _)| name != field),
+            HashEncoding::Hashtable(table) => {
+                table.remove(field);
+            }
+        }
+        existed
+    }
+
+    pub fn get_expire(&self, field: &str) -> Option<DateTime<Utc>> {
+        self.expires.get(field).copied()
+    }
+    pub fn set_expire(&mut self, field: &str, expire: DateTime<Utc>) {
+        self.expires.insert(field.to_string(), expire);
+    }
+    pub fn persist(&mut self, field: &str) -> bool {
+        self.expires.remove(field).is_some()
+    }
+    pub fn has_expired(&self) -> bool {
+        let now = Utc::now();
+        self.expires.values().any(|expire| *expire < now)
+    }
+    //Drop the fields whose ttl ran out, returning their names
+    pub fn purge_expired(&mut self) -> Vec<String> {
+        let now = Utc::now();
+        let expired = self
+            .expires
+            .iter()
+            .filter(|(_, expire)| **expire < now)
+            .map(|(field, _)| field.clone())
+            .collect::<Vec<String>>();
+        for field in expired.iter() {
+            self.remove(field);
+        }
+        expired
+    }
+}
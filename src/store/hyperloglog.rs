@@ -0,0 +1,559 @@
+use anyhow::Result;
+
+//Redis compatible HyperLogLog, the bytes are exactly what redis keeps in the string:
+//"HYLL", encoding, 3 unused bytes, 8 bytes cached cardinality, then the registers
+const HLL_P: u32 = 14;
+const HLL_Q: u32 = 64 - HLL_P;
+pub const HLL_REGISTERS: usize = 1 << HLL_P;
+const HLL_P_MASK: u64 = HLL_REGISTERS as u64 - 1;
+const HLL_BITS: usize = 6;
+const HLL_REGISTER_MAX: u8 = (1 << HLL_BITS) - 1;
+const HLL_HDR_SIZE: usize = 16;
+const HLL_DENSE_SIZE: usize = HLL_HDR_SIZE + (HLL_REGISTERS * HLL_BITS).div_ceil(8);
+const HLL_DENSE: u8 = 0;
+const HLL_SPARSE: u8 = 1;
+const HLL_ALPHA_INF: f64 = 0.721_347_520_444_481_7;
+//Past this size a sparse HyperLogLog is promoted to dense, like hll-sparse-max-bytes
+const HLL_SPARSE_MAX_BYTES: usize = 3000;
+//Sparse opcodes: ZERO 00xxxxxx, XZERO 01xxxxxx xxxxxxxx, VAL 1vvvvvxx
+const HLL_SPARSE_VAL_MAX_VALUE: u8 = 32;
+const HLL_SPARSE_VAL_MAX_LEN: usize = 4;
+const HLL_SPARSE_ZERO_MAX_LEN: usize = 64;
+const HLL_SPARSE_XZERO_MAX_LEN: usize = 16384;
+
+pub const INVALID_HLL: &str = "INVALIDOBJ Corrupted HLL object detected";
+
+//One decoded sparse opcode
+#[derive(Clone, Copy, Debug)]
+enum SparseOp {
+    Zero(usize),
+    XZero(usize),
+    Val(u8, usize),
+}
+impl SparseOp {
+    fn read(bytes: &[u8]) -> Option<SparseOp> {
+        let first = *bytes.first()?;
+        match first >> 6 {
+            0b00 => Some(SparseOp::Zero((first & 0x3f) as usize + 1)),
+            0b01 => {
+                let second = *bytes.get(1)?;
+                Some(SparseOp::XZero(((((first & 0x3f) as usize) << 8) | second as usize) + 1))
+            }
+            _ => Some(SparseOp::Val(((first >> 2) & 0x1f) + 1, (first & 0x03) as usize + 1)),
+        }
+    }
+    fn write(&self, output: &mut Vec<u8>) {
+        match *self {
+            SparseOp::Zero(len) => output.push((len - 1) as u8),
+            SparseOp::XZero(len) => {
+                output.push(0x40 | ((len - 1) >> 8) as u8);
+                output.push((len - 1) as u8);
+            }
+            SparseOp::Val(value, len) => output.push(0x80 | ((value - 1) << 2) | (len - 1) as u8),
+        }
+    }
+    fn size(&self) -> usize {
+        match self {
+            SparseOp::XZero(_) => 2,
+            _ => 1,
+        }
+    }
+    fn span(&self) -> usize {
+        match *self {
+            SparseOp::Zero(len) | SparseOp::XZero(len) | SparseOp::Val(_, len) => len,
+        }
+    }
+    //Run of zero registers, ZERO when it fits in one byte
+    fn zeros(len: usize) -> SparseOp {
+        if len > HLL_SPARSE_ZERO_MAX_LEN {
+            SparseOp::XZero(len)
+        } else {
+            SparseOp::Zero(len)
+        }
+    }
+}
+
+//MurmurHash64A with the seed redis uses for HyperLogLog
+fn murmurhash64a(key: &[u8], seed: u64) -> u64 {
+    const M: u64 = 0xc6a4a7935bd1e995;
+    const R: u32 = 47;
+    let mut h = seed ^ (key.len() as u64).wrapping_mul(M);
+    let mut chunks = key.chunks_exact(8);
+    for chunk in chunks.by_ref() {
+        let mut k = u64::from_le_bytes(chunk.try_into().unwrap());
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h ^= k;
+        h = h.wrapping_mul(M);
+    }
+    let rest = chunks.remainder();
+    if !rest.is_empty() {
+        for (index, byte) in rest.iter().enumerate() {
+            h ^= (*byte as u64) << (8 * index);
+        }
+        h = h.wrapping_mul(M);
+    }
+    h ^= h >> R;
+    h = h.wrapping_mul(M);
+    h ^= h >> R;
+    h
+}
+//Register an element falls in and the length of its run of zeros plus one
+fn pattern_len(element: &[u8]) -> (usize, u8) {
+    let hash = murmurhash64a(element, 0xadc83b19);
+    let index = (hash & HLL_P_MASK) as usize;
+    //the extra bit makes the count stop at Q + 1
+    let hash = (hash >> HLL_P) | (1u64 << HLL_Q);
+    (index, hash.trailing_zeros() as u8 + 1)
+}
+
+fn dense_get(registers: &[u8], index: usize) -> u8 {
+    let byte = index * HLL_BITS / 8;
+    let fb = (index * HLL_BITS) & 7;
+    let b0 = registers[byte] as u16;
+    let b1 = registers.get(byte + 1).copied().unwrap_or(0) as u16;
+    (((b0 >> fb) | (b1 << (8 - fb))) & HLL_REGISTER_MAX as u16) as u8
+}
+fn dense_set(registers: &mut [u8], index: usize, value: u8) {
+    let byte = index * HLL_BITS / 8;
+    let fb = (index * HLL_BITS) & 7;
+    let value = value as u16;
+    let mask = HLL_REGISTER_MAX as u16;
+    registers[byte] = ((registers[byte] as u16 & !(mask << fb)) | (value << fb)) as u8;
+    if let Some(next) = registers.get_mut(byte + 1) {
+        *next = ((*next as u16 & !(mask >> (8 - fb))) | (value >> (8 - fb))) as u8;
+    }
+}
+
+fn tau(mut x: f64) -> f64 {
+    if x == 0f64 || x == 1f64 {
+        return 0f64;
+    }
+    let mut y = 1f64;
+    let mut z = 1f64 - x;
+    loop {
+        x = x.sqrt();
+        let previous = z;
+        y *= 0.5;
+        z -= (1f64 - x).powi(2) * y;
+        if previous == z {
+            return z / 3f64;
+        }
+    }
+}
+fn sigma(mut x: f64) -> f64 {
+    if x == 1f64 {
+        return f64::INFINITY;
+    }
+    let mut y = 1f64;
+    let mut z = x;
+    loop {
+        x *= x;
+        let previous = z;
+        z += x * y;
+        y += y;
+        if previous == z {
+            return z;
+        }
+    }
+}
+//Ertl's improved estimator over the register histogram, the one redis uses
+fn estimate(histogram: &[usize]) -> u64 {
+    let m = HLL_REGISTERS as f64;
+    let q = HLL_Q as usize;
+    let mut z = m * tau((m - histogram[q + 1] as f64) / m);
+    for count in histogram[1..=q].iter().rev() {
+        z += *count as f64;
+        z *= 0.5;
+    }
+    z += m * sigma(histogram[0] as f64 / m);
+    (HLL_ALPHA_INF * m * m / z).round() as u64
+}
+//Cardinality of raw registers, one byte each, as PFCOUNT builds them for several keys
+pub fn count_registers(registers: &[u8]) -> u64 {
+    let mut histogram = [0usize; HLL_Q as usize + 2];
+    for register in registers {
+        histogram[*register as usize] += 1;
+    }
+    estimate(&histogram)
+}
+
+#[derive(Clone, Debug)]
+pub struct HyperLogLog {
+    bytes: Vec<u8>,
+}
+impl HyperLogLog {
+    //Empty sparse HyperLogLog, one XZERO covering every register
+    pub fn new() -> Self {
+        let mut bytes = b"HYLL".to_vec();
+        bytes.push(HLL_SPARSE);
+        bytes.extend([0u8; 11]);
+        SparseOp::XZero(HLL_SPARSE_XZERO_MAX_LEN).write(&mut bytes);
+        HyperLogLog { bytes }
+    }
+    //Accept bytes only when they look like a HyperLogLog, e.g. a string loaded from rdb
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < HLL_HDR_SIZE || &bytes[..4] != b"HYLL" {
+            return None;
+        }
+        match bytes[4] {
+            HLL_DENSE if bytes.len() == HLL_DENSE_SIZE => {}
+            HLL_SPARSE => {}
+            _ => return None,
+        }
+        Some(HyperLogLog { bytes: bytes.to_vec() })
+    }
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+    pub fn is_sparse(&self) -> bool {
+        self.bytes[4] == HLL_SPARSE
+    }
+
+    fn cached_count(&self) -> Option<u64> {
+        let card = u64::from_le_bytes(self.bytes[8..16].try_into().unwrap());
+        Some(card).filter(|_| self.bytes[15] & 0x80 == 0)
+    }
+    fn invalidate_cache(&mut self) {
+        self.bytes[15] |= 0x80;
+    }
+    fn sparse_ops(&self) -> Result<Vec<SparseOp>> {
+        let mut ops = Vec::new();
+        let mut position = HLL_HDR_SIZE;
+        let mut registers = 0;
+        while position < self.bytes.len() {
+            let op = SparseOp::read(&self.bytes[position..]).ok_or_else(|| anyhow::anyhow!(INVALID_HLL))?;
+            position += op.size();
+            registers += op.span();
+            ops.push(op);
+        }
+        if registers != HLL_REGISTERS {
+            return Err(anyhow::anyhow!(INVALID_HLL));
+        }
+        Ok(ops)
+    }
+    //Every register, one byte each
+    pub fn registers(&self) -> Result<Vec<u8>> {
+        if !self.is_sparse() {
+            let registers = &self.bytes[HLL_HDR_SIZE..];
+            return Ok((0..HLL_REGISTERS).map(|index| dense_get(registers, index)).collect());
+        }
+        let mut registers = Vec::with_capacity(HLL_REGISTERS);
+        for op in self.sparse_ops()? {
+            match op {
+                SparseOp::Zero(len) | SparseOp::XZero(len) => registers.extend(std::iter::repeat_n(0, len)),
+                SparseOp::Val(value, len) => registers.extend(std::iter::repeat_n(value, len)),
+            }
+        }
+        Ok(registers)
+    }
+    //Switch to the dense encoding, false when it already was dense
+    pub fn make_dense(&mut self) -> Result<bool> {
+        if !self.is_sparse() {
+            return Ok(false);
+        }
+        let registers = self.registers()?;
+        let mut bytes = self.bytes[..HLL_HDR_SIZE].to_vec();
+        bytes[4] = HLL_DENSE;
+        bytes.resize(HLL_DENSE_SIZE, 0);
+        for (index, register) in registers.into_iter().enumerate() {
+            if register > 0 {
+                dense_set(&mut bytes[HLL_HDR_SIZE..], index, register);
+            }
+        }
+        self.bytes = bytes;
+        Ok(true)
+    }
+
+    //True when a register grew, the cached count is left for the caller to invalidate
+    fn set_register(&mut self, index: usize, value: u8) -> Result<bool> {
+        if !self.is_sparse() {
+            let registers = &mut self.bytes[HLL_HDR_SIZE..];
+            if dense_get(registers, index) >= value {
+                return Ok(false);
+            }
+            dense_set(registers, index, value);
+            return Ok(true);
+        }
+        if value > HLL_SPARSE_VAL_MAX_VALUE {
+            self.make_dense()?;
+            return self.set_register(index, value);
+        }
+        //find the opcode covering the register, and the one before it
+        let mut position = HLL_HDR_SIZE;
+        let mut previous = None;
+        let mut first = 0;
+        let op = loop {
+            let op = SparseOp::read(self.bytes.get(position..).unwrap_or_default())
+                .ok_or_else(|| anyhow::anyhow!(INVALID_HLL))?;
+            if index < first + op.span() {
+                break op;
+            }
+            previous = Some(position);
+            position += op.size();
+            first += op.span();
+        };
+        let last = first + op.span() - 1;
+        let mut sequence = Vec::new();
+        match op {
+            SparseOp::Val(current, _) if current >= value => return Ok(false),
+            SparseOp::Val(_, 1) | SparseOp::Zero(1) => SparseOp::Val(value, 1).write(&mut sequence),
+            SparseOp::Zero(_) | SparseOp::XZero(_) => {
+                if index != first {
+                    SparseOp::zeros(index - first).write(&mut sequence);
+                }
+                SparseOp::Val(value, 1).write(&mut sequence);
+                if index != last {
+                    SparseOp::zeros(last - index).write(&mut sequence);
+                }
+            }
+            SparseOp::Val(current, _) => {
+                if index != first {
+                    SparseOp::Val(current, index - first).write(&mut sequence);
+                }
+                SparseOp::Val(value, 1).write(&mut sequence);
+                if index != last {
+                    SparseOp::Val(current, last - index).write(&mut sequence);
+                }
+            }
+        }
+        if sequence.len() > op.size() && self.bytes.len() + sequence.len() - op.size() > HLL_SPARSE_MAX_BYTES {
+            self.make_dense()?;
+            return self.set_register(index, value);
+        }
+        self.bytes.splice(position..position + op.size(), sequence);
+        self.merge_values(previous.unwrap_or(HLL_HDR_SIZE));
+        Ok(true)
+    }
+    //Merge adjacent VAL opcodes with the same value, scanning a few opcodes like redis
+    fn merge_values(&mut self, mut position: usize) {
+        for _ in 0..5 {
+            let op = match SparseOp::read(self.bytes.get(position..).unwrap_or_default()) {
+                Some(op) => op,
+                None => return,
+            };
+            let next = self.bytes.get(position + 1..).and_then(SparseOp::read);
+            match (op, next) {
+                (SparseOp::Val(value, len), Some(SparseOp::Val(next_value, next_len)))
+                    if value == next_value && len + next_len <= HLL_SPARSE_VAL_MAX_LEN =>
+                {
+                    let mut merged = Vec::new();
+                    SparseOp::Val(value, len + next_len).write(&mut merged);
+                    self.bytes.splice(position..position + 2, merged);
+                }
+                _ => position += op.size(),
+            }
+        }
+    }
+
+    //PFADD one element, true when a register changed
+    pub fn add(&mut self, element: &[u8]) -> Result<bool> {
+        let (index, count) = pattern_len(element);
+        let updated = self.set_register(index, count)?;
+        if updated {
+            self.invalidate_cache();
+        }
+        Ok(updated)
+    }
+    //Raise registers to the given values, what PFMERGE writes to its destination
+    pub fn merge_registers(&mut self, registers: &[u8], dense: bool) -> Result<()> {
+        if dense {
+            self.make_dense()?;
+        }
+        for (index, register) in registers.iter().enumerate() {
+            if *register > 0 {
+                self.set_register(index, *register)?;
+            }
+        }
+        self.invalidate_cache();
+        Ok(())
+    }
+    //Cardinality, served from the cached value when it is still valid
+    pub fn count(&mut self) -> Result<u64> {
+        if let Some(count) = self.cached_count() {
+            return Ok(count);
+        }
+        let count = count_registers(&self.registers()?);
+        self.bytes[8..16].copy_from_slice(&count.to_le_bytes());
+        Ok(count)
+    }
+    //PFDEBUG DECODE, e.g. "Z:8000 v:1,1 z:3"
+    pub fn decode(&self) -> Result<String> {
+        let ops = self.sparse_ops()?;
+        let decoded = ops
+            .iter()
+            .map(|op| match op {
+                SparseOp::Zero(len) => format!("z:{}", len),
+                SparseOp::XZero(len) => format!("Z:{}", len),
+                SparseOp::Val(value, len) => format!("v:{},{}", value, len),
+            })
+            .collect::<Vec<String>>();
+        Ok(decoded.join(" "))
+    }
+}
+
+//PFSELFTEST, register packing and the estimate error for both encodings
+pub fn self_test() -> Result<()> {
+    let mut registers = vec![0u8; HLL_DENSE_SIZE - HLL_HDR_SIZE];
+    let mut seed = 0x2545F4914F6CDD1Du64;
+    for _ in 0..100 {
+        let mut expected = Vec::with_capacity(HLL_REGISTERS);
+        for index in 0..HLL_REGISTERS {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            let value = (seed % (HLL_REGISTER_MAX as u64 + 1)) as u8;
+            dense_set(&mut registers, index, value);
+            expected.push(value);
+        }
+        for (index, value) in expected.iter().enumerate() {
+            if dense_get(&registers, index) != *value {
+                return Err(anyhow::anyhow!("TESTFAILED Register error at {}", index));
+            }
+        }
+    }
+    let mut sparse = HyperLogLog::new();
+    let mut dense = HyperLogLog::new();
+    dense.make_dense()?;
+    //relative error is 1.04/sqrt(m), allow 5 standard errors
+    let max_error = 5f64 * 1.04 / (HLL_REGISTERS as f64).sqrt();
+    let mut checkpoint = 10;
+    for element in 1..=100_000u64 {
+        sparse.add(element.to_string().as_bytes())?;
+        dense.add(element.to_string().as_bytes())?;
+        if element == checkpoint {
+            let count = dense.count()?;
+            if sparse.count()? != count {
+                return Err(anyhow::anyhow!("TESTFAILED sparse and dense disagree at {}", checkpoint));
+            }
+            let error = (count as f64 - checkpoint as f64).abs() / checkpoint as f64;
+            if error > max_error {
+                return Err(anyhow::anyhow!(
+                    "TESTFAILED Too big error. card:{} abserr:{}",
+                    checkpoint,
+                    error
+                ));
+            }
+            checkpoint *= 10;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::store::Store;
+
+    fn hyperloglog(elements: impl Iterator<Item = u64>) -> HyperLogLog {
+        let mut hll = HyperLogLog::new();
+        for element in elements {
+            hll.add(element.to_string().as_bytes()).unwrap();
+        }
+        hll
+    }
+
+    #[test]
+    fn hashes_match_redis() {
+        //MurmurHash64A from redis hyperloglog.c, seed 0xadc83b19
+        for (element, hash, index, count) in [
+            ("", 0xd8dfea6585bc9732u64, 5938, 2),
+            ("a", 0x53d2470a9b43b1a7, 12711, 2),
+            ("hello", 0x0f656f01eecfe400, 9216, 1),
+            ("12345678", 0x95ebb86389132953, 10579, 3),
+            ("redis-hyperloglog", 0x43aef1272907412a, 298, 1),
+        ] {
+            assert_eq!(murmurhash64a(element.as_bytes(), 0xadc83b19), hash, "{:?}", element);
+            assert_eq!(pattern_len(element.as_bytes()), (index, count), "{:?}", element);
+        }
+    }
+
+    #[test]
+    fn sparse_becomes_dense() {
+        //a register past the largest VAL value only fits the dense encoding
+        let mut hll = HyperLogLog::new();
+        assert!(hll.set_register(100, HLL_SPARSE_VAL_MAX_VALUE).unwrap());
+        assert!(hll.is_sparse());
+        assert!(hll.set_register(200, HLL_SPARSE_VAL_MAX_VALUE + 1).unwrap());
+        assert!(!hll.is_sparse());
+        assert_eq!(hll.as_bytes().len(), HLL_DENSE_SIZE);
+        let registers = hll.registers().unwrap();
+        assert_eq!((registers[100], registers[200]), (HLL_SPARSE_VAL_MAX_VALUE, HLL_SPARSE_VAL_MAX_VALUE + 1));
+        assert_eq!(registers.iter().filter(|register| **register > 0).count(), 2);
+
+        //so does a representation past HLL_SPARSE_MAX_BYTES, the registers carry over
+        let mut hll = HyperLogLog::new();
+        let mut element = 0;
+        while hll.is_sparse() {
+            let before = hll.registers().unwrap();
+            hll.add(element.to_string().as_bytes()).unwrap();
+            if !hll.is_sparse() {
+                let (index, count) = pattern_len(element.to_string().as_bytes());
+                let mut expected = before;
+                expected[index] = expected[index].max(count);
+                assert_eq!(hll.registers().unwrap(), expected);
+            } else {
+                assert!(hll.as_bytes().len() <= HLL_SPARSE_MAX_BYTES);
+            }
+            element += 1;
+        }
+        assert!(element > 100);
+        assert_eq!(hll.count().unwrap(), hyperloglog(0..element).count().unwrap());
+        assert!(!hll.make_dense().unwrap());
+    }
+
+    #[test]
+    fn merge_across_encodings() {
+        let mut store = Store::new();
+        let elements = |range: std::ops::Range<u64>| range.map(|element| element.to_string()).collect::<Vec<String>>();
+        store.add_hyperloglog("sparse", &elements(0..100)).unwrap();
+        store.add_hyperloglog("dense", &elements(50..5000)).unwrap();
+        assert!(store.get_hyperloglog("sparse").unwrap().unwrap().is_sparse());
+        assert!(!store.get_hyperloglog("dense").unwrap().unwrap().is_sparse());
+        let expected = hyperloglog(0..5000).count().unwrap();
+
+        //sparse sources give a sparse destination
+        store.merge_hyperloglog("only_sparse", &["sparse".to_string()]).unwrap();
+        assert!(store.get_hyperloglog("only_sparse").unwrap().unwrap().is_sparse());
+        assert_eq!(
+            store.count_hyperloglog(&["only_sparse".to_string()]).unwrap(),
+            hyperloglog(0..100).count().unwrap()
+        );
+
+        //one dense source makes it dense, in either order
+        for (destination, sources) in [("sparse", ["dense"]), ("dense", ["sparse"])] {
+            store
+                .merge_hyperloglog(destination, &sources.map(|source| source.to_string()))
+                .unwrap();
+            assert!(!store.get_hyperloglog(destination).unwrap().unwrap().is_sparse());
+            assert_eq!(store.count_hyperloglog(&[destination.to_string()]).unwrap(), expected);
+        }
+        //PFCOUNT over several keys is the count of their union
+        assert_eq!(
+            store.count_hyperloglog(&["only_sparse".to_string(), "dense".to_string()]).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn count_error_is_bounded() {
+        //standard error is 1.04/sqrt(m), 0.81% with 16384 registers, allow three of them
+        let max_error = 3f64 * 1.04 / (HLL_REGISTERS as f64).sqrt();
+        let mut sparse = HyperLogLog::new();
+        let mut dense = HyperLogLog::new();
+        dense.make_dense().unwrap();
+        let mut checkpoints = vec![1, 10, 100, 1000, 10_000, 100_000, 1_000_000].into_iter().peekable();
+        for element in 1..=1_000_000u64 {
+            sparse.add(element.to_string().as_bytes()).unwrap();
+            dense.add(element.to_string().as_bytes()).unwrap();
+            if checkpoints.next_if_eq(&element).is_some() {
+                let count = sparse.count().unwrap();
+                assert_eq!(count, dense.count().unwrap(), "encodings disagree at {}", element);
+                let error = (count as f64 - element as f64).abs() / element as f64;
+                assert!(error <= max_error, "count {} for {} elements", count, element);
+            }
+        }
+        assert!(checkpoints.next().is_none());
+    }
+}
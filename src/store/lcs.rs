@@ -0,0 +1,74 @@
+//Longest common subsequence of two strings, what LCS replies with
+
+//Contiguous run of the subsequence, inclusive byte ranges in both strings
+#[derive(Clone, Debug)]
+pub struct LcsMatch {
+    pub a: (usize, usize),
+    pub b: (usize, usize),
+}
+impl LcsMatch {
+    pub fn len(&self) -> usize {
+        self.a.1 - self.a.0 + 1
+    }
+}
+
+//Cells of the dynamic programming table, LCS refuses strings that would need more
+pub const LCS_MAX_CELLS: usize = 128 * 1024 * 1024;
+
+//The subsequence and its runs from the end of the strings backward, like redis walks the table
+pub fn lcs(a: &[u8], b: &[u8]) -> (Vec<u8>, Vec<LcsMatch>) {
+    let width = b.len() + 1;
+    let mut table = vec![0u32; (a.len() + 1) * width];
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            table[i * width + j] = if a[i - 1] == b[j - 1] {
+                table[(i - 1) * width + j - 1] + 1
+            } else {
+                table[(i - 1) * width + j].max(table[i * width + j - 1])
+            };
+        }
+    }
+    let mut index = table[a.len() * width + b.len()] as usize;
+    let mut result = vec![0u8; index];
+    let mut matches = Vec::new();
+    let mut current: Option<LcsMatch> = None;
+    let (mut i, mut j) = (a.len(), b.len());
+    while i > 0 && j > 0 {
+        let mut emit = false;
+        if a[i - 1] == b[j - 1] {
+            result[index - 1] = a[i - 1];
+            match current.as_mut() {
+                None => {
+                    current = Some(LcsMatch {
+                        a: (i - 1, i - 1),
+                        b: (j - 1, j - 1),
+                    })
+                }
+                //still contiguous, grow the run backward
+                Some(run) if run.a.0 == i && run.b.0 == j => {
+                    run.a.0 -= 1;
+                    run.b.0 -= 1;
+                }
+                Some(_) => emit = true,
+            }
+            //the start of one string was reached, the loop ends here
+            if current.as_ref().is_some_and(|run| run.a.0 == 0 || run.b.0 == 0) {
+                emit = true;
+            }
+            index -= 1;
+            i -= 1;
+            j -= 1;
+        } else {
+            if table[(i - 1) * width + j] > table[i * width + j - 1] {
+                i -= 1;
+            } else {
+                j -= 1;
+            }
+            emit = current.is_some();
+        }
+        if emit {
+            matches.extend(current.take());
+        }
+    }
+    (result, matches)
+}
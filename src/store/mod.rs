@@ -1,3 +1,12 @@
 pub mod store;
+pub mod bitmap;
+pub mod blocking;
 pub mod entry;
+pub mod geo;
+pub mod handle;
+pub mod hash;
+pub mod hyperloglog;
+pub mod lcs;
+pub mod set;
+pub mod sorted_set;
 pub mod transaction;
\ No newline at end of file
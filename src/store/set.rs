@@ -0,0 +1,101 @@
+use std::collections::HashSet;
+
+//Past this many members an intset becomes a hashtable, like set-max-intset-entries
+pub const SET_MAX_INTSET_ENTRIES: usize = 512;
+
+#[derive(Clone, Debug)]
+enum SetEncoding {
+    //sets of integers only, kept sorted so lookups are a binary search
+    Intset(Vec<i64>),
+    Hashtable(HashSet<String>),
+}
+
+//Only members that print back the same way can live in an intset, "007" stays a string
+fn parse_intset_member(member: &str) -> Option<i64> {
+    member
+        .parse::<i64>()
+        .ok()
+        .filter(|value| value.to_string() == member)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum SetOperation {
+    Inter,
+    Union,
+    Diff,
+}
+
+#[derive(Clone, Debug)]
+pub struct Set {
+    encoding: SetEncoding,
+}
+impl Set {
+    pub fn new() -> Self {
+        Set {
+            encoding: SetEncoding::Intset(Vec::new()),
+        }
+    }
+    pub fn is_intset(&self) -> bool {
+        matches!(self.encoding, SetEncoding::Intset(_))
+    }
+    pub fn len(&self) -> usize {
+        match &self.encoding {
+            SetEncoding::Intset(values) => values.len(),
+            SetEncoding::Hashtable(members) => members.len(),
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    pub fn contains(&self, member: &str) -> bool {
+        match &self.encoding {
+            SetEncoding::Intset(values) => {
+                parse_intset_member(member).is_some_and(|value| values.binary_search(&value).is_ok())
+            }
+            SetEncoding::Hashtable(members) => members.contains(member),
+        }
+    }
+    //Sorted for an intset, in no particular order for a hashtable
+    pub fn members(&self) -> Vec<String> {
+        match &self.encoding {
+            SetEncoding::Intset(values) => values.iter().map(|value| value.to_string()).collect(),
+            SetEncoding::Hashtable(members) => members.iter().cloned().collect(),
+        }
+    }
+    //True when the member is new
+    pub fn insert(&mut self, member: &str) -> bool {
+        if let SetEncoding::Intset(values) = &mut self.encoding {
+            if let Some(value) = parse_intset_member(member) {
+                match values.binary_search(&value) {
+                    Ok(_) => return false,
+                    Err(position) if values.len() < SET_MAX_INTSET_ENTRIES => {
+                        values.insert(position, value);
+                        return true;
+                    }
+                    Err(_) => {}
+                }
+            }
+            //never converted back, like redis
+            let members = values.iter().map(|value| value.to_string()).collect();
+            self.encoding = SetEncoding::Hashtable(members);
+        }
+        match &mut self.encoding {
+            SetEncoding::Hashtable(members) => members.insert(member.to_string()),
+            SetEncoding::Intset(_) => false,
+        }
+    }
+    pub fn remove(&mut self, member: &str) -> bool {
+        match &mut self.encoding {
+            SetEncoding::Intset(values) => {
+                match parse_intset_member(member).and_then(|value| values.binary_search(&value).ok()) {
+                    Some(position) => {
+                        values.remove(position);
+                        true
+                    }
+                    None => false,
+                }
+            }
+            SetEncoding::Hashtable(members) => members.remove(member),
+        }
+    }
+}
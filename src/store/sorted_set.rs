@@ -0,0 +1,641 @@
+use anyhow::Result;
+use std::{cmp::Ordering, collections::HashMap};
+
+use crate::store::store::random_index;
+
+const SKIPLIST_MAXLEVEL: usize = 32;
+//a node gets one more level with probability 1/SKIPLIST_BRANCHING
+const SKIPLIST_BRANCHING: usize = 4;
+
+#[derive(Clone, Debug)]
+struct SkipLevel {
+    forward: Option<usize>,
+    //nodes skipped by following forward, what makes rank queries O(log n)
+    span: usize,
+}
+#[derive(Clone, Debug)]
+struct SkipNode {
+    member: String,
+    score: f64,
+    backward: Option<usize>,
+    levels: Vec<SkipLevel>,
+}
+impl SkipNode {
+    fn cmp_entry(&self, score: f64, member: &str) -> Ordering {
+        self.score
+            .partial_cmp(&score)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| self.member.as_str().cmp(member))
+    }
+}
+
+//Nodes ordered by (score, member), kept in an arena where index 0 is the header
+#[derive(Clone, Debug)]
+struct SkipList {
+    nodes: Vec<SkipNode>,
+    free: Vec<usize>,
+    level: usize,
+    length: usize,
+    tail: Option<usize>,
+}
+impl SkipList {
+    fn new() -> Self {
+        SkipList {
+            nodes: vec![SkipNode {
+                member: String::new(),
+                score: 0f64,
+                backward: None,
+                levels: vec![SkipLevel { forward: None, span: 0 }; SKIPLIST_MAXLEVEL],
+            }],
+            free: Vec::new(),
+            level: 1,
+            length: 0,
+            tail: None,
+        }
+    }
+    fn random_level() -> usize {
+        let mut level = 1;
+        while level < SKIPLIST_MAXLEVEL && random_index(SKIPLIST_BRANCHING) == 0 {
+            level += 1;
+        }
+        level
+    }
+    fn forward(&self, node: usize, level: usize) -> Option<usize> {
+        self.nodes[node].levels[level].forward
+    }
+    fn entry(&self, node: usize) -> (String, f64) {
+        (self.nodes[node].member.clone(), self.nodes[node].score)
+    }
+    //Last node on each level that sorts before (score, member)
+    fn find_update(&self, score: f64, member: &str) -> ([usize; SKIPLIST_MAXLEVEL], [usize; SKIPLIST_MAXLEVEL]) {
+        let mut update = [0usize; SKIPLIST_MAXLEVEL];
+        let mut rank = [0usize; SKIPLIST_MAXLEVEL];
+        let mut x = 0;
+        for i in (0..self.level).rev() {
+            rank[i] = if i == self.level - 1 { 0 } else { rank[i + 1] };
+            while let Some(next) = self.forward(x, i) {
+                if self.nodes[next].cmp_entry(score, member) != Ordering::Less {
+                    break;
+                }
+                rank[i] += self.nodes[x].levels[i].span;
+                x = next;
+            }
+            update[i] = x;
+        }
+        (update, rank)
+    }
+    //The member must not be in the list already
+    fn insert(&mut self, score: f64, member: &str) {
+        let (mut update, mut rank) = self.find_update(score, member);
+        let level = Self::random_level();
+        if level > self.level {
+            for i in self.level..level {
+                rank[i] = 0;
+                update[i] = 0;
+                self.nodes[0].levels[i].span = self.length;
+            }
+            self.level = level;
+        }
+        let node = SkipNode {
+            member: member.to_string(),
+            score,
+            backward: None,
+            levels: vec![SkipLevel { forward: None, span: 0 }; level],
+        };
+        let x = match self.free.pop() {
+            Some(x) => {
+                self.nodes[x] = node;
+                x
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        };
+        for i in 0..level {
+            let previous = &mut self.nodes[update[i]].levels[i];
+            let (forward, span) = (previous.forward, previous.span);
+            previous.forward = Some(x);
+            previous.span = rank[0] - rank[i] + 1;
+            self.nodes[x].levels[i] = SkipLevel {
+                forward,
+                span: span - (rank[0] - rank[i]),
+            };
+        }
+        for (i, previous) in update.iter().enumerate().take(self.level).skip(level) {
+            self.nodes[*previous].levels[i].span += 1;
+        }
+        self.nodes[x].backward = if update[0] == 0 { None } else { Some(update[0]) };
+        match self.forward(x, 0) {
+            Some(next) => self.nodes[next].backward = Some(x),
+            None => self.tail = Some(x),
+        }
+        self.length += 1;
+    }
+    fn delete(&mut self, score: f64, member: &str) -> bool {
+        let (update, _) = self.find_update(score, member);
+        let x = match self.forward(update[0], 0) {
+            Some(x) if self.nodes[x].cmp_entry(score, member) == Ordering::Equal => x,
+            _ => return false,
+        };
+        for (i, previous) in update.iter().enumerate().take(self.level) {
+            if self.forward(*previous, i) == Some(x) {
+                let removed = self.nodes[x].levels[i].clone();
+                let previous = &mut self.nodes[*previous].levels[i];
+                previous.span += removed.span;
+                previous.span -= 1;
+                previous.forward = removed.forward;
+            } else {
+                self.nodes[*previous].levels[i].span -= 1;
+            }
+        }
+        match self.forward(x, 0) {
+            Some(next) => self.nodes[next].backward = self.nodes[x].backward,
+            None => self.tail = self.nodes[x].backward,
+        }
+        while self.level > 1 && self.forward(0, self.level - 1).is_none() {
+            self.level -= 1;
+        }
+        self.nodes[x].member = String::new();
+        self.free.push(x);
+        self.length -= 1;
+        true
+    }
+    //0 based position of (score, member)
+    fn rank(&self, score: f64, member: &str) -> Option<usize> {
+        let mut rank = 0;
+        let mut x = 0;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.forward(x, i) {
+                if self.nodes[next].cmp_entry(score, member) == Ordering::Greater {
+                    break;
+                }
+                rank += self.nodes[x].levels[i].span;
+                x = next;
+            }
+            if x != 0 && self.nodes[x].cmp_entry(score, member) == Ordering::Equal {
+                return Some(rank - 1);
+            }
+        }
+        None
+    }
+    fn at_rank(&self, rank: usize) -> Option<usize> {
+        let target = rank + 1;
+        let mut traversed = 0;
+        let mut x = 0;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.forward(x, i) {
+                if traversed + self.nodes[x].levels[i].span > target {
+                    break;
+                }
+                traversed += self.nodes[x].levels[i].span;
+                x = next;
+            }
+            if traversed == target {
+                return Some(x);
+            }
+        }
+        None
+    }
+    //First node that is not before the range, before must hold for a prefix of the list
+    fn first_where_not(&self, before: impl Fn(&SkipNode) -> bool) -> Option<usize> {
+        let mut x = 0;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.forward(x, i) {
+                if !before(&self.nodes[next]) {
+                    break;
+                }
+                x = next;
+            }
+        }
+        self.forward(x, 0)
+    }
+    //Last node still inside the range, within must hold for a prefix of the list
+    fn last_where(&self, within: impl Fn(&SkipNode) -> bool) -> Option<usize> {
+        let mut x = 0;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.forward(x, i) {
+                if !within(&self.nodes[next]) {
+                    break;
+                }
+                x = next;
+            }
+        }
+        if x == 0 {
+            None
+        } else {
+            Some(x)
+        }
+    }
+}
+
+//Score end of a range, "(1.5" excludes 1.5
+#[derive(Clone, Copy, Debug)]
+pub struct ScoreBound {
+    value: f64,
+    exclusive: bool,
+}
+impl ScoreBound {
+    pub fn parse(bound: &str) -> Option<Self> {
+        let (value, exclusive) = match bound.strip_prefix('(') {
+            Some(value) => (value, true),
+            None => (bound, false),
+        };
+        match value.parse::<f64>() {
+            Ok(value) if !value.is_nan() => Some(ScoreBound { value, exclusive }),
+            _ => None,
+        }
+    }
+    fn below_min(&self, score: f64) -> bool {
+        if self.exclusive {
+            score <= self.value
+        } else {
+            score < self.value
+        }
+    }
+    fn within_max(&self, score: f64) -> bool {
+        if self.exclusive {
+            score < self.value
+        } else {
+            score <= self.value
+        }
+    }
+}
+
+//Member end of a range, "-" and "+" are the smallest and largest strings
+#[derive(Clone, Debug)]
+pub enum LexBound {
+    Min,
+    Max,
+    Inclusive(String),
+    Exclusive(String),
+}
+impl LexBound {
+    pub fn parse(bound: &str) -> Option<Self> {
+        match bound {
+            "-" => Some(LexBound::Min),
+            "+" => Some(LexBound::Max),
+            _ => match bound.split_at_checked(1) {
+                Some(("[", member)) => Some(LexBound::Inclusive(member.to_string())),
+                Some(("(", member)) => Some(LexBound::Exclusive(member.to_string())),
+                _ => None,
+            },
+        }
+    }
+    fn below_min(&self, member: &str) -> bool {
+        match self {
+            LexBound::Min => false,
+            LexBound::Max => true,
+            LexBound::Inclusive(min) => member < min.as_str(),
+            LexBound::Exclusive(min) => member <= min.as_str(),
+        }
+    }
+    fn within_max(&self, member: &str) -> bool {
+        match self {
+            LexBound::Min => false,
+            LexBound::Max => true,
+            LexBound::Inclusive(max) => member <= max.as_str(),
+            LexBound::Exclusive(max) => member < max.as_str(),
+        }
+    }
+}
+
+//What ZRANGE selects, score and lex ranges are always given as (min, max)
+#[derive(Clone, Debug)]
+pub enum ZRangeSpec {
+    Rank(i64, i64),
+    Score(ScoreBound, ScoreBound),
+    Lex(LexBound, LexBound),
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct ZAddOptions {
+    pub nx: bool,
+    pub xx: bool,
+    pub gt: bool,
+    pub lt: bool,
+    pub incr: bool,
+}
+//What ZADD did with one element, with the score the member has afterwards
+#[derive(Clone, Copy, Debug)]
+pub enum ZAddResult {
+    Added(f64),
+    Updated(f64),
+    Unchanged(f64),
+    Skipped,
+}
+
+//How ZUNION and ZINTER merge the scores of a member found in several keys
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Aggregate {
+    Sum,
+    Min,
+    Max,
+}
+impl Aggregate {
+    pub fn parse(aggregate: &str) -> Option<Self> {
+        match aggregate.to_uppercase().as_str() {
+            "SUM" => Some(Aggregate::Sum),
+            "MIN" => Some(Aggregate::Min),
+            "MAX" => Some(Aggregate::Max),
+            _ => None,
+        }
+    }
+    pub fn apply(&self, current: f64, score: f64) -> f64 {
+        match self {
+            //inf + -inf is NaN, redis makes it 0
+            Aggregate::Sum => Some(current + score).filter(|sum| !sum.is_nan()).unwrap_or(0f64),
+            Aggregate::Min => current.min(score),
+            Aggregate::Max => current.max(score),
+        }
+    }
+}
+
+//Scores as redis prints them, inf and -inf included
+pub fn format_score(score: f64) -> String {
+    score.to_string()
+}
+
+//Tells whether a node is still inside the range being walked
+type NodeFilter<'a> = Box<dyn Fn(&SkipNode) -> bool + 'a>;
+
+//Sorted set, the map answers ZSCORE and the skiplist keeps the order
+#[derive(Clone, Debug)]
+pub struct SortedSet {
+    list: SkipList,
+    scores: HashMap<String, f64>,
+}
+impl SortedSet {
+    pub fn new() -> Self {
+        SortedSet {
+            list: SkipList::new(),
+            scores: HashMap::new(),
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+    pub fn score(&self, member: &str) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+    pub fn add(&mut self, member: &str, score: f64, options: &ZAddOptions) -> Result<ZAddResult> {
+        let current = self.score(member);
+        let score = match (current, options.incr) {
+            (Some(current), true) => current + score,
+            _ => score,
+        };
+        if score.is_nan() {
+            return Err(anyhow::anyhow!("ERR resulting score is not a number (NaN)"));
+        }
+        let current = match current {
+            None if options.xx => return Ok(ZAddResult::Skipped),
+            None => {
+                self.list.insert(score, member);
+                self.scores.insert(member.to_string(), score);
+                return Ok(ZAddResult::Added(score));
+            }
+            Some(current) => current,
+        };
+        if options.nx || (options.gt && score <= current) || (options.lt && score >= current) {
+            return Ok(ZAddResult::Skipped);
+        }
+        if score == current {
+            return Ok(ZAddResult::Unchanged(score));
+        }
+        self.list.delete(current, member);
+        self.list.insert(score, member);
+        self.scores.insert(member.to_string(), score);
+        Ok(ZAddResult::Updated(score))
+    }
+    pub fn remove(&mut self, member: &str) -> bool {
+        match self.scores.remove(member) {
+            Some(score) => self.list.delete(score, member),
+            None => false,
+        }
+    }
+    //0 based, counted from the highest score when reverse
+    pub fn rank(&self, member: &str, reverse: bool) -> Option<usize> {
+        let rank = self.list.rank(self.score(member)?, member)?;
+        Some(if reverse { self.len() - 1 - rank } else { rank })
+    }
+    pub fn count_by_score(&self, min: &ScoreBound, max: &ScoreBound) -> usize {
+        let first = match self.list.first_where_not(|node| min.below_min(node.score)) {
+            Some(first) => first,
+            None => return 0,
+        };
+        let last = match self.list.last_where(|node| max.within_max(node.score)) {
+            Some(last) => last,
+            None => return 0,
+        };
+        let (first, last) = (self.list.entry(first), self.list.entry(last));
+        match (self.list.rank(first.1, &first.0), self.list.rank(last.1, &last.0)) {
+            (Some(first), Some(last)) if first <= last => last - first + 1,
+            _ => 0,
+        }
+    }
+    //Members with their scores, skipping offset matches and keeping at most limit
+    pub fn range(&self, spec: &ZRangeSpec, reverse: bool, offset: usize, limit: Option<usize>) -> Vec<(String, f64)> {
+        let limit = limit.unwrap_or(usize::MAX);
+        let (start, in_range): (Option<usize>, NodeFilter) = match spec {
+            ZRangeSpec::Rank(start, stop) => {
+                let len = self.len() as i64;
+                let start = if *start < 0 { (len + start).max(0) } else { *start };
+                let stop = if *stop < 0 { len + stop } else { (*stop).min(len - 1) };
+                if start > stop || start >= len {
+                    return Vec::new();
+                }
+                let first = if reverse { len - 1 - start } else { start };
+                let count = (stop - start + 1) as usize;
+                let mut result = Vec::new();
+                let mut node = self.list.at_rank(first as usize);
+                while let Some(x) = node.filter(|_| result.len() < count) {
+                    result.push(self.list.entry(x));
+                    node = self.step(x, reverse);
+                }
+                return result;
+            }
+            ZRangeSpec::Score(min, max) if reverse => (
+                self.list.last_where(|node| max.within_max(node.score)),
+                Box::new(move |node: &SkipNode| !min.below_min(node.score)),
+            ),
+            ZRangeSpec::Score(min, max) => (
+                self.list.first_where_not(|node| min.below_min(node.score)),
+                Box::new(move |node: &SkipNode| max.within_max(node.score)),
+            ),
+            ZRangeSpec::Lex(min, max) if reverse => (
+                self.list.last_where(|node| max.within_max(&node.member)),
+                Box::new(move |node: &SkipNode| !min.below_min(&node.member)),
+            ),
+            ZRangeSpec::Lex(min, max) => (
+                self.list.first_where_not(|node| min.below_min(&node.member)),
+                Box::new(move |node: &SkipNode| max.within_max(&node.member)),
+            ),
+        };
+        let mut result = Vec::new();
+        let mut skipped = 0;
+        let mut node = start;
+        while let Some(x) = node {
+            if result.len() >= limit || !in_range(&self.list.nodes[x]) {
+                break;
+            }
+            if skipped < offset {
+                skipped += 1;
+            } else {
+                result.push(self.list.entry(x));
+            }
+            node = self.step(x, reverse);
+        }
+        result
+    }
+    fn step(&self, node: usize, reverse: bool) -> Option<usize> {
+        if reverse {
+            self.list.nodes[node].backward
+        } else {
+            self.list.forward(node, 0)
+        }
+    }
+    //Remove up to count members from the lowest scores, or the highest when max
+    pub fn pop(&mut self, count: usize, max: bool) -> Vec<(String, f64)> {
+        let mut popped = Vec::new();
+        while popped.len() < count {
+            let node = if max { self.list.tail } else { self.list.forward(0, 0) };
+            let (member, score) = match node {
+                Some(node) => self.list.entry(node),
+                None => break,
+            };
+            self.remove(&member);
+            popped.push((member, score));
+        }
+        popped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Every span must be the number of level 0 steps to the forward node, or to the end
+    fn check_spans(list: &SkipList) {
+        let mut positions = HashMap::new();
+        let mut node = list.forward(0, 0);
+        while let Some(x) = node {
+            positions.insert(x, positions.len() + 1);
+            node = list.forward(x, 0);
+        }
+        assert_eq!(positions.len(), list.length);
+        let mut nodes = vec![0];
+        nodes.extend(positions.keys().copied());
+        for x in nodes {
+            let position = if x == 0 { 0 } else { positions[&x] };
+            for i in 0..list.level.min(list.nodes[x].levels.len()) {
+                let level = &list.nodes[x].levels[i];
+                let expected = match level.forward {
+                    Some(forward) => positions[&forward] - position,
+                    None => list.length - position,
+                };
+                assert_eq!(level.span, expected, "node {} level {}", x, i);
+            }
+        }
+    }
+
+    fn sorted_set(members: &[(&str, f64)]) -> SortedSet {
+        let mut sorted_set = SortedSet::new();
+        for (member, score) in members {
+            sorted_set.add(member, *score, &ZAddOptions::default()).unwrap();
+        }
+        sorted_set
+    }
+
+    fn members(range: Vec<(String, f64)>) -> Vec<String> {
+        range.into_iter().map(|(member, _)| member).collect()
+    }
+
+    #[test]
+    fn spans_hold_through_inserts_and_deletes() {
+        let mut sorted_set = SortedSet::new();
+        for index in 0..500 {
+            let score = ((index * 7919) % 500) as f64;
+            sorted_set.add(&format!("m{}", index), score, &ZAddOptions::default()).unwrap();
+        }
+        check_spans(&sorted_set.list);
+        for index in (0..500).step_by(3) {
+            assert!(sorted_set.remove(&format!("m{}", index)));
+        }
+        check_spans(&sorted_set.list);
+        assert_eq!(sorted_set.len(), 333);
+        assert!(!sorted_set.remove("m0"));
+        //freed nodes are reused
+        for index in 0..100 {
+            sorted_set.add(&format!("n{}", index), index as f64, &ZAddOptions::default()).unwrap();
+        }
+        check_spans(&sorted_set.list);
+        assert_eq!(sorted_set.list.length, sorted_set.len());
+    }
+
+    #[test]
+    fn rank_follows_score_then_member() {
+        let mut sorted_set = sorted_set(&[("c", 1.0), ("b", 1.0), ("a", 2.0), ("d", 0.5)]);
+        assert_eq!(sorted_set.rank("d", false), Some(0));
+        assert_eq!(sorted_set.rank("b", false), Some(1));
+        assert_eq!(sorted_set.rank("c", false), Some(2));
+        assert_eq!(sorted_set.rank("a", false), Some(3));
+        assert_eq!(sorted_set.rank("a", true), Some(0));
+        assert_eq!(sorted_set.rank("missing", false), None);
+        sorted_set.add("d", 5.0, &ZAddOptions::default()).unwrap();
+        assert_eq!(sorted_set.rank("d", false), Some(3));
+        assert_eq!(sorted_set.rank("b", false), Some(0));
+        check_spans(&sorted_set.list);
+    }
+
+    #[test]
+    fn score_ranges() {
+        let sorted_set = sorted_set(&[("a", 1.0), ("b", 2.0), ("c", 3.0), ("d", 4.0)]);
+        let score_range = |min: &str, max: &str, reverse: bool| {
+            let spec = ZRangeSpec::Score(ScoreBound::parse(min).unwrap(), ScoreBound::parse(max).unwrap());
+            members(sorted_set.range(&spec, reverse, 0, None))
+        };
+        assert_eq!(score_range("2", "3", false), ["b", "c"]);
+        assert_eq!(score_range("(2", "3", false), ["c"]);
+        assert_eq!(score_range("2", "(3", false), ["b"]);
+        assert_eq!(score_range("(2", "(3", false), Vec::<String>::new());
+        assert_eq!(score_range("-inf", "+inf", false), ["a", "b", "c", "d"]);
+        assert_eq!(score_range("-inf", "(2", true), ["a"]);
+        assert_eq!(score_range("(1", "+inf", true), ["d", "c", "b"]);
+        assert_eq!(score_range("5", "+inf", false), Vec::<String>::new());
+        assert_eq!(score_range("3", "1", false), Vec::<String>::new());
+        let min = ScoreBound::parse("(1").unwrap();
+        let max = ScoreBound::parse("4").unwrap();
+        assert_eq!(sorted_set.count_by_score(&min, &max), 3);
+        let limited = sorted_set.range(&ZRangeSpec::Score(min, max), false, 1, Some(1));
+        assert_eq!(members(limited), ["c"]);
+    }
+
+    #[test]
+    fn rank_ranges() {
+        let sorted_set = sorted_set(&[("a", 1.0), ("b", 2.0), ("c", 3.0)]);
+        let rank_range = |start, stop, reverse| members(sorted_set.range(&ZRangeSpec::Rank(start, stop), reverse, 0, None));
+        assert_eq!(rank_range(0, -1, false), ["a", "b", "c"]);
+        assert_eq!(rank_range(-2, -1, false), ["b", "c"]);
+        assert_eq!(rank_range(-100, 100, false), ["a", "b", "c"]);
+        assert_eq!(rank_range(0, 0, true), ["c"]);
+        assert_eq!(rank_range(3, 10, false), Vec::<String>::new());
+        assert_eq!(rank_range(2, 1, false), Vec::<String>::new());
+        assert_eq!(rank_range(0, -4, false), Vec::<String>::new());
+    }
+
+    #[test]
+    fn lex_ranges() {
+        let sorted_set = sorted_set(&[("a", 0.0), ("b", 0.0), ("c", 0.0), ("d", 0.0)]);
+        let lex_range = |min: &str, max: &str, reverse: bool| {
+            let spec = ZRangeSpec::Lex(LexBound::parse(min).unwrap(), LexBound::parse(max).unwrap());
+            members(sorted_set.range(&spec, reverse, 0, None))
+        };
+        assert_eq!(lex_range("-", "+", false), ["a", "b", "c", "d"]);
+        assert_eq!(lex_range("[b", "(d", false), ["b", "c"]);
+        assert_eq!(lex_range("(b", "[d", true), ["d", "c"]);
+        assert_eq!(lex_range("+", "-", false), Vec::<String>::new());
+    }
+}
@@ -1,53 +1,699 @@
-use crate::store::{entry::Entry};
+use crate::{
+    pubsub::notify::{
+        keyspace_messages, Notifier, NOTIFY_EXPIRED, NOTIFY_GENERIC, NOTIFY_HASH, NOTIFY_LIST,
+        NOTIFY_SET, NOTIFY_STRING, NOTIFY_ZSET,
+    },
+    rdb::parse_rdb::{self, RdbFile},
+    resp::{resp::unwrap_value_to_string, value::Value},
+    store::{
+        blocking::BlockingKeys,
+        entry::{Entry, StreamEntryValidate},
+        hash::Hash,
+        hyperloglog::{count_registers, HyperLogLog, HLL_REGISTERS},
+        set::{Set, SetOperation},
+        sorted_set::{format_score, Aggregate, ScoreBound, SortedSet, ZAddOptions, ZAddResult, ZRangeSpec},
+    },
+};
 use anyhow::{Result};
 use chrono::{DateTime, Duration, Utc};
-use std::collections::{HashMap, VecDeque};
+use std::{
+    borrow::Cow,
+    collections::{hash_map::RandomState, HashMap, VecDeque},
+    hash::{BuildHasher, Hasher},
+};
 
 #[derive(Clone, Debug)]
 pub enum StoreValueType {
-    String(String),
+    //binary safe, bitmaps live here
+    String(Vec<u8>),
+    //a string changed in place (APPEND, SETRANGE, SETBIT, BITFIELD), redis never makes those embstr again
+    RawString(Vec<u8>),
     Interger(i64),
-    List(VecDeque<String>)
+    List(VecDeque<String>),
+    Hash(Hash),
+    Set(Set),
+    SortedSet(SortedSet),
+    //a string to TYPE and GET, kept apart so its bytes survive
+    HyperLogLog(HyperLogLog),
 }
 impl StoreValueType {
+    //Name reported by TYPE
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            StoreValueType::String(_)
+            | StoreValueType::RawString(_)
+            | StoreValueType::Interger(_)
+            | StoreValueType::HyperLogLog(_) => "string",
+            StoreValueType::List(_) => "list",
+            StoreValueType::Hash(_) => "hash",
+            StoreValueType::Set(_) => "set",
+            StoreValueType::SortedSet(_) => "zset",
+        }
+    }
+    //Name reported by OBJECT ENCODING, an integer is only a compact way to keep a string
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            StoreValueType::Interger(_) => "int",
+            StoreValueType::String(bytes) if bytes.len() <= OBJ_ENCODING_EMBSTR_SIZE_LIMIT => "embstr",
+            StoreValueType::String(_) | StoreValueType::RawString(_) | StoreValueType::HyperLogLog(_) => "raw",
+            StoreValueType::List(list) => {
+                if list.len() <= LIST_MAX_LISTPACK_ENTRIES
+                    && list.iter().all(|value| value.len() <= LIST_MAX_LISTPACK_VALUE)
+                {
+                    "listpack"
+                } else {
+                    "quicklist"
+                }
+            }
+            StoreValueType::Hash(hash) if hash.is_listpack() => "listpack",
+            StoreValueType::Set(set) if set.is_intset() => "intset",
+            StoreValueType::Hash(_) | StoreValueType::Set(_) => "hashtable",
+            StoreValueType::SortedSet(_) => "skiplist",
+        }
+    }
     pub fn to_string(&self) -> String {
         match self {
-            StoreValueType::String(s) => s.clone(),
+            StoreValueType::String(s) | StoreValueType::RawString(s) => String::from_utf8_lossy(s).to_string(),
             StoreValueType::Interger(i) => i.to_string(),
+            StoreValueType::HyperLogLog(hll) => String::from_utf8_lossy(hll.as_bytes()).to_string(),
             _ => "".to_string()
         }
     }
+    //Raw bytes of a string value, empty for other types
+    pub fn to_bytes(&self) -> Cow<'_, [u8]> {
+        match self {
+            StoreValueType::String(s) | StoreValueType::RawString(s) => Cow::Borrowed(s),
+            StoreValueType::Interger(i) => Cow::Owned(i.to_string().into_bytes()),
+            StoreValueType::HyperLogLog(hll) => Cow::Borrowed(hll.as_bytes()),
+            _ => Cow::Borrowed(&[]),
+        }
+    }
+}
+
+//Position for an index that may count from the tail
+fn list_index(len: usize, index: i64) -> Option<usize> {
+    let index = if index < 0 { len as i64 + index } else { index };
+    if index < 0 || index >= len as i64 {
+        None
+    } else {
+        Some(index as usize)
+    }
+}
+
+//Uniform pick in 0..bound, bound must not be 0
+pub fn random_index(bound: usize) -> usize {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_i64(chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default());
+    (hasher.finish() % bound as u64) as usize
+}
+
+//Longest string redis allocates together with its object
+const OBJ_ENCODING_EMBSTR_SIZE_LIMIT: usize = 44;
+//Lists are one block in memory, the sizes OBJECT ENCODING calls listpack
+const LIST_MAX_LISTPACK_ENTRIES: usize = 128;
+const LIST_MAX_LISTPACK_VALUE: usize = 64;
+
+pub const WRONGTYPE: &str = "WRONGTYPE Operation against a key holding the wrong kind of value";
+pub const WRONGTYPE_HLL: &str = "WRONGTYPE Key is not a valid HyperLogLog string value.";
+
+//Integers and HyperLogLogs written as plain strings get their own representation
+fn parse_store_value(value: Vec<u8>) -> StoreValueType {
+    //no i64 is longer than 20 characters, big bitmaps are not scanned
+    match value.len() <= 20 {
+        true => match parse_integer(&value) {
+            Some(number) => StoreValueType::Interger(number),
+            None => plain_store_value(value),
+        },
+        false => plain_store_value(value),
+    }
+}
+//A string written as a whole, short ones are embstr again
+fn plain_store_value(value: Vec<u8>) -> StoreValueType {
+    match raw_store_value(value) {
+        StoreValueType::RawString(value) => StoreValueType::String(value),
+        value => value,
+    }
+}
+//Strings changed in place like APPEND or SETBIT stay raw, only a HyperLogLog is recognized
+fn raw_store_value(value: Vec<u8>) -> StoreValueType {
+    if value.starts_with(b"HYLL") {
+        if let Some(hll) = HyperLogLog::from_bytes(&value) {
+            return StoreValueType::HyperLogLog(hll);
+        }
+    }
+    StoreValueType::RawString(value)
+}
+//Only integers that print back the same way, "007" or "+1" stay strings like in redis
+fn parse_integer(bytes: &[u8]) -> Option<i64> {
+    let text = std::str::from_utf8(bytes).ok()?;
+    text.parse::<i64>().ok().filter(|number| number.to_string() == text)
 }
 
 #[derive(Clone)]
 pub struct Store {
     collections: HashMap<String, (StoreValueType, Option<DateTime<Utc>>)>,
-    pub entry: Entry
+    pub entry: Entry,
+    //clients waiting in BLPOP or XREAD BLOCK, woken by writes to their keys
+    pub blocking: BlockingKeys,
+    //bumped on every write to a key, WATCH compares them at EXEC
+    versions: HashMap<String, u64>,
+    next_version: u64,
+    //keyspace notifications, see pubsub::notify
+    notify_flags: u32,
+    notifier: Option<Notifier>,
 }
 impl Store {
     pub fn new() -> Self {
         Store {
             collections: HashMap::new(),
             entry: Entry::new(),
+            blocking: BlockingKeys::new(),
+            versions: HashMap::new(),
+            next_version: 1,
+            notify_flags: 0,
+            notifier: None,
             // transaction: Transaction::new()
         }
     }
 
-    pub fn set_value(&mut self, key: &str, value: &str, px: Option<&str>) -> Result<String> {
+    pub fn set_value(&mut self, key: &str, value: &[u8], px: Option<&str>) -> Result<String> {
         let px = match px {
             Some(px_time) => {
                 Some(chrono::Utc::now() + Duration::milliseconds(px_time.parse::<i64>().unwrap()))
             }
             None => None,
         };
-        let value = match value.parse::<i64>() {
-            Ok(value) => StoreValueType::Interger(value),
-            _ => StoreValueType::String(value.to_string()),
-        };
+        let value = parse_store_value(value.to_vec());
         // println!("LOG_FROM_set_value value and px: {:?}:{:?}", value, px);
+        //SET replaces whatever the key held, streams included
+        self.entry.remove_stream_key(key);
         self.collections.insert(key.to_string(), (value, px));
+        self.touch(key);
+        self.notify(NOTIFY_STRING, "set", key);
+        if px.is_some() {
+            self.notify(NOTIFY_GENERIC, "expire", key);
+        }
         Ok(String::from("OK"))
     }
+    //SET with KEEPTTL, the new value keeps the expiry the old one had
+    pub fn set_value_keep_ttl(&mut self, key: &str, value: &[u8]) -> Result<String> {
+        let px = self.get_alive(key).and(self.collections.get(key)).and_then(|(_, px)| *px);
+        self.set_value(key, value, None)?;
+        if let Some(entry) = self.collections.get_mut(key) {
+            entry.1 = px;
+        }
+        Ok(String::from("OK"))
+    }
+
+    //Mark key as modified for clients watching it
+    pub fn touch(&mut self, key: &str) {
+        self.versions.insert(key.to_string(), self.next_version);
+        self.next_version += 1;
+    }
+    pub fn get_version(&self, key: &str) -> u64 {
+        self.versions.get(key).copied().unwrap_or(0)
+    }
+
+    pub fn set_notifier(&mut self, notifier: Notifier) {
+        self.notifier = Some(notifier);
+    }
+    pub fn set_notify_flags(&mut self, notify_flags: u32) {
+        self.notify_flags = notify_flags;
+    }
+    pub fn get_notify_flags(&self) -> u32 {
+        self.notify_flags
+    }
+    //Queue __keyspace@0__ / __keyevent@0__ messages if the class is enabled
+    pub fn notify(&self, class: u32, event: &str, key: &str) {
+        if let Some(notifier) = &self.notifier {
+            for message in keyspace_messages(self.notify_flags, class, event, key) {
+                let _ = notifier.send(message);
+            }
+        }
+    }
+
+    //A blocked client is done, hand leftover list or sorted set items to the next one in line
+    pub fn unblock(&mut self, id: u64, keys: &[String]) {
+        self.blocking.unregister(id, keys);
+        for key in keys {
+            if self.get_list_size(key).unwrap_or(0) > 0 || self.get_sorted_set_size(key).unwrap_or(0) > 0 {
+                self.blocking.signal(key);
+            }
+        }
+    }
+    //New stream entries can be read by every blocked XREAD
+    pub fn signal_stream(&self, key: &str) {
+        self.blocking.signal_all(key);
+    }
+
+    //Lazy expire, drop the given keys if their ttl ran out
+    pub fn expire_keys(&mut self, keys: &[String]) {
+        let now = chrono::Utc::now();
+        for key in keys {
+            if self
+                .collections
+                .get(key)
+                .is_some_and(|(_, px)| px.is_some_and(|px| px < now))
+            {
+                self.expire(key);
+            } else {
+                self.expire_hash_fields_due(key);
+            }
+        }
+    }
+    //Active expire, drop every key whose ttl ran out and every hash field past its HEXPIRE
+    pub fn active_expire_cycle(&mut self) {
+        let now = chrono::Utc::now();
+        let expired = self
+            .collections
+            .iter()
+            .filter(|(_, (_, px))| px.is_some_and(|px| px < now))
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<String>>();
+        for key in expired.iter() {
+            self.expire(key);
+        }
+        let hashes = self
+            .collections
+            .iter()
+            .filter(|(_, (value, _))| matches!(value, StoreValueType::Hash(hash) if hash.has_expired()))
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<String>>();
+        for key in hashes.iter() {
+            self.expire_hash_fields_due(key);
+        }
+    }
+    fn expire(&mut self, key: &str) {
+        self.collections.remove(key);
+        self.touch(key);
+        self.notify(NOTIFY_EXPIRED, "expired", key);
+    }
+
+    //Drop every key, used before loading a new dataset
+    pub fn flush(&mut self) {
+        for key in self.keys() {
+            self.touch(&key);
+        }
+        self.collections.clear();
+        self.entry = Entry::new();
+    }
+    pub fn load_rdb_file(&mut self, rdb_file: &RdbFile) -> Result<()> {
+        for (key, entry) in rdb_file.map.iter() {
+            if entry.1.is_some_and(|px| px < chrono::Utc::now()) {
+                continue;
+            }
+            self.collections
+                .insert(key.to_owned(), (parse_store_value(entry.0.clone().into_bytes()), entry.1));
+            self.touch(key);
+        }
+        for (key, entry) in rdb_file.sets.iter() {
+            if entry.expiry.is_some_and(|px| px < chrono::Utc::now()) {
+                continue;
+            }
+            let mut set = Set::new();
+            for member in entry.members.iter() {
+                set.insert(member);
+            }
+            self.collections
+                .insert(key.to_owned(), (StoreValueType::Set(set), entry.expiry));
+            self.touch(key);
+        }
+        for (key, entry) in rdb_file.binaries.iter() {
+            if entry.expiry.is_some_and(|px| px < chrono::Utc::now()) {
+                continue;
+            }
+            self.collections
+                .insert(key.to_owned(), (parse_store_value(entry.bytes.clone()), entry.expiry));
+            self.touch(key);
+        }
+        for (key, entry) in rdb_file.lists.iter() {
+            if entry.expiry.is_some_and(|px| px < chrono::Utc::now()) {
+                continue;
+            }
+            self.collections.insert(
+                key.to_owned(),
+                (StoreValueType::List(entry.values.iter().cloned().collect()), entry.expiry),
+            );
+            self.touch(key);
+        }
+        for (key, entry) in rdb_file.hashes.iter() {
+            if entry.expiry.is_some_and(|px| px < chrono::Utc::now()) {
+                continue;
+            }
+            let mut hash = Hash::new();
+            for (field, value, expire) in entry.fields.iter() {
+                hash.set(field, value);
+                if let Some(expire) = expire {
+                    hash.set_expire(field, *expire);
+                }
+            }
+            //fields that expired while saved are dropped now
+            hash.purge_expired();
+            if !hash.is_empty() {
+                self.collections
+                    .insert(key.to_owned(), (StoreValueType::Hash(hash), entry.expiry));
+                self.touch(key);
+            }
+        }
+        for (key, entry) in rdb_file.sorted_sets.iter() {
+            if entry.expiry.is_some_and(|px| px < chrono::Utc::now()) {
+                continue;
+            }
+            let mut sorted_set = SortedSet::new();
+            for (member, score) in entry.members.iter() {
+                sorted_set.add(member, *score, &ZAddOptions::default())?;
+            }
+            self.collections
+                .insert(key.to_owned(), (StoreValueType::SortedSet(sorted_set), entry.expiry));
+            self.touch(key);
+        }
+        for (key, entry) in rdb_file.streams.iter() {
+            self.entry.add_new_stream_key(key)?;
+            for (stream_id, fields) in entry.entries.iter() {
+                if let StreamEntryValidate::Successfull(_) = self.entry.add_stream(key, stream_id) {
+                    for (field, value) in fields.iter() {
+                        self.entry.add_to_stream(key, stream_id, field, value)?;
+                    }
+                }
+            }
+            self.touch(key);
+        }
+        Ok(())
+    }
+
+    //Snapshot of the keys that are still alive, used for full resync
+    pub fn to_rdb_file(&self) -> RdbFile {
+        let mut rdb_file = RdbFile::new();
+        for (key, (value, px)) in self.collections.iter() {
+            if px.is_some_and(|px| px < chrono::Utc::now()) {
+                continue;
+            }
+            match value {
+                StoreValueType::String(bytes) | StoreValueType::RawString(bytes)
+                    if std::str::from_utf8(bytes).is_err() =>
+                {
+                    rdb_file.binaries.insert(
+                        key.to_owned(),
+                        parse_rdb::BinaryEntry {
+                            bytes: bytes.clone(),
+                            expiry: *px,
+                        },
+                    );
+                }
+                StoreValueType::String(_) | StoreValueType::RawString(_) | StoreValueType::Interger(_) => {
+                    rdb_file
+                        .map
+                        .insert(key.to_owned(), parse_rdb::Entry(value.to_string(), *px));
+                }
+                StoreValueType::Set(set) => {
+                    rdb_file.sets.insert(
+                        key.to_owned(),
+                        parse_rdb::SetEntry {
+                            members: set.members(),
+                            intset: set.is_intset(),
+                            expiry: *px,
+                        },
+                    );
+                }
+                StoreValueType::HyperLogLog(hll) => {
+                    rdb_file.binaries.insert(
+                        key.to_owned(),
+                        parse_rdb::BinaryEntry {
+                            bytes: hll.as_bytes().to_vec(),
+                            expiry: *px,
+                        },
+                    );
+                }
+                StoreValueType::List(list) => {
+                    rdb_file.lists.insert(
+                        key.to_owned(),
+                        parse_rdb::ListEntry {
+                            values: list.iter().cloned().collect(),
+                            expiry: *px,
+                        },
+                    );
+                }
+                StoreValueType::Hash(hash) => {
+                    let fields = hash
+                        .pairs()
+                        .into_iter()
+                        .map(|(field, value)| (field.clone(), value.clone(), hash.get_expire(field)))
+                        .collect();
+                    rdb_file
+                        .hashes
+                        .insert(key.to_owned(), parse_rdb::HashEntry { fields, expiry: *px });
+                }
+                StoreValueType::SortedSet(sorted_set) => {
+                    rdb_file.sorted_sets.insert(
+                        key.to_owned(),
+                        parse_rdb::SortedSetEntry {
+                            members: sorted_set.range(&ZRangeSpec::Rank(0, -1), false, 0, None),
+                            expiry: *px,
+                        },
+                    );
+                }
+            }
+        }
+        for key in self.entry.get_stream_keys() {
+            let entries = self
+                .entry
+                .get_streams(&key)
+                .into_iter()
+                .filter_map(|stream| {
+                    let fields = stream.get_collection().ok()?;
+                    let fields = fields.iter().map(|(field, value)| (field.clone(), value.clone()));
+                    Some((stream.get_stream_id().ok()?, fields.collect()))
+                })
+                .collect();
+            rdb_file.streams.insert(key, parse_rdb::StreamEntry { entries });
+        }
+        rdb_file
+    }
+
+    //Every key that is still alive, streams included
+    pub fn keys(&self) -> Vec<String> {
+        let now = chrono::Utc::now();
+        let mut keys = self
+            .collections
+            .iter()
+            .filter(|(_, (_, px))| px.is_none_or(|px| px >= now))
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<String>>();
+        keys.extend(self.entry.get_stream_keys());
+        keys
+    }
+    pub fn exists(&self, key: &str) -> bool {
+        self.get_type(key).is_some()
+    }
+    //Type name as TYPE reports it, None when the key does not exist
+    pub fn get_type(&self, key: &str) -> Option<&'static str> {
+        if self.entry.check_stream_key_exist(key) {
+            return Some("stream");
+        }
+        self.get_alive(key).map(|value| value.type_name())
+    }
+    //OBJECT ENCODING, None when the key does not exist
+    pub fn get_encoding(&self, key: &str) -> Option<&'static str> {
+        if self.entry.check_stream_key_exist(key) {
+            return Some("stream");
+        }
+        self.get_alive(key).map(|value| value.encoding())
+    }
+    //One keyspace for every type, a key holding another type is an error
+    pub fn check_type(&self, key: &str, expected: &str) -> Result<()> {
+        match self.get_type(key) {
+            Some(key_type) if key_type != expected => Err(anyhow::anyhow!(WRONGTYPE)),
+            _ => Ok(()),
+        }
+    }
+    //Create an empty stream, dropping an expired value left under the same key
+    pub fn add_stream_key(&mut self, key: &str) -> Result<()> {
+        self.check_type(key, "stream")?;
+        if !self.entry.check_stream_key_exist(key) {
+            self.collections.remove(key);
+            self.entry.add_new_stream_key(key)?;
+        }
+        Ok(())
+    }
+    fn get_alive(&self, key: &str) -> Option<&StoreValueType> {
+        match self.collections.get(key) {
+            Some((value, px)) if px.is_none_or(|px| px >= chrono::Utc::now()) => Some(value),
+            _ => None,
+        }
+    }
+    pub fn remove(&mut self, key: &str) -> bool {
+        let removed = self.delete(key);
+        if removed {
+            self.notify(NOTIFY_GENERIC, "del", key);
+        }
+        removed
+    }
+    fn delete(&mut self, key: &str) -> bool {
+        let removed = self.collections.remove(key).is_some();
+        let removed = self.entry.remove_stream_key(key) || removed;
+        if removed {
+            self.touch(key);
+        }
+        removed
+    }
+
+    //Serialized value and remaining ttl in milliseconds, the format RESTORE reads back
+    pub fn dump(&self, key: &str) -> Option<(String, Option<i64>)> {
+        if self.entry.check_stream_key_exist(key) {
+            let mut payload = vec![Value::BulkString("stream".to_string())];
+            for stream in self.entry.get_streams(key) {
+                let mut fields = Vec::new();
+                for (field, value) in stream.get_collection().ok()? {
+                    fields.push(Value::BulkString(field.clone()));
+                    fields.push(Value::BulkString(value.clone()));
+                }
+                payload.push(Value::Array(vec![
+                    Value::BulkString(stream.get_stream_id().ok()?),
+                    Value::Array(fields),
+                ]));
+            }
+            return Some((String::from_utf8(Value::Array(payload).serialize()).ok()?, None));
+        }
+        let (value, px) = self.collections.get(key)?;
+        let ttl = match px {
+            Some(px) if *px < chrono::Utc::now() => return None,
+            Some(px) => Some((*px - chrono::Utc::now()).num_milliseconds().max(1)),
+            None => None,
+        };
+        let payload = match value {
+            //hex keeps the payload printable
+            StoreValueType::String(bytes) | StoreValueType::RawString(bytes)
+                if std::str::from_utf8(bytes).is_err() =>
+            {
+                vec![
+                    Value::BulkString("bytes".to_string()),
+                    Value::BulkString(bytes.iter().map(|byte| format!("{:02x}", byte)).collect()),
+                ]
+            }
+            StoreValueType::HyperLogLog(hll) => vec![
+                Value::BulkString("bytes".to_string()),
+                Value::BulkString(hll.as_bytes().iter().map(|byte| format!("{:02x}", byte)).collect()),
+            ],
+            StoreValueType::String(_) | StoreValueType::RawString(_) | StoreValueType::Interger(_) => vec![
+                Value::BulkString("string".to_string()),
+                Value::BulkString(value.to_string()),
+            ],
+            StoreValueType::List(list) => {
+                let mut payload = vec![Value::BulkString("list".to_string())];
+                payload.extend(list.iter().cloned().map(Value::BulkString));
+                payload
+            }
+            StoreValueType::Hash(hash) => {
+                let mut payload = vec![Value::BulkString("hash".to_string())];
+                for (field, value) in hash.pairs() {
+                    payload.push(Value::BulkString(field.clone()));
+                    payload.push(Value::BulkString(value.clone()));
+                }
+                payload
+            }
+            StoreValueType::Set(set) => {
+                let mut payload = vec![Value::BulkString("set".to_string())];
+                payload.extend(set.members().into_iter().map(Value::BulkString));
+                payload
+            }
+            StoreValueType::SortedSet(sorted_set) => {
+                let mut payload = vec![Value::BulkString("zset".to_string())];
+                for (member, score) in sorted_set.range(&ZRangeSpec::Rank(0, -1), false, 0, None) {
+                    payload.push(Value::BulkString(member));
+                    payload.push(Value::BulkString(format_score(score)));
+                }
+                payload
+            }
+        };
+        Some((String::from_utf8(Value::Array(payload).serialize()).ok()?, ttl))
+    }
+    pub fn restore(&mut self, key: &str, payload: &Value, ttl: Option<i64>) -> Result<()> {
+        let payload = match payload {
+            Value::Array(payload) if !payload.is_empty() => payload,
+            _ => return Err(anyhow::anyhow!("Bad payload for key {}", key)),
+        };
+        let px = ttl.map(|ttl| chrono::Utc::now() + Duration::milliseconds(ttl));
+        self.delete(key);
+        self.touch(key);
+        match unwrap_value_to_string(&payload[0])?.as_str() {
+            "string" if payload.len() == 2 => {
+                let value = unwrap_value_to_string(&payload[1])?;
+                self.collections
+                    .insert(key.to_string(), (parse_store_value(value.into_bytes()), px));
+            }
+            "list" => {
+                let mut list = VecDeque::new();
+                for item in &payload[1..] {
+                    list.push_back(unwrap_value_to_string(item)?);
+                }
+                self.collections
+                    .insert(key.to_string(), (StoreValueType::List(list), px));
+            }
+            "hash" if payload.len() % 2 == 1 => {
+                let mut hash = Hash::new();
+                for pair in payload[1..].chunks(2) {
+                    hash.set(&unwrap_value_to_string(&pair[0])?, &unwrap_value_to_string(&pair[1])?);
+                }
+                self.collections
+                    .insert(key.to_string(), (StoreValueType::Hash(hash), px));
+            }
+            "set" => {
+                let mut set = Set::new();
+                for member in &payload[1..] {
+                    set.insert(&unwrap_value_to_string(member)?);
+                }
+                self.collections
+                    .insert(key.to_string(), (StoreValueType::Set(set), px));
+            }
+            "zset" if payload.len() % 2 == 1 => {
+                let mut sorted_set = SortedSet::new();
+                for pair in payload[1..].chunks(2) {
+                    let score = unwrap_value_to_string(&pair[1])?.parse::<f64>()?;
+                    sorted_set.add(&unwrap_value_to_string(&pair[0])?, score, &ZAddOptions::default())?;
+                }
+                self.collections
+                    .insert(key.to_string(), (StoreValueType::SortedSet(sorted_set), px));
+            }
+            "bytes" if payload.len() == 2 => {
+                let hex = unwrap_value_to_string(&payload[1])?;
+                let bytes = (0..hex.len())
+                    .step_by(2)
+                    .map(|index| u8::from_str_radix(hex.get(index..index + 2).unwrap_or_default(), 16))
+                    .collect::<std::result::Result<Vec<u8>, _>>()?;
+                self.collections
+                    .insert(key.to_string(), (parse_store_value(bytes), px));
+            }
+            "stream" => {
+                self.entry.add_new_stream_key(key)?;
+                for stream in &payload[1..] {
+                    let (stream_id, fields) = match stream {
+                        Value::Array(stream) if stream.len() == 2 => (&stream[0], &stream[1]),
+                        _ => return Err(anyhow::anyhow!("Bad stream entry for key {}", key)),
+                    };
+                    let stream_id = unwrap_value_to_string(stream_id)?;
+                    if let StreamEntryValidate::Successfull(_) = self.entry.add_stream(key, &stream_id) {
+                        if let Value::Array(fields) = fields {
+                            for field in fields.chunks(2) {
+                                if let [field, value] = field {
+                                    self.entry.add_to_stream(
+                                        key,
+                                        &stream_id,
+                                        &unwrap_value_to_string(field)?,
+                                        &unwrap_value_to_string(value)?,
+                                    )?;
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            kind => return Err(anyhow::anyhow!("Unknown payload type {} for key {}", kind, key)),
+        }
+        self.notify(NOTIFY_GENERIC, "restore", key);
+        //list waiters still take turns, they only recheck
+        self.blocking.signal_all(key);
+        Ok(())
+    }
 
     pub fn get_value(&self, key: &str) -> Result<StoreValueType> {
         let (value, px_time) = if let Some(values) = self.collections.get(key) {
@@ -67,109 +713,989 @@ impl Store {
             ));
         }
     }
-    pub fn increase(&mut self, key: &str) -> Result<()> {
-        let value = match self.collections.get_mut(key){
-            Some(value) => &mut value.0,
-            None => {
-                self.set_value(key, "0", None).unwrap();
-                &mut self.collections.get_mut(key).unwrap().0
-            }
+    //INCR, DECR, INCRBY and DECRBY, the ttl is kept
+    pub fn increase_by(&mut self, key: &str, increment: i64) -> Result<i64> {
+        self.check_type(key, "string")?;
+        let (current, px) = match self.get_alive(key) {
+            Some(StoreValueType::Interger(number)) => (*number, self.collections[key].1),
+            Some(StoreValueType::String(bytes) | StoreValueType::RawString(bytes)) => match parse_integer(bytes) {
+                Some(number) => (number, self.collections[key].1),
+                None => return Err(anyhow::anyhow!("ERR value is not an integer or out of range")),
+            },
+            Some(_) => return Err(anyhow::anyhow!("ERR value is not an integer or out of range")),
+            None => (0, None),
         };
-        match value {
-            StoreValueType::Interger(num) => {
-                *num += 1i64;
-                Ok(())
-            }
-            _ => Err(anyhow::anyhow!("Value of key {} is not interger", key)),
+        let value = current
+            .checked_add(increment)
+            .ok_or_else(|| anyhow::anyhow!("ERR increment or decrement would overflow"))?;
+        self.collections
+            .insert(key.to_string(), (StoreValueType::Interger(value), px));
+        self.touch(key);
+        self.notify(NOTIFY_STRING, "incrby", key);
+        Ok(value)
+    }
+    pub fn increase_by_float(&mut self, key: &str, increment: f64) -> Result<String> {
+        self.check_type(key, "string")?;
+        let (current, px) = match self.get_alive(key) {
+            Some(value) => match std::str::from_utf8(&value.to_bytes()).ok().and_then(|value| value.parse::<f64>().ok()) {
+                Some(number) if number.is_finite() => (number, self.collections[key].1),
+                _ => return Err(anyhow::anyhow!("ERR value is not a valid float")),
+            },
+            None => (0f64, None),
+        };
+        let value = current + increment;
+        if !value.is_finite() {
+            return Err(anyhow::anyhow!("ERR increment would produce NaN or Infinity"));
         }
+        let value = value.to_string();
+        self.collections
+            .insert(key.to_string(), (StoreValueType::String(value.clone().into_bytes()), px));
+        self.touch(key);
+        self.notify(NOTIFY_STRING, "incrbyfloat", key);
+        Ok(value)
+    }
+    //New ttl for a live key, a time already past deletes it, false when the key does not exist
+    pub fn set_expiry(&mut self, key: &str, px: Option<DateTime<Utc>>) -> bool {
+        if self.get_alive(key).is_none() {
+            return false;
+        }
+        if px.is_some_and(|px| px <= chrono::Utc::now()) {
+            return self.remove(key);
+        }
+        let expiry = &mut self.collections.get_mut(key).unwrap().1;
+        let had_ttl = expiry.is_some();
+        *expiry = px;
+        if px.is_some() {
+            self.touch(key);
+            self.notify(NOTIFY_GENERIC, "expire", key);
+        } else if had_ttl {
+            self.touch(key);
+            self.notify(NOTIFY_GENERIC, "persist", key);
+        }
+        true
     }
 
-    pub fn push(&mut self, key: &str, value: &str) -> Result<usize> {
-        if self.collections.get(key).is_none(){
+    pub fn push(&mut self, key: &str, values: &[String]) -> Result<usize> {
+        self.push_list(key, values, false)
+    }
+    pub fn push_head(&mut self, key: &str, values: &[String]) -> Result<usize> {
+        self.push_list(key, values, true)
+    }
+    //One rpush or lpush event per call, like one command
+    fn push_list(&mut self, key: &str, values: &[String], head: bool) -> Result<usize> {
+        if self.get_list(key)?.is_none(){
             self.collections.insert(key.to_string(), (StoreValueType::List(VecDeque::new()), None));
         }
-        let (list, _) = self.collections.get_mut(key).unwrap();
-        match list{
-            StoreValueType::List(list) => {
+        self.touch(key);
+        let list = self.get_list_mut(key)?.unwrap();
+        for value in values {
+            if head {
+                list.push_front(value.to_string());
+            } else {
                 list.push_back(value.to_string());
-                Ok(list.len())
             }
-            _ => {
-                Err(anyhow::anyhow!("Key {} has no type list anymore", key))
+        }
+        let size = list.len();
+        self.notify(NOTIFY_LIST, if head { "lpush" } else { "rpush" }, key);
+        self.blocking.signal(key);
+        Ok(size)
+    }
+    pub fn get_list_size(&self, key: &str) -> Result<usize>{
+        Ok(self.get_list(key)?.map_or(0, |list| list.len()))
+    }
+    pub fn get_list_range(&self, key: &str, mut start: i64, mut end: i64) -> Result<VecDeque<String>> { 
+        let list = match self.get_list(key)? {
+            Some(list) => list,
+            None => return Ok(VecDeque::new()),
+        };
+
+        if start < 0 { start = list.len() as i64 + start;}
+        if end < 0 { end = list.len() as i64 + end;}
+
+        if end > list.len() as i64{ end = list.len() as i64 - 1;}
+        if start < 0 { start = 0}
+
+        if start >= 0 && end >= 0 && end >= start{
+            let start = start as usize;
+            let end = end as usize;
+            Ok(list.iter().skip(start).take(end-start+1).cloned().collect())
+        }
+        else{
+            Ok(VecDeque::new())
+        }
+    }
+    //Item at index, negative counts from the tail
+    pub fn get_list_index(&self, key: &str, index: i64) -> Result<Option<String>> {
+        match self.get_list(key)? {
+            Some(list) => Ok(list_index(list.len(), index).and_then(|index| list.get(index).cloned())),
+            None => Ok(None),
+        }
+    }
+    pub fn set_list_index(&mut self, key: &str, index: i64, value: &str) -> Result<()> {
+        let list = match self.get_list_mut(key)? {
+            Some(list) => list,
+            None => return Err(anyhow::anyhow!("ERR no such key")),
+        };
+        match list_index(list.len(), index) {
+            Some(index) => list[index] = value.to_string(),
+            None => return Err(anyhow::anyhow!("ERR index out of range")),
+        }
+        self.touch(key);
+        self.notify(NOTIFY_LIST, "lset", key);
+        Ok(())
+    }
+    //New length, 0 when the key is missing and -1 when pivot is not found
+    pub fn insert_list(&mut self, key: &str, before: bool, pivot: &str, value: &str) -> Result<i64> {
+        let list = match self.get_list_mut(key)? {
+            Some(list) => list,
+            None => return Ok(0),
+        };
+        let position = match list.iter().position(|item| item == pivot) {
+            Some(position) => position,
+            None => return Ok(-1),
+        };
+        list.insert(if before { position } else { position + 1 }, value.to_string());
+        let size = list.len() as i64;
+        self.touch(key);
+        self.notify(NOTIFY_LIST, "linsert", key);
+        Ok(size)
+    }
+    //Remove count matches from the head, from the tail when negative, all of them when 0
+    pub fn remove_list(&mut self, key: &str, count: i64, value: &str) -> Result<usize> {
+        let list = match self.get_list_mut(key)? {
+            Some(list) => list,
+            None => return Ok(0),
+        };
+        let limit = if count == 0 { usize::MAX } else { count.unsigned_abs() as usize };
+        let mut positions = list
+            .iter()
+            .enumerate()
+            .filter(|(_, item)| *item == value)
+            .map(|(position, _)| position)
+            .collect::<Vec<usize>>();
+        if count < 0 {
+            positions.reverse();
+        }
+        positions.truncate(limit);
+        positions.sort_unstable();
+        for position in positions.iter().rev() {
+            list.remove(*position);
+        }
+        if !positions.is_empty() {
+            self.touch(key);
+            self.notify(NOTIFY_LIST, "lrem", key);
+            self.remove_empty_list(key);
+        }
+        Ok(positions.len())
+    }
+    //Keep only start..=end, the list goes away when nothing is left
+    pub fn trim_list(&mut self, key: &str, start: i64, end: i64) -> Result<()> {
+        let list = match self.get_list_mut(key)? {
+            Some(list) => list,
+            None => return Ok(()),
+        };
+        let len = list.len() as i64;
+        let start = if start < 0 { (len + start).max(0) } else { start };
+        let end = if end < 0 { len + end } else { end.min(len - 1) };
+        if start > end || start >= len {
+            list.clear();
+        } else {
+            list.truncate(end as usize + 1);
+            list.drain(..start as usize);
+        }
+        self.touch(key);
+        self.notify(NOTIFY_LIST, "ltrim", key);
+        self.remove_empty_list(key);
+        Ok(())
+    }
+    //Indexes of element, rank picks the nth match (negative from the tail),
+    //count 0 means every match and maxlen 0 scans the whole list
+    pub fn get_list_positions(&self, key: &str, element: &str, rank: i64, count: usize, maxlen: usize) -> Result<Vec<usize>> {
+        let list = match self.get_list(key)? {
+            Some(list) => list,
+            None => return Ok(Vec::new()),
+        };
+        let maxlen = if maxlen == 0 { list.len() } else { maxlen };
+        let count = if count == 0 { usize::MAX } else { count };
+        let skip = rank.unsigned_abs() as usize - 1;
+        let positions = (0..list.len()).filter(|position| list[*position] == element);
+        let positions = if rank > 0 {
+            positions.filter(|position| *position < maxlen).collect::<Vec<usize>>()
+        } else {
+            positions
+                .rev()
+                .filter(|position| list.len() - position <= maxlen)
+                .collect::<Vec<usize>>()
+        };
+        Ok(positions.into_iter().skip(skip).take(count).collect())
+    }
+    fn get_list(&self, key: &str) -> Result<Option<&VecDeque<String>>> {
+        self.check_type(key, "list")?;
+        match self.get_alive(key) {
+            Some(StoreValueType::List(list)) => Ok(Some(list)),
+            _ => Ok(None),
+        }
+    }
+    fn get_list_mut(&mut self, key: &str) -> Result<Option<&mut VecDeque<String>>> {
+        if self.get_list(key)?.is_none() {
+            return Ok(None);
+        }
+        match self.collections.get_mut(key) {
+            Some((StoreValueType::List(list), _)) => Ok(Some(list)),
+            _ => Ok(None),
+        }
+    }
+    //A list never stays empty, TYPE must say none afterwards
+    fn remove_empty_list(&mut self, key: &str) {
+        if let Some((StoreValueType::List(list), _)) = self.collections.get(key) {
+            if list.is_empty() {
+                self.collections.remove(key);
+                self.notify(NOTIFY_GENERIC, "del", key);
             }
         }
     }
-    pub fn push_head(&mut self, key: &str, value: &str) -> Result<usize> {
-        if self.collections.get(key).is_none(){
-            self.collections.insert(key.to_string(), (StoreValueType::List(VecDeque::new()), None));
+    //Pop up to number items from the head or the tail, None when the key is not a list
+    pub fn pop_list(&mut self, key: &str, mut number: usize, head: bool) -> Result<Option<Vec<String>>>{
+        let list = match self.get_list_mut(key)? {
+            Some(list) => list,
+            None => return Ok(None),
+        };
+        let mut values = Vec::new();
+        if number > list.len() { number = list.len()}
+        for _ in 1..=number{
+            if head {
+                values.push(list.pop_front().unwrap())
+            } else {
+                values.push(list.pop_back().unwrap())
+            }
         }
-        let (list, _) = self.collections.get_mut(key).unwrap();
-        match list{
-            StoreValueType::List(list) => {
-                list.push_front(value.to_string());
-                Ok(list.len())
+        if !values.is_empty() {
+            self.touch(key);
+            self.notify(NOTIFY_LIST, if head { "lpop" } else { "rpop" }, key);
+            self.remove_empty_list(key);
+        }
+        Ok(Some(values))
+    }
+    //Pop one item from source and push it on destination, None when source is empty
+    pub fn move_list(&mut self, source: &str, destination: &str, from_head: bool, to_head: bool) -> Result<Option<String>>{
+        self.check_type(destination, "list")?;
+        let value = match self.pop_list(source, 1, from_head)?.and_then(|values| values.into_iter().next()) {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        self.push_list(destination, std::slice::from_ref(&value), to_head)?;
+        Ok(Some(value))
+    }
+
+    //HSET and HSETNX, number of fields that did not exist before
+    pub fn set_hash(&mut self, key: &str, pairs: &[(String, String)], only_new: bool) -> Result<usize> {
+        if self.get_hash(key)?.is_none() {
+            self.collections
+                .insert(key.to_string(), (StoreValueType::Hash(Hash::new()), None));
+        }
+        let hash = self.get_hash_mut(key)?.unwrap();
+        let mut added = 0;
+        for (field, value) in pairs {
+            if only_new && hash.contains(field) {
+                continue;
             }
-            _ => {
-                Err(anyhow::anyhow!("Key {} has no type list anymore", key))
+            if hash.set(field, value) {
+                added += 1;
             }
         }
+        if !only_new || added > 0 {
+            self.touch(key);
+            self.notify(NOTIFY_HASH, "hset", key);
+        }
+        Ok(added)
     }
-    pub fn get_list_size(&self, key: &str) -> Result<usize>{
-        match self.collections.get(key){
-            Some((list, _)) => {
-                match list{
-                    StoreValueType::List(list) => {Ok(list.len())}
-                    _ => {Ok(0)}
+    pub fn get_hash_field(&self, key: &str, field: &str) -> Result<Option<String>> {
+        Ok(self.get_hash(key)?.and_then(|hash| hash.get(field).cloned()))
+    }
+    //Every live field and value, empty when the key is missing
+    pub fn get_hash_pairs(&self, key: &str) -> Result<Vec<(String, String)>> {
+        Ok(self.get_hash(key)?.map_or(Vec::new(), |hash| {
+            hash.pairs()
+                .into_iter()
+                .map(|(field, value)| (field.clone(), value.clone()))
+                .collect()
+        }))
+    }
+    pub fn get_hash_size(&self, key: &str) -> Result<usize> {
+        Ok(self.get_hash(key)?.map_or(0, |hash| hash.len()))
+    }
+    pub fn remove_hash_fields(&mut self, key: &str, fields: &[String]) -> Result<usize> {
+        let hash = match self.get_hash_mut(key)? {
+            Some(hash) => hash,
+            None => return Ok(0),
+        };
+        let removed = fields.iter().filter(|field| hash.remove(field)).count();
+        if removed > 0 {
+            self.touch(key);
+            self.notify(NOTIFY_HASH, "hdel", key);
+            self.remove_empty_hash(key);
+        }
+        Ok(removed)
+    }
+    pub fn increase_hash(&mut self, key: &str, field: &str, increment: i64) -> Result<i64> {
+        let current = match self.get_hash_field(key, field)? {
+            Some(value) => value
+                .parse::<i64>()
+                .map_err(|_| anyhow::anyhow!("ERR hash value is not an integer"))?,
+            None => 0,
+        };
+        let value = current
+            .checked_add(increment)
+            .ok_or_else(|| anyhow::anyhow!("ERR increment or decrement would overflow"))?;
+        self.write_hash_field(key, field, &value.to_string(), "hincrby")?;
+        Ok(value)
+    }
+    pub fn increase_hash_float(&mut self, key: &str, field: &str, increment: f64) -> Result<String> {
+        let current = match self.get_hash_field(key, field)? {
+            Some(value) => value
+                .parse::<f64>()
+                .ok()
+                .filter(|value| value.is_finite())
+                .ok_or_else(|| anyhow::anyhow!("ERR hash value is not a float"))?,
+            None => 0f64,
+        };
+        let value = current + increment;
+        if !value.is_finite() {
+            return Err(anyhow::anyhow!("ERR increment would produce NaN or Infinity"));
+        }
+        let value = value.to_string();
+        self.write_hash_field(key, field, &value, "hincrbyfloat")?;
+        Ok(value)
+    }
+    //Set one field keeping its ttl, the way HINCRBY updates in place
+    fn write_hash_field(&mut self, key: &str, field: &str, value: &str, event: &str) -> Result<()> {
+        if self.get_hash(key)?.is_none() {
+            self.collections
+                .insert(key.to_string(), (StoreValueType::Hash(Hash::new()), None));
+        }
+        let hash = self.get_hash_mut(key)?.unwrap();
+        let expire = hash.get_expire(field);
+        hash.set(field, value);
+        if let Some(expire) = expire {
+            hash.set_expire(field, expire);
+        }
+        self.touch(key);
+        self.notify(NOTIFY_HASH, event, key);
+        Ok(())
+    }
+    //HEXPIRE reply per field: -2 no such field, 0 condition not met, 1 ttl set, 2 deleted
+    //because the time is already in the past
+    pub fn expire_hash_fields(
+        &mut self,
+        key: &str,
+        fields: &[String],
+        expire: DateTime<Utc>,
+        condition: Option<&str>,
+    ) -> Result<Vec<i64>> {
+        let hash = match self.get_hash_mut(key)? {
+            Some(hash) => hash,
+            None => return Ok(vec![-2; fields.len()]),
+        };
+        let now = chrono::Utc::now();
+        let mut replies = Vec::new();
+        for field in fields {
+            if !hash.contains(field) {
+                replies.push(-2);
+                continue;
+            }
+            let current = hash.get_expire(field);
+            let allowed = match condition {
+                Some("NX") => current.is_none(),
+                Some("XX") => current.is_some(),
+                //no ttl counts as forever
+                Some("GT") => current.is_some_and(|current| expire > current),
+                Some("LT") => current.is_none_or(|current| expire < current),
+                _ => true,
+            };
+            if !allowed {
+                replies.push(0);
+            } else if expire <= now {
+                hash.remove(field);
+                replies.push(2);
+            } else {
+                hash.set_expire(field, expire);
+                replies.push(1);
+            }
+        }
+        if replies.contains(&2) {
+            self.touch(key);
+            self.notify(NOTIFY_HASH, "hdel", key);
+        }
+        if replies.contains(&1) {
+            self.touch(key);
+            self.notify(NOTIFY_HASH, "hexpire", key);
+        }
+        self.remove_empty_hash(key);
+        Ok(replies)
+    }
+    //HTTL reply per field in milliseconds: -2 no such field, -1 no ttl
+    pub fn get_hash_ttls(&self, key: &str, fields: &[String]) -> Result<Vec<i64>> {
+        let hash = match self.get_hash(key)? {
+            Some(hash) => hash,
+            None => return Ok(vec![-2; fields.len()]),
+        };
+        let now = chrono::Utc::now();
+        Ok(fields
+            .iter()
+            .map(|field| match hash.get_expire(field) {
+                _ if !hash.contains(field) => -2,
+                Some(expire) => (expire - now).num_milliseconds().max(0),
+                None => -1,
+            })
+            .collect())
+    }
+    //HPERSIST reply per field: -2 no such field, -1 no ttl, 1 ttl removed
+    pub fn persist_hash_fields(&mut self, key: &str, fields: &[String]) -> Result<Vec<i64>> {
+        let hash = match self.get_hash_mut(key)? {
+            Some(hash) => hash,
+            None => return Ok(vec![-2; fields.len()]),
+        };
+        let replies = fields
+            .iter()
+            .map(|field| {
+                if !hash.contains(field) {
+                    -2
+                } else if hash.persist(field) {
+                    1
+                } else {
+                    -1
                 }
+            })
+            .collect::<Vec<i64>>();
+        if replies.contains(&1) {
+            self.touch(key);
+            self.notify(NOTIFY_HASH, "hpersist", key);
+        }
+        Ok(replies)
+    }
+    fn get_hash(&self, key: &str) -> Result<Option<&Hash>> {
+        self.check_type(key, "hash")?;
+        match self.get_alive(key) {
+            Some(StoreValueType::Hash(hash)) => Ok(Some(hash)),
+            _ => Ok(None),
+        }
+    }
+    fn get_hash_mut(&mut self, key: &str) -> Result<Option<&mut Hash>> {
+        if self.get_hash(key)?.is_none() {
+            return Ok(None);
+        }
+        match self.collections.get_mut(key) {
+            Some((StoreValueType::Hash(hash), _)) => Ok(Some(hash)),
+            _ => Ok(None),
+        }
+    }
+    //Fields past their HEXPIRE go away, and the hash with its last field
+    fn expire_hash_fields_due(&mut self, key: &str) {
+        let expired = match self.collections.get_mut(key) {
+            Some((StoreValueType::Hash(hash), _)) => hash.purge_expired(),
+            _ => return,
+        };
+        if !expired.is_empty() {
+            self.touch(key);
+            self.notify(NOTIFY_HASH, "hexpired", key);
+            self.remove_empty_hash(key);
+        }
+    }
+    fn remove_empty_hash(&mut self, key: &str) {
+        if let Some((StoreValueType::Hash(hash), _)) = self.collections.get(key) {
+            if hash.is_empty() {
+                self.collections.remove(key);
+                self.notify(NOTIFY_GENERIC, "del", key);
             }
-            None => Ok(0)
         }
     }
-    pub fn get_list_range(&self, key: &str, mut start: i64, mut end: i64) -> Result<VecDeque<String>> { 
-        if let Some((list,_)) = self.collections.get(key){
-            match list{
-                StoreValueType::List(list) => {
 
-                    if start < 0 { start = list.len() as i64 + start;}
-                    if end < 0 { end = list.len() as i64 + end;}
-
-                    if end > list.len() as i64{ end = list.len() as i64 - 1;}
-                    if start < 0 { start = 0}
-
-                    if start >= 0 && end >= 0{
-                        if end >= start{
-                            let start = start as usize;
-                            let end = end as usize;
-                            Ok(list.iter().skip(start).take(end-start+1).cloned().collect())
-                        }
-                        else{
-                            Ok(VecDeque::new())
-                        }
-                    }
-                    else{
-                        Ok(VecDeque::new())
+    //SADD, number of members that were not in the set
+    pub fn add_set(&mut self, key: &str, members: &[String]) -> Result<usize> {
+        if self.get_set(key)?.is_none() {
+            self.collections
+                .insert(key.to_string(), (StoreValueType::Set(Set::new()), None));
+        }
+        let set = self.get_set_mut(key)?.unwrap();
+        let added = members.iter().filter(|member| set.insert(member)).count();
+        if added > 0 {
+            self.touch(key);
+            self.notify(NOTIFY_SET, "sadd", key);
+        }
+        Ok(added)
+    }
+    pub fn remove_set(&mut self, key: &str, members: &[String]) -> Result<usize> {
+        let set = match self.get_set_mut(key)? {
+            Some(set) => set,
+            None => return Ok(0),
+        };
+        let removed = members.iter().filter(|member| set.remove(member)).count();
+        if removed > 0 {
+            self.touch(key);
+            self.notify(NOTIFY_SET, "srem", key);
+            self.remove_empty_set(key);
+        }
+        Ok(removed)
+    }
+    pub fn is_set_member(&self, key: &str, member: &str) -> Result<bool> {
+        Ok(self.get_set(key)?.is_some_and(|set| set.contains(member)))
+    }
+    //Every member, empty when the key is missing
+    pub fn get_set_members(&self, key: &str) -> Result<Vec<String>> {
+        Ok(self.get_set(key)?.map_or(Vec::new(), |set| set.members()))
+    }
+    pub fn get_set_size(&self, key: &str) -> Result<usize> {
+        Ok(self.get_set(key)?.map_or(0, |set| set.len()))
+    }
+    //SPOP, remove up to count random members
+    pub fn pop_set(&mut self, key: &str, count: usize) -> Result<Vec<String>> {
+        let set = match self.get_set_mut(key)? {
+            Some(set) => set,
+            None => return Ok(Vec::new()),
+        };
+        let mut members = set.members();
+        let mut popped = Vec::new();
+        while popped.len() < count && !members.is_empty() {
+            let member = members.swap_remove(random_index(members.len()));
+            set.remove(&member);
+            popped.push(member);
+        }
+        if !popped.is_empty() {
+            self.touch(key);
+            self.notify(NOTIFY_SET, "spop", key);
+            self.remove_empty_set(key);
+        }
+        Ok(popped)
+    }
+    //SMOVE, false when member is not in source
+    pub fn move_set(&mut self, source: &str, destination: &str, member: &str) -> Result<bool> {
+        self.check_type(destination, "set")?;
+        if !self.is_set_member(source, member)? {
+            return Ok(false);
+        }
+        self.remove_set(source, &[member.to_string()])?;
+        self.add_set(destination, &[member.to_string()])?;
+        Ok(true)
+    }
+    //SINTER, SUNION and SDIFF over the given keys, a missing key is an empty set
+    pub fn combine_sets(&self, keys: &[String], operation: SetOperation) -> Result<Vec<String>> {
+        let mut sets = Vec::new();
+        for key in keys {
+            sets.push(self.get_set(key)?);
+        }
+        let first = match sets.first() {
+            Some(Some(first)) => first,
+            _ => {
+                return Ok(match operation {
+                    SetOperation::Union => self.combine_sets(&keys[1..], operation)?,
+                    _ => Vec::new(),
+                })
+            }
+        };
+        let rest = &sets[1..];
+        let members = match operation {
+            SetOperation::Inter => first
+                .members()
+                .into_iter()
+                .filter(|member| rest.iter().all(|set| set.is_some_and(|set| set.contains(member))))
+                .collect(),
+            SetOperation::Diff => first
+                .members()
+                .into_iter()
+                .filter(|member| !rest.iter().any(|set| set.is_some_and(|set| set.contains(member))))
+                .collect(),
+            SetOperation::Union => {
+                let mut union = Set::new();
+                for set in sets.iter().flatten() {
+                    for member in set.members() {
+                        union.insert(&member);
                     }
                 }
-                _ => {Ok(VecDeque::new())}
+                union.members()
+            }
+        };
+        Ok(members)
+    }
+    //The *STORE commands, destination is replaced whatever it held and removed when empty
+    pub fn store_set(&mut self, destination: &str, members: &[String], event: &str) -> usize {
+        let existed = self.delete(destination);
+        if members.is_empty() {
+            if existed {
+                self.notify(NOTIFY_GENERIC, "del", destination);
             }
+            return 0;
+        }
+        let mut set = Set::new();
+        for member in members {
+            set.insert(member);
         }
-        else{ Ok(VecDeque::new())}
+        let size = set.len();
+        self.collections
+            .insert(destination.to_string(), (StoreValueType::Set(set), None));
+        self.touch(destination);
+        self.notify(NOTIFY_SET, event, destination);
+        size
     }
-    pub fn pop_front_list(&mut self, key: &str, mut number: usize) -> Result<Option<Vec<String>>>{
-        if let Some((value, _)) = self.collections.get_mut(key){
-            match value {
-                StoreValueType::List(list) => {
-                    let mut values = Vec::new();
-                    if number > list.len() { number = list.len()}
-                    for _ in 1..=number{
-                        values.push(list.pop_front().unwrap())
+    fn get_set(&self, key: &str) -> Result<Option<&Set>> {
+        self.check_type(key, "set")?;
+        match self.get_alive(key) {
+            Some(StoreValueType::Set(set)) => Ok(Some(set)),
+            _ => Ok(None),
+        }
+    }
+    fn get_set_mut(&mut self, key: &str) -> Result<Option<&mut Set>> {
+        if self.get_set(key)?.is_none() {
+            return Ok(None);
+        }
+        match self.collections.get_mut(key) {
+            Some((StoreValueType::Set(set), _)) => Ok(Some(set)),
+            _ => Ok(None),
+        }
+    }
+    fn remove_empty_set(&mut self, key: &str) {
+        if let Some((StoreValueType::Set(set), _)) = self.collections.get(key) {
+            if set.is_empty() {
+                self.collections.remove(key);
+                self.notify(NOTIFY_GENERIC, "del", key);
+            }
+        }
+    }
+
+    //ZADD and ZINCRBY, one result per (score, member) pair
+    pub fn add_sorted_set(&mut self, key: &str, pairs: &[(f64, String)], options: &ZAddOptions) -> Result<Vec<ZAddResult>> {
+        if self.get_sorted_set(key)?.is_none() {
+            if options.xx {
+                return Ok(vec![ZAddResult::Skipped; pairs.len()]);
+            }
+            self.collections
+                .insert(key.to_string(), (StoreValueType::SortedSet(SortedSet::new()), None));
+        }
+        let sorted_set = self.get_sorted_set_mut(key)?.unwrap();
+        let mut results = Vec::new();
+        for (score, member) in pairs {
+            results.push(sorted_set.add(member, *score, options)?);
+        }
+        if results
+            .iter()
+            .any(|result| matches!(result, ZAddResult::Added(_) | ZAddResult::Updated(_)))
+        {
+            self.touch(key);
+            self.notify(NOTIFY_ZSET, if options.incr { "zincr" } else { "zadd" }, key);
+            self.blocking.signal(key);
+        }
+        Ok(results)
+    }
+    pub fn remove_sorted_set(&mut self, key: &str, members: &[String]) -> Result<usize> {
+        let sorted_set = match self.get_sorted_set_mut(key)? {
+            Some(sorted_set) => sorted_set,
+            None => return Ok(0),
+        };
+        let removed = members.iter().filter(|member| sorted_set.remove(member)).count();
+        if removed > 0 {
+            self.touch(key);
+            self.notify(NOTIFY_ZSET, "zrem", key);
+            self.remove_empty_sorted_set(key);
+        }
+        Ok(removed)
+    }
+    pub fn get_sorted_set_score(&self, key: &str, member: &str) -> Result<Option<f64>> {
+        Ok(self.get_sorted_set(key)?.and_then(|sorted_set| sorted_set.score(member)))
+    }
+    pub fn get_sorted_set_size(&self, key: &str) -> Result<usize> {
+        Ok(self.get_sorted_set(key)?.map_or(0, |sorted_set| sorted_set.len()))
+    }
+    //Rank and score of member, None when it is not in the sorted set
+    pub fn get_sorted_set_rank(&self, key: &str, member: &str, reverse: bool) -> Result<Option<(usize, f64)>> {
+        Ok(self.get_sorted_set(key)?.and_then(|sorted_set| {
+            Some((sorted_set.rank(member, reverse)?, sorted_set.score(member)?))
+        }))
+    }
+    pub fn count_sorted_set(&self, key: &str, min: &ScoreBound, max: &ScoreBound) -> Result<usize> {
+        Ok(self
+            .get_sorted_set(key)?
+            .map_or(0, |sorted_set| sorted_set.count_by_score(min, max)))
+    }
+    pub fn range_sorted_set(
+        &self,
+        key: &str,
+        spec: &ZRangeSpec,
+        reverse: bool,
+        offset: usize,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, f64)>> {
+        Ok(self
+            .get_sorted_set(key)?
+            .map_or(Vec::new(), |sorted_set| sorted_set.range(spec, reverse, offset, limit)))
+    }
+    //ZREMRANGEBYRANK, ZREMRANGEBYSCORE and ZREMRANGEBYLEX
+    pub fn remove_range_sorted_set(&mut self, key: &str, spec: &ZRangeSpec, event: &str) -> Result<usize> {
+        let members = self
+            .range_sorted_set(key, spec, false, 0, None)?
+            .into_iter()
+            .map(|(member, _)| member)
+            .collect::<Vec<String>>();
+        if members.is_empty() {
+            return Ok(0);
+        }
+        let sorted_set = self.get_sorted_set_mut(key)?.unwrap();
+        for member in members.iter() {
+            sorted_set.remove(member);
+        }
+        self.touch(key);
+        self.notify(NOTIFY_ZSET, event, key);
+        self.remove_empty_sorted_set(key);
+        Ok(members.len())
+    }
+    //ZPOPMIN and ZPOPMAX
+    pub fn pop_sorted_set(&mut self, key: &str, count: usize, max: bool) -> Result<Vec<(String, f64)>> {
+        let sorted_set = match self.get_sorted_set_mut(key)? {
+            Some(sorted_set) => sorted_set,
+            None => return Ok(Vec::new()),
+        };
+        let popped = sorted_set.pop(count, max);
+        if !popped.is_empty() {
+            self.touch(key);
+            self.notify(NOTIFY_ZSET, if max { "zpopmax" } else { "zpopmin" }, key);
+            self.remove_empty_sorted_set(key);
+        }
+        Ok(popped)
+    }
+    //ZRANGESTORE, destination is replaced whatever it held and removed when empty
+    pub fn store_sorted_set(&mut self, destination: &str, pairs: &[(String, f64)], event: &str) -> Result<usize> {
+        let existed = self.delete(destination);
+        if pairs.is_empty() {
+            if existed {
+                self.notify(NOTIFY_GENERIC, "del", destination);
+            }
+            return Ok(0);
+        }
+        let mut sorted_set = SortedSet::new();
+        for (member, score) in pairs {
+            sorted_set.add(member, *score, &ZAddOptions::default())?;
+        }
+        let size = sorted_set.len();
+        self.collections
+            .insert(destination.to_string(), (StoreValueType::SortedSet(sorted_set), None));
+        self.touch(destination);
+        self.notify(NOTIFY_ZSET, event, destination);
+        self.blocking.signal(destination);
+        Ok(size)
+    }
+    //ZUNION, ZINTER and ZDIFF, plain sets count as members with score 1.
+    //Weights multiply each key's scores, the result is ordered like a sorted set
+    pub fn combine_sorted_sets(
+        &self,
+        keys: &[String],
+        weights: &[f64],
+        aggregate: Aggregate,
+        operation: SetOperation,
+    ) -> Result<Vec<(String, f64)>> {
+        let mut inputs = Vec::new();
+        for (index, key) in keys.iter().enumerate() {
+            let weight = weights.get(index).copied().unwrap_or(1f64);
+            let pairs = match self.get_type(key) {
+                Some("zset") => self.range_sorted_set(key, &ZRangeSpec::Rank(0, -1), false, 0, None)?,
+                Some("set") => self
+                    .get_set_members(key)?
+                    .into_iter()
+                    .map(|member| (member, 1f64))
+                    .collect(),
+                Some(_) => return Err(anyhow::anyhow!(WRONGTYPE)),
+                None => Vec::new(),
+            };
+            let pairs = pairs
+                .into_iter()
+                .map(|(member, score)| {
+                    //0 * inf is NaN, redis makes it 0
+                    let score = Some(score * weight).filter(|score| !score.is_nan()).unwrap_or(0f64);
+                    (member, score)
+                })
+                .collect::<HashMap<String, f64>>();
+            inputs.push(pairs);
+        }
+        let (first, rest) = match inputs.split_first() {
+            Some(split) => split,
+            None => return Ok(Vec::new()),
+        };
+        let mut result = match operation {
+            SetOperation::Union => {
+                let mut union: HashMap<String, f64> = HashMap::new();
+                for input in inputs.iter() {
+                    for (member, score) in input {
+                        union
+                            .entry(member.clone())
+                            .and_modify(|current| *current = aggregate.apply(*current, *score))
+                            .or_insert(*score);
                     }
-                    return Ok(Some(values));
                 }
-                _ => return Ok(None)
+                union.into_iter().collect::<Vec<(String, f64)>>()
             }
+            SetOperation::Inter => first
+                .iter()
+                .filter_map(|(member, score)| {
+                    let mut score = *score;
+                    for input in rest {
+                        score = aggregate.apply(score, *input.get(member)?);
+                    }
+                    Some((member.clone(), score))
+                })
+                .collect(),
+            SetOperation::Diff => first
+                .iter()
+                .filter(|(member, _)| !rest.iter().any(|input| input.contains_key(*member)))
+                .map(|(member, score)| (member.clone(), *score))
+                .collect(),
+        };
+        result.sort_by(|a, b| a.1.total_cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+        Ok(result)
+    }
+    fn get_sorted_set(&self, key: &str) -> Result<Option<&SortedSet>> {
+        self.check_type(key, "zset")?;
+        match self.get_alive(key) {
+            Some(StoreValueType::SortedSet(sorted_set)) => Ok(Some(sorted_set)),
+            _ => Ok(None),
+        }
+    }
+    fn get_sorted_set_mut(&mut self, key: &str) -> Result<Option<&mut SortedSet>> {
+        if self.get_sorted_set(key)?.is_none() {
+            return Ok(None);
+        }
+        match self.collections.get_mut(key) {
+            Some((StoreValueType::SortedSet(sorted_set), _)) => Ok(Some(sorted_set)),
+            _ => Ok(None),
+        }
+    }
+    fn remove_empty_sorted_set(&mut self, key: &str) {
+        if let Some((StoreValueType::SortedSet(sorted_set), _)) = self.collections.get(key) {
+            if sorted_set.is_empty() {
+                self.collections.remove(key);
+                self.notify(NOTIFY_GENERIC, "del", key);
+            }
+        }
+    }
+
+    //Bytes of a string key, for the bit commands
+    pub fn get_string_bytes(&self, key: &str) -> Result<Option<Cow<'_, [u8]>>> {
+        self.check_type(key, "string")?;
+        Ok(self.get_alive(key).map(|value| value.to_bytes()))
+    }
+    //Change a string key in place, a missing key starts empty and the ttl is kept
+    pub fn update_string<R>(&mut self, key: &str, event: &str, update: impl FnOnce(&mut Vec<u8>) -> R) -> Result<R> {
+        self.check_type(key, "string")?;
+        let (mut bytes, px) = match self.collections.remove(key) {
+            Some((value, px)) if px.is_none_or(|px| px >= chrono::Utc::now()) => match value {
+                StoreValueType::String(bytes) | StoreValueType::RawString(bytes) => (bytes, px),
+                value => (value.to_bytes().into_owned(), px),
+            },
+            _ => (Vec::new(), None),
+        };
+        let result = update(&mut bytes);
+        self.collections
+            .insert(key.to_string(), (raw_store_value(bytes), px));
+        self.touch(key);
+        self.notify(NOTIFY_STRING, event, key);
+        Ok(result)
+    }
+    //Replace a key with a string, an empty one deletes the key instead
+    pub fn store_string(&mut self, destination: &str, bytes: Vec<u8>, event: &str) -> usize {
+        let len = bytes.len();
+        if len == 0 {
+            self.remove(destination);
+            return 0;
+        }
+        self.delete(destination);
+        self.collections
+            .insert(destination.to_string(), (raw_store_value(bytes), None));
+        self.touch(destination);
+        self.notify(NOTIFY_STRING, event, destination);
+        len
+    }
+
+    //PFADD, true when the key was created or a register changed
+    pub fn add_hyperloglog(&mut self, key: &str, elements: &[String]) -> Result<bool> {
+        let mut updated = false;
+        if self.get_hyperloglog(key)?.is_none() {
+            self.collections
+                .insert(key.to_string(), (StoreValueType::HyperLogLog(HyperLogLog::new()), None));
+            updated = true;
+        }
+        let hll = self.get_hyperloglog_mut(key)?.unwrap();
+        for element in elements {
+            updated = hll.add(element.as_bytes())? || updated;
+        }
+        if updated {
+            self.touch(key);
+            self.notify(NOTIFY_STRING, "pfadd", key);
+        }
+        Ok(updated)
+    }
+    //PFCOUNT, several keys are merged on the fly and leave no cached value behind
+    pub fn count_hyperloglog(&mut self, keys: &[String]) -> Result<u64> {
+        if let [key] = keys {
+            return match self.get_hyperloglog_mut(key)? {
+                Some(hll) => hll.count(),
+                None => Ok(0),
+            };
+        }
+        let mut registers = vec![0u8; HLL_REGISTERS];
+        for key in keys {
+            if let Some(hll) = self.get_hyperloglog(key)? {
+                merge_max(&mut registers, &hll.registers()?);
+            }
+        }
+        Ok(count_registers(&registers))
+    }
+    //PFMERGE, the destination is one of the sources and stays sparse unless a source is dense
+    pub fn merge_hyperloglog(&mut self, destination: &str, sources: &[String]) -> Result<()> {
+        let mut registers = vec![0u8; HLL_REGISTERS];
+        let mut dense = false;
+        for key in std::iter::once(&destination.to_string()).chain(sources) {
+            if let Some(hll) = self.get_hyperloglog(key)? {
+                dense = dense || !hll.is_sparse();
+                merge_max(&mut registers, &hll.registers()?);
+            }
+        }
+        if self.get_hyperloglog(destination)?.is_none() {
+            self.collections.insert(
+                destination.to_string(),
+                (StoreValueType::HyperLogLog(HyperLogLog::new()), None),
+            );
+        }
+        self.get_hyperloglog_mut(destination)?
+            .unwrap()
+            .merge_registers(&registers, dense)?;
+        self.touch(destination);
+        self.notify(NOTIFY_STRING, "pfadd", destination);
+        Ok(())
+    }
+    //PFDEBUG TODENSE, None when the key does not exist
+    pub fn hyperloglog_to_dense(&mut self, key: &str) -> Result<Option<bool>> {
+        let converted = match self.get_hyperloglog_mut(key)? {
+            Some(hll) => hll.make_dense()?,
+            None => return Ok(None),
+        };
+        if converted {
+            self.touch(key);
+        }
+        Ok(Some(converted))
+    }
+    //A string that is not a HyperLogLog is an error of its own
+    pub fn get_hyperloglog(&self, key: &str) -> Result<Option<&HyperLogLog>> {
+        self.check_type(key, "string")?;
+        match self.get_alive(key) {
+            Some(StoreValueType::HyperLogLog(hll)) => Ok(Some(hll)),
+            Some(_) => Err(anyhow::anyhow!(WRONGTYPE_HLL)),
+            None => Ok(None),
+        }
+    }
+    fn get_hyperloglog_mut(&mut self, key: &str) -> Result<Option<&mut HyperLogLog>> {
+        if self.get_hyperloglog(key)?.is_none() {
+            return Ok(None);
+        }
+        match self.collections.get_mut(key) {
+            Some((StoreValueType::HyperLogLog(hll), _)) => Ok(Some(hll)),
+            _ => Ok(None),
         }
-        Ok(None)
     }
     // pub fn get_all(&self) -> Result<Vec<(String, String)>>{
     //     let mut result = Vec::new();
@@ -181,3 +1707,55 @@ impl Store {
     //     Ok(result)
     // }
 }
+
+//Register wise maximum, how HyperLogLogs are merged
+fn merge_max(registers: &mut [u8], other: &[u8]) {
+    for (register, value) in registers.iter_mut().zip(other) {
+        *register = (*register).max(*value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strings_changed_in_place_are_raw() {
+        let mut store = Store::new();
+        store.set_value("s", b"hello", None).unwrap();
+        assert_eq!(store.get_encoding("s"), Some("embstr"));
+        store.update_string("s", "append", |bytes| bytes.extend(b" world")).unwrap();
+        assert_eq!(store.get_encoding("s"), Some("raw"));
+        //a new SET makes it embstr again
+        store.set_value("s", b"hello", None).unwrap();
+        assert_eq!(store.get_encoding("s"), Some("embstr"));
+
+        store.set_value("n", b"12", None).unwrap();
+        assert_eq!(store.get_encoding("n"), Some("int"));
+        store.update_string("n", "append", |bytes| bytes.push(b'3')).unwrap();
+        assert_eq!(store.get_encoding("n"), Some("raw"));
+        //INCR still reads it as a number
+        assert_eq!(store.increase_by("n", 1).unwrap(), 124);
+        assert_eq!(store.get_encoding("n"), Some("int"));
+
+        store.update_string("r", "setrange", |bytes| bytes.extend(b"abc")).unwrap();
+        assert_eq!(store.get_encoding("r"), Some("raw"));
+        store.update_string("b", "setbit", |bytes| crate::store::bitmap::set_bit(bytes, 7, 1)).unwrap();
+        assert_eq!(store.get_encoding("b"), Some("raw"));
+        store.store_string("d", b"x".to_vec(), "set");
+        assert_eq!(store.get_encoding("d"), Some("raw"));
+    }
+
+    #[test]
+    fn keep_ttl_keeps_the_old_expiry() {
+        let mut store = Store::new();
+        store.set_value("k", b"1.5", Some("100000")).unwrap();
+        let expiry = store.collections["k"].1;
+        store.set_value_keep_ttl("k", b"1.6").unwrap();
+        assert_eq!(store.collections["k"].1, expiry);
+        assert_eq!(store.get_value("k").unwrap().to_bytes().as_ref(), b"1.6");
+        //a key without a ttl stays without one
+        store.set_value_keep_ttl("new", b"v").unwrap();
+        assert_eq!(store.collections["new"].1, None);
+    }
+}
@@ -1,48 +1,67 @@
-use crate::{resp::value::Value};
-use anyhow::Result;
-use std::{collections::VecDeque};
+use crate::{resp::value::Value, store::store::Store};
 
 #[derive(Clone)]
 pub struct Transaction {
-    queue: VecDeque<Value>,
+    in_multi: bool,
+    queue: Vec<(String, Vec<Value>)>,
+    //a command failed to queue, EXEC must refuse the whole transaction
+    aborted: bool,
+    //queued commands are running, blocking commands must not wait
+    executing: bool,
+    //key, version and whether it existed when WATCH was called
+    watched: Vec<(String, u64, bool)>,
 }
 impl Transaction {
     pub fn new() -> Self {
         Transaction {
-            queue: VecDeque::new(),
+            in_multi: false,
+            queue: Vec::new(),
+            aborted: false,
+            executing: false,
+            watched: Vec::new(),
         }
     }
-    pub fn push_back(&mut self, value: &Value) -> Result<()> {
-        self.queue.push_back(value.clone());
-        Ok(())
-    }
-    pub fn get_font(&mut self) -> Option<&mut Value> {
-        self.queue.get_mut(0)
-    }
-    pub fn get_font_value(&mut self) -> Option<Value> {
-        let value = match self.queue.get(0) {
-            Some(value) => value.clone(),
-            None => return None,
-        };
-        self.queue.remove(0).unwrap();
-        Some(value)
-    }
-    pub fn len(&self) -> usize {
-        self.queue.len()
-    }
-    pub fn put(&mut self, command: &str, command_content: &mut Vec<Value>) -> Result<Option<Value>>{
-        if let Some(value) = self.get_font() {
-            if value == &Value::BulkString("MULTI".to_string()) {
-                let mut full_cmd = vec![Value::BulkString(command.to_string())];
-                full_cmd.append(command_content);
-                self
-                    .push_back(&Value::Array(full_cmd))
-                    .unwrap();
-                return Ok(Some(Value::SimpleString("QUEUED".to_string())));
-            }
-            //if it has no multi, then return none to execute agular
-            return Ok(None);
-        }
-        else{ Ok(None) }
+    pub fn begin(&mut self) {
+        self.in_multi = true;
+        self.queue.clear();
+        self.aborted = false;
+    }
+    pub fn is_in_multi(&self) -> bool {
+        self.in_multi
+    }
+    pub fn queue(&mut self, command: String, command_content: Vec<Value>) -> Value {
+        self.queue.push((command, command_content));
+        Value::SimpleString("QUEUED".to_string())
+    }
+    pub fn abort(&mut self) {
+        self.aborted = true;
+    }
+    pub fn is_aborted(&self) -> bool {
+        self.aborted
+    }
+    //Leave MULTI and hand over the queued commands
+    pub fn take_queue(&mut self) -> Vec<(String, Vec<Value>)> {
+        self.in_multi = false;
+        self.aborted = false;
+        std::mem::take(&mut self.queue)
     }
-}
\ No newline at end of file
+    pub fn set_executing(&mut self, executing: bool) {
+        self.executing = executing;
+    }
+    pub fn is_executing(&self) -> bool {
+        self.executing
+    }
+    pub fn watch(&mut self, key: &str, storage: &Store) {
+        self.watched
+            .push((key.to_string(), storage.get_version(key), storage.exists(key)));
+    }
+    pub fn unwatch(&mut self) {
+        self.watched.clear();
+    }
+    //A watched key was written, deleted or expired since WATCH
+    pub fn is_watch_dirty(&self, storage: &Store) -> bool {
+        self.watched.iter().any(|(key, version, existed)| {
+            storage.get_version(key) != *version || (*existed && !storage.exists(key))
+        })
+    }
+}